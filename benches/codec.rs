@@ -0,0 +1,49 @@
+//! Benchmarks for the codec hot paths: decoding commands and
+//! status reports as they arrive, and encoding commands to send.
+use bytes::{Bytes, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use lights::codec::{decode_with, encode_into, options1, Message, Priority, SR_CHK};
+
+fn decode(c: &mut Criterion) {
+    let options = options1();
+    let command = Bytes::from_static(b"05003800790446");
+    let multiple = Bytes::from_static(b"050038007904010540");
+    let status = Bytes::from_static(b"86081500F74038B000000000000000000000000000000000000000003E");
+    let levels = Bytes::from_static(
+        b"86081500F9073810A5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAA5AAE9",
+    );
+
+    for frame in [&command, &multiple, &status, &levels] {
+        let m = decode_with(frame.clone(), &options);
+        assert!(!matches!(m[..], [Message::Unrecognised(..)]), "{m:?}");
+    }
+
+    c.bench_function("decode command", |b| {
+        b.iter(|| decode_with(black_box(command.clone()), &options))
+    });
+    c.bench_function("decode multiple commands", |b| {
+        b.iter(|| decode_with(black_box(multiple.clone()), &options))
+    });
+    c.bench_function("decode 20 group status", |b| {
+        b.iter(|| decode_with(black_box(status.clone()), &options))
+    });
+    c.bench_function("decode 20 group levels", |b| {
+        b.iter(|| decode_with(black_box(levels.clone()), &options))
+    });
+}
+
+fn encode(c: &mut Criterion) {
+    let mesg = Message::set(4).level(50).ramp_secs(30).build().unwrap();
+    let mut buf = BytesMut::with_capacity(64);
+
+    c.bench_function("encode setvar", |b| {
+        b.iter(|| {
+            buf.clear();
+            encode_into(black_box(&mesg), &SR_CHK, Priority::Low, None, &mut buf);
+        })
+    });
+}
+
+criterion_group!(benches, decode, encode);
+criterion_main!(benches);
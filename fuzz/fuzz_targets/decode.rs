@@ -0,0 +1,23 @@
+//! Feed arbitrary frames through the decoder and echo what it recognises.
+//!
+//! Run with `cargo fuzz run decode` from the repository root.
+#![no_main]
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/codec.rs"]
+mod codec;
+
+use codec::{decode_with, echo, Setting, SR_CHK};
+
+fuzz_target!(|data: &[u8]| {
+    let frame = Bytes::copy_from_slice(data);
+    for options in [Setting::new(0), SR_CHK] {
+        for mesg in decode_with(frame.clone(), &options) {
+            if let Some(echoed) = echo(&mesg) {
+                assert_eq!(decode_with(echoed, &SR_CHK), [mesg]);
+            }
+        }
+    }
+});
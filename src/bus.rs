@@ -0,0 +1,385 @@
+//! `bus` names the subscribers to the broadcast channels and counts
+//! the messages each one misses.
+//!
+//! A subscriber that falls more than the channel capacity behind loses
+//! the oldest messages.  Rather than pass that on as an error, the loss
+//! is logged and counted and the subscriber carries on.
+//!
+//! Each subscriber to inbound events has its own channel and a
+//! [`Filter`], so that it is only woken, and can only fall behind, for
+//! the events it wants.
+use crate::codec::{Application, Group, Message};
+use crate::time::{Clock, System};
+use crate::{Envelope, Event, Outbound, Source};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError, error::SendError, Receiver, Sender};
+use tracing::warn;
+
+/// The capacity of each channel: how far a subscriber may fall behind.
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelConfig {
+    pub inbound: usize,
+    pub outbound: usize,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            inbound: 16,
+            outbound: 16,
+        }
+    }
+}
+
+/// The inbound events a subscriber wants.  Each criterion given narrows
+/// the selection and each value given for it widens it again.
+///
+/// ```ignore
+/// let lighting = Filter::all().source(Source::Cbus).application(0x38);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    sources: Option<Vec<Source>>,
+    applications: Option<Vec<Application>>,
+    groups: Option<Vec<Group>>,
+}
+
+impl Filter {
+    /// Every event.
+    pub fn all() -> Self {
+        Filter::default()
+    }
+
+    /// Events from `source`.
+    pub fn source(mut self, source: Source) -> Self {
+        self.sources.get_or_insert_with(Vec::new).push(source);
+        self
+    }
+
+    /// Events carrying a message for application `app`.
+    pub fn application(mut self, app: u8) -> Self {
+        let apps = self.applications.get_or_insert_with(Vec::new);
+        apps.push(Application(app));
+        self
+    }
+
+    /// Events carrying a message about `group`, in any application.
+    pub fn group(mut self, group: u8) -> Self {
+        self.groups.get_or_insert_with(Vec::new).push(Group(group));
+        self
+    }
+
+    /// Whether `envelope` is wanted.  Events without a CBUS message,
+    /// such as HMI posts, are selected by source alone.
+    pub fn matches(&self, envelope: &Envelope) -> bool {
+        if let Some(sources) = &self.sources {
+            if !sources.contains(&envelope.source) {
+                return false;
+            }
+        }
+        match envelope.event.message() {
+            Some(mesg) => self.for_application(mesg) && self.for_group(mesg),
+            None => true,
+        }
+    }
+
+    fn for_application(&self, mesg: &Message) -> bool {
+        match &self.applications {
+            Some(apps) => mesg.application().is_some_and(|a| apps.contains(&a)),
+            None => true,
+        }
+    }
+
+    fn for_group(&self, mesg: &Message) -> bool {
+        let Some(groups) = &self.groups else {
+            return true;
+        };
+        if let Some(g) = mesg.group() {
+            return groups.contains(g);
+        }
+        // a status report covers many groups
+        mesg.group_states()
+            .is_some_and(|states| states.iter().any(|(g, _)| groups.contains(g)))
+    }
+}
+
+/// A subscriber's channel and what it wants sent to it.
+#[derive(Debug)]
+struct Route {
+    filter: Filter,
+    sender: Sender<Envelope>,
+}
+
+/// The subscribers to inbound events and the next sequence number.
+#[derive(Debug, Default)]
+struct Routes {
+    seq: u64,
+    routes: Vec<Route>,
+}
+
+/// The inbound channel, which numbers and timestamps each event and
+/// passes it to the subscribers that want it.
+#[derive(Debug, Clone)]
+pub struct Inbound {
+    capacity: usize,
+    routes: Arc<Mutex<Routes>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Inbound {
+    /// A channel on which each subscriber may fall `capacity` behind.
+    pub fn new(capacity: usize) -> Self {
+        Inbound::with_clock(capacity, Arc::new(System))
+    }
+
+    /// A channel whose events are stamped by `clock`.
+    pub fn with_clock(capacity: usize, clock: Arc<dyn Clock>) -> Self {
+        Inbound {
+            capacity,
+            routes: Arc::default(),
+            clock,
+        }
+    }
+
+    /// The clock that stamps events, for publishers to time by.
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Subscribe as `name` to the events selected by `filter`, counting
+    /// missed events in `lags`.
+    pub fn subscribe(
+        &self,
+        name: &'static str,
+        filter: Filter,
+        lags: &Lags,
+    ) -> Subscriber<Envelope> {
+        let (sender, _) = broadcast::channel(self.capacity);
+        let subscriber = subscribe(&sender, name, lags);
+        let route = Route { filter, sender };
+        self.routes.lock().unwrap().routes.push(route);
+        subscriber
+    }
+
+    /// Send `event` from `source` to the subscribers that want it and
+    /// return how many those are, failing if there are no subscribers.
+    pub fn publish(&self, source: Source, event: Event) -> Result<usize, SendError<Envelope>> {
+        let mut routes = self.routes.lock().unwrap();
+        routes.routes.retain(|r| r.sender.receiver_count() > 0);
+        let envelope = Envelope {
+            seq: routes.seq,
+            source,
+            at: self.clock.now(),
+            time: self.clock.wall(),
+            event,
+        };
+        routes.seq += 1;
+        if routes.routes.is_empty() {
+            return Err(SendError(envelope));
+        }
+        let mut sent = 0;
+        for route in routes.routes.iter().filter(|r| r.filter.matches(&envelope)) {
+            if route.sender.send(envelope.clone()).is_ok() {
+                sent += 1;
+            }
+        }
+        Ok(sent)
+    }
+}
+
+/// The inbound and outbound channels and the lag of their subscribers.
+#[derive(Debug, Clone)]
+pub struct Bus {
+    pub inbound: Inbound,
+    pub outbound: Sender<Outbound>,
+    pub lags: Lags,
+}
+
+impl Bus {
+    pub fn new(config: ChannelConfig) -> Self {
+        Bus::with_clock(config, Arc::new(System))
+    }
+
+    /// The channels, timed by `clock`.
+    pub fn with_clock(config: ChannelConfig, clock: Arc<dyn Clock>) -> Self {
+        let (outbound, _) = broadcast::channel(config.outbound);
+        Bus {
+            inbound: Inbound::with_clock(config.inbound, clock),
+            outbound,
+            lags: Lags::default(),
+        }
+    }
+
+    /// The clock the daemons on this bus go by.
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        self.inbound.clock()
+    }
+
+    /// Subscribe to every inbound event as `name`.
+    pub fn events(&self, name: &'static str) -> Subscriber<Envelope> {
+        self.filtered(name, Filter::all())
+    }
+
+    /// Subscribe to the inbound events selected by `filter` as `name`.
+    pub fn filtered(&self, name: &'static str, filter: Filter) -> Subscriber<Envelope> {
+        self.inbound.subscribe(name, filter, &self.lags)
+    }
+
+    /// Subscribe to outbound messages as `name`.
+    pub fn messages(&self, name: &'static str) -> Subscriber<Outbound> {
+        subscribe(&self.outbound, name, &self.lags)
+    }
+}
+
+/// Messages missed so far, by subscriber name.
+pub type Lags = Arc<Mutex<BTreeMap<&'static str, LagInfo>>>;
+
+/// The messages missed by one subscriber.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct LagInfo {
+    /// Messages lost in total.
+    pub dropped: u64,
+    /// Occasions on which messages were lost.
+    pub episodes: u64,
+}
+
+/// A named receiver that counts the messages it misses.
+#[derive(Debug)]
+pub struct Subscriber<T> {
+    name: &'static str,
+    receiver: Receiver<T>,
+    lags: Lags,
+}
+
+/// Subscribe to `sender` as `name`, counting missed messages in `lags`.
+pub fn subscribe<T: Clone>(sender: &Sender<T>, name: &'static str, lags: &Lags) -> Subscriber<T> {
+    Subscriber {
+        name,
+        receiver: sender.subscribe(),
+        lags: lags.clone(),
+    }
+}
+
+impl<T: Clone> Subscriber<T> {
+    /// The next message, or `None` once the channel is closed.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(t) => return Some(t),
+                Err(RecvError::Lagged(n)) => self.lagged(n),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    fn lagged(&self, n: u64) {
+        let dropped = {
+            let mut lags = self.lags.lock().unwrap();
+            let info = lags.entry(self.name).or_default();
+            info.dropped += n;
+            info.episodes += 1;
+            info.dropped
+        };
+        warn!(
+            subscriber = self.name,
+            dropped, "lagging, missed {n} messages"
+        );
+    }
+}
+
+impl Subscriber<Envelope> {
+    /// The next event without its envelope, or `None` once the channel
+    /// is closed.
+    pub async fn event(&mut self) -> Option<Event> {
+        self.recv().await.map(|e| e.event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{CLOCK, LIGHTING, OFF, ON};
+    use tokio::sync::broadcast;
+
+    #[tokio::test]
+    async fn counts_missed() {
+        let lags = Lags::default();
+        let (sender, _) = broadcast::channel(2);
+        let mut slow = subscribe(&sender, "slow", &lags);
+        for n in 0..5 {
+            sender.send(n).unwrap();
+        }
+        assert_eq!(slow.recv().await, Some(3));
+        assert_eq!(slow.recv().await, Some(4));
+        drop(sender);
+        assert_eq!(slow.recv().await, None);
+        let info = LagInfo {
+            dropped: 3,
+            episodes: 1,
+        };
+        assert_eq!(lags.lock().unwrap()["slow"], info);
+    }
+
+    #[tokio::test]
+    async fn numbered() {
+        let bus = Bus::new(ChannelConfig::default());
+        let mut events = bus.events("test");
+        bus.inbound
+            .publish(Source::Cbus, Event::Connected(0))
+            .unwrap();
+        bus.inbound
+            .publish(Source::Control, Event::Connected(1))
+            .unwrap();
+        let first = events.recv().await.unwrap();
+        let second = events.recv().await.unwrap();
+        assert_eq!((first.seq, first.source), (0, Source::Cbus));
+        assert_eq!((second.seq, second.source), (1, Source::Control));
+        assert!(second.at >= first.at);
+        assert_eq!(second.event, Event::Connected(1));
+    }
+
+    #[tokio::test]
+    async fn filtered() {
+        let bus = Bus::new(ChannelConfig {
+            inbound: 2,
+            outbound: 2,
+        });
+        let clock = Filter::all().source(Source::Cbus).application(CLOCK.0);
+        let mut clocks = bus.filtered("clock", clock);
+        let mut kitchen = bus.filtered("kitchen", Filter::all().group(4));
+        let flood = Message::LevelStatus(LIGHTING, vec![(Group(8), ON), (Group(4), OFF)]);
+        for _ in 0..5 {
+            bus.inbound
+                .publish(Source::Cbus, Event::Cbus(0, flood.clone()))
+                .unwrap();
+        }
+        let request = Event::Cbus(0, Message::ClockRequest);
+        bus.inbound
+            .publish(Source::Control, request.clone())
+            .unwrap();
+        assert_eq!(
+            bus.inbound.publish(Source::Cbus, request.clone()).unwrap(),
+            1
+        );
+        bus.inbound
+            .publish(Source::Hmi, Event::Connected(0))
+            .unwrap();
+
+        let heard = clocks.recv().await.unwrap();
+        assert_eq!((heard.seq, heard.event), (6, request));
+        assert!(!bus.lags.lock().unwrap().contains_key("clock"));
+        // status reports cover many groups
+        assert_eq!(kitchen.event().await, Some(Event::Cbus(0, flood)));
+        assert_eq!(bus.lags.lock().unwrap()["kitchen"].dropped, 4);
+        assert_eq!(kitchen.event().await, Some(Event::Connected(0)));
+
+        drop((clocks, kitchen));
+        assert!(bus
+            .inbound
+            .publish(Source::Hmi, Event::Connected(0))
+            .is_err());
+    }
+}
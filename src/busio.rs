@@ -1,27 +1,168 @@
 //! Buffered reader tailored to the CBUS serial interface.
+//!
+//! The interface is normally ASCII: hex encoded frames ending in CR or LF.
+//! In binary mode each frame is a packet: a length byte then the frame.
+//! If the high bit of the length is set the packet is ASCII text, such as
+//! a confirmation or a reset, otherwise it is the frame's bytes before hex encoding.
+//!
+//! Terminal servers that speak telnet are handled by wrapping the
+//! connection in `Telnet`, which removes option negotiation from the input.
 
-use bytes::{Bytes, BytesMut};
-use nom::character::streaming::{line_ending, not_line_ending};
-use nom::sequence::pair;
-use nom::IResult;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter, Write};
 use std::future::Future;
 use std::io::{Error, ErrorKind};
 use std::pin::Pin;
-use tokio::io::{self, AsyncRead, AsyncReadExt};
-use tokio::sync::mpsc::Sender;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::{pin, select};
+use tokio_util::sync::CancellationToken;
 
 const LINE_LEN: usize = 1024;
 const CHUNK_LEN: usize = 4096;
+const TEXT_BIT: u8 = 0x80;
+const LONG_LINE_HEAD: usize = 32;
 
-/// Read available input up to CHUNK_LEN bytes and append it to a buffer
-async fn read_more<I>(inp: &mut I, buf: &mut BytesMut) -> io::Result<()>
+/// How frames are delimited on the wire.
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Framing {
+    Ascii,
+    Binary,
+}
+
+/// Buffer limits for reading lines and packets.
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct LineReaderConfig {
+    /// The longest line accepted, longer lines are dropped.
+    pub line_len: usize,
+    /// The most input read at once.
+    pub chunk_len: usize,
+}
+
+impl Default for LineReaderConfig {
+    fn default() -> Self {
+        LineReaderConfig {
+            line_len: LINE_LEN,
+            chunk_len: CHUNK_LEN,
+        }
+    }
+}
+
+/// Counters describing a link, shared by its reader and writer.
+#[derive(Debug, Default)]
+pub struct IoStats {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    lines: AtomicU64,
+    long_lines: AtomicU64,
+    reconnects: AtomicU64,
+}
+
+/// The values of the `IoStats` counters at one time.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Serialize)]
+pub struct StatsSnapshot {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub lines: u64,
+    pub long_lines: u64,
+    pub reconnects: u64,
+}
+
+impl IoStats {
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        StatsSnapshot {
+            bytes_read: get(&self.bytes_read),
+            bytes_written: get(&self.bytes_written),
+            lines: get(&self.lines),
+            long_lines: get(&self.long_lines),
+            reconnects: get(&self.reconnects),
+        }
+    }
+
+    /// Count a new connection after the first.
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+impl Display for StatsSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes in, {} bytes out, {} lines, {} long lines dropped, {} reconnects",
+            self.bytes_read, self.bytes_written, self.lines, self.long_lines, self.reconnects
+        )
+    }
+}
+
+/// A connection, or either half of one, that counts the bytes through it.
+pub struct Metered<S> {
+    inner: S,
+    stats: Arc<IoStats>,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, stats: Arc<IoStats>) -> Self {
+        Metered { inner, stats }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        IoStats::add(&this.stats.bytes_read, buf.filled().len() - start);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        IoStats::add(&this.stats.bytes_written, n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Read available input up to `chunk_len` bytes and append it to a buffer
+///
+/// Reads into the spare capacity of the buffer, which is not initialised first.
+async fn read_more<I>(inp: &mut I, buf: &mut BytesMut, chunk_len: usize) -> io::Result<()>
 where
     I: AsyncRead + Unpin,
 {
-    let start = buf.len();
-    buf.resize(start + CHUNK_LEN, 0); // could use set_len() to save initialisation
-    let fresh = inp.read(&mut buf[start..]).await?;
-    buf.truncate(start + fresh);
+    buf.reserve(chunk_len);
+    let fresh = inp.read_buf(&mut (&mut *buf).limit(chunk_len)).await?;
     if fresh > 0 {
         Ok(())
     } else {
@@ -30,93 +171,655 @@ where
 }
 
 /// Split a line from the buffer, if possible.
-fn split_line(buf: &mut BytesMut) -> Option<Bytes> {
-    let p: IResult<&[u8], _> = pair(not_line_ending, line_ending)(buf);
-    match p {
-        Ok((_, (nle, le))) => {
-            let n = nle.len();
-            let m = le.len();
-            let line = buf.split_to(n);
-            drop(buf.split_to(m));
-            Some(line.freeze())
-        }
-        Err(_) => None,
-    }
+///
+/// A line ends with CR, LF or CRLF.  A CR at the end of the buffer is
+/// taken as a line end, so the LF of a CRLF split across reads yields
+/// an empty line.
+///
+/// `scanned` is how much of the buffer is known to hold no line end.
+/// It is updated so that a partial line is not scanned again.
+fn split_line(buf: &mut BytesMut, scanned: &mut usize) -> Option<Bytes> {
+    let start = (*scanned).min(buf.len());
+    let end = buf[start..].iter().position(|c| *c == b'\r' || *c == b'\n');
+    let Some(end) = end.map(|n| start + n) else {
+        *scanned = buf.len();
+        return None;
+    };
+    let ending = match &buf[end..] {
+        [b'\r', b'\n', ..] => 2,
+        _ => 1,
+    };
+    let line = buf.split_to(end).freeze();
+    buf.advance(ending);
+    *scanned = 0;
+    Some(line)
 }
 
 /// Drop all input up to and including the next line end.
-async fn drop_long_line<I>(inp: &mut I, buf: &mut BytesMut) -> io::Result<()>
+///
+/// Returns the length of the line dropped, including what was in the buffer.
+async fn drop_long_line<I>(inp: &mut I, buf: &mut BytesMut, chunk_len: usize) -> io::Result<usize>
 where
     I: AsyncRead + Unpin,
 {
+    let mut dropped = 0;
     loop {
+        dropped += buf.len();
         buf.clear();
-        read_more(inp, buf).await?;
-        if let Some(line) = split_line(buf) {
-            drop(line);
-            break Ok(());
+        read_more(inp, buf, chunk_len).await?;
+        if let Some(line) = split_line(buf, &mut 0) {
+            break Ok(dropped + line.len());
         }
     }
 }
 
-/// Emit the complete lines currently in the buffer.
-async fn emit_lines<O, F>(buf: &mut BytesMut, out: &mut O)
+/// What the line reader found in the input.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Line {
+    /// A line without its ending.
+    Text(Bytes),
+    /// A line too long to keep: its first `LONG_LINE_HEAD` bytes and its length.
+    TooLong(Bytes, usize),
+}
+
+impl Line {
+    fn too_long(head: &[u8], len: usize) -> Line {
+        let head = &head[..head.len().min(LONG_LINE_HEAD)];
+        Line::TooLong(Bytes::copy_from_slice(head), len)
+    }
+}
+
+/// A stream of lines read from the input.
+///
+/// Reads `chunk_len` bytes at a time or as available and yields
+/// each line without its line ending.  Empty lines are skipped.
+///
+/// Any line longer than `line_len` is dropped and reported as `TooLong`.
+/// The buffer space requirement is not affected by long lines.
+///
+/// The stream ends after the first error.  If the end of the
+/// input is reached an `UnexpectedEof` error is yielded.
+pub fn lines<I>(
+    inp: I,
+    config: LineReaderConfig,
+    stats: Arc<IoStats>,
+) -> impl Stream<Item = io::Result<Line>>
 where
-    O: FnMut(Bytes) -> F,
-    F: Future<Output = ()>,
+    I: AsyncRead + Unpin,
 {
-    while let Some(line) = split_line(buf) {
-        if line.len() <= LINE_LEN {
-            out(line).await;
+    let LineReaderConfig {
+        line_len,
+        chunk_len,
+    } = config;
+    let buf = BytesMut::with_capacity(line_len + chunk_len);
+
+    stream::unfold(Some((inp, buf, 0, stats)), move |state| async move {
+        let (mut inp, mut buf, mut scanned, stats) = state?;
+
+        // each iteration emits a line or reads a chunk containing 0 or more lines and 0 or 1 partial line.
+        // (a partial line is scanned once, then only the input that extends it)
+        loop {
+            while let Some(line) = split_line(&mut buf, &mut scanned) {
+                if line.is_empty() {
+                    continue;
+                }
+                let line = if line.len() <= line_len {
+                    IoStats::add(&stats.lines, 1);
+                    Line::Text(line)
+                } else {
+                    IoStats::add(&stats.long_lines, 1);
+                    Line::too_long(&line, line.len())
+                };
+                return Some((Ok(line), Some((inp, buf, scanned, stats))));
+            }
+
+            // remainder in buf is a partial line longer than the limit
+            if buf.len() > line_len {
+                IoStats::add(&stats.long_lines, 1);
+                scanned = 0;
+                let head = Bytes::copy_from_slice(&buf[..LONG_LINE_HEAD.min(buf.len())]);
+                let res = drop_long_line(&mut inp, &mut buf, chunk_len).await;
+                let line = res.map(|len| Line::too_long(&head, len));
+                let state = line.is_ok().then_some((inp, buf, scanned, stats));
+                return Some((line, state));
+            }
+            if let Err(e) = read_more(&mut inp, &mut buf, chunk_len).await {
+                return Some((Err(e), None));
+            }
         }
-    }
+    })
 }
 
 /// Continuously read lines from a stream.
 ///
-/// Reads CHUNK_LEN bytes at a time or as available and
-/// passes it line by line to a function or closure.
-///
-/// Ignores any line longer than LINE_LEN.
-/// The buffer space requirement is not affected by long lines.
+/// Passes each line, or report of a long line, from `lines`
+/// to a function or closure.
 ///
-/// Never returns normally (result type could be `!`).  
+/// Returns normally only when cancelled.
 /// If the end of the stream is reached an `UnexpectedEof`
 /// error is returned.
 ///
-pub async fn read_lines<I, O, F>(mut inp: I, mut out: O) -> io::Result<()>
+pub async fn read_lines<I, O, F>(
+    inp: I,
+    config: LineReaderConfig,
+    stats: Arc<IoStats>,
+    cancel: CancellationToken,
+    mut out: O,
+) -> io::Result<()>
 where
     I: AsyncRead + Unpin,
-    O: FnMut(Bytes) -> F,
+    O: FnMut(Line) -> F,
     F: Future<Output = ()>,
 {
-    let mut buf = BytesMut::with_capacity(LINE_LEN + CHUNK_LEN);
+    let lines = lines(inp, config, stats);
+    pin!(lines);
+    loop {
+        let line = select! {
+            _ = cancel.cancelled() => return Ok(()),
+            line = lines.next() => line,
+        };
+        match line {
+            Some(line) => out(line?).await,
+            None => return Err(Error::from(ErrorKind::UnexpectedEof)),
+        }
+    }
+}
+
+/// Split a packet from the buffer, if complete.
+fn split_packet(buf: &mut BytesMut) -> Option<Bytes> {
+    let n = usize::from(*buf.first()? & !TEXT_BIT);
+    if buf.len() > n {
+        Some(buf.split_to(n + 1).freeze())
+    } else {
+        None
+    }
+}
 
-    // each iteration reads a chunk containing 0 or more lines and 0 or 1 partial line.
-    // (if input arrives in small pieces a partial line maybe repeatedly scanned).
+/// Continuously read packets from a stream in binary mode.
+///
+/// Each packet is passed to a function or closure as the
+/// ASCII line it stands for, so it can be decoded as usual.
+/// Packets are never too long.
+///
+/// Returns normally only when cancelled. If the end of the stream
+/// is reached an `UnexpectedEof` error is returned.
+pub async fn read_packets<I, O, F>(
+    mut inp: I,
+    config: LineReaderConfig,
+    stats: Arc<IoStats>,
+    cancel: CancellationToken,
+    mut out: O,
+) -> io::Result<()>
+where
+    I: AsyncRead + Unpin,
+    O: FnMut(Line) -> F,
+    F: Future<Output = ()>,
+{
+    let mut buf = BytesMut::with_capacity(config.chunk_len);
     loop {
-        read_more(&mut inp, &mut buf).await?;
-        emit_lines(&mut buf, &mut out).await;
+        select! {
+            _ = cancel.cancelled() => return Ok(()),
+            res = read_more(&mut inp, &mut buf, config.chunk_len) => res?,
+        }
+        while let Some(packet) = split_packet(&mut buf) {
+            IoStats::add(&stats.lines, 1);
+            out(Line::Text(packet_to_line(&packet))).await;
+        }
+    }
+}
+
+/// The ASCII line a packet, including its length byte, stands for.
+pub fn packet_to_line(packet: &[u8]) -> Bytes {
+    match packet {
+        [n, text @ ..] if n & TEXT_BIT != 0 => Bytes::copy_from_slice(text),
+        [_, frame @ ..] => {
+            let mut line = String::with_capacity(2 * frame.len());
+            for b in frame {
+                let _ = write!(line, "{b:02X}");
+            }
+            Bytes::from(line)
+        }
+        [] => Bytes::new(),
+    }
+}
+
+/// The packet for an encoded ASCII command.
+///
+/// The prefix character and any confirmation code are kept as they are
+/// and the hex digits between them are sent as bytes. Commands without
+/// hex digits, such as a reset, are sent as text.
+pub fn line_to_packet(line: &[u8]) -> Bytes {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let mut packet = BytesMut::with_capacity(line.len());
+    match line {
+        [prefix @ (b'\\' | b'@'), rest @ ..] => {
+            let digits = rest.iter().take_while(|c| c.is_ascii_hexdigit()).count() & !1;
+            let (hex, code) = rest.split_at(digits);
+            packet.put_u8(0);
+            packet.put_u8(*prefix);
+            for pair in hex.chunks_exact(2) {
+                let digit = |c: u8| (c as char).to_digit(16).unwrap_or(0) as u8;
+                packet.put_u8(digit(pair[0]) << 4 | digit(pair[1]));
+            }
+            packet.put_slice(code);
+            packet[0] = (packet.len() - 1).min(usize::from(!TEXT_BIT)) as u8;
+        }
+        text => {
+            packet.put_u8(TEXT_BIT | text.len().min(usize::from(!TEXT_BIT)) as u8);
+            packet.put_slice(text);
+        }
+    }
+    packet.freeze()
+}
+
+/// Write an encoded ASCII command with the given framing.
+pub async fn write_frame<O>(output: &mut O, framing: Framing, line: &[u8]) -> io::Result<()>
+where
+    O: AsyncWrite + Unpin,
+{
+    match framing {
+        Framing::Ascii => output.write_all(line).await,
+        Framing::Binary => output.write_all(&line_to_packet(line)).await,
+    }
+}
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const BINARY: u8 = 0;
+const SGA: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+
+/// Ask a telnet server for an 8 bit clean link without go-aheads.
+pub const TELNET_OFFER: [u8; 12] = [
+    IAC, WILL, BINARY, IAC, DO, BINARY, IAC, WILL, SGA, IAC, DO, SGA,
+];
+
+/// Flow control on a serial port.
+#[derive(PartialEq, Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    #[default]
+    None,
+    XonXoff,
+    Hardware,
+}
+
+impl FromStr for FlowControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(FlowControl::None),
+            "xonxoff" => Ok(FlowControl::XonXoff),
+            "hardware" => Ok(FlowControl::Hardware),
+            _ => Err(format!("unknown flow control {s}")),
+        }
+    }
+}
+
+/// The RFC 2217 commands that set up a terminal server's serial port
+/// for the PCI: `baud`, 8 data bits, no parity, 1 stop bit and `flow`.
+///
+/// Replies from the server are removed from the input by `Telnet`.
+pub fn com_port_setup(baud: u32, flow: FlowControl) -> Vec<u8> {
+    let control = match flow {
+        FlowControl::None => 1,
+        FlowControl::XonXoff => 2,
+        FlowControl::Hardware => 3,
+    };
+    let settings: [(u8, &[u8]); 5] = [
+        (SET_BAUDRATE, &baud.to_be_bytes()),
+        (SET_DATASIZE, &[8]),
+        (SET_PARITY, &[1]),
+        (SET_STOPSIZE, &[1]),
+        (SET_CONTROL, &[control]),
+    ];
+    let mut commands = vec![IAC, WILL, COM_PORT_OPTION];
+    for (setting, value) in settings {
+        commands.extend_from_slice(&[IAC, SB, COM_PORT_OPTION, setting]);
+        for b in value {
+            // IAC is doubled within a subnegotiation
+            commands.extend_from_slice(if *b == IAC {
+                &[IAC, IAC]
+            } else {
+                std::slice::from_ref(b)
+            });
+        }
+        commands.extend_from_slice(&[IAC, SE]);
+    }
+    commands
+}
+
+/// Where the telnet filter is within a command.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+enum TelnetState {
+    #[default]
+    Data,
+    Cr,
+    Iac,
+    Opt,
+    Sub,
+    SubIac,
+}
+
+/// A telnet connection, or either half of one.
+///
+/// Input has commands and option negotiation removed and escaped
+/// IAC bytes restored.  Output has IAC bytes escaped.
+/// Options the server requests are not answered.
+pub struct Telnet<S> {
+    inner: S,
+    state: TelnetState,
+    owe_iac: bool,
+}
+
+impl<S> Telnet<S> {
+    pub fn new(inner: S) -> Self {
+        Telnet {
+            inner,
+            state: TelnetState::Data,
+            owe_iac: false,
+        }
+    }
 
-        // remainder in buf is a partial line longer than the limit
-        while buf.len() > LINE_LEN {
-            drop_long_line(&mut inp, &mut buf).await?;
-            emit_lines(&mut buf, &mut out).await;
+    /// Remove telnet commands from `data` in place, returning the length kept.
+    fn filter(&mut self, data: &mut [u8]) -> usize {
+        use TelnetState::*;
+        let mut kept = 0;
+        for i in 0..data.len() {
+            let b = data[i];
+            let (keep, next) = match (self.state, b) {
+                (Data | Cr, IAC) => (false, Iac),
+                (Cr, 0) => (false, Data),
+                (Data | Cr, b'\r') => (true, Cr),
+                (Data | Cr, _) => (true, Data),
+                (Iac, IAC) => (true, Data),
+                (Iac, WILL..=DONT) => (false, Opt),
+                (Iac, SB) => (false, Sub),
+                (Iac | Opt, _) => (false, Data),
+                (Sub, IAC) => (false, SubIac),
+                (Sub, _) => (false, Sub),
+                (SubIac, SE) => (false, Data),
+                (SubIac, _) => (false, Sub),
+            };
+            if keep {
+                data[kept] = b;
+                kept += 1;
+            }
+            self.state = next;
         }
+        kept
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Telnet<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let start = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            let fresh = buf.filled().len() - start;
+            if fresh == 0 {
+                return Poll::Ready(Ok(())); // end of input
+            }
+            let kept = this.filter(&mut buf.filled_mut()[start..]);
+            buf.set_filled(start + kept);
+            if kept > 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Telnet<S> {
+    /// Write the IAC owed from escaping an earlier one.
+    fn poll_owed(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.owe_iac {
+            if ready!(Pin::new(&mut self.inner).poll_write(cx, &[IAC]))? == 1 {
+                self.owe_iac = false;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Telnet<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_owed(cx))?;
+        // write up to and including the first IAC, then owe its escape
+        let end = buf
+            .iter()
+            .position(|b| *b == IAC)
+            .map_or(buf.len(), |i| i + 1);
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..end]))?;
+        this.owe_iac = n > 0 && buf[n - 1] == IAC;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_owed(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_owed(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::read_lines;
+    use super::*;
     use std::io::Cursor;
 
+    fn text(s: &'static str) -> Line {
+        Line::Text(Bytes::from(s))
+    }
+
+    #[tokio::test]
+    async fn line_stream() {
+        let long = [b'x'; LINE_LEN + 10];
+        let inp = Cursor::new([&b"hello\r\nworld\n"[..], &long, b"\nover\nand out"].concat());
+        let stats = Arc::new(IoStats::default());
+        let lines: Vec<_> = lines(inp, LineReaderConfig::default(), stats.clone())
+            .collect()
+            .await;
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0].as_ref().unwrap(), &text("hello"));
+        assert_eq!(lines[1].as_ref().unwrap(), &text("world"));
+        assert_eq!(
+            lines[2].as_ref().unwrap(),
+            &Line::TooLong(Bytes::copy_from_slice(&long[..LONG_LINE_HEAD]), long.len())
+        );
+        assert_eq!(lines[3].as_ref().unwrap(), &text("over"));
+        assert_eq!(
+            lines[4].as_ref().unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        assert_eq!(stats.snapshot().lines, 3);
+        assert_eq!(stats.snapshot().long_lines, 1);
+    }
+
+    #[test]
+    fn partial_scanned_once() {
+        let mut buf = BytesMut::from(&b"0500"[..]);
+        let mut scanned = 0;
+        assert_eq!(split_line(&mut buf, &mut scanned), None);
+        assert_eq!(scanned, 4);
+        buf.extend_from_slice(b"38\r\nnext");
+        assert_eq!(
+            split_line(&mut buf, &mut scanned),
+            Some(Bytes::from("050038"))
+        );
+        assert_eq!((&buf[..], scanned), (&b"next"[..], 0));
+    }
+
+    #[tokio::test]
+    async fn mixed_endings() {
+        let (inp, mut pci) = io::duplex(64);
+        let lines = lines(inp, LineReaderConfig::default(), Arc::default());
+        pin!(lines);
+        pci.write_all(b"one\rtwo\nthree\r\n\nfour\r").await.unwrap();
+        for expected in ["one", "two", "three", "four"] {
+            let line = lines.next().await.unwrap().unwrap();
+            assert_eq!(line, text(expected));
+        }
+        // the LF of a split CRLF is not a line
+        pci.write_all(b"\nfive\n").await.unwrap();
+        assert_eq!(lines.next().await.unwrap().unwrap(), text("five"));
+    }
+
+    #[tokio::test]
+    async fn metered() {
+        let stats = Arc::new(IoStats::default());
+        let mut inp = Metered::new(Cursor::new(b"hello\n"), stats.clone());
+        let mut out = Metered::new(Vec::new(), stats.clone());
+        io::copy(&mut inp, &mut out).await.unwrap();
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.bytes_read, snapshot.bytes_written), (6, 6));
+    }
+
+    #[tokio::test]
+    async fn cancelled() {
+        let (inp, mut pci) = io::duplex(64);
+        let cancel = CancellationToken::new();
+        let reader = tokio::spawn(read_lines(
+            inp,
+            LineReaderConfig::default(),
+            Arc::default(),
+            cancel.clone(),
+            |_| async {},
+        ));
+        pci.write_all(b"partial").await.unwrap();
+        cancel.cancel();
+        assert!(reader.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn small_buffers() {
+        let config = LineReaderConfig {
+            line_len: 4,
+            chunk_len: 2,
+        };
+        let inp = Cursor::new(b"hello\nall\nthe world\nover\n");
+        let lines: Vec<_> = lines(inp, config, Arc::default()).collect().await;
+        let lines: Vec<_> = lines.into_iter().map_while(Result::ok).collect();
+        assert_eq!(
+            lines,
+            [
+                Line::TooLong(Bytes::from("hello"), 5),
+                text("all"),
+                Line::TooLong(Bytes::from("the wo"), 9),
+                text("over")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn packets() {
+        let inp = Cursor::new(b"\x04\x05\x00\x38\x00\x82g.");
+        let mut lines = Vec::new();
+        let cancel = CancellationToken::new();
+        let config = LineReaderConfig::default();
+        let res = read_packets(inp, config, Arc::default(), cancel, |line| {
+            lines.push(line);
+            async {}
+        })
+        .await;
+        assert_eq!(lines, [text("05003800"), text("g.")]);
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn command_packets() {
+        let p = line_to_packet(b"\\053800790446g\r");
+        assert_eq!(&p[..], b"\x08\\\x05\x38\x00\x79\x04\x46g");
+        assert_eq!(&line_to_packet(b"~")[..], b"\x81~");
+    }
+
+    #[tokio::test]
+    async fn telnet_input() {
+        let raw = [
+            &TELNET_OFFER[..],
+            b"05\xff\xff38\r\0",
+            &[IAC, SB, 24, 1, IAC, IAC, IAC, SE],
+            b"g.\r\n",
+        ]
+        .concat();
+        let mut text = Vec::new();
+        Telnet::new(Cursor::new(raw))
+            .read_to_end(&mut text)
+            .await
+            .unwrap();
+        assert_eq!(text, b"05\xff38\rg.\r\n");
+    }
+
+    #[test]
+    fn com_port() {
+        let setup = com_port_setup(9600, FlowControl::None);
+        assert_eq!(&setup[..3], &[IAC, WILL, COM_PORT_OPTION]);
+        let baud = [
+            IAC,
+            SB,
+            COM_PORT_OPTION,
+            SET_BAUDRATE,
+            0,
+            0,
+            0x25,
+            0x80,
+            IAC,
+            SE,
+        ];
+        assert_eq!(&setup[3..13], &baud);
+        let control = [IAC, SB, COM_PORT_OPTION, SET_CONTROL, 1, IAC, SE];
+        assert!(setup.ends_with(&control));
+
+        // a baud rate containing 0xff has it escaped
+        let setup = com_port_setup(0xff, FlowControl::Hardware);
+        assert_eq!(&setup[7..12], &[0, 0, 0, IAC, IAC]);
+    }
+
+    #[tokio::test]
+    async fn telnet_output() {
+        let mut out = Telnet::new(Vec::new());
+        out.write_all(b"\x02\xff\xffx").await.unwrap();
+        out.flush().await.unwrap();
+        assert_eq!(out.inner, b"\x02\xff\xff\xff\xffx");
+    }
+
     #[tokio::test]
     async fn example() {
         let inp = Cursor::new(b"hello\nworld\nover");
-        let res = read_lines(inp, |bytes| async move {
-            println!("> {:?}", bytes);
-        })
+        let cancel = CancellationToken::new();
+        let res = read_lines(
+            inp,
+            LineReaderConfig::default(),
+            Arc::default(),
+            cancel,
+            |bytes| async move {
+                println!("> {:?}", bytes);
+            },
+        )
         .await;
         println!("* {:?}", res)
     }
@@ -0,0 +1,164 @@
+//! `clock` keeps the clocks of units on the network in sync.
+//!
+use crate::bus::{Filter, Subscriber};
+use crate::codec::{Date, Message, Priority, Time, CLOCK};
+use crate::daemon::Daemon;
+use crate::time;
+use crate::{Envelope, Event, Network, Outbound, Source};
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, Timelike};
+use futures_util::future::{BoxFuture, FutureExt};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::select;
+use tokio::sync::broadcast::Sender;
+use tokio::time::Duration;
+use tracing::warn;
+
+const SYNC_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Broadcast the date and time on each network on startup and
+/// once a day thereafter, and on a network whenever a unit requests it.
+pub async fn clock_daemon(
+    mut inbound: Subscriber<Envelope>,
+    outbound: Sender<Outbound>,
+    networks: Vec<Network>,
+    clock: Arc<dyn time::Clock>,
+) {
+    let mut due = clock.now();
+    loop {
+        select! {
+            _ = clock.sleep_until(due) => {
+                due += SYNC_PERIOD;
+                for network in &networks {
+                    broadcast(*network, &outbound, clock.wall())
+                }
+            },
+            res = inbound.event() => match res {
+                Some(Event::Cbus(network, Message::ClockRequest)) => {
+                    broadcast(network, &outbound, clock.wall())
+                }
+                Some(_) => (),
+                None => return,
+            }
+        }
+    }
+}
+
+/// The clock for `networks` as a pluggable daemon, telling the time
+/// by `clock`.
+pub struct Clock {
+    pub networks: Vec<Network>,
+    pub clock: Arc<dyn time::Clock>,
+}
+
+impl Daemon for Clock {
+    fn name(&self) -> &'static str {
+        "clock"
+    }
+
+    fn filter(&self) -> Filter {
+        Filter::all().source(Source::Cbus).application(CLOCK.0)
+    }
+
+    fn run(
+        &self,
+        events: Subscriber<Envelope>,
+        outbound: Sender<Outbound>,
+    ) -> BoxFuture<'static, ()> {
+        clock_daemon(events, outbound, self.networks.clone(), self.clock.clone()).boxed()
+    }
+}
+
+fn broadcast(network: Network, outbound: &Sender<Outbound>, wall: SystemTime) {
+    let now = DateTime::<Local>::from(wall).naive_local();
+    for mesg in clock_messages(now) {
+        let res = outbound.send((network, mesg, Priority::Low));
+        if let Err(e) = res {
+            warn!("clock: {e}")
+        }
+    }
+}
+
+/// The clock application messages announcing a given local time.
+pub fn clock_messages(now: NaiveDateTime) -> [Message; 2] {
+    let date = Date {
+        year: now.year() as u16,
+        month: now.month() as u8,
+        day: now.day() as u8,
+        weekday: now.weekday().num_days_from_monday() as u8,
+    };
+    let time = Time {
+        hour: now.hour() as u8,
+        minute: now.minute() as u8,
+        second: now.second() as u8,
+        dst: 0,
+    };
+    [Message::ClockDate(date), Message::ClockTime(time)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, ChannelConfig};
+    use crate::time::Manual;
+    use chrono::NaiveDate;
+    use futures_util::FutureExt;
+    use tokio::task;
+
+    #[test]
+    fn messages() {
+        let now = NaiveDate::from_ymd_opt(2026, 10, 16)
+            .unwrap()
+            .and_hms_opt(20, 35, 59)
+            .unwrap();
+        let [date, time] = clock_messages(now);
+        assert_eq!(
+            date,
+            Message::ClockDate(Date {
+                year: 2026,
+                month: 10,
+                day: 16,
+                weekday: 4
+            })
+        );
+        assert_eq!(
+            time,
+            Message::ClockTime(Time {
+                hour: 20,
+                minute: 35,
+                second: 59,
+                dst: 0
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn daily() {
+        let clock = Manual::default();
+        let bus = Bus::new(ChannelConfig::default());
+        let mut sent = bus.messages("test");
+        task::spawn(clock_daemon(
+            bus.events("clock"),
+            bus.outbound.clone(),
+            vec![0],
+            Arc::new(clock.clone()),
+        ));
+        assert!(matches!(
+            sent.recv().await,
+            Some((0, Message::ClockDate(_), Priority::Low))
+        ));
+        assert!(matches!(
+            sent.recv().await,
+            Some((0, Message::ClockTime(_), Priority::Low))
+        ));
+
+        clock.advance(Duration::from_secs(23 * 60 * 60));
+        task::yield_now().await;
+        assert!(sent.recv().now_or_never().is_none());
+        clock.advance(Duration::from_secs(60 * 60));
+        assert!(matches!(
+            sent.recv().await,
+            Some((0, Message::ClockDate(_), Priority::Low))
+        ));
+    }
+}
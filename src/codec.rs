@@ -2,19 +2,23 @@
 //! these work with a standard CBUS serial interface over RS232 or TCP.
 #![allow(dead_code)]
 
+use std::fmt::{self, Display, Formatter, Write};
 use std::ops::BitOr;
+use std::time::Duration;
 
 use bytes::{Bytes, BytesMut};
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take},
-    combinator::{all_consuming, map_opt, opt},
+    bytes::complete::{tag_no_case, take},
+    character::complete::hex_digit1,
+    combinator::{all_consuming, map, map_opt, verify},
     multi::many1,
-    sequence::{preceded, tuple},
+    sequence::{pair, preceded, tuple},
     IResult, Parser,
 };
+use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct Setting(u8);
 
 // Options 1
@@ -30,6 +34,26 @@ pub const LOCAL_SAL: Setting = Setting(1 << 1);
 pub const POWER_UP_NOTIFY: Setting = Setting(1 << 2);
 pub const EX_STAT: Setting = Setting(1 << 3);
 
+impl Setting {
+    pub const fn new(bits: u8) -> Setting {
+        Setting(bits)
+    }
+
+    pub fn value(&self) -> u8 {
+        self.0
+    }
+
+    /// True if every option in `other` is also set in `self`.
+    pub fn contains(&self, other: &Setting) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// These options less those in `other`.
+    pub fn without(&self, other: &Setting) -> Setting {
+        Setting(self.0 & !other.0)
+    }
+}
+
 // Options can be combined
 impl BitOr for Setting {
     type Output = Setting;
@@ -39,8 +63,35 @@ impl BitOr for Setting {
     }
 }
 
+impl From<u8> for Setting {
+    fn from(bits: u8) -> Self {
+        Setting(bits)
+    }
+}
+
+impl From<Setting> for u8 {
+    fn from(s: Setting) -> Self {
+        s.0
+    }
+}
+
+/// A value outside the range of a codec type.
 #[derive(PartialEq, Debug, Clone)]
-pub struct Param(u8);
+pub struct RangeError {
+    pub kind: &'static str,
+    pub value: u32,
+}
+
+impl Display for RangeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} out of range: {}", self.kind, self.value)
+    }
+}
+
+impl std::error::Error for RangeError {}
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Param(pub u8);
 pub const APPLICATION1: Param = Param(0x21);
 pub const APPLICATION2: Param = Param(0x22);
 pub const OPTIONS1: Param = Param(0x30);
@@ -66,200 +117,2489 @@ static RAMP_CODES: [(u8, u16); 16] = [
     (0x7a, 1020),
 ];
 
-#[derive(PartialEq, Debug, Clone)]
-pub struct Ramp(pub u16);
+/// The duration of a lighting fade.
+///
+/// Only the rates in `RAMP_CODES` go on the wire; `encode` picks one.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub struct Ramp(Duration);
+
+/// A change that takes effect immediately.
+pub const INSTANT: Ramp = Ramp(Duration::ZERO);
 
 impl Ramp {
+    /// The longest ramp the lighting application supports.
+    pub const MAX: Duration = Duration::from_secs(1020);
+
+    /// A ramp of `secs` seconds, which must not exceed `MAX`.
+    pub fn new(secs: u16) -> Result<Ramp, RangeError> {
+        Ramp::from_duration(Duration::from_secs(u64::from(secs)))
+    }
+
+    /// A ramp lasting `d`, which must not exceed `MAX`.
+    pub fn from_duration(d: Duration) -> Result<Ramp, RangeError> {
+        if d <= Ramp::MAX {
+            Ok(Ramp(d))
+        } else {
+            Err(RangeError {
+                kind: "ramp",
+                value: u32::try_from(d.as_secs()).unwrap_or(u32::MAX),
+            })
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.0
+    }
+
+    /// The duration in whole seconds, rounded up.
+    pub fn secs(&self) -> u16 {
+        let secs = self.0.as_secs() + u64::from(self.0.subsec_nanos() > 0);
+        u16::try_from(secs).unwrap_or(u16::MAX)
+    }
+
     pub fn decode(code: u8) -> Option<Ramp> {
         for (c, s) in RAMP_CODES {
             if c == code {
-                return Some(Ramp(s));
+                return Some(Ramp(Duration::from_secs(u64::from(s))));
             }
         }
         None
     }
 
     pub fn encode(&self) -> u8 {
-        let secs = self.0;
-        for (c, s) in RAMP_CODES {
-            if secs <= s {
-                return c;
-            }
-        }
-        0x7a
+        self.encode_rounded(Rounding::Ceiling).unwrap_or(0x7a)
+    }
+
+    /// The ramp code for this duration, choosing among rates per `rounding`.
+    pub fn encode_rounded(&self, rounding: Rounding) -> Result<u8, RangeError> {
+        let rate = |&(_, s): &(u8, u16)| Duration::from_secs(u64::from(s));
+        let below = RAMP_CODES.iter().rev().find(|r| rate(r) <= self.0);
+        let above = RAMP_CODES.iter().find(|r| rate(r) >= self.0);
+        let choice = match rounding {
+            Rounding::Floor => below,
+            Rounding::Ceiling => above.or(RAMP_CODES.last()),
+            Rounding::Nearest => match (below, above) {
+                (Some(b), Some(a)) if self.0 - rate(b) < rate(a) - self.0 => Some(b),
+                (b, a) => a.or(b),
+            },
+            Rounding::Strict => above.filter(|r| rate(r) == self.0),
+        };
+        choice.map(|&(c, _)| c).ok_or(RangeError {
+            kind: "ramp rate",
+            value: u32::from(self.secs()),
+        })
+    }
+
+    /// This ramp adjusted to a rate the lighting application supports.
+    pub fn rounded(&self, rounding: Rounding) -> Result<Ramp, RangeError> {
+        let code = self.encode_rounded(rounding)?;
+        Ok(Ramp::decode(code).unwrap_or(INSTANT))
     }
 }
 
-#[derive(PartialEq, Debug, Clone)]
+/// How to choose a supported ramp rate for an arbitrary duration.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Rounding {
+    Nearest,
+    Floor,
+    /// The next longer rate: this is what `Ramp::encode` uses.
+    #[default]
+    Ceiling,
+    /// Only exact rates; anything else is an error.
+    Strict,
+}
+
+impl TryFrom<u16> for Ramp {
+    type Error = RangeError;
+
+    fn try_from(secs: u16) -> Result<Self, Self::Error> {
+        Ramp::new(secs)
+    }
+}
+
+impl TryFrom<Duration> for Ramp {
+    type Error = RangeError;
+
+    fn try_from(d: Duration) -> Result<Self, Self::Error> {
+        Ramp::from_duration(d)
+    }
+}
+
+impl From<Ramp> for u16 {
+    fn from(r: Ramp) -> Self {
+        r.secs()
+    }
+}
+
+impl From<Ramp> for Duration {
+    fn from(r: Ramp) -> Self {
+        r.0
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct Level(pub u8);
 pub const ON: Level = Level(0xff);
 pub const OFF: Level = Level(0x0);
 
-#[derive(PartialEq, Debug, Clone)]
+impl Level {
+    pub const fn new(level: u8) -> Level {
+        Level(level)
+    }
+
+    pub fn value(&self) -> u8 {
+        self.0
+    }
+
+    /// The level nearest to `percent`, clamped to 0–100%.
+    pub fn from_percent(percent: f32) -> Level {
+        let p = if percent.is_nan() {
+            0.0
+        } else {
+            percent.clamp(0.0, 100.0)
+        };
+        Level((p * 255.0 / 100.0).round() as u8)
+    }
+
+    pub fn as_percent(&self) -> f32 {
+        f32::from(self.0) * 100.0 / 255.0
+    }
+}
+
+impl From<u8> for Level {
+    fn from(level: u8) -> Self {
+        Level(level)
+    }
+}
+
+impl From<Level> for u8 {
+    fn from(l: Level) -> Self {
+        l.0
+    }
+}
+
+impl TryFrom<u16> for Level {
+    type Error = RangeError;
+
+    fn try_from(level: u16) -> Result<Self, Self::Error> {
+        u8::try_from(level).map(Level).map_err(|_| RangeError {
+            kind: "level",
+            value: u32::from(level),
+        })
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 pub struct Group(pub u8);
 
-#[derive(PartialEq, Debug, Clone)]
-pub enum Message {
-    SetParam(Param, Setting),
-    SetVar(Group, Level, Ramp),
-    Reset,
-    StopRamp(Group),
-    Status(Group, Vec<u8>),
-    Unrecognised(Bytes),
+impl Group {
+    /// The group address that stands for every group in an application.
+    pub const ALL: Group = Group(0xff);
+
+    pub const fn new(group: u8) -> Group {
+        Group(group)
+    }
+
+    pub fn value(&self) -> u8 {
+        self.0
+    }
 }
-use Message::*;
 
-fn hex_extract(raw: &[u8]) -> Option<u8> {
-    let s = std::str::from_utf8(raw).ok()?;
-    u8::from_str_radix(s, 16).ok()
+impl From<u8> for Group {
+    fn from(group: u8) -> Self {
+        Group(group)
+    }
 }
 
-pub fn hex_byte(input: &[u8]) -> IResult<&[u8], u8> {
-    map_opt(take(2usize), hex_extract).parse(input)
+impl From<Group> for u8 {
+    fn from(g: Group) -> Self {
+        g.0
+    }
+}
+
+impl TryFrom<u16> for Group {
+    type Error = RangeError;
+
+    fn try_from(group: u16) -> Result<Self, Self::Error> {
+        u8::try_from(group).map(Group).map_err(|_| RangeError {
+            kind: "group",
+            value: u32::from(group),
+        })
+    }
+}
+
+impl Display for Ramp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}s", self.0.as_secs_f64())
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let percent = (u32::from(self.0) * 100 + 127) / 255;
+        write!(f, "{percent}%")
+    }
 }
 
-pub fn command_from_parts(parts: (u8, u8, u8, Option<u8>)) -> Option<Message> {
-    match parts {
-        (0x79, group, _check, None) => Some(SetVar(Group(group), ON, Ramp(0))),
-        (0x01, group, _check, None) => Some(SetVar(Group(group), OFF, Ramp(0))),
-        (0x09, group, _check, None) => Some(StopRamp(Group(group))),
-        (rate, group, level, Some(_check)) => {
-            Some(SetVar(Group(group), Level(level), Ramp::decode(rate)?))
+impl Display for Group {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Target::from(self.clone()))
+    }
+}
+
+/// What a lighting command applies to: one group or all of them.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub enum Target {
+    Group(Group),
+    All,
+}
+
+impl Target {
+    /// The group address that carries this target on the wire.
+    pub fn as_group(&self) -> Group {
+        match self {
+            Target::Group(g) => g.clone(),
+            Target::All => Group::ALL,
         }
-        _ => None,
     }
 }
 
-pub fn status_from_parts(parts: (u8, Vec<u8>)) -> Option<Message> {
-    match parts {
-        (offset, mut status) => {
-            if let Some(_check) = status.pop() {
-                Some(Status(Group(offset), status))
-            } else {
-                None
-            }
+impl From<Group> for Target {
+    fn from(g: Group) -> Self {
+        if g == Group::ALL {
+            Target::All
+        } else {
+            Target::Group(g)
         }
     }
 }
 
-pub fn decode(bytes: Bytes) -> Message {
-    let command_pattern = map_opt(
-        preceded(
-            tuple((tag("05"), take(2usize), tag("3800"))),
-            tuple((hex_byte, hex_byte, hex_byte, opt(hex_byte))),
-        ),
-        command_from_parts,
-    );
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Group(Group(g)) => write!(f, "group {g}"),
+            Target::All => write!(f, "all groups"),
+        }
+    }
+}
 
-    let status_pattern = map_opt(
-        preceded(
-            tuple((
-                tag("86"),
-                take(4usize),
-                tag("00"),
-                take(2usize),
-                tag("4038"),
-            )),
-            tuple((hex_byte, many1(hex_byte))),
-        ),
-        status_from_parts,
-    );
+/// Either a group number or `all`.
+impl std::str::FromStr for Target {
+    type Err = std::num::ParseIntError;
 
-    let mut pattern = all_consuming(alt((command_pattern, status_pattern)));
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("all") {
+            Ok(Target::All)
+        } else {
+            s.parse().map(|g| Target::from(Group(g)))
+        }
+    }
+}
 
-    let result = pattern.parse(&bytes[..]);
+/// A CBUS application address.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Application(pub u8);
+pub const LIGHTING: Application = Application(0x38);
+pub const TRIGGER: Application = Application(0xca);
+pub const ENABLE: Application = Application(0xcb);
+pub const SECURITY: Application = Application(0xd0);
+pub const MEASUREMENT: Application = Application(0xe4);
+pub const TEMPERATURE: Application = Application(0x19);
+pub const CLOCK: Application = Application(0xdf);
 
-    match result {
-        Ok((_, mesg)) => mesg,
-        _ => Unrecognised(bytes.clone()),
+impl Application {
+    /// True for the range of applications that use the lighting command set.
+    pub fn is_lighting(&self) -> bool {
+        (0x30..=0x5f).contains(&self.0)
     }
 }
 
-pub fn encode(mesg: Message) -> Bytes {
-    match mesg {
-        SetVar(Group(g), Level(l), Ramp(_s)) => Bytes::from(format!("\\05380002{g:02X}{l:02X}\r")),
-        SetParam(Param(p), Setting(s)) => Bytes::from(format!("@A3{p:02x}00{s:02x}\r")),
-        Reset => Bytes::from(b"~".as_ref()),
-        _ => Bytes::new(),
+/// The address of a unit on the network.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Address(pub u8);
+
+/// An attribute of a unit that can be requested with `Identify`.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Attribute(pub u8);
+pub const MANUFACTURER: Attribute = Attribute(0x00);
+pub const UNIT_TYPE: Attribute = Attribute(0x01);
+pub const FIRMWARE_VERSION: Attribute = Attribute(0x02);
+pub const SUMMARY: Attribute = Attribute(0x03);
+
+/// A trigger control action selector.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Action(pub u8);
+pub const TRIGGER_MIN: Action = Action(0x00);
+pub const TRIGGER_MAX: Action = Action(0xff);
+
+/// An enable control network variable.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Variable(pub u8);
+
+/// A security zone.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Zone(pub u8);
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub enum ZoneState {
+    Sealed,
+    Unsealed,
+}
+
+/// A security system arming mode.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct ArmMode(pub u8);
+pub const DISARMED: ArmMode = ArmMode(0);
+pub const ARMED_AWAY: ArmMode = ArmMode(1);
+pub const ARMED_NIGHT: ArmMode = ArmMode(2);
+pub const ARMED_DAY: ArmMode = ArmMode(3);
+
+/// The unit of a measurement.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Unit(pub u8);
+pub const CELSIUS: Unit = Unit(0x00);
+pub const AMPS: Unit = Unit(0x01);
+pub const VOLTS: Unit = Unit(0x1b);
+pub const LUX: Unit = Unit(0x10);
+
+/// A reading broadcast by the measurement application.
+///
+/// The quantity is `value` × 10^`exponent` in `unit`.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Reading {
+    pub device: u8,
+    pub channel: u8,
+    pub unit: Unit,
+    pub value: i16,
+    pub exponent: i8,
+}
+
+impl Reading {
+    pub fn quantity(&self) -> f64 {
+        f64::from(self.value) * 10f64.powi(i32::from(self.exponent))
     }
 }
 
-pub fn preamble() -> Bytes {
-    let mut p = BytesMut::new();
-    p.extend(encode(Reset));
-    p.extend(encode(SetParam(OPTIONS3, LOCAL_SAL | EX_STAT)));
-    p.extend(encode(SetParam(
-        OPTIONS1,
-        SMART | ID_MON | CONNECT | MONITOR,
-    )));
-    p.freeze()
+/// A broadcast temperature in units of a quarter degree Celsius.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Degrees(pub u8);
+
+impl Degrees {
+    pub fn celsius(&self) -> f32 {
+        f32::from(self.0) / 4.0
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// A time of day broadcast by the clock application.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Time {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub dst: u8,
+}
 
-    #[test]
-    fn setvar_on() {
-        let m = decode(b"05003800790400".as_ref().into());
-        assert_eq!(m, SetVar(Group(4), ON, Ramp(0)))
+/// A date broadcast by the clock application, weekday 0 is Monday.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub weekday: u8,
+}
+
+/// A confirmation code, one of the characters `g` to `z`,
+/// appended to a command to request a delivery report from the PCI.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Code(u8);
+
+impl Code {
+    pub const COUNT: usize = 20;
+
+    pub fn new(c: u8) -> Option<Code> {
+        if (b'g'..=b'z').contains(&c) {
+            Some(Code(c))
+        } else {
+            None
+        }
     }
 
-    #[test]
-    fn setvar_off() {
-        let m = decode(b"05003800010400".as_ref().into());
-        assert_eq!(m, SetVar(Group(4), OFF, Ramp(0)))
+    /// The next code in sequence, wrapping from `z` to `g`.
+    pub fn succ(&self) -> Code {
+        if self.0 == b'z' {
+            Code(b'g')
+        } else {
+            Code(self.0 + 1)
+        }
     }
+}
 
-    #[test]
-    fn stop_ramp() {
-        let m = decode(b"05003800090400".as_ref().into());
-        assert_eq!(m, StopRamp(Group(4)))
+impl Default for Code {
+    fn default() -> Self {
+        Code(b'g')
     }
+}
 
-    #[test]
-    fn setvar_level() {
-        let m = decode(b"050038002A041F00".as_ref().into());
-        assert_eq!(m, SetVar(Group(4), Level(0x1f), Ramp(30)))
+/// The result reported by the PCI for a confirmed command.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Outcome {
+    Delivered,
+    Failed,
+}
+
+/// Why a received frame could not be decoded.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum FaultKind {
+    /// A character that is not a hex digit.
+    NonHex,
+    /// The frame ends part way through a byte, header or command.
+    Truncated,
+    /// The check byte does not match the frame.
+    BadChecksum,
+    /// A header this codec does not handle.
+    UnknownHeader,
+    /// A SAL command the addressed application does not define.
+    WrongApplication,
+    /// A reply or command that is otherwise not understood.
+    Malformed,
+}
+
+/// A decoding fault and its offset, in characters, within the frame.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Fault {
+    pub kind: FaultKind,
+    pub offset: usize,
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            FaultKind::NonHex => "non-hex character",
+            FaultKind::Truncated => "truncated",
+            FaultKind::BadChecksum => "bad checksum",
+            FaultKind::UnknownHeader => "unknown header",
+            FaultKind::WrongApplication => "command not in application",
+            FaultKind::Malformed => "malformed",
+        };
+        write!(f, "{kind} at {}", self.offset)
     }
+}
 
-    #[test]
-    fn status_zero() {
-        let m = decode(
-            b"86081500F74038B000000000000000000000000000000000000000003E"
-                .as_ref()
-                .into(),
-        );
-        assert_eq!(m, Status(Group(176), vec![0; 20]));
+/// Binary data held in its hex encoded wire form.
+///
+/// When decoded from a frame this is a slice of the received `Bytes`,
+/// so no copy is made until the data is read.
+#[derive(Clone, Default)]
+pub struct HexBytes(Bytes);
+
+impl HexBytes {
+    pub fn len(&self) -> usize {
+        self.0.len() / 2
     }
 
-    #[test]
-    fn short_message() {
-        assert_unrecognised(b"050038007904".as_ref().into());
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 
-    #[test]
-    fn empty_message() {
-        assert_unrecognised(b"".as_ref().into());
+    pub fn get(&self, index: usize) -> Option<u8> {
+        self.0.get(2 * index..2 * index + 2).and_then(hex_extract)
     }
 
-    #[test]
-    fn long_message() {
-        assert_unrecognised(b"0500380024041F00A1".as_ref().into());
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        self.0.chunks_exact(2).filter_map(hex_extract)
     }
 
-    #[test]
-    fn odd_length() {
-        assert_unrecognised(b"0500380079040".as_ref().into());
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.iter().collect()
     }
 
-    #[test]
-    fn not_hex() {
-        assert_unrecognised(b"0500380009z400".as_ref().into());
+    /// The hex digits as received.
+    pub fn as_hex(&self) -> &[u8] {
+        &self.0[..]
     }
+}
 
-    fn assert_unrecognised(bytes: Bytes) {
-        let m = decode(bytes.clone());
-        assert_eq!(m, Unrecognised(bytes))
+impl From<&[u8]> for HexBytes {
+    fn from(data: &[u8]) -> Self {
+        let mut text = String::with_capacity(2 * data.len());
+        for b in data {
+            let _ = write!(text, "{b:02X}");
+        }
+        HexBytes(Bytes::from(text))
+    }
+}
+
+impl From<Vec<u8>> for HexBytes {
+    fn from(data: Vec<u8>) -> Self {
+        HexBytes::from(&data[..])
+    }
+}
+
+impl PartialEq for HexBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl fmt::Debug for HexBytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl Serialize for HexBytes {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for HexBytes {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<u8>::deserialize(deserializer).map(HexBytes::from)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub enum Message {
+    SetParam(Param, Setting),
+    SetVar(Application, Group, Level, Ramp),
+    Reset,
+    StopRamp(Application, Group),
+    Status(Application, Group, HexBytes),
+    LevelStatus(Application, Vec<(Group, Level)>),
+    StatusRequest(Application, Group),
+    LevelRequest(Application, Group),
+    Identify(Address, Attribute),
+    Recall(Address, Param, u8),
+    WriteParam(Address, Param, Vec<u8>),
+    ReadLabel(Address, Group),
+    Reply(Address, u8, Vec<u8>),
+    Acknowledge(Address, u8, u8),
+    Trigger(Group, Action),
+    IndicatorKill(Group),
+    SetNetworkVar(Variable, u8),
+    ZoneChange(Zone, ZoneState),
+    Armed(ArmMode),
+    Alarm(bool),
+    Measurement(Reading),
+    Temperature(Group, Degrees),
+    ClockTime(Time),
+    ClockDate(Date),
+    ClockRequest,
+    Sal(Application, Vec<u8>),
+    /// Text for the dynamic label of a group, shown on DLT switches.
+    Label(Application, Group, Box<str>),
+    /// A SAL command sent via a bridge to the given network.
+    Bridged(Address, u8, Box<Message>),
+    Confirmation(Code, Outcome),
+    Prompt,
+    /// The PCI has powered up and lost its configuration.
+    PowerUp,
+    /// A PCI parameter was changed, by us or another client.
+    ParamChanged(Param, u8),
+    PciError,
+    ResetAck,
+    Unrecognised(Bytes, Fault),
+}
+use Message::*;
+
+impl Message {
+    /// Start building a lighting command for every group.
+    pub fn set_all() -> SetBuilder {
+        Message::set(Group::ALL.0)
+    }
+
+    /// Start building a lighting command for `group`.
+    ///
+    /// ```ignore
+    /// let m = Message::set(4).level(50).ramp_secs(30).build()?;
+    /// ```
+    pub fn set(group: u8) -> SetBuilder {
+        SetBuilder {
+            app: LIGHTING,
+            group: Group(group),
+            level: ON,
+            ramp: INSTANT,
+            route: None,
+            error: None,
+        }
+    }
+
+    /// The group a message refers to, if any.
+    pub fn group(&self) -> Option<&Group> {
+        match self {
+            SetVar(_, g, _, _)
+            | StopRamp(_, g)
+            | Trigger(g, _)
+            | IndicatorKill(g)
+            | Temperature(g, _)
+            | ReadLabel(_, g)
+            | Label(_, g, _) => Some(g),
+            Bridged(_, _, m) => m.group(),
+            _ => None,
+        }
+    }
+
+    /// What a lighting command applies to.
+    pub fn target(&self) -> Option<Target> {
+        match self {
+            SetVar(_, g, _, _) | StopRamp(_, g) => Some(Target::from(g.clone())),
+            Bridged(_, _, m) => m.target(),
+            _ => None,
+        }
+    }
+
+    /// The application a SAL message is for, if any.
+    pub fn application(&self) -> Option<Application> {
+        match self {
+            SetVar(a, ..)
+            | StopRamp(a, _)
+            | Status(a, ..)
+            | LevelStatus(a, _)
+            | StatusRequest(a, _)
+            | LevelRequest(a, _)
+            | Sal(a, _)
+            | Label(a, ..) => Some(*a),
+            Trigger(..) | IndicatorKill(_) => Some(TRIGGER),
+            SetNetworkVar(..) => Some(ENABLE),
+            ZoneChange(..) | Armed(_) | Alarm(_) => Some(SECURITY),
+            Measurement(_) => Some(MEASUREMENT),
+            Temperature(..) => Some(TEMPERATURE),
+            ClockTime(_) | ClockDate(_) | ClockRequest => Some(CLOCK),
+            Bridged(_, _, m) => m.application(),
+            _ => None,
+        }
+    }
+
+    /// The on/off state of each group in a status report of either kind.
+    pub fn group_states(&self) -> Option<Vec<(Group, GroupState)>> {
+        match self {
+            Status(_, Group(block), data) => Some(states_from_status(*block, data)),
+            LevelStatus(_, levels) => Some(
+                levels
+                    .iter()
+                    .map(|(g, l)| {
+                        let state = if *l == OFF {
+                            GroupState::Off
+                        } else {
+                            GroupState::On
+                        };
+                        (g.clone(), state)
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// True if the PCI will confirm this message when sent with a code.
+    pub fn is_confirmable(&self) -> bool {
+        if let Bridged(_, _, m) = self {
+            return m.is_confirmable();
+        }
+        matches!(
+            self,
+            SetVar(..)
+                | StopRamp(..)
+                | Trigger(..)
+                | IndicatorKill(_)
+                | SetNetworkVar(..)
+                | ClockTime(_)
+                | ClockDate(_)
+                | ClockRequest
+                | Identify(..)
+                | Recall(..)
+                | WriteParam(..)
+                | ReadLabel(..)
+                | StatusRequest(..)
+                | LevelRequest(..)
+                | Sal(..)
+                | Label(..)
+                | SetParam(..)
+        )
+    }
+}
+
+/// A lighting command under construction, see `Message::set`.
+///
+/// The first invalid value given is reported by `build`.
+#[derive(Clone, Debug)]
+pub struct SetBuilder {
+    app: Application,
+    group: Group,
+    level: Level,
+    ramp: Ramp,
+    route: Option<(Address, u8)>,
+    error: Option<RangeError>,
+}
+
+impl SetBuilder {
+    fn check<T>(mut self, value: Result<T, RangeError>, set: impl FnOnce(&mut Self, T)) -> Self {
+        match value {
+            Ok(v) => set(&mut self, v),
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    /// Use a lighting application other than the default 0x38.
+    pub fn application(self, app: u8) -> Self {
+        let value = if Application(app).is_lighting() {
+            Ok(Application(app))
+        } else {
+            Err(RangeError {
+                kind: "lighting application",
+                value: u32::from(app),
+            })
+        };
+        self.check(value, |b, a| b.app = a)
+    }
+
+    /// The target level as a percentage, 0 to 100.
+    pub fn level(self, percent: u8) -> Self {
+        let value = if percent <= 100 {
+            Ok(Level::from_percent(f32::from(percent)))
+        } else {
+            Err(RangeError {
+                kind: "percent",
+                value: u32::from(percent),
+            })
+        };
+        self.check(value, |b, l| b.level = l)
+    }
+
+    pub fn on(mut self) -> Self {
+        self.level = ON;
+        self
+    }
+
+    pub fn off(mut self) -> Self {
+        self.level = OFF;
+        self
+    }
+
+    pub fn ramp_secs(self, secs: u16) -> Self {
+        self.check(Ramp::new(secs), |b, r| b.ramp = r)
+    }
+
+    pub fn ramp(self, d: Duration) -> Self {
+        self.check(Ramp::from_duration(d), |b, r| b.ramp = r)
+    }
+
+    /// Send the command via a bridge to another network.
+    pub fn via(mut self, bridge: u8, network: u8) -> Self {
+        self.route = Some((Address(bridge), network));
+        self
+    }
+
+    pub fn build(self) -> Result<Message, RangeError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let mesg = SetVar(self.app, self.group, self.level, self.ramp);
+        Ok(match self.route {
+            Some((bridge, network)) => Bridged(bridge, network, Box::new(mesg)),
+            None => mesg,
+        })
+    }
+
+    /// A command to stop any ramp in progress instead.
+    pub fn stop(self) -> Result<Message, RangeError> {
+        let app = self.app;
+        let group = self.group.clone();
+        self.build().map(|m| match m {
+            Bridged(b, n, _) => Bridged(b, n, Box::new(StopRamp(app, group))),
+            _ => StopRamp(app, group),
+        })
+    }
+}
+
+/// A concise description for logs, `Debug` gives the full detail.
+impl Display for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let app = |f: &mut Formatter<'_>, a: &Application| {
+            if a.is_lighting() && *a != LIGHTING {
+                write!(f, "application {:#04x} ", a.0)
+            } else {
+                Ok(())
+            }
+        };
+        match self {
+            SetVar(a, g, l, r) => {
+                app(f, a)?;
+                write!(f, "{g} -> {l}")?;
+                if *r != INSTANT {
+                    write!(f, " over {r}")?;
+                }
+                Ok(())
+            }
+            StopRamp(a, g) => {
+                app(f, a)?;
+                write!(f, "{g} stop")
+            }
+            LevelStatus(a, levels) => {
+                app(f, a)?;
+                write!(f, "levels")?;
+                for (g, l) in levels {
+                    write!(f, " {}:{l}", g.0)?;
+                }
+                Ok(())
+            }
+            Trigger(g, Action(x)) => write!(f, "trigger {} action {x}", g.0),
+            ParamChanged(Param(p), v) => write!(f, "parameter {p:#04x} changed to {v:#04x}"),
+            Label(a, g, text) => {
+                app(f, a)?;
+                write!(f, "{g} label {text:?}")
+            }
+            Bridged(Address(b), n, m) => write!(f, "via bridge {b} to network {n}: {m}"),
+            Temperature(g, d) => write!(f, "temperature zone {} {}°C", g.0, d.celsius()),
+            Confirmation(Code(c), o) => write!(f, "confirmation {} {o:?}", *c as char),
+            Unrecognised(frame, fault) => write!(f, "unrecognised {frame:?}: {fault}"),
+            _ => write!(f, "{self:?}"),
+        }
+    }
+}
+
+/// Two hex digits in either case.  A sign is not a digit.
+fn hex_extract(raw: &[u8]) -> Option<u8> {
+    if !raw.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let s = std::str::from_utf8(raw).ok()?;
+    u8::from_str_radix(s, 16).ok()
+}
+
+pub fn hex_byte(input: &[u8]) -> IResult<&[u8], u8> {
+    map_opt(take(2usize), hex_extract).parse(input)
+}
+
+/// A header byte with the given address type in its low 3 bits and any priority class.
+fn address_type<'a>(t: u8) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], u8> {
+    verify(hex_byte, move |h| h & 0x07 == t)
+}
+
+pub fn application(input: &[u8]) -> IResult<&[u8], Application> {
+    map(hex_byte, Application).parse(input)
+}
+
+/// Label options: plain text rather than an icon.
+const LABEL_TEXT: u8 = 0x00;
+const LABEL_ENGLISH: u8 = 0x01;
+
+/// The longest label text that fits in one SAL command.
+pub const LABEL_LEN: usize = 0x1f - 3;
+
+fn lighting_from_sal(app: Application, sal: &[u8]) -> Option<Message> {
+    match *sal {
+        [0x79, group] => Some(SetVar(app, Group(group), ON, INSTANT)),
+        [0x01, group] => Some(SetVar(app, Group(group), OFF, INSTANT)),
+        [0x09, group] => Some(StopRamp(app, Group(group))),
+        [rate, group, level] => Some(SetVar(app, Group(group), Level(level), Ramp::decode(rate)?)),
+        [0xa0..=0xbf, group, LABEL_TEXT, _language, ref text @ ..] => {
+            let text = String::from_utf8_lossy(text);
+            Some(Label(app, Group(group), text.into()))
+        }
+        _ => None,
+    }
+}
+
+fn trigger_from_sal(sal: &[u8]) -> Option<Message> {
+    match *sal {
+        [0x79, group] => Some(Trigger(Group(group), TRIGGER_MAX)),
+        [0x01, group] => Some(Trigger(Group(group), TRIGGER_MIN)),
+        [0x09, group] => Some(IndicatorKill(Group(group))),
+        [0x02, group, action] => Some(Trigger(Group(group), Action(action))),
+        _ => None,
+    }
+}
+
+fn enable_from_sal(sal: &[u8]) -> Option<Message> {
+    match *sal {
+        [0x82, var, value] => Some(SetNetworkVar(Variable(var), value)),
+        _ => None,
+    }
+}
+
+fn security_from_sal(sal: &[u8]) -> Option<Message> {
+    match *sal {
+        [0x08] => Some(Alarm(true)),
+        [0x10] => Some(Alarm(false)),
+        [0x19, mode] => Some(Armed(ArmMode(mode))),
+        [0x21, zone] => Some(ZoneChange(Zone(zone), ZoneState::Sealed)),
+        [0x29, zone] => Some(ZoneChange(Zone(zone), ZoneState::Unsealed)),
+        _ => None,
+    }
+}
+
+fn measurement_from_sal(sal: &[u8]) -> Option<Message> {
+    match *sal {
+        [0x0e, device, channel, unit, exponent, msb, lsb] => Some(Measurement(Reading {
+            device,
+            channel,
+            unit: Unit(unit),
+            value: i16::from_be_bytes([msb, lsb]),
+            exponent: exponent as i8,
+        })),
+        _ => None,
+    }
+}
+
+fn temperature_from_sal(sal: &[u8]) -> Option<Message> {
+    match *sal {
+        [0x02, zone, value] => Some(Temperature(Group(zone), Degrees(value))),
+        _ => None,
+    }
+}
+
+fn clock_from_sal(sal: &[u8]) -> Option<Message> {
+    match *sal {
+        [0x0d, 0x01, hour, minute, second, dst] => Some(ClockTime(Time {
+            hour,
+            minute,
+            second,
+            dst,
+        })),
+        [0x0e, 0x02, y1, y0, month, day, weekday] => Some(ClockDate(Date {
+            year: u16::from_be_bytes([y1, y0]),
+            month,
+            day,
+            weekday,
+        })),
+        [0x11, 0x03] => Some(ClockRequest),
+        _ => None,
+    }
+}
+
+/// The number of argument bytes following a SAL command byte.
+///
+/// Long form commands (high bit set) carry a 5 bit length,
+/// short form commands a 3 bit length.
+fn sal_len(command: u8) -> usize {
+    if command & 0x80 != 0 {
+        usize::from(command & 0x1f)
+    } else {
+        usize::from(command & 0x07)
+    }
+}
+
+/// Split concatenated SAL commands, each a command byte and its arguments.
+fn split_sals(mut data: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut sals = Vec::new();
+    while let [command, ..] = *data {
+        let n = 1 + sal_len(command);
+        if data.len() < n {
+            return None;
+        }
+        let (sal, rest) = data.split_at(n);
+        sals.push(sal);
+        data = rest;
+    }
+    Some(sals)
+}
+
+/// Interpret the SAL data of an application, less the check byte.
+///
+/// A frame may carry several SAL commands. Every command must
+/// be recognised or the frame as a whole is rejected.
+pub fn sal_from_parts(parts: (Application, Vec<u8>)) -> Option<Vec<Message>> {
+    let (app, mut data) = parts;
+    data.pop()?;
+    if data.is_empty() {
+        return None;
+    }
+    let Some(interpret) = interpreter(app) else {
+        return Some(vec![Sal(app, data)]);
+    };
+    split_sals(&data)?
+        .into_iter()
+        .map(|sal| interpret(app, sal))
+        .collect()
+}
+
+type Interpreter = fn(Application, &[u8]) -> Option<Message>;
+
+/// The SAL interpreter for an application this codec understands.
+fn interpreter(app: Application) -> Option<Interpreter> {
+    let interpret: Interpreter = match app {
+        app if app.is_lighting() => lighting_from_sal,
+        TRIGGER => |_, sal| trigger_from_sal(sal),
+        ENABLE => |_, sal| enable_from_sal(sal),
+        SECURITY => |_, sal| security_from_sal(sal),
+        MEASUREMENT => |_, sal| measurement_from_sal(sal),
+        TEMPERATURE => |_, sal| temperature_from_sal(sal),
+        CLOCK => |_, sal| clock_from_sal(sal),
+        _ => return None,
+    };
+    Some(interpret)
+}
+
+/// Decode one byte of a level status report: two symbols, each
+/// one of 0x5, 0x6, 0x9, 0xA representing 2 bits of the level.
+fn level_nibble(b: u8) -> Option<u8> {
+    let symbol = |s| match s {
+        0x5 => Some(0),
+        0x6 => Some(1),
+        0x9 => Some(2),
+        0xa => Some(3),
+        _ => None,
+    };
+    Some(symbol(b >> 4)? << 2 | symbol(b & 0x0f)?)
+}
+
+/// Decode the levels in an extended level status report.
+///
+/// Each group occupies two bytes, low order nibble first.
+/// Groups not present on the network are reported as zero bytes and omitted.
+fn levels_from_status(block: u8, levels: &[u8]) -> Option<Vec<(Group, Level)>> {
+    if levels.is_empty() || !levels.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut result = Vec::new();
+    for (i, pair) in levels.chunks(2).enumerate() {
+        if pair == [0, 0] {
+            continue;
+        }
+        let level = level_nibble(pair[1])? << 4 | level_nibble(pair[0])?;
+        let group = block.checked_add(i as u8)?;
+        result.push((Group(group), Level(level)));
+    }
+    Some(result)
+}
+
+/// The state of a group as given by a status report.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum GroupState {
+    On,
+    Off,
+    Error,
+}
+
+/// Decode a binary status report: each byte holds four groups,
+/// two bits each, lowest order bits first.
+/// Groups not present on the network are omitted.
+fn states_from_status(block: u8, data: &HexBytes) -> Vec<(Group, GroupState)> {
+    let mut result = Vec::new();
+    for (i, b) in data.iter().enumerate() {
+        for j in 0..4 {
+            let state = match (b >> (2 * j)) & 0x03 {
+                0b01 => GroupState::On,
+                0b10 => GroupState::Off,
+                0b11 => GroupState::Error,
+                _ => continue,
+            };
+            match u8::try_from(4 * i + j)
+                .ok()
+                .and_then(|n| block.checked_add(n))
+            {
+                Some(g) => result.push((Group(g), state)),
+                None => return result,
+            }
+        }
+    }
+    result
+}
+
+/// Interpret a CAL reply from a unit.
+///
+/// `raw` is the hex encoded remainder of `frame` including the check byte.
+/// Status data is sliced from `frame` rather than copied.
+fn cal_from_raw(frame: &Bytes, source: Address, raw: &[u8]) -> Option<Vec<Message>> {
+    let data = raw.get(..raw.len().checked_sub(2)?)?;
+    let byte = |i: usize| data.get(2 * i..2 * i + 2).and_then(hex_extract);
+    let bytes_from = |i: usize| {
+        data.get(2 * i..).map(|d| {
+            d.chunks_exact(2)
+                .filter_map(hex_extract)
+                .collect::<Vec<u8>>()
+        })
+    };
+    let mesg = match (byte(0)?, byte(1)?) {
+        (0xe0..=0xff, 0x40) if data.len() > 8 => Status(
+            Application(byte(2)?),
+            Group(byte(3)?),
+            HexBytes(frame.slice_ref(&data[8..])),
+        ),
+        // standard status: no coding byte, the same binary status
+        (0xc0..=0xdf, app) if data.len() > 6 => Status(
+            Application(app),
+            Group(byte(2)?),
+            HexBytes(frame.slice_ref(&data[6..])),
+        ),
+        (0xe0..=0xff, 0x07 | 0x47) => {
+            let block = byte(3)?;
+            LevelStatus(
+                Application(byte(2)?),
+                levels_from_status(block, &bytes_from(4)?)?,
+            )
+        }
+        (0x80..=0x9f, param) => Reply(source, param, bytes_from(2)?),
+        (0x32, param) if data.len() == 6 => Acknowledge(source, param, byte(2)?),
+        // parameter change notification: the parameter, a reserved byte and its new value
+        (0xa3, param) if data.len() == 8 => ParamChanged(Param(param), byte(3)?),
+        _ => return None,
+    };
+    Some(vec![mesg])
+}
+
+/// The CBUS checksum: two's complement of the sum of the bytes.
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg()
+}
+
+/// True if the hex encoded frame, including its trailing check byte, sums to zero.
+fn checksum_valid(raw: &[u8]) -> bool {
+    match all_consuming(many1(hex_byte)).parse(raw) {
+        Ok((_, frame)) => checksum(&frame) == 0,
+        _ => false,
+    }
+}
+
+/// Explain why a frame that is not a control response was not recognised.
+fn diagnose(raw: &[u8]) -> Fault {
+    use FaultKind::*;
+    let fault = |kind, offset| Fault { kind, offset };
+    if let Some(i) = raw.iter().position(|c| !c.is_ascii_hexdigit()) {
+        return fault(NonHex, i);
+    }
+    if !raw.len().is_multiple_of(2) {
+        return fault(Truncated, raw.len() - 1);
+    }
+    let data: Vec<u8> = raw.chunks_exact(2).filter_map(hex_extract).collect();
+
+    // the header length, less the route byte, for each kind of frame
+    let start = match data.first() {
+        None => return fault(Truncated, 0),
+        Some(0x86) => 4,
+        Some(h) if h & 0x07 == 0x05 => 3,
+        Some(h) if h & 0x07 == 0x03 => 5,
+        Some(_) => return fault(UnknownHeader, 0),
+    };
+
+    // header, route, at least one command byte and the check byte
+    if data.len() < start + 3 {
+        return fault(Truncated, raw.len());
+    }
+    if data[start] != 0 {
+        return fault(Malformed, 2 * start);
+    }
+    if data[0] == 0x86 {
+        return fault(Malformed, 2 * start + 2);
+    }
+
+    let app = Application(data[start - 1]);
+    let end = data.len() - 1;
+    let mut pos = start + 1;
+    while pos < end {
+        let n = 1 + sal_len(data[pos]);
+        if pos + n > end {
+            return fault(Truncated, 2 * pos);
+        }
+        if interpreter(app).is_some_and(|interpret| interpret(app, &data[pos..pos + n]).is_none()) {
+            return fault(WrongApplication, 2 * pos);
+        }
+        pos += n;
+    }
+    fault(Malformed, 0)
+}
+
+/// Decode a PCI control response.
+///
+/// These are the bare `+` prompt, `++` power up notification,
+/// `!` error and `=` reset acknowledgement and confirmations:
+/// a code followed by `.` for success or one of `#$%'` for failure.
+fn decode_control(raw: &[u8]) -> Option<Message> {
+    match raw {
+        b"+" => Some(Prompt),
+        b"++" => Some(PowerUp),
+        b"!" => Some(PciError),
+        b"=" => Some(ResetAck),
+        [c, b'.'] => Some(Confirmation(Code::new(*c)?, Outcome::Delivered)),
+        [c, b'#' | b'$' | b'%' | b'\''] => Some(Confirmation(Code::new(*c)?, Outcome::Failed)),
+        _ => None,
+    }
+}
+
+/// Decode the messages in a frame without validating its checksum.
+pub fn decode(bytes: Bytes) -> Vec<Message> {
+    decode_with(bytes, &Setting(0))
+}
+
+/// Decode the messages in a frame received from an interface configured with `options`.
+///
+/// A frame yields one message, or several if it carries multiple SAL commands.
+/// If `options` includes SR_CHK the trailing check byte is verified.
+/// A frame that cannot be decoded is returned as `Unrecognised`
+/// with the reason and where in the frame it was found.
+pub fn decode_with(bytes: Bytes, options: &Setting) -> Vec<Message> {
+    if let Some(mesg) = decode_control(&bytes[..]) {
+        return vec![mesg];
+    }
+
+    // short form: header, source
+    // long form: header, source, bridge, network
+    let sal_header = alt((
+        map(preceded(address_type(0x05), take(2usize)), |_| None),
+        map(
+            preceded(
+                address_type(0x03),
+                preceded(take(2usize), pair(hex_byte, hex_byte)),
+            ),
+            Some,
+        ),
+    ));
+
+    let sal_pattern = map_opt(
+        tuple((
+            sal_header,
+            application,
+            preceded(tag_no_case("00"), many1(hex_byte)),
+        )),
+        |(route, app, data)| {
+            let mesgs = sal_from_parts((app, data))?;
+            Some(match route {
+                Some((b, n)) => mesgs
+                    .into_iter()
+                    .map(|m| Bridged(Address(b), n, Box::new(m)))
+                    .collect(),
+                None => mesgs,
+            })
+        },
+    );
+
+    // header, source, destination, route then the CAL left encoded
+    let reply_pattern = map_opt(
+        tuple((
+            preceded(tag_no_case("86"), map(hex_byte, Address)),
+            preceded(
+                tuple((take(2usize), tag_no_case("00"))),
+                verify(hex_digit1, |h: &[u8]| h.len().is_multiple_of(2)),
+            ),
+        )),
+        |(source, raw)| cal_from_raw(&bytes, source, raw),
+    );
+
+    let mut pattern = all_consuming(alt((reply_pattern, sal_pattern)));
+
+    let result = pattern.parse(&bytes[..]);
+
+    match result {
+        Ok(_) if options.contains(&SR_CHK) && !checksum_valid(&bytes[..]) => {
+            let fault = Fault {
+                kind: FaultKind::BadChecksum,
+                offset: bytes.len() - 2,
+            };
+            vec![Unrecognised(bytes.clone(), fault)]
+        }
+        Ok((_, mesgs)) => mesgs,
+        _ => vec![Unrecognised(bytes.clone(), diagnose(&bytes[..]))],
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+/// Append a byte as two upper case hex digits.
+fn put_hex(buf: &mut BytesMut, b: u8) {
+    buf.extend_from_slice(&[
+        HEX_DIGITS[usize::from(b >> 4)],
+        HEX_DIGITS[usize::from(b & 0xf)],
+    ]);
+}
+
+/// Append a frame: a prefix, hex encoded parts, optional check byte,
+/// optional confirmation code and CR.
+fn frame(buf: &mut BytesMut, prefix: u8, parts: &[&[u8]], check: bool, code: Option<Code>) {
+    let len: usize = parts.iter().map(|p| p.len()).sum();
+    buf.reserve(2 * len + 5);
+    buf.extend_from_slice(&[prefix]);
+    for b in parts.iter().copied().flatten() {
+        put_hex(buf, *b);
+    }
+    if check {
+        let sum = parts.iter().fold(0u8, |s, p| s.wrapping_add(checksum(p)));
+        put_hex(buf, sum);
+    }
+    if let Some(Code(c)) = code {
+        buf.extend_from_slice(&[c]);
+    }
+    buf.extend_from_slice(b"\r");
+}
+
+/// Encode a message without a checksum.
+pub fn encode(mesg: Message) -> Bytes {
+    encode_with(mesg, &Setting(0))
+}
+
+/// Encode a message for an interface configured with `options`.
+///
+/// If `options` includes SR_CHK a check byte is appended to the frame.
+pub fn encode_with(mesg: Message, options: &Setting) -> Bytes {
+    encode_confirmed(mesg, options, None)
+}
+
+/// Encode a message requesting confirmation under the given `code`.
+pub fn encode_confirmed(mesg: Message, options: &Setting, code: Option<Code>) -> Bytes {
+    let mut buf = BytesMut::new();
+    encode_into(&mesg, options, Priority::Low, code, &mut buf);
+    buf.freeze()
+}
+
+/// Append the encoded message to `buf`, which can be reused between messages.
+///
+/// CBUS commands are sent in the given `priority` class.
+/// Messages that cannot be sent append nothing.
+pub fn encode_into(
+    mesg: &Message,
+    options: &Setting,
+    priority: Priority,
+    code: Option<Code>,
+    buf: &mut BytesMut,
+) {
+    let check = options.contains(&SR_CHK);
+    let bits = priority.bits();
+    match *mesg {
+        // point to point to multipoint: header, bridge, network
+        Bridged(Address(b), n, ref inner) => {
+            encode_routed(inner, &[0x03 | bits, b, n], bits, check, code, buf)
+        }
+        ref mesg => encode_routed(mesg, &[0x05 | bits], bits, check, code, buf),
+    }
+}
+
+/// Encode a message, sending any SAL command along `route`.
+///
+/// Commands addressed to a unit can only be sent on the local network.
+fn encode_routed(
+    mesg: &Message,
+    route: &[u8],
+    bits: u8,
+    check: bool,
+    code: Option<Code>,
+    buf: &mut BytesMut,
+) {
+    let local = route.len() == 1;
+    let unicast = 0x06 | bits;
+    let mut frame = |prefix, parts: &[&[u8]]| frame(buf, prefix, parts, check, code);
+    match *mesg {
+        SetVar(Application(a), Group(g), ON, INSTANT) => {
+            frame(b'\\', &[route, &[a, 0x00, 0x79, g]])
+        }
+        SetVar(Application(a), Group(g), OFF, INSTANT) => {
+            frame(b'\\', &[route, &[a, 0x00, 0x01, g]])
+        }
+        SetVar(Application(a), Group(g), Level(l), ref r) => {
+            frame(b'\\', &[route, &[a, 0x00, r.encode(), g, l]])
+        }
+        StopRamp(Application(a), Group(g)) => frame(b'\\', &[route, &[a, 0x00, 0x09, g]]),
+        Trigger(Group(g), Action(x)) => frame(b'\\', &[route, &[TRIGGER.0, 0x00, 0x02, g, x]]),
+        IndicatorKill(Group(g)) => frame(b'\\', &[route, &[TRIGGER.0, 0x00, 0x09, g]]),
+        SetNetworkVar(Variable(v), x) => frame(b'\\', &[route, &[ENABLE.0, 0x00, 0x82, v, x]]),
+        ClockTime(Time {
+            hour,
+            minute,
+            second,
+            dst,
+        }) => frame(
+            b'\\',
+            &[
+                route,
+                &[CLOCK.0, 0x00, 0x0d, 0x01, hour, minute, second, dst],
+            ],
+        ),
+        ClockDate(Date {
+            year,
+            month,
+            day,
+            weekday,
+        }) => {
+            let [y1, y0] = year.to_be_bytes();
+            frame(
+                b'\\',
+                &[
+                    route,
+                    &[CLOCK.0, 0x00, 0x0e, 0x02, y1, y0, month, day, weekday],
+                ],
+            )
+        }
+        ClockRequest => frame(b'\\', &[route, &[CLOCK.0, 0x00, 0x11, 0x03]]),
+        StatusRequest(Application(a), Group(b)) => {
+            frame(b'\\', &[route, &[0xff, 0x00, 0x7a, a, b]])
+        }
+        LevelRequest(Application(a), Group(b)) => {
+            frame(b'\\', &[route, &[0xff, 0x00, 0x73, 0x07, a, b]])
+        }
+        Sal(Application(a), ref data) => frame(b'\\', &[route, &[a, 0x00], data]),
+        Label(Application(a), Group(g), ref text) => {
+            let text = &text.as_bytes()[..text.len().min(LABEL_LEN)];
+            let command = 0xa0 | (3 + text.len()) as u8;
+            let head = [a, 0x00, command, g, LABEL_TEXT, LABEL_ENGLISH];
+            frame(b'\\', &[route, &head, text])
+        }
+        Identify(Address(u), Attribute(x)) if local => {
+            frame(b'\\', &[&[unicast, u, 0x00, 0x21, x]])
+        }
+        Recall(Address(u), Param(p), n) if local => {
+            frame(b'\\', &[&[unicast, u, 0x00, 0x1a, p, n]])
+        }
+        WriteParam(Address(u), Param(p), ref value) if local => {
+            let command = 0xa0 | (value.len().saturating_add(1).min(0x1f) as u8);
+            frame(b'\\', &[&[unicast, u, 0x00, command, p], value])
+        }
+        ReadLabel(Address(u), Group(g)) if local => frame(b'\\', &[&[unicast, u, 0x00, 0x11, g]]),
+        SetParam(Param(p), Setting(s)) if local => frame(b'@', &[&[0xA3, p, 0x00, s]]),
+        Reset if local => buf.extend_from_slice(b"~"),
+        _ => (),
+    }
+}
+
+/// The frame a PCI would report on seeing `mesg` sent from unit 0.
+///
+/// Only short form SAL commands are echoed; this is the basis of
+/// the encode/decode round trip checked by the fuzzer.
+pub fn echo(mesg: &Message) -> Option<Bytes> {
+    let mut sent = BytesMut::new();
+    encode_into(mesg, &SR_CHK, Priority::Low, None, &mut sent);
+    let body = sent.strip_prefix(b"\\05")?.strip_suffix(b"\r")?;
+    Some(Bytes::from([b"0500", body].concat()))
+}
+
+/// The priority class of a command, carried in the high bits of its header.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum Priority {
+    #[default]
+    Low,
+    Medium,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    fn bits(self) -> u8 {
+        match self {
+            Priority::Low => 0x00,
+            Priority::Medium => 0x40,
+            Priority::High => 0x80,
+            Priority::Urgent => 0xc0,
+        }
+    }
+}
+
+/// The Options 1 settings established by the preamble.
+pub fn options1() -> Setting {
+    SMART | ID_MON | CONNECT | MONITOR | SR_CHK
+}
+
+/// The Options 3 settings established by the preamble.
+pub fn options3() -> Setting {
+    LOCAL_SAL | EX_STAT | POWER_UP_NOTIFY | PARAM_CHANGE_NOTIFY
+}
+
+/// The interface options the preamble sets, by default `options1()`
+/// and `options3()`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct PciOptions {
+    pub options1: Setting,
+    pub options3: Setting,
+}
+
+impl Default for PciOptions {
+    fn default() -> Self {
+        PciOptions {
+            options1: options1(),
+            options3: options3(),
+        }
+    }
+}
+
+impl PciOptions {
+    /// These options for a PCI that only has basic mode and standard
+    /// status reports, as older ones do.
+    pub fn basic(&self) -> PciOptions {
+        PciOptions {
+            options1: self.options1.without(&SMART),
+            options3: self.options3.without(&EX_STAT),
+        }
+    }
+
+    /// The name of the mode these options put the PCI in.
+    pub fn mode(&self) -> &'static str {
+        if self.options1.contains(&SMART) {
+            "smart"
+        } else {
+            "basic"
+        }
+    }
+}
+
+/// The commands that reset and configure the PCI, in order.
+pub fn preamble_messages(options: &PciOptions) -> [Message; 3] {
+    [
+        Reset,
+        SetParam(OPTIONS3, options.options3.clone()),
+        SetParam(OPTIONS1, options.options1.clone()),
+    ]
+}
+
+pub fn preamble(options: &PciOptions) -> Bytes {
+    let mut p = BytesMut::new();
+    for mesg in preamble_messages(options) {
+        p.extend(encode(mesg));
+    }
+    p.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn setvar_on() {
+        let m = decode(b"05003800790400".as_ref().into());
+        assert_eq!(m, [SetVar(LIGHTING, Group(4), ON, INSTANT)])
+    }
+
+    #[test]
+    fn setvar_off() {
+        let m = decode(b"05003800010400".as_ref().into());
+        assert_eq!(m, [SetVar(LIGHTING, Group(4), OFF, INSTANT)])
+    }
+
+    #[test]
+    fn stop_ramp() {
+        let m = decode(b"05003800090400".as_ref().into());
+        assert_eq!(m, [StopRamp(LIGHTING, Group(4))])
+    }
+
+    #[test]
+    fn setvar_level() {
+        let m = decode(b"050038002A041F00".as_ref().into());
+        assert_eq!(m, [SetVar(LIGHTING, Group(4), Level(0x1f), ramp(30))])
+    }
+
+    #[test]
+    fn lowercase_frames() {
+        let m = decode(b"050038002a041f00".as_ref().into());
+        assert_eq!(m, [SetVar(LIGHTING, Group(4), Level(0x1f), ramp(30))]);
+        let m = decode_with(b"0500cB0082037F2c".as_ref().into(), &SR_CHK);
+        assert_eq!(m, [SetNetworkVar(Variable(3), 0x7f)]);
+        let m = decode(b"86081500e90738100000a5aA5a6500".as_ref().into());
+        assert_eq!(
+            m,
+            [LevelStatus(
+                LIGHTING,
+                vec![(Group(17), Level(0xfc)), (Group(18), Level(0x43))]
+            )]
+        );
+    }
+
+    #[test]
+    fn signed_hex_rejected() {
+        assert_unrecognised(b"0500380079+400".as_ref().into());
+    }
+
+    #[test]
+    fn status_shares_frame() {
+        let frame = Bytes::from_static(b"86081500F74038B0FF7F0080C6");
+        let m = decode(frame.clone());
+        let [Status(_, _, data)] = &m[..] else {
+            panic!("expected status: {m:?}")
+        };
+        assert_eq!(data.to_vec(), [0xff, 0x7f, 0x00, 0x80]);
+        assert_eq!(data.as_hex().as_ptr(), frame[16..].as_ptr());
+    }
+
+    #[test]
+    fn status_zero() {
+        let m = decode(
+            b"86081500F74038B000000000000000000000000000000000000000003E"
+                .as_ref()
+                .into(),
+        );
+        assert_eq!(m, [Status(LIGHTING, Group(176), vec![0; 20].into())]);
+    }
+
+    #[test]
+    fn level_status() {
+        let m = decode(b"86081500E90738100000A5AA5A6500".as_ref().into());
+        assert_eq!(
+            m,
+            [LevelStatus(
+                LIGHTING,
+                vec![(Group(17), Level(0xfc)), (Group(18), Level(0x43))]
+            )]
+        );
+    }
+
+    #[test]
+    fn level_status_bad_symbol() {
+        assert_unrecognised(b"86081500E7073810A5AB00".as_ref().into());
+    }
+
+    #[test]
+    fn level_status_odd_length() {
+        assert_unrecognised(b"86081500E6073810A500".as_ref().into());
+    }
+
+    #[test]
+    fn short_message() {
+        assert_unrecognised(b"050038007904".as_ref().into());
+    }
+
+    #[test]
+    fn empty_message() {
+        assert_unrecognised(b"".as_ref().into());
+    }
+
+    #[test]
+    fn long_message() {
+        assert_unrecognised(b"0500380024041F00A1".as_ref().into());
+    }
+
+    #[test]
+    fn odd_length() {
+        assert_unrecognised(b"0500380079040".as_ref().into());
+    }
+
+    #[test]
+    fn not_hex() {
+        assert_unrecognised(b"0500380009z400".as_ref().into());
+    }
+
+    #[test]
+    fn checksum_computed() {
+        assert_eq!(checksum(&[0x05, 0x38, 0x00, 0x79, 0x04]), 0x46);
+        assert_eq!(checksum(&[]), 0);
+    }
+
+    #[test]
+    fn checksum_good() {
+        let m = decode_with(b"05003800790446".as_ref().into(), &SR_CHK);
+        assert_eq!(m, [SetVar(LIGHTING, Group(4), ON, INSTANT)])
+    }
+
+    #[test]
+    fn checksum_good_status() {
+        let m = decode_with(
+            b"86081500F74038B000000000000000000000000000000000000000003E"
+                .as_ref()
+                .into(),
+            &(SMART | SR_CHK),
+        );
+        assert_eq!(m, [Status(LIGHTING, Group(176), vec![0; 20].into())]);
+    }
+
+    #[test]
+    fn checksum_bad() {
+        let bytes: Bytes = b"05003800790447".as_ref().into();
+        let m = decode_with(bytes.clone(), &SR_CHK);
+        let fault = Fault {
+            kind: FaultKind::BadChecksum,
+            offset: 12,
+        };
+        assert_eq!(m, [Unrecognised(bytes, fault)])
+    }
+
+    #[test]
+    fn checksum_ignored() {
+        let m = decode_with(b"05003800790447".as_ref().into(), &SMART);
+        assert_eq!(m, [SetVar(LIGHTING, Group(4), ON, INSTANT)])
+    }
+
+    #[test]
+    fn checksum_unrecognised() {
+        let bytes: Bytes = b"0500380079040".as_ref().into();
+        let m = decode_with(bytes, &SR_CHK);
+        assert!(matches!(
+            m[..],
+            [Unrecognised(
+                _,
+                Fault {
+                    kind: FaultKind::Truncated,
+                    ..
+                }
+            )]
+        ))
+    }
+
+    #[test]
+    fn encode_setvar() {
+        let b = encode(SetVar(LIGHTING, Group(4), Level(0x80), INSTANT));
+        assert_eq!(&b[..], b"\\053800020480\r");
+    }
+
+    #[test]
+    fn encode_setvar_ramp() {
+        let b = encode(SetVar(LIGHTING, Group(4), Level(0x1f), ramp(30)));
+        assert_eq!(&b[..], b"\\0538002A041F\r");
+    }
+
+    #[test]
+    fn encode_setvar_ramp_rounds_up() {
+        let b = encode(SetVar(LIGHTING, Group(4), Level(0x1f), ramp(25)));
+        assert_eq!(&b[..], b"\\0538002A041F\r");
+    }
+
+    #[test]
+    fn encode_setvar_ramp_too_long() {
+        let b = encode(SetVar(LIGHTING, Group(4), Level(0x1f), ramp(2000)));
+        assert_eq!(&b[..], b"\\0538007A041F\r");
+    }
+
+    #[test]
+    fn encode_on() {
+        let b = encode(SetVar(LIGHTING, Group(4), ON, INSTANT));
+        assert_eq!(&b[..], b"\\0538007904\r");
+    }
+
+    #[test]
+    fn encode_off() {
+        let b = encode(SetVar(LIGHTING, Group(4), OFF, INSTANT));
+        assert_eq!(&b[..], b"\\0538000104\r");
+    }
+
+    #[test]
+    fn encode_on_with_ramp() {
+        let b = encode(SetVar(LIGHTING, Group(4), ON, ramp(4)));
+        assert_eq!(&b[..], b"\\0538000A04FF\r");
+    }
+
+    #[test]
+    fn encode_stop_ramp() {
+        let b = encode_with(StopRamp(LIGHTING, Group(4)), &SR_CHK);
+        assert_eq!(&b[..], b"\\0538000904B6\r");
+    }
+
+    #[test]
+    fn encode_setvar_checked() {
+        let b = encode_with(SetVar(LIGHTING, Group(4), Level(0x80), INSTANT), &SR_CHK);
+        assert_eq!(&b[..], b"\\0538000204803D\r");
+        assert!(checksum_valid(&b[1..b.len() - 1]));
+    }
+
+    #[test]
+    fn encode_setparam_checked() {
+        let b = encode_with(SetParam(OPTIONS3, LOCAL_SAL | EX_STAT), &SR_CHK);
+        assert_eq!(&b[..], b"@A342000A11\r");
+    }
+
+    #[test]
+    fn basic_options() {
+        let basic = PciOptions::default().basic();
+        assert_eq!(basic.options1, CONNECT | SR_CHK | MONITOR | ID_MON);
+        assert_eq!(basic.mode(), "basic");
+        assert!(!basic.options3.contains(&EX_STAT));
+        assert_eq!(basic.basic(), basic);
+    }
+
+    #[test]
+    fn preamble_unchecked() {
+        let p = preamble(&PciOptions::default());
+        assert_eq!(&p[..], b"~@A342000F\r@A3300079\r");
+    }
+
+    #[test]
+    fn encode_confirmation_code() {
+        let b = encode_confirmed(StopRamp(LIGHTING, Group(4)), &SR_CHK, Code::new(b'h'));
+        assert_eq!(&b[..], b"\\0538000904B6h\r");
+    }
+
+    #[test]
+    fn confirmation_delivered() {
+        let m = decode_with(b"g.".as_ref().into(), &SR_CHK);
+        assert_eq!(m, [Confirmation(Code(b'g'), Outcome::Delivered)])
+    }
+
+    #[test]
+    fn confirmation_failed() {
+        let m = decode(b"z#".as_ref().into());
+        assert_eq!(m, [Confirmation(Code(b'z'), Outcome::Failed)])
+    }
+
+    #[test]
+    fn confirmation_bad_code() {
+        assert_unrecognised(b"a.".as_ref().into());
+    }
+
+    #[test]
+    fn control_responses() {
+        assert_eq!(decode(b"+".as_ref().into()), [Prompt]);
+        assert_eq!(decode(b"++".as_ref().into()), [PowerUp]);
+        assert_eq!(decode_with(b"!".as_ref().into(), &SR_CHK), [PciError]);
+        assert_eq!(decode(b"=".as_ref().into()), [ResetAck]);
+        assert_unrecognised(b"!!".as_ref().into());
+    }
+
+    #[test]
+    fn code_wraps() {
+        assert_eq!(Code(b'z').succ(), Code(b'g'));
+        assert_eq!(Code::new(b'f'), None);
+    }
+
+    #[test]
+    fn other_lighting_application() {
+        let m = decode(b"05003900790400".as_ref().into());
+        assert_eq!(m, [SetVar(Application(0x39), Group(4), ON, INSTANT)])
+    }
+
+    #[test]
+    fn other_application() {
+        let m = decode(b"0500C00002050100".as_ref().into());
+        assert_eq!(m, [Sal(Application(0xc0), vec![0x02, 0x05, 0x01])])
+    }
+
+    #[test]
+    fn encode_application() {
+        let b = encode(SetVar(Application(0x39), Group(4), OFF, INSTANT));
+        assert_eq!(&b[..], b"\\0539000104\r");
+        let b = encode(Sal(Application(0xc0), vec![0x02, 0x05, 0x01]));
+        assert_eq!(&b[..], b"\\05C000020501\r");
+    }
+
+    #[test]
+    fn trigger_event() {
+        let m = decode(b"0500CA0002050100".as_ref().into());
+        assert_eq!(m, [Trigger(Group(5), Action(1))])
+    }
+
+    #[test]
+    fn trigger_min_max() {
+        let m = decode(b"0500CA00790500".as_ref().into());
+        assert_eq!(m, [Trigger(Group(5), TRIGGER_MAX)]);
+        let m = decode(b"0500CA00010500".as_ref().into());
+        assert_eq!(m, [Trigger(Group(5), TRIGGER_MIN)]);
+    }
+
+    #[test]
+    fn indicator_kill() {
+        let m = decode(b"0500CA00090500".as_ref().into());
+        assert_eq!(m, [IndicatorKill(Group(5))])
+    }
+
+    #[test]
+    fn trigger_bad_length() {
+        assert_unrecognised(b"0500CA000205010200".as_ref().into());
+    }
+
+    #[test]
+    fn encode_trigger() {
+        let b = encode_with(Trigger(Group(5), Action(1)), &SR_CHK);
+        assert_eq!(&b[..], b"\\05CA0002050129\r");
+        let b = encode(IndicatorKill(Group(5)));
+        assert_eq!(&b[..], b"\\05CA000905\r");
+    }
+
+    #[test]
+    fn enable_network_var() {
+        let m = decode(b"0500CB0082037F00".as_ref().into());
+        assert_eq!(m, [SetNetworkVar(Variable(3), 0x7f)])
+    }
+
+    #[test]
+    fn enable_unknown_command() {
+        assert_unrecognised(b"0500CB0002037F00".as_ref().into());
+    }
+
+    #[test]
+    fn encode_enable() {
+        let b = encode(SetNetworkVar(Variable(3), 0x7f));
+        assert_eq!(&b[..], b"\\05CB0082037F\r");
+    }
+
+    #[test]
+    fn security_zones() {
+        let m = decode(b"0500D000210700".as_ref().into());
+        assert_eq!(m, [ZoneChange(Zone(7), ZoneState::Sealed)]);
+        let m = decode(b"0500D000290700".as_ref().into());
+        assert_eq!(m, [ZoneChange(Zone(7), ZoneState::Unsealed)]);
+    }
+
+    #[test]
+    fn security_arming() {
+        let m = decode(b"0500D000190100".as_ref().into());
+        assert_eq!(m, [Armed(ARMED_AWAY)]);
+        let m = decode(b"0500D000190000".as_ref().into());
+        assert_eq!(m, [Armed(DISARMED)]);
+    }
+
+    #[test]
+    fn security_alarm() {
+        assert_eq!(decode(b"0500D0000800".as_ref().into()), [Alarm(true)]);
+        assert_eq!(decode(b"0500D0001000".as_ref().into()), [Alarm(false)]);
+    }
+
+    #[test]
+    fn measurement_lux() {
+        let m = decode(b"0500E4000E0102100001F400".as_ref().into());
+        let r = Reading {
+            device: 1,
+            channel: 2,
+            unit: LUX,
+            value: 500,
+            exponent: 0,
+        };
+        assert_eq!(m, [Measurement(r)])
+    }
+
+    #[test]
+    fn measurement_negative() {
+        let m = decode(b"0500E4000E010100FFFF1500".as_ref().into());
+        match &m[..] {
+            [Measurement(r)] => {
+                assert_eq!(r.unit, CELSIUS);
+                assert!((r.quantity() - -23.5).abs() < 1e-9)
+            }
+            _ => panic!("expected a measurement: {m:?}"),
+        }
+    }
+
+    #[test]
+    fn temperature_broadcast() {
+        let m = decode(b"050019000203550A".as_ref().into());
+        assert_eq!(m, [Temperature(Group(3), Degrees(0x55))]);
+        assert_eq!(Degrees(0x55).celsius(), 21.25);
+    }
+
+    #[test]
+    fn clock_time() {
+        let m = decode(b"0500DF000D0114233B0100".as_ref().into());
+        let t = Time {
+            hour: 20,
+            minute: 35,
+            second: 59,
+            dst: 1,
+        };
+        assert_eq!(m, [ClockTime(t.clone())]);
+        let b = encode(ClockTime(t));
+        assert_eq!(&b[..], b"\\05DF000D0114233B01\r");
+    }
+
+    #[test]
+    fn clock_date() {
+        let m = decode(b"0500DF000E0207EA0A100300".as_ref().into());
+        let d = Date {
+            year: 2026,
+            month: 10,
+            day: 16,
+            weekday: 3,
+        };
+        assert_eq!(m, [ClockDate(d.clone())]);
+        let b = encode(ClockDate(d));
+        assert_eq!(&b[..], b"\\05DF000E0207EA0A1003\r");
+    }
+
+    #[test]
+    fn clock_request() {
+        assert_eq!(decode(b"0500DF00110300".as_ref().into()), [ClockRequest]);
+    }
+
+    #[test]
+    fn multiple_sals() {
+        let m = decode(b"05003800790401052A061F090000".as_ref().into());
+        assert_eq!(
+            m,
+            [
+                SetVar(LIGHTING, Group(4), ON, INSTANT),
+                SetVar(LIGHTING, Group(5), OFF, INSTANT),
+                SetVar(LIGHTING, Group(6), Level(0x1f), ramp(30)),
+                StopRamp(LIGHTING, Group(0)),
+            ]
+        )
+    }
+
+    #[test]
+    fn multiple_sals_checked() {
+        let m = decode_with(b"050038007904010540".as_ref().into(), &SR_CHK);
+        assert_eq!(
+            m,
+            [
+                SetVar(LIGHTING, Group(4), ON, INSTANT),
+                SetVar(LIGHTING, Group(5), OFF, INSTANT),
+            ]
+        )
+    }
+
+    #[test]
+    fn multiple_sals_one_bad() {
+        assert_unrecognised(b"050038007904FF0500".as_ref().into());
+    }
+
+    #[test]
+    fn multiple_sals_truncated() {
+        assert_unrecognised(b"05003800790401052A0600".as_ref().into());
+    }
+
+    #[test]
+    fn priority_header() {
+        let m = decode(b"C5003800790400".as_ref().into());
+        assert_eq!(m, [SetVar(LIGHTING, Group(4), ON, INSTANT)])
+    }
+
+    #[test]
+    fn bridged_header() {
+        let m = decode_with(b"030005023800790441".as_ref().into(), &SR_CHK);
+        let on = SetVar(LIGHTING, Group(4), ON, INSTANT);
+        assert_eq!(m, [Bridged(Address(5), 2, Box::new(on))])
+    }
+
+    #[test]
+    fn bridged_header_truncated() {
+        assert_unrecognised(b"03000502380000".as_ref().into());
+    }
+
+    #[test]
+    fn bridged_round_trip() {
+        let on = SetVar(LIGHTING, Group(4), ON, INSTANT);
+        let m = Bridged(Address(5), 2, Box::new(on));
+        let sent = encode_with(m.clone(), &SR_CHK);
+        // as monitored, with the source unit after the header
+        let heard = [&sent[1..3], b"00", &sent[3..sent.len() - 1]].concat();
+        assert_eq!(decode_with(Bytes::from(heard), &SR_CHK), [m]);
+    }
+
+    #[test]
+    fn encode_identify() {
+        let b = encode_with(Identify(Address(3), FIRMWARE_VERSION), &SR_CHK);
+        assert_eq!(&b[..], b"\\0603002102D4\r");
+    }
+
+    #[test]
+    fn encode_recall() {
+        let b = encode(Recall(Address(3), Param(0x30), 1));
+        assert_eq!(&b[..], b"\\0603001A3001\r");
+    }
+
+    #[test]
+    fn encode_write_param() {
+        let b = encode(WriteParam(Address(3), Param(0x30), vec![0x79]));
+        assert_eq!(&b[..], b"\\060300A23079\r");
+    }
+
+    #[test]
+    fn encode_read_label() {
+        let b = encode(ReadLabel(Address(3), Group(4)));
+        assert_eq!(&b[..], b"\\0603001104\r");
+    }
+
+    #[test]
+    fn decode_label_reply() {
+        let m = decode(b"86031500860448616C6C00".as_ref().into());
+        assert_eq!(m, [Reply(Address(3), 4, b"Hall".to_vec())])
+    }
+
+    #[test]
+    fn encode_status_request() {
+        let b = encode_with(StatusRequest(LIGHTING, Group(0x20)), &SR_CHK);
+        assert_eq!(&b[..], b"\\05FF007A38202A\r");
+    }
+
+    #[test]
+    fn encode_level_request() {
+        let b = encode_with(LevelRequest(LIGHTING, Group(0)), &SR_CHK);
+        assert_eq!(&b[..], b"\\05FF00730738004A\r");
+    }
+
+    #[test]
+    fn decode_reply() {
+        let m = decode(b"86031500840201020300".as_ref().into());
+        assert_eq!(m, [Reply(Address(3), 2, vec![1, 2, 3])])
+    }
+
+    #[test]
+    fn decode_acknowledge() {
+        let m = decode(b"8603150032300000".as_ref().into());
+        assert_eq!(m, [Acknowledge(Address(3), 0x30, 0)])
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let messages = vec![
+            SetVar(LIGHTING, Group(4), Level(0x1f), ramp(30)),
+            Status(LIGHTING, Group(176), vec![0; 4].into()),
+            Confirmation(Code(b'g'), Outcome::Delivered),
+            Unrecognised(
+                b"0500".as_ref().into(),
+                Fault {
+                    kind: FaultKind::Truncated,
+                    offset: 4,
+                },
+            ),
+        ];
+        let json = serde_json::to_string(&messages).unwrap();
+        let back: Vec<Message> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, messages);
+    }
+
+    #[test]
+    fn json_form() {
+        let json = serde_json::to_string(&SetVar(LIGHTING, Group(4), ON, INSTANT)).unwrap();
+        assert_eq!(json, r#"{"SetVar":[56,4,255,0]}"#);
+    }
+
+    #[test]
+    fn display() {
+        let m = SetVar(LIGHTING, Group(4), Level(0x8c), ramp(30));
+        assert_eq!(m.to_string(), "group 4 -> 55% over 30s");
+        let m = SetVar(Application(0x39), Group(4), OFF, INSTANT);
+        assert_eq!(m.to_string(), "application 0x39 group 4 -> 0%");
+        let m = LevelStatus(LIGHTING, vec![(Group(17), ON), (Group(18), Level(0x40))]);
+        assert_eq!(m.to_string(), "levels 17:100% 18:25%");
+        assert_eq!(Reset.to_string(), "Reset");
+    }
+
+    #[test]
+    fn ramp_range() {
+        assert_eq!(Ramp::new(1020).map(|r| r.secs()), Ok(1020));
+        assert_eq!(
+            Ramp::try_from(1021),
+            Err(RangeError {
+                kind: "ramp",
+                value: 1021
+            })
+        );
+    }
+
+    #[test]
+    fn byte_range() {
+        assert_eq!(Group::try_from(255u16), Ok(Group::new(255)));
+        assert!(Group::try_from(256u16).is_err());
+        assert_eq!(Level::try_from(128u16).map(u8::from), Ok(128));
+        assert_eq!(
+            Level::try_from(300u16).unwrap_err().to_string(),
+            "level out of range: 300"
+        );
+    }
+
+    #[test]
+    fn level_percent() {
+        assert_eq!(Level::from_percent(0.0), OFF);
+        assert_eq!(Level::from_percent(100.0), ON);
+        assert_eq!(Level::from_percent(50.0), Level(128));
+        assert_eq!(Level::from_percent(150.0), ON);
+        assert_eq!(Level::from_percent(-5.0), OFF);
+        assert_eq!(Level::from_percent(f32::NAN), OFF);
+        for v in 0..=255 {
+            assert_eq!(Level::from_percent(Level(v).as_percent()), Level(v));
+        }
+    }
+
+    #[test]
+    fn setting_value() {
+        assert_eq!((SMART | SR_CHK).value(), 0x18);
+        assert_eq!(Setting::from(0x18), SMART | SR_CHK);
+    }
+
+    fn ramp(secs: u64) -> Ramp {
+        Ramp(Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn ramp_duration() {
+        let r = Ramp::from_duration(Duration::from_millis(2500)).unwrap();
+        assert_eq!(r.duration(), Duration::from_millis(2500));
+        assert_eq!(r.secs(), 3);
+        assert_eq!(r.encode(), 0x0a);
+        assert_eq!(r.to_string(), "2.5s");
+        assert_eq!(
+            Ramp::decode(0x2a).map(|r| r.duration()),
+            Some(Duration::from_secs(30))
+        );
+        assert!(Ramp::from_duration(Duration::from_secs(1021)).is_err());
+    }
+
+    #[test]
+    fn ramp_rounding() {
+        let r = ramp(25);
+        assert_eq!(r.encode_rounded(Rounding::Ceiling), Ok(0x2a));
+        assert_eq!(r.encode_rounded(Rounding::Floor), Ok(0x22));
+        assert_eq!(r.encode_rounded(Rounding::Nearest), Ok(0x2a));
+        assert_eq!(ramp(22).encode_rounded(Rounding::Nearest), Ok(0x22));
+        assert!(r.encode_rounded(Rounding::Strict).is_err());
+        assert_eq!(ramp(30).encode_rounded(Rounding::Strict), Ok(0x2a));
+        assert_eq!(ramp(2000).encode_rounded(Rounding::Nearest), Ok(0x7a));
+        assert_eq!(r.rounded(Rounding::Floor), Ok(ramp(20)));
+    }
+
+    #[test]
+    fn encode_label() {
+        let b = encode(Label(LIGHTING, Group(4), "Dinner".into()));
+        assert_eq!(&b[..], b"\\053800A904000144696E6E6572\r");
+        let long = Label(LIGHTING, Group(4), "x".repeat(40).into());
+        assert_eq!(encode(long).len(), 1 + 2 * (7 + LABEL_LEN) + 1);
+    }
+
+    #[test]
+    fn label_round_trip() {
+        let m = Label(LIGHTING, Group(4), "Dinner".into());
+        assert_eq!(
+            decode_with(echo(&m).unwrap(), &SR_CHK),
+            std::slice::from_ref(&m)
+        );
+        assert_eq!(m.to_string(), r#"group 4 label "Dinner""#);
+    }
+
+    #[test]
+    fn standard_status() {
+        let m = decode(b"86081500D73810650200".as_ref().into());
+        assert_eq!(m, [Status(LIGHTING, Group(16), vec![0x65, 0x02].into())]);
+        assert_eq!(
+            m[0].group_states(),
+            Some(vec![
+                (Group(16), GroupState::On),
+                (Group(17), GroupState::On),
+                (Group(18), GroupState::Off),
+                (Group(19), GroupState::On),
+                (Group(20), GroupState::Off),
+            ])
+        );
+    }
+
+    #[test]
+    fn level_status_states() {
+        let m = LevelStatus(LIGHTING, vec![(Group(17), OFF), (Group(18), Level(0x40))]);
+        assert_eq!(
+            m.group_states(),
+            Some(vec![
+                (Group(17), GroupState::Off),
+                (Group(18), GroupState::On)
+            ])
+        );
+    }
+
+    #[test]
+    fn all_groups() {
+        let m = Message::set_all().off().build().unwrap();
+        assert_eq!(m.target(), Some(Target::All));
+        assert_eq!(m.to_string(), "all groups -> 0%");
+        assert_eq!(&encode(m)[..], b"\\05380001FF\r");
+        let m = decode(b"05003800790400".as_ref().into());
+        assert_eq!(m[0].target(), Some(Target::Group(Group(4))));
+        assert_eq!("ALL".parse(), Ok(Target::All));
+        assert_eq!("255".parse(), Ok(Target::All));
+        assert_eq!("4".parse(), Ok(Target::Group(Group(4))));
+    }
+
+    #[test]
+    fn param_changed() {
+        let m = decode_with(b"86FAFA00A33000793A".as_ref().into(), &SR_CHK);
+        assert_eq!(m, [ParamChanged(OPTIONS1, 0x79)]);
+        assert_eq!(m[0].to_string(), "parameter 0x30 changed to 0x79");
+    }
+
+    #[test]
+    fn builder() {
+        let m = Message::set(4).level(50).ramp_secs(30).build();
+        assert_eq!(m, Ok(SetVar(LIGHTING, Group(4), Level(128), ramp(30))));
+        let m = Message::set(4).application(0x39).off().build();
+        assert_eq!(m, Ok(SetVar(Application(0x39), Group(4), OFF, INSTANT)));
+        let m = Message::set(4).via(5, 2).stop();
+        assert_eq!(
+            m,
+            Ok(Bridged(
+                Address(5),
+                2,
+                Box::new(StopRamp(LIGHTING, Group(4)))
+            ))
+        );
+    }
+
+    #[test]
+    fn builder_validates() {
+        let e = Message::set(4).level(101).ramp_secs(2000).build();
+        assert_eq!(e.unwrap_err().to_string(), "percent out of range: 101");
+        assert!(Message::set(4).application(0xca).build().is_err());
+        assert!(Message::set(4)
+            .ramp(Duration::from_secs(1021))
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn encode_bridged() {
+        let m = Bridged(
+            Address(5),
+            2,
+            Box::new(SetVar(LIGHTING, Group(4), ON, INSTANT)),
+        );
+        assert!(m.is_confirmable());
+        assert_eq!(m.group(), Some(&Group(4)));
+        let b = encode_with(m, &SR_CHK);
+        assert_eq!(&b[..], b"\\0305023800790441\r");
+        let b = encode(Bridged(
+            Address(5),
+            2,
+            Box::new(Identify(Address(3), UNIT_TYPE)),
+        ));
+        assert!(b.is_empty());
+    }
+
+    #[test]
+    fn encode_priority() {
+        let mut buf = BytesMut::new();
+        let m = SetVar(LIGHTING, Group(4), ON, INSTANT);
+        encode_into(&m, &SR_CHK, Priority::High, None, &mut buf);
+        assert_eq!(&buf[..], b"\\8538007904C6\r");
+    }
+
+    #[test]
+    fn encode_reuses_buffer() {
+        let mut buf = BytesMut::with_capacity(64);
+        let m = SetVar(LIGHTING, Group(4), Level(0x80), INSTANT);
+        encode_into(&m, &SR_CHK, Priority::Low, Code::new(b'g'), &mut buf);
+        assert_eq!(buf, encode_confirmed(m.clone(), &SR_CHK, Code::new(b'g')));
+        let ptr = buf.as_ptr();
+        buf.clear();
+        encode_into(&m, &SR_CHK, Priority::Low, None, &mut buf);
+        assert_eq!(buf, encode_with(m, &SR_CHK));
+        assert_eq!(buf.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn echo_round_trip() {
+        let frames: [&[u8]; 6] = [
+            b"050038007904010500",
+            b"C5003900090400",
+            b"0500CB0082037F00",
+            b"0500CA00020501090700",
+            b"0500DF000D0114233B0100",
+            b"0500AB00010203040500",
+        ];
+        for raw in frames {
+            for mesg in decode(Bytes::copy_from_slice(raw)) {
+                let echoed = echo(&mesg).expect("a SAL message");
+                assert_eq!(decode_with(echoed, &SR_CHK), [mesg]);
+            }
+        }
+    }
+
+    #[test]
+    fn write_long_param() {
+        let b = encode(WriteParam(Address(3), Param(0x10), vec![0; 255]));
+        assert_eq!(&b[..11], b"\\060300BF10");
+    }
+
+    #[test]
+    fn diagnostics() {
+        use FaultKind::*;
+        let cases: [(&[u8], FaultKind, usize); 10] = [
+            (b"0500380009z400", NonHex, 10),
+            (b"0500380079040", Truncated, 12),
+            (b"", Truncated, 0),
+            (b"050038007904", Truncated, 8),
+            (b"05003800790401052A0600", Truncated, 16),
+            (b"06003800790400", UnknownHeader, 0),
+            (b"0500CB0002037F00", WrongApplication, 8),
+            (b"05003801790400", Malformed, 6),
+            (b"86081500E7073810A5AB00", Malformed, 8),
+            (b"!!", NonHex, 0),
+        ];
+        for (raw, kind, offset) in cases {
+            let bytes = Bytes::copy_from_slice(raw);
+            let m = decode(bytes.clone());
+            assert_eq!(m, [Unrecognised(bytes, Fault { kind, offset })], "{raw:?}");
+        }
+    }
+
+    #[test]
+    fn display_fault() {
+        let m = decode(b"0500380009z400".as_ref().into());
+        assert_eq!(
+            m[0].to_string(),
+            r#"unrecognised b"0500380009z400": non-hex character at 10"#
+        );
+    }
+
+    fn assert_unrecognised(bytes: Bytes) {
+        let m = decode(bytes.clone());
+        assert!(
+            matches!(&m[..], [Unrecognised(b, _)] if *b == bytes),
+            "{m:?}"
+        )
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn ramp() -> impl Strategy<Value = Ramp> {
+            proptest::sample::select(&RAMP_CODES[..]).prop_map(|(c, _)| Ramp::decode(c).unwrap())
+        }
+
+        fn lighting() -> impl Strategy<Value = Application> {
+            (0x30u8..=0x5f).prop_map(Application)
+        }
+
+        fn other_app() -> impl Strategy<Value = Application> {
+            any::<u8>()
+                .prop_map(Application)
+                .prop_filter("a decoded application", |a| interpreter(*a).is_none())
+        }
+
+        /// Messages sent as short form SAL commands.
+        fn sal_message() -> impl Strategy<Value = Message> {
+            prop_oneof![
+                (lighting(), any::<u8>(), any::<u8>(), ramp()).prop_map(|(a, g, l, r)| SetVar(
+                    a,
+                    Group(g),
+                    Level(l),
+                    r
+                )),
+                (lighting(), any::<u8>()).prop_map(|(a, g)| StopRamp(a, Group(g))),
+                (any::<u8>(), any::<u8>()).prop_map(|(g, x)| Trigger(Group(g), Action(x))),
+                any::<u8>().prop_map(|g| IndicatorKill(Group(g))),
+                (any::<u8>(), any::<u8>()).prop_map(|(v, x)| SetNetworkVar(Variable(v), x)),
+                any::<[u8; 4]>().prop_map(|[hour, minute, second, dst]| ClockTime(Time {
+                    hour,
+                    minute,
+                    second,
+                    dst
+                })),
+                (any::<u16>(), any::<[u8; 3]>()).prop_map(|(year, [month, day, weekday])| {
+                    ClockDate(Date {
+                        year,
+                        month,
+                        day,
+                        weekday,
+                    })
+                }),
+                Just(ClockRequest),
+                (other_app(), proptest::collection::vec(any::<u8>(), 1..20))
+                    .prop_map(|(a, data)| Sal(a, data)),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn sal_round_trip(m in sal_message()) {
+                let echoed = echo(&m).expect("a SAL message");
+                prop_assert_eq!(decode_with(echoed, &SR_CHK), [m]);
+            }
+
+            #[test]
+            fn ramp_encode_is_ceiling(secs in 0..=Ramp::MAX.as_secs()) {
+                let r = Ramp(Duration::from_secs(secs));
+                let sent = Ramp::decode(r.encode()).unwrap();
+                prop_assert!(sent.duration() >= r.duration());
+                let floor = Ramp::decode(r.encode_rounded(Rounding::Floor).unwrap()).unwrap();
+                prop_assert!(floor.duration() <= r.duration());
+            }
+
+            #[test]
+            fn decode_never_panics(raw in proptest::collection::vec(any::<u8>(), 0..40)) {
+                let m = decode_with(Bytes::from(raw), &SR_CHK);
+                prop_assert!(!m.is_empty());
+            }
+        }
+
+        #[test]
+        fn ramp_codes_round_trip() {
+            for (c, _) in RAMP_CODES {
+                assert_eq!(Ramp::decode(c).map(|r| r.encode()), Some(c));
+            }
+        }
     }
 }
@@ -0,0 +1,140 @@
+//! `command` says what should happen to the lights, leaving the gaffer
+//! to decide which CBUS messages will do it.
+//!
+//! A command is for a group, or a room or scene named in the
+//! configuration:
+//!
+//! ```toml
+//! [rooms]
+//! kitchen = [4, 5]
+//!
+//! [scenes.evening]
+//! 4 = 128
+//! 5 = 0
+//! ```
+use crate::codec::{Group, Level, Message, Ramp, LIGHTING, ON};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+/// What a command is for.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Subject {
+    Group(Group),
+    Room(Box<str>),
+    Scene(Box<str>),
+}
+
+/// An intent, such as dimming a room.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Command {
+    /// Bring the subject to a level over the ramp.  A scene's groups go
+    /// to their own levels scaled by this one, so `ON` recalls it.
+    Level(Subject, Level, Ramp),
+    /// Stop the subject's groups where they are in a ramp.
+    Stop(Subject),
+    /// Ask for the levels of the subject's groups.
+    Query(Subject),
+}
+
+/// The named rooms and scenes.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Layout {
+    pub rooms: BTreeMap<String, Vec<Group>>,
+    pub scenes: BTreeMap<String, Vec<(Group, Level)>>,
+}
+
+impl Layout {
+    /// Each group of `subject` with its level in a scene, otherwise `ON`.
+    fn groups(&self, subject: &Subject) -> Result<Vec<(Group, Level)>, String> {
+        match subject {
+            Subject::Group(g) => Ok(vec![(g.clone(), ON)]),
+            Subject::Room(name) => match self.rooms.get(&**name) {
+                Some(groups) => Ok(groups.iter().map(|g| (g.clone(), ON)).collect()),
+                None => Err(format!("no room {name}")),
+            },
+            Subject::Scene(name) => match self.scenes.get(&**name) {
+                Some(levels) => Ok(levels.clone()),
+                None => Err(format!("no scene {name}")),
+            },
+        }
+    }
+
+    /// The lighting messages that carry out `command`, failing if it
+    /// names an unknown room or scene.
+    pub fn messages(&self, command: &Command) -> Result<Vec<Message>, String> {
+        let messages = match command {
+            Command::Level(subject, level, ramp) => self
+                .groups(subject)?
+                .into_iter()
+                .map(|(g, preset)| {
+                    Message::SetVar(LIGHTING, g, scale(&preset, level), ramp.clone())
+                })
+                .collect(),
+            Command::Stop(subject) => self
+                .groups(subject)?
+                .into_iter()
+                .map(|(g, _)| Message::StopRamp(LIGHTING, g))
+                .collect(),
+            Command::Query(subject) => self
+                .groups(subject)?
+                .into_iter()
+                .map(|(g, _)| Message::LevelRequest(LIGHTING, g))
+                .collect(),
+        };
+        Ok(messages)
+    }
+}
+
+/// The layout in force, replaced whole when the configuration is reloaded.
+pub type SharedLayout = Arc<RwLock<Arc<Layout>>>;
+
+/// `preset` in proportion to `level`, rounded.
+fn scale(preset: &Level, level: &Level) -> Level {
+    let scaled = (u16::from(preset.0) * u16::from(level.0) + 127) / 255;
+    Level(scaled as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{INSTANT, OFF};
+
+    fn layout() -> Layout {
+        let mut layout = Layout::default();
+        layout
+            .rooms
+            .insert("kitchen".into(), vec![Group(4), Group(5)]);
+        let evening = vec![(Group(4), Level(128)), (Group(6), OFF)];
+        layout.scenes.insert("evening".into(), evening);
+        layout
+    }
+
+    #[test]
+    fn translated() {
+        let layout = layout();
+        let room = Command::Level(Subject::Room("kitchen".into()), Level(40), INSTANT);
+        assert_eq!(
+            layout.messages(&room),
+            Ok(vec![
+                Message::SetVar(LIGHTING, Group(4), Level(40), INSTANT),
+                Message::SetVar(LIGHTING, Group(5), Level(40), INSTANT),
+            ])
+        );
+        let dimmed = Command::Level(Subject::Scene("evening".into()), Level(128), INSTANT);
+        assert_eq!(
+            layout.messages(&dimmed),
+            Ok(vec![
+                Message::SetVar(LIGHTING, Group(4), Level(64), INSTANT),
+                Message::SetVar(LIGHTING, Group(6), OFF, INSTANT),
+            ])
+        );
+        let poll = Command::Query(Subject::Group(Group(16)));
+        assert_eq!(
+            layout.messages(&poll),
+            Ok(vec![Message::LevelRequest(LIGHTING, Group(16))])
+        );
+        let stop = Command::Stop(Subject::Room("attic".into()));
+        assert_eq!(layout.messages(&stop), Err("no room attic".into()));
+    }
+}
@@ -0,0 +1,1122 @@
+//! `config` holds the settings chosen at start up.
+//!
+//! Settings are read from a TOML file, conventionally `lights.toml`,
+//! given by `--config` and then from the command line.
+//! Anything not set has a default.
+use crate::bus::ChannelConfig;
+use crate::busio::{FlowControl, Framing, LineReaderConfig};
+use crate::codec::{self, Group, Level, PciOptions, Setting};
+use crate::command::Layout;
+use crate::control::ControlConfig;
+use crate::corpus::CorpusConfig;
+use crate::daemonize::DaemonizeConfig;
+use crate::gaffer::GafferConfig;
+use crate::ha::HaConfig;
+use crate::health::HealthConfig;
+use crate::otlp::{self, OtlpConfig};
+use crate::post::Endpoint;
+use crate::proxy::ProxyConfig;
+use crate::report::ReportConfig;
+use crate::secret::Secret;
+use crate::state::StateConfig;
+use crate::supervise::Reconnect;
+use crate::throttle::RateLimit;
+use crate::tunnel::{SshConfig, TlsConfig};
+use crate::{LightsError, Network};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+const HOST: &str = "C228F35.gracelands";
+const PORT: u16 = 10001;
+const BAUD: u32 = 9600;
+const BIND: ([u8; 4], u16) = ([127, 0, 0, 1], 3030);
+
+/// The environment variables that override the configuration file, for
+/// containers, and the option each stands for.  Options on the command
+/// line take precedence over them.
+pub const ENV_OVERRIDES: &[(&str, &str)] = &[
+    ("LIGHTS_HOST", "--host"),
+    ("LIGHTS_PORT", "--port"),
+    ("LIGHTS_BIND", "--bind"),
+    ("LIGHTS_LOG", "--log"),
+];
+
+/// How to reach the PCI.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Transport {
+    /// A terminal server or CNI on the network.
+    Tcp { host: String, port: u16 },
+    /// A PCI on a local serial port.
+    Serial { device: String, baud: u32 },
+    /// A virtual PCI and network, see `simulate`.
+    Simulate,
+    /// Traffic recorded by `trace_wire`, played back `speed` times faster.
+    Replay { file: PathBuf, speed: f64 },
+}
+
+/// Settings for the connection to one PCI.
+#[derive(PartialEq, Debug, Clone)]
+pub struct CbusConfig {
+    pub transport: Transport,
+    pub framing: Framing,
+    /// The interface options set on connecting.
+    pub options: PciOptions,
+    /// Buffer limits for input.
+    pub reader: LineReaderConfig,
+    /// Negotiate with and filter a telnet terminal server.
+    pub telnet: bool,
+    /// Set up the terminal server's serial port using RFC 2217.
+    pub remote_serial: Option<RemoteSerial>,
+    /// Limit the rate of commands sent to the bus.
+    pub rate: Option<RateLimit>,
+    /// Record the raw traffic on the link to this file.
+    pub trace_wire: Option<PathBuf>,
+    /// Reconnect if nothing is heard from the PCI for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Probe the PCI this often so a healthy link is never idle.
+    pub keepalive: Option<Duration>,
+    /// Ask the bus for a level report this often, reconnecting if none
+    /// comes back.
+    pub heartbeat: Option<Duration>,
+    /// Delays between attempts to connect.
+    pub reconnect: Reconnect,
+    /// Connect to this address rather than looking up the host, which
+    /// still names the CNI for TLS.
+    pub address: Option<IpAddr>,
+    /// Reach a CNI through an SSH server.
+    pub ssh: Option<SshConfig>,
+    /// Or through a SOCKS5 or HTTP proxy.
+    pub proxy: Option<ProxyConfig>,
+    /// Talk TLS to the CNI.
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Config {
+    /// The connection for network 0, set by `[cbus]` or the command line.
+    pub cbus: CbusConfig,
+    /// Connections for further networks, set by `[network.N]`.
+    pub networks: BTreeMap<Network, CbusConfig>,
+    /// Where the HTTP server listens.
+    pub bind: SocketAddr,
+    /// The bearer token HTTP clients must present, if any.
+    pub token: Option<Secret>,
+    /// Names for groups, used until labels are read from a unit.
+    pub groups: BTreeMap<u8, String>,
+    /// Rooms and scenes, set by `[rooms]` and `[scenes.NAME]`.
+    pub layout: Layout,
+    pub gaffer: GafferConfig,
+    pub log: LogConfig,
+    pub channels: ChannelConfig,
+    pub state: StateConfig,
+    pub corpus: CorpusConfig,
+    pub health: HealthConfig,
+    pub control: ControlConfig,
+    pub daemonize: DaemonizeConfig,
+    pub ha: HaConfig,
+    pub otlp: OtlpConfig,
+    pub report: ReportConfig,
+}
+
+/// Serial port settings for a terminal server, see `busio::com_port_setup`.
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteSerial {
+    pub baud: u32,
+    #[serde(default)]
+    pub flow: FlowControl,
+}
+
+/// What is logged.
+#[derive(PartialEq, Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Log every event.
+    pub events: bool,
+    /// A `tracing` filter such as `"info,warp=warn"`, unless `RUST_LOG` is set.
+    pub filter: Option<String>,
+    /// Write each record as a line of JSON.
+    pub json: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            events: true,
+            filter: None,
+            json: false,
+        }
+    }
+}
+
+/// The kinds of `Transport`.
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TransportKind {
+    Tcp,
+    Serial,
+    Simulate,
+    Replay,
+}
+
+/// The `[cbus]` table of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CbusFile {
+    transport: Option<TransportKind>,
+    host: Option<String>,
+    address: Option<IpAddr>,
+    port: Option<u16>,
+    device: Option<String>,
+    baud: Option<u32>,
+    file: Option<PathBuf>,
+    speed: Option<f64>,
+    framing: Option<Framing>,
+    options1: Option<Vec<Options1>>,
+    options3: Option<Vec<Options3>>,
+    reader: Option<LineReaderConfig>,
+    telnet: Option<bool>,
+    remote_serial: Option<RemoteSerial>,
+    rate: Option<RateLimit>,
+    trace_wire: Option<PathBuf>,
+    idle_timeout: Option<u64>,
+    keepalive: Option<u64>,
+    heartbeat: Option<u64>,
+    reconnect: Option<Reconnect>,
+    ssh: Option<SshConfig>,
+    proxy: Option<ProxyConfig>,
+    tls: Option<TlsConfig>,
+}
+
+/// An Options 1 setting of the PCI, by name.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Options1 {
+    Connect,
+    SrChk,
+    /// Off for basic mode.
+    Smart,
+    Monitor,
+    IdMon,
+}
+
+impl Options1 {
+    fn setting(self) -> Setting {
+        match self {
+            Options1::Connect => codec::CONNECT,
+            Options1::SrChk => codec::SR_CHK,
+            Options1::Smart => codec::SMART,
+            Options1::Monitor => codec::MONITOR,
+            Options1::IdMon => codec::ID_MON,
+        }
+    }
+}
+
+/// An Options 3 setting of the PCI, by name.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Options3 {
+    ParamChangeNotify,
+    LocalSal,
+    PowerUpNotify,
+    ExStat,
+}
+
+impl Options3 {
+    fn setting(self) -> Setting {
+        match self {
+            Options3::ParamChangeNotify => codec::PARAM_CHANGE_NOTIFY,
+            Options3::LocalSal => codec::LOCAL_SAL,
+            Options3::PowerUpNotify => codec::POWER_UP_NOTIFY,
+            Options3::ExStat => codec::EX_STAT,
+        }
+    }
+}
+
+/// No options set.
+fn none() -> Setting {
+    Setting::new(0)
+}
+
+/// The `[http]` table of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HttpFile {
+    bind: Option<SocketAddr>,
+    token: Option<Secret>,
+}
+
+/// The configuration file.  Every setting is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    cbus: CbusFile,
+    /// Further connections keyed by network number.
+    network: BTreeMap<String, CbusFile>,
+    http: HttpFile,
+    /// Group names keyed by group number, as TOML keys are strings.
+    groups: BTreeMap<String, String>,
+    /// The groups in each room.
+    rooms: BTreeMap<String, Vec<u8>>,
+    /// The level of each group in each scene, keyed by group number.
+    scenes: BTreeMap<String, BTreeMap<String, u8>>,
+    gaffer: GafferConfig,
+    log: LogConfig,
+    channels: ChannelConfig,
+    state: StateConfig,
+    corpus: CorpusConfig,
+    health: HealthConfig,
+    control: ControlConfig,
+    daemonize: DaemonizeConfig,
+    ha: HaConfig,
+    otlp: OtlpConfig,
+    report: ReportConfig,
+}
+
+impl Default for CbusConfig {
+    fn default() -> Self {
+        CbusConfig {
+            transport: Transport::Tcp {
+                host: HOST.into(),
+                port: PORT,
+            },
+            framing: Framing::Ascii,
+            options: PciOptions::default(),
+            reader: LineReaderConfig::default(),
+            telnet: false,
+            remote_serial: None,
+            rate: None,
+            trace_wire: None,
+            idle_timeout: None,
+            keepalive: None,
+            heartbeat: None,
+            reconnect: Reconnect::default(),
+            address: None,
+            ssh: None,
+            proxy: None,
+            tls: None,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cbus: CbusConfig::default(),
+            networks: BTreeMap::new(),
+            bind: BIND.into(),
+            token: None,
+            groups: BTreeMap::new(),
+            layout: Layout::default(),
+            gaffer: GafferConfig::default(),
+            log: LogConfig::default(),
+            channels: ChannelConfig::default(),
+            state: StateConfig::default(),
+            corpus: CorpusConfig::default(),
+            health: HealthConfig::default(),
+            control: ControlConfig::default(),
+            daemonize: DaemonizeConfig::default(),
+            ha: HaConfig::default(),
+            otlp: OtlpConfig::default(),
+            report: ReportConfig::default(),
+        }
+    }
+}
+
+impl CbusFile {
+    /// The connection settings from a `[cbus]` or `[network.N]` table.
+    fn into_config(self) -> Result<CbusConfig, String> {
+        let mut config = CbusConfig::default();
+
+        let kind = match (self.transport, &self.device) {
+            (Some(kind), _) => kind,
+            (None, Some(_)) => TransportKind::Serial,
+            (None, None) => TransportKind::Tcp,
+        };
+        if kind != TransportKind::Replay && (self.file.is_some() || self.speed.is_some()) {
+            return Err("file and speed are for the replay transport".into());
+        }
+        config.transport = match kind {
+            TransportKind::Tcp => {
+                if self.device.is_some() || self.baud.is_some() {
+                    return Err("device and baud are for the serial transport".into());
+                }
+                Transport::Tcp {
+                    host: self.host.unwrap_or_else(|| HOST.into()),
+                    port: self.port.unwrap_or(PORT),
+                }
+            }
+            TransportKind::Serial => {
+                if self.host.is_some() || self.port.is_some() {
+                    return Err("host and port are for the tcp transport".into());
+                }
+                Transport::Serial {
+                    device: self.device.ok_or("the serial transport needs a device")?,
+                    baud: self.baud.unwrap_or(BAUD),
+                }
+            }
+            TransportKind::Simulate => {
+                if self.host.is_some() || self.port.is_some() || self.device.is_some() {
+                    return Err("the simulate transport has no host, port or device".into());
+                }
+                Transport::Simulate
+            }
+            TransportKind::Replay => {
+                if self.host.is_some() || self.port.is_some() || self.device.is_some() {
+                    return Err("the replay transport has no host, port or device".into());
+                }
+                Transport::Replay {
+                    file: self.file.ok_or("the replay transport needs a file")?,
+                    speed: self.speed.unwrap_or(1.0),
+                }
+            }
+        };
+        config.framing = self.framing.unwrap_or(config.framing);
+        if let Some(names) = self.options1 {
+            config.options.options1 = names
+                .into_iter()
+                .map(Options1::setting)
+                .fold(none(), |a, b| a | b);
+        }
+        if let Some(names) = self.options3 {
+            config.options.options3 = names
+                .into_iter()
+                .map(Options3::setting)
+                .fold(none(), |a, b| a | b);
+        }
+        config.reader = self.reader.unwrap_or(config.reader);
+        config.remote_serial = self.remote_serial;
+        // RFC 2217 is a telnet option
+        config.telnet = self.telnet.unwrap_or(self.remote_serial.is_some());
+        config.rate = self.rate;
+        config.trace_wire = self.trace_wire;
+        config.idle_timeout = self.idle_timeout.map(Duration::from_secs);
+        config.keepalive = self.keepalive.map(Duration::from_secs);
+        config.heartbeat = self.heartbeat.map(Duration::from_secs);
+        config.reconnect = self.reconnect.unwrap_or(config.reconnect);
+        config.address = self.address;
+        config.ssh = self.ssh;
+        config.proxy = self.proxy;
+        config.tls = self.tls;
+        Ok(config)
+    }
+}
+
+impl CbusConfig {
+    /// Check settings that are valid individually but not together.
+    fn validate(&self) -> Result<(), String> {
+        match &self.transport {
+            Transport::Serial { device, .. } if device.is_empty() => {
+                return Err("the serial transport needs a device".into())
+            }
+            Transport::Tcp { port: 0, .. } => return Err("port must be positive".into()),
+            Transport::Replay { file, .. } if file.as_os_str().is_empty() => {
+                return Err("the replay transport needs a file".into())
+            }
+            Transport::Replay { speed, .. } if speed.is_nan() || *speed <= 0.0 => {
+                return Err("replay speed must be positive".into())
+            }
+            Transport::Serial { .. } | Transport::Simulate | Transport::Replay { .. }
+                if self.telnet =>
+            {
+                return Err("telnet is for the tcp transport".into())
+            }
+            Transport::Simulate | Transport::Replay { .. } if self.framing == Framing::Binary => {
+                return Err("simulated and replayed links use ascii framing".into())
+            }
+            _ => (),
+        }
+        let tcp = matches!(self.transport, Transport::Tcp { .. });
+        if (self.ssh.is_some() || self.proxy.is_some() || self.tls.is_some()) && !tcp {
+            return Err("ssh, proxy and tls are for the tcp transport".into());
+        }
+        if self.ssh.is_some() && self.proxy.is_some() {
+            return Err("use either ssh or a proxy".into());
+        }
+        if self.address.is_some() && !tcp {
+            return Err("address is for the tcp transport".into());
+        }
+        if let Some(tls) = &self.tls {
+            if !cfg!(feature = "tls") {
+                return Err("this build has no tls".into());
+            }
+            if tls.cert.is_some() != tls.key.is_some() {
+                return Err("a tls client cert needs a key and vice versa".into());
+            }
+        }
+        if self.remote_serial.is_some() && !self.telnet {
+            return Err("RFC 2217 needs telnet".into());
+        }
+        if self.reader.line_len == 0 || self.reader.chunk_len == 0 {
+            return Err("line and chunk lengths must be positive".into());
+        }
+        if self.reconnect.min == 0 || self.reconnect.min > self.reconnect.max {
+            return Err("reconnect delays must be positive, min no more than max".into());
+        }
+        if let Some(rate) = self.rate {
+            if rate.per_sec == 0 || rate.burst == 0 {
+                return Err("rate and burst must be positive".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Settings from the file at `path`.
+    pub fn load(path: &Path) -> Result<Config, LightsError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| LightsError::Config(format!("{}: {e}", path.display())))?;
+        Config::parse(&text).map_err(|e| LightsError::Config(format!("{}: {e}", path.display())))
+    }
+
+    /// Settings from the text of a configuration file.
+    pub fn from_toml(text: &str) -> Result<Config, LightsError> {
+        Config::parse(text).map_err(LightsError::Config)
+    }
+
+    fn parse(text: &str) -> Result<Config, String> {
+        let file: ConfigFile = toml::from_str(text).map_err(|e| e.to_string())?;
+        let mut config = Config {
+            cbus: file.cbus.into_config()?,
+            ..Config::default()
+        };
+        for (n, cbus) in file.network {
+            let n = n
+                .parse()
+                .map_err(|_| format!("{n} is not a network number"))?;
+            let cbus = cbus
+                .into_config()
+                .map_err(|e| format!("network {n}: {e}"))?;
+            config.networks.insert(n, cbus);
+        }
+        config.bind = file.http.bind.unwrap_or(config.bind);
+        config.token = file.http.token;
+        for (g, name) in file.groups {
+            let g = g
+                .parse()
+                .map_err(|_| format!("{g} is not a group number"))?;
+            config.groups.insert(g, name);
+        }
+        for (name, groups) in file.rooms {
+            let groups = groups.into_iter().map(Group).collect();
+            config.layout.rooms.insert(name, groups);
+        }
+        for (name, levels) in file.scenes {
+            let mut scene = Vec::new();
+            for (g, level) in levels {
+                let g = g
+                    .parse()
+                    .map_err(|_| format!("scene {name}: {g} is not a group number"))?;
+                scene.push((Group(g), Level(level)));
+            }
+            config.layout.scenes.insert(name, scene);
+        }
+        config.gaffer = file.gaffer;
+        config.log = file.log;
+        config.channels = file.channels;
+        config.state = file.state;
+        config.corpus = file.corpus;
+        config.health = file.health;
+        config.control = file.control;
+        config.daemonize = file.daemonize;
+        config.ha = file.ha;
+        config.otlp = file.otlp;
+        config.report = file.report;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Each connection to make with its network number.
+    pub fn links(&self) -> impl Iterator<Item = (Network, &CbusConfig)> {
+        std::iter::once((0, &self.cbus)).chain(self.networks.iter().map(|(n, c)| (*n, c)))
+    }
+
+    /// Check settings that are valid individually but not together.
+    fn validate(&self) -> Result<(), String> {
+        self.cbus.validate()?;
+        for (n, cbus) in &self.networks {
+            if *n == 0 {
+                return Err("network 0 is set by [cbus]".into());
+            }
+            cbus.validate().map_err(|e| format!("network {n}: {e}"))?;
+        }
+        if let Some((g, _)) = self.groups.iter().find(|(_, name)| name.trim().is_empty()) {
+            return Err(format!("group {g} has an empty name"));
+        }
+        if let Some((name, _)) = self
+            .layout
+            .rooms
+            .iter()
+            .find(|(_, groups)| groups.is_empty())
+        {
+            return Err(format!("room {name} has no groups"));
+        }
+        if self.channels.inbound == 0 || self.channels.outbound == 0 {
+            return Err("channel capacities must be positive".into());
+        }
+        if self.state.period == 0 {
+            return Err("state period must be positive".into());
+        }
+        let [first, last] = self.state.groups;
+        if first > last {
+            return Err(format!("state groups {first} to {last} are out of order"));
+        }
+        if self.corpus.period == 0 {
+            return Err("corpus period must be positive".into());
+        }
+        if self.corpus.limit == 0 {
+            return Err("corpus limit must be positive".into());
+        }
+        if self.health.silence == Some(0) {
+            return Err("health silence must be positive".into());
+        }
+        if self.ha.timeout < 2 {
+            return Err("ha timeout must be at least 2 seconds".into());
+        }
+        if let Some(endpoint) = &self.otlp.endpoint {
+            if !cfg!(feature = "otlp") {
+                return Err("this build has no otlp".into());
+            }
+            otlp::parse_endpoint(endpoint)?;
+        }
+        if self.otlp.interval == 0 {
+            return Err("otlp interval must be positive".into());
+        }
+        if self.report.enabled() && !cfg!(feature = "report") {
+            return Err("this build has no report".into());
+        }
+        if let Some(url) = &self.report.webhook {
+            Endpoint::parse(url).map_err(|e| format!("report webhook: {e}"))?;
+        }
+        if let Some(filter) = &self.log.filter {
+            EnvFilter::try_new(filter).map_err(|e| format!("log filter: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Settings from a file given by `--config`, overridden by the
+    /// environment, see [`ENV_OVERRIDES`], then from the command line:
+    ///
+    /// `--config PATH`, `--bind ADDR`,
+    /// `--host HOST`, `--port PORT`, `--serial DEVICE`, `--baud BAUD`, `--binary`, `--telnet`,
+    /// `--line-len BYTES`, `--chunk-len BYTES`, `--trace-wire PATH`,
+    /// `--rate PER_SEC`, `--burst COUNT`, `--rfc2217 BAUD`, `--flow none|xonxoff|hardware`,
+    /// `--idle-timeout SECS`, `--keepalive SECS`, `--heartbeat SECS`, `--log FILTER`, `--log-json`,
+    /// `--simulate`, `--replay PATH`, `--speed N`, `--state PATH`, `--control PATH`,
+    /// `--daemonize`, `--pidfile PATH`, `--log-file PATH`
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config, LightsError> {
+        Config::from_env_and_args(std::env::vars(), args)
+    }
+
+    /// Settings as for `from_args` with the environment given as `vars`.
+    pub fn from_env_and_args(
+        vars: impl IntoIterator<Item = (String, String)>,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Config, LightsError> {
+        let args: Vec<String> = args.into_iter().collect();
+        let config = match args.iter().position(|a| a == "--config") {
+            Some(i) => {
+                let path = args
+                    .get(i + 1)
+                    .ok_or(LightsError::Config("--config needs a value".into()))?;
+                Config::load(Path::new(path))?
+            }
+            None => Config::default(),
+        };
+        let config = Config::with_env(config, vars).map_err(LightsError::Config)?;
+        Config::with_args(config, args).map_err(LightsError::Config)
+    }
+
+    /// `config` overridden by the variables in `vars` that are named in
+    /// [`ENV_OVERRIDES`].
+    fn with_env(
+        mut config: Config,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Config, String> {
+        for (var, value) in vars {
+            let Some((_, option)) = ENV_OVERRIDES.iter().find(|(v, _)| *v == var) else {
+                continue;
+            };
+            let args = vec![option.to_string(), value];
+            config = Config::with_args(config, args).map_err(|e| format!("{var}: {e}"))?;
+        }
+        Ok(config)
+    }
+
+    /// `config` overridden by the options in `args`.
+    fn with_args(mut config: Config, args: Vec<String>) -> Result<Config, String> {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+            match arg.as_str() {
+                // already loaded
+                "--config" => drop(value()?),
+                "--bind" => config.bind = value()?.parse().map_err(|e| format!("{arg}: {e}"))?,
+                "--host" | "--port" => {
+                    let (mut host, mut port) = match config.cbus.transport {
+                        Transport::Tcp { host, port } => (host, port),
+                        _ => (HOST.into(), PORT),
+                    };
+                    if arg == "--host" {
+                        host = value()?;
+                    } else {
+                        port = value()?.parse().map_err(|e| format!("{arg}: {e}"))?;
+                    }
+                    config.cbus.transport = Transport::Tcp { host, port };
+                }
+                "--serial" | "--baud" => {
+                    let (mut device, mut baud) = match config.cbus.transport {
+                        Transport::Serial { device, baud } => (device, baud),
+                        _ => (String::new(), BAUD),
+                    };
+                    if arg == "--serial" {
+                        device = value()?;
+                    } else {
+                        baud = value()?.parse().map_err(|e| format!("{arg}: {e}"))?;
+                    }
+                    config.cbus.transport = Transport::Serial { device, baud };
+                }
+                "--replay" | "--speed" => {
+                    let (mut file, mut speed) = match config.cbus.transport {
+                        Transport::Replay { file, speed } => (file, speed),
+                        _ => (PathBuf::new(), 1.0),
+                    };
+                    if arg == "--replay" {
+                        file = value()?.into();
+                    } else {
+                        speed = value()?.parse().map_err(|e| format!("{arg}: {e}"))?;
+                    }
+                    config.cbus.transport = Transport::Replay { file, speed };
+                }
+                "--simulate" => {
+                    config.cbus.transport = Transport::Simulate;
+                    for cbus in config.networks.values_mut() {
+                        cbus.transport = Transport::Simulate;
+                    }
+                }
+                "--binary" => config.cbus.framing = Framing::Binary,
+                "--telnet" => config.cbus.telnet = true,
+                "--rfc2217" | "--flow" => {
+                    let mut remote = config.cbus.remote_serial.unwrap_or(RemoteSerial {
+                        baud: BAUD,
+                        flow: FlowControl::None,
+                    });
+                    if arg == "--rfc2217" {
+                        remote.baud = value()?.parse().map_err(|e| format!("{arg}: {e}"))?;
+                    } else {
+                        remote.flow = value()?.parse()?;
+                    }
+                    // RFC 2217 is a telnet option
+                    config.cbus.telnet = true;
+                    config.cbus.remote_serial = Some(remote);
+                }
+                "--rate" | "--burst" => {
+                    let n = value()?.parse().map_err(|e| format!("{arg}: {e}"))?;
+                    let mut rate = config.cbus.rate.unwrap_or(RateLimit {
+                        per_sec: n,
+                        burst: n,
+                    });
+                    if arg == "--rate" {
+                        rate.per_sec = n;
+                    } else {
+                        rate.burst = n;
+                    }
+                    config.cbus.rate = Some(rate);
+                }
+                "--trace-wire" => config.cbus.trace_wire = Some(value()?.into()),
+                "--line-len" | "--chunk-len" => {
+                    let len = value()?.parse().map_err(|e| format!("{arg}: {e}"))?;
+                    if arg == "--line-len" {
+                        config.cbus.reader.line_len = len;
+                    } else {
+                        config.cbus.reader.chunk_len = len;
+                    }
+                }
+                "--state" => config.state.file = Some(value()?.into()),
+                "--control" => config.control.socket = Some(value()?.into()),
+                "--daemonize" => config.daemonize.enabled = true,
+                "--pidfile" => config.daemonize.pidfile = Some(value()?.into()),
+                "--log-file" => config.daemonize.log_file = Some(value()?.into()),
+                "--log" => config.log.filter = Some(value()?),
+                "--log-json" => config.log.json = true,
+                "--idle-timeout" | "--keepalive" | "--heartbeat" => {
+                    let secs = value()?.parse().map_err(|e| format!("{arg}: {e}"))?;
+                    let period = Some(Duration::from_secs(secs));
+                    match arg.as_str() {
+                        "--keepalive" => config.cbus.keepalive = period,
+                        "--heartbeat" => config.cbus.heartbeat = period,
+                        _ => config.cbus.idle_timeout = period,
+                    }
+                }
+                _ => return Err(format!("unknown option {arg}")),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::Protocol;
+    use crate::Source;
+
+    /// Settings from `s` alone, whatever the environment of the test.
+    fn args(s: &str) -> Result<Config, String> {
+        let args = s.split_whitespace().map(String::from);
+        Config::from_env_and_args(std::iter::empty(), args).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(args(""), Ok(Config::default()));
+    }
+
+    #[test]
+    fn serial() {
+        let config = args("--serial /dev/ttyUSB0 --baud 4800 --binary").unwrap();
+        assert!(!config.cbus.telnet);
+        assert_eq!(
+            config.cbus.transport,
+            Transport::Serial {
+                device: "/dev/ttyUSB0".into(),
+                baud: 4800
+            }
+        );
+        assert_eq!(config.cbus.framing, Framing::Binary);
+    }
+
+    #[test]
+    fn bad_args() {
+        assert!(args("--port x").is_err());
+        assert!(args("--baud 9600").is_err());
+        assert!(args("--serial").is_err());
+        assert!(args("--verbose").is_err());
+        assert!(args("--keepalive -1").is_err());
+        assert!(args("--chunk-len 0").is_err());
+    }
+
+    #[test]
+    fn keepalive() {
+        let config = args("--idle-timeout 90 --keepalive 30 --heartbeat 120").unwrap();
+        assert_eq!(config.cbus.idle_timeout, Some(Duration::from_secs(90)));
+        assert_eq!(config.cbus.keepalive, Some(Duration::from_secs(30)));
+        assert_eq!(config.cbus.heartbeat, Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn example_file() {
+        let config = Config::from_toml(include_str!("../lights.toml")).unwrap();
+        assert_eq!(config.groups.get(&4).map(String::as_str), Some("Kitchen"));
+        assert_eq!(config.bind, "127.0.0.1:3030".parse().unwrap());
+        assert_eq!(config.cbus.transport, CbusConfig::default().transport);
+    }
+
+    #[test]
+    fn empty_file() {
+        assert_eq!(Config::from_toml("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn serial_file() {
+        let text = "[cbus]\ndevice = \"/dev/ttyUSB0\"\nkeepalive = 30\n";
+        let config = Config::from_toml(text).unwrap();
+        let device = "/dev/ttyUSB0".into();
+        let transport = Transport::Serial { device, baud: BAUD };
+        assert_eq!(config.cbus.transport, transport);
+        assert_eq!(config.cbus.keepalive, Some(Duration::from_secs(30)));
+        assert_eq!(config.cbus.reconnect, Reconnect::default());
+    }
+
+    #[test]
+    fn bad_files() {
+        let bad = [
+            "[cbus]\ntransport = \"serial\"\n",
+            "[cbus]\nhost = \"pci\"\nbaud = 9600\n",
+            "[cbus]\nport = 0\n",
+            "[cbus]\nspeed = 9600\n",
+            "[cbus]\nrate = { per_sec = 0, burst = 1 }\n",
+            "[cbus]\nreconnect = { min = 0 }\n",
+            "[cbus]\ndevice = \"/dev/ttyS0\"\nssh = { host = \"gw\" }\n",
+            "[cbus]\ntransport = \"simulate\"\naddress = \"10.0.0.5\"\n",
+            "[cbus]\naddress = \"cni.local\"\n",
+            "[cbus]\nssh = { host = \"gw\" }\nproxy = { protocol = \"http\", host = \"gw\" }\n",
+            "[cbus]\nproxy = { protocol = \"ftp\", host = \"gw\" }\n",
+            "[cbus.tls]\nca = \"ca.pem\"\ncert = \"me.pem\"\n",
+            "[cbus]\nreconnect = { min = 90, max = 60 }\n",
+            "[http]\nbind = \"localhost\"\n",
+            "[groups]\n4 = \" \"\n",
+            "[groups]\n256 = \"Attic\"\n",
+        ];
+        for text in bad {
+            assert!(Config::from_toml(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn secured() {
+        let text = "[cbus]\nhost = \"cni\"\nssh = { host = \"gw\", user = \"pi\" }\n\
+                    [cbus.tls]\nca = \"ca.pem\"\ncert = \"me.pem\"\nkey = \"me.key\"\n";
+        let config = Config::from_toml(text).unwrap();
+        assert_eq!(config.cbus.ssh.unwrap().user.as_deref(), Some("pi"));
+        assert_eq!(config.cbus.address, None);
+        let tls = config.cbus.tls.unwrap();
+        assert_eq!(tls.ca, PathBuf::from("ca.pem"));
+        assert_eq!(tls.key, Some("me.key".into()));
+    }
+
+    #[test]
+    fn pci_options() {
+        let text = "[cbus]\noptions1 = [\"connect\", \"sr_chk\", \"smart\", \"monitor\"]\n";
+        let config = Config::from_toml(text).unwrap();
+        let options = &config.cbus.options;
+        assert!(!options.options1.contains(&codec::ID_MON));
+        assert_eq!(options.options1.value(), 0x39);
+        assert_eq!(options.options3, codec::options3());
+        let config = Config::from_toml("[cbus]\noptions3 = []\n").unwrap();
+        assert_eq!(config.cbus.options.options3.value(), 0);
+        assert!(Config::from_toml("[cbus]\noptions1 = [\"idmon\"]\n").is_err());
+    }
+
+    #[test]
+    fn proxied() {
+        let text = "[cbus]\nproxy = { protocol = \"socks5\", host = \"vps\" }\n";
+        let config = Config::from_toml(text).unwrap();
+        let proxy = config.cbus.proxy.unwrap();
+        assert_eq!(proxy.protocol, Protocol::Socks5);
+        assert_eq!((proxy.host.as_str(), proxy.port), ("vps", None));
+    }
+
+    #[test]
+    fn gaffer() {
+        let config = Config::from_toml("[gaffer]\nsuppress = [\"hmi\", \"control\"]\n").unwrap();
+        assert_eq!(config.gaffer.suppress, [Source::Hmi, Source::Control]);
+        assert!(Config::default().gaffer.suppress.is_empty());
+        assert!(Config::from_toml("[gaffer]\nsuppress = [\"cron\"]\n").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "otlp")]
+    fn otlp() {
+        let text = "[otlp]\nendpoint = \"http://collector:4318\"\ninterval = 30\n";
+        let config = Config::from_toml(text).unwrap();
+        assert_eq!(
+            config.otlp.endpoint.as_deref(),
+            Some("http://collector:4318")
+        );
+        assert_eq!(
+            (config.otlp.interval, config.otlp.service.as_str()),
+            (30, "lights")
+        );
+        assert!(Config::from_toml("[otlp]\nendpoint = \"collector\"\n").is_err());
+        assert!(Config::from_toml("[otlp]\ninterval = 0\n").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "report")]
+    fn report() {
+        let text = "[report]\nwebhook = \"http://hooks.local/lights\"\n\
+                    sentry = { env = \"LIGHTS_SENTRY_DSN\" }\n";
+        let config = Config::from_toml(text).unwrap();
+        assert!(config.report.enabled());
+        assert_eq!(config.report.events, 50);
+        let sentry = Secret::Env("LIGHTS_SENTRY_DSN".into());
+        assert_eq!(config.report.sentry, Some(sentry));
+        assert!(!Config::from_toml("").unwrap().report.enabled());
+        assert!(Config::from_toml("[report]\nwebhook = \"hooks.local\"\n").is_err());
+    }
+
+    #[test]
+    fn networks() {
+        let text = "[network.1]\nhost = \"cni2\"\naddress = \"10.0.0.5\"\n[network.2]\ndevice = \"/dev/ttyUSB1\"\n";
+        let config = Config::from_toml(text).unwrap();
+        let links: Vec<Network> = config.links().map(|(n, _)| n).collect();
+        assert_eq!(links, [0, 1, 2]);
+        let host = "cni2".into();
+        let transport = Transport::Tcp { host, port: PORT };
+        assert_eq!(config.networks[&1].transport, transport);
+        assert_eq!(config.networks[&1].address, Some([10, 0, 0, 5].into()));
+        assert!(Config::from_toml("[network.0]\n").is_err());
+        assert!(Config::from_toml("[network.x]\n").is_err());
+        assert!(Config::from_toml("[network.1]\nport = 0\n").is_err());
+    }
+
+    #[test]
+    fn simulate() {
+        let config = args("--simulate").unwrap();
+        assert_eq!(config.cbus.transport, Transport::Simulate);
+        let config = Config::from_toml("[cbus]\ntransport = \"simulate\"\n").unwrap();
+        assert_eq!(config.cbus.transport, Transport::Simulate);
+        assert!(args("--simulate --binary").is_err());
+        assert!(Config::from_toml("[cbus]\ntransport = \"simulate\"\nport = 1\n").is_err());
+    }
+
+    #[test]
+    fn replay() {
+        let config = args("--speed 10 --replay wire.log").unwrap();
+        let transport = Transport::Replay {
+            file: "wire.log".into(),
+            speed: 10.0,
+        };
+        assert_eq!(config.cbus.transport, transport);
+        assert!(args("--speed 10").is_err());
+        assert!(args("--replay wire.log --speed 0").is_err());
+        let text = "[cbus]\ntransport = \"replay\"\nfile = \"wire.log\"\n";
+        assert!(Config::from_toml(text).is_ok());
+        assert!(Config::from_toml("[cbus]\ntransport = \"replay\"\n").is_err());
+        assert!(Config::from_toml("[cbus]\nspeed = 2.0\n").is_err());
+    }
+
+    #[test]
+    fn env_overrides() {
+        let env = |vars: &[(&str, &str)], args: &str| {
+            let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+            let args = args.split_whitespace().map(String::from);
+            Config::from_env_and_args(vars, args).map_err(|e| e.to_string())
+        };
+        let vars = [
+            ("LIGHTS_HOST", "cni.local"),
+            ("LIGHTS_BIND", "0.0.0.0:8080"),
+            ("LIGHTS_LOG", "debug"),
+            ("HOME", "/root"),
+        ];
+        let config = env(&vars, "--config lights.toml").unwrap();
+        let cni = Transport::Tcp {
+            host: "cni.local".into(),
+            port: PORT,
+        };
+        assert_eq!(config.cbus.transport, cni);
+        assert_eq!(config.bind, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.log.filter.as_deref(), Some("debug"));
+
+        let config = env(&vars, "--bind 127.0.0.1:9000").unwrap();
+        assert_eq!(config.bind, "127.0.0.1:9000".parse().unwrap());
+        let bad = env(&[("LIGHTS_PORT", "cbus")], "").unwrap_err();
+        assert!(bad.starts_with("LIGHTS_PORT: --port"));
+    }
+
+    #[test]
+    fn token() {
+        let config = Config::from_toml("[http]\ntoken = { env = \"LIGHTS_TOKEN\" }\n").unwrap();
+        assert_eq!(config.token, Some(Secret::Env("LIGHTS_TOKEN".into())));
+        assert!(Config::from_toml("[http]\ntoken = \"hunter2\"\n").is_err());
+    }
+
+    #[test]
+    fn ha() {
+        let text = "[ha]\npeer = \"192.168.1.21:3031\"\npriority = 2\n";
+        let config = Config::from_toml(text).unwrap();
+        assert_eq!(config.ha.peer, Some("192.168.1.21:3031".parse().unwrap()));
+        assert_eq!((config.ha.priority, config.ha.timeout), (2, 5));
+        assert!(Config::from_toml("[ha]\ntimeout = 1\n").is_err());
+    }
+
+    #[test]
+    fn args_override_file() {
+        let config = args("--config lights.toml --bind 0.0.0.0:8080").unwrap();
+        assert_eq!(config.bind, "0.0.0.0:8080".parse().unwrap());
+        assert!(args("--config missing.toml").is_err());
+    }
+
+    #[test]
+    fn daemonize() {
+        let config = args("--daemonize --pidfile /run/lights.pid").unwrap();
+        assert!(config.daemonize.enabled);
+        assert_eq!(config.daemonize.pidfile, Some("/run/lights.pid".into()));
+        assert_eq!(config.daemonize.log_file, None);
+    }
+
+    #[test]
+    fn rooms_and_scenes() {
+        let text = "[rooms]\nkitchen = [4, 5]\n[scenes.evening]\n4 = 128\n";
+        let layout = Config::from_toml(text).unwrap().layout;
+        assert_eq!(layout.rooms["kitchen"], [Group(4), Group(5)]);
+        assert_eq!(layout.scenes["evening"], [(Group(4), Level(128))]);
+        assert!(Config::from_toml("[rooms]\nattic = []\n").is_err());
+        assert!(Config::from_toml("[scenes.evening]\nhall = 9\n").is_err());
+    }
+
+    #[test]
+    fn remote_serial() {
+        let config = args("--rfc2217 4800 --flow hardware").unwrap();
+        assert!(config.cbus.telnet);
+        let remote = RemoteSerial {
+            baud: 4800,
+            flow: FlowControl::Hardware,
+        };
+        assert_eq!(config.cbus.remote_serial, Some(remote));
+        assert!(args("--flow rts").is_err());
+    }
+
+    #[test]
+    fn rate() {
+        let config = args("--rate 20").unwrap();
+        let rate = RateLimit {
+            per_sec: 20,
+            burst: 20,
+        };
+        assert_eq!(config.cbus.rate, Some(rate));
+        let config = args("--burst 5 --rate 20").unwrap();
+        let rate = RateLimit {
+            per_sec: 20,
+            burst: 5,
+        };
+        assert_eq!(config.cbus.rate, Some(rate));
+        assert!(args("--rate 0").is_err());
+    }
+
+    #[test]
+    fn state() {
+        let config = args("--state /var/lib/lights/state.toml").unwrap();
+        let file = Some("/var/lib/lights/state.toml".into());
+        assert_eq!(config.state.file, file);
+        assert_eq!(config.state.period, 60);
+        assert!(Config::from_toml("[state]\nperiod = 0\n").is_err());
+        let config = Config::from_toml("[state]\ngroups = [16, 63]\n").unwrap();
+        assert_eq!(config.state.groups, [16, 63]);
+        assert!(config.state.poll);
+        assert!(Config::from_toml("[state]\ngroups = [63, 16]\n").is_err());
+        let config =
+            Config::from_toml("[corpus]\nfile = \"/var/lib/lights/corpus.toml\"\n").unwrap();
+        assert_eq!(
+            config.corpus.file,
+            Some("/var/lib/lights/corpus.toml".into())
+        );
+        assert!(Config::from_toml("[corpus]\nperiod = 0\n").is_err());
+        assert!(Config::from_toml("[corpus]\nlimit = 0\n").is_err());
+    }
+
+    #[test]
+    fn channels() {
+        let config = Config::from_toml("[channels]\ninbound = 256\n").unwrap();
+        assert_eq!(config.channels.inbound, 256);
+        assert_eq!(config.channels.outbound, 16);
+        assert!(Config::from_toml("[channels]\noutbound = 0\n").is_err());
+    }
+
+    #[test]
+    fn logging() {
+        let config = args("--log info,warp=warn --log-json").unwrap();
+        assert_eq!(config.log.filter.as_deref(), Some("info,warp=warn"));
+        assert!(config.log.json);
+        assert!(args("--log lights=loud").is_err());
+    }
+
+    #[test]
+    fn buffers() {
+        let config = args("--line-len 4096").unwrap();
+        assert_eq!(config.cbus.reader.line_len, 4096);
+        assert_eq!(config.cbus.trace_wire, None);
+        assert_eq!(
+            config.cbus.reader.chunk_len,
+            LineReaderConfig::default().chunk_len
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! `confirm` correlates PCI confirmations with the commands that requested them.
+
+use crate::codec::{Code, Message, Priority};
+use std::collections::hash_map::{Entry, HashMap};
+use tokio::time::{Duration, Instant};
+
+/// How long to wait for a confirmation and how often to resend.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            timeout: Duration::from_millis(1000),
+            retries: 2,
+        }
+    }
+}
+
+/// What to do about a command whose confirmation is overdue.
+#[derive(PartialEq, Debug)]
+pub enum Expiry {
+    Resend(Code, Message, Priority),
+    GiveUp(Message),
+}
+
+#[derive(Debug)]
+struct Pending {
+    mesg: Message,
+    priority: Priority,
+    deadline: Instant,
+    retries: u32,
+}
+
+/// Commands awaiting confirmation, indexed by confirmation code.
+///
+/// Codes are allocated round robin so that a late reply is
+/// unlikely to be attributed to a newer command.
+#[derive(Debug)]
+pub struct Confirmations {
+    pending: HashMap<Code, Pending>,
+    next: Code,
+    policy: RetryPolicy,
+}
+
+impl Confirmations {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Confirmations {
+            pending: HashMap::new(),
+            next: Code::default(),
+            policy,
+        }
+    }
+
+    /// Allocate a free code for a confirmable message and record it as
+    /// pending, to be resent at `priority`.
+    ///
+    /// Returns `None` if the message cannot be confirmed or all codes are in use.
+    pub fn allocate(&mut self, mesg: &Message, priority: Priority, now: Instant) -> Option<Code> {
+        if !mesg.is_confirmable() {
+            return None;
+        }
+        for _ in 0..Code::COUNT {
+            let code = self.next;
+            self.next = code.succ();
+            if let Entry::Vacant(slot) = self.pending.entry(code) {
+                slot.insert(Pending {
+                    mesg: mesg.clone(),
+                    priority,
+                    deadline: now + self.policy.timeout,
+                    retries: 0,
+                });
+                return Some(code);
+            }
+        }
+        None
+    }
+
+    /// Remove and return the command confirmed by `code`, if any.
+    pub fn resolve(&mut self, code: &Code) -> Option<Message> {
+        self.pending.remove(code).map(|p| p.mesg)
+    }
+
+    /// The earliest time at which a pending command will expire.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|p| p.deadline).min()
+    }
+
+    /// Deal with commands whose deadline has passed.
+    ///
+    /// Each is either rescheduled for resending under the same code
+    /// or, once its retries are exhausted, abandoned.
+    pub fn expire(&mut self, now: Instant) -> Vec<Expiry> {
+        let mut expired = Vec::new();
+        let policy = &self.policy;
+        self.pending.retain(|code, p| {
+            if p.deadline > now {
+                true
+            } else if p.retries < policy.retries {
+                p.retries += 1;
+                p.deadline = now + policy.timeout;
+                expired.push(Expiry::Resend(*code, p.mesg.clone(), p.priority));
+                true
+            } else {
+                expired.push(Expiry::GiveUp(p.mesg.clone()));
+                false
+            }
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setvar(g: u8) -> Message {
+        Message::set(g).level(50).build().unwrap()
+    }
+
+    fn table() -> Confirmations {
+        Confirmations::new(RetryPolicy::default())
+    }
+
+    #[test]
+    fn allocate_and_resolve() {
+        let mut table = table();
+        let code = table
+            .allocate(&setvar(1), Priority::Low, Instant::now())
+            .unwrap();
+        assert_eq!(table.resolve(&code), Some(setvar(1)));
+        assert_eq!(table.resolve(&code), None);
+    }
+
+    #[test]
+    fn codes_are_distinct() {
+        let mut table = table();
+        let now = Instant::now();
+        let a = table.allocate(&setvar(1), Priority::Low, now).unwrap();
+        let b = table.allocate(&setvar(2), Priority::Low, now).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(table.resolve(&b), Some(setvar(2)));
+    }
+
+    #[test]
+    fn exhausted() {
+        let mut table = table();
+        let now = Instant::now();
+        for g in 0..Code::COUNT {
+            assert!(table
+                .allocate(&setvar(g as u8), Priority::Low, now)
+                .is_some());
+        }
+        assert_eq!(table.allocate(&setvar(99), Priority::Low, now), None);
+    }
+
+    #[test]
+    fn not_confirmable() {
+        let mut table = table();
+        assert_eq!(
+            table.allocate(&Message::Reset, Priority::Low, Instant::now()),
+            None
+        );
+        assert_eq!(table.deadline(), None);
+    }
+
+    #[test]
+    fn retry_then_give_up() {
+        let mut table = table();
+        let timeout = table.policy.timeout;
+        let now = Instant::now();
+        let code = table.allocate(&setvar(1), Priority::High, now).unwrap();
+        assert_eq!(table.deadline(), Some(now + timeout));
+        assert_eq!(table.expire(now), vec![]);
+
+        let now = now + timeout;
+        assert_eq!(
+            table.expire(now),
+            vec![Expiry::Resend(code, setvar(1), Priority::High)]
+        );
+        let now = now + timeout;
+        assert_eq!(
+            table.expire(now),
+            vec![Expiry::Resend(code, setvar(1), Priority::High)]
+        );
+        let now = now + timeout;
+        assert_eq!(table.expire(now), vec![Expiry::GiveUp(setvar(1))]);
+        assert_eq!(table.deadline(), None);
+    }
+
+    #[test]
+    fn confirmed_before_timeout() {
+        let mut table = table();
+        let now = Instant::now();
+        let code = table.allocate(&setvar(1), Priority::Low, now).unwrap();
+        assert!(table.resolve(&code).is_some());
+        assert_eq!(table.expire(now + table.policy.timeout), vec![]);
+    }
+}
@@ -0,0 +1,265 @@
+//! `control` takes commands from local scripts on a unix socket.
+//!
+//! Each line is a command.  The reply is any output, a line at a time,
+//! then `ok` or `error: ` and the reason.  The socket is only open to
+//! the user running the daemon, which is all the authentication needed.
+//!
+//! ```text
+//! status                                the state of each link
+//! send GROUP LEVEL [RAMP] [network N]   set a group to 0-255, on or off
+//! reconnect [N]                         reconnect every link, or one
+//! reload                                re-read the configuration
+//! ```
+use crate::bus::Inbound;
+use crate::codec::{Level, Ramp, Target, OFF, ON};
+use crate::command::{Command, Subject};
+use crate::health::{self, Health};
+use crate::reload::Reload;
+use crate::{Event, Network, Post, Result, Source};
+use serde::Deserialize;
+use std::fs::{self, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{select, task};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Where to listen for commands.
+#[derive(PartialEq, Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// The socket, or none for no control interface.
+    pub socket: Option<PathBuf>,
+}
+
+/// What commands act on.
+#[derive(Debug, Clone)]
+pub struct Control {
+    pub inbound: Inbound,
+    pub health: Health,
+    pub reload: Reload,
+}
+
+/// The state of each link, a line each.
+fn status(health: &Health) -> Vec<String> {
+    let health = health.lock().unwrap();
+    health
+        .iter()
+        .map(|(network, link)| {
+            let mut line = format!(
+                "network {network} {} connections={} silent={}s stage={} failures={}",
+                if link.connected { "up" } else { "down" },
+                link.connections,
+                link.silent_secs,
+                format!("{:?}", link.stage).to_lowercase(),
+                link.failures,
+            );
+            if let Some(e) = &link.last_error {
+                line.push_str(&format!(" error={e:?}"));
+            }
+            line
+        })
+        .collect()
+}
+
+/// The post for `send` and its arguments.
+fn send(args: &[&str]) -> Result<Post, String> {
+    let (args, network) = match args {
+        [rest @ .., "network", n] => (rest, n.parse().map_err(|e| format!("network: {e}"))?),
+        _ => (args, 0),
+    };
+    let (group, level, ramp) = match args {
+        [group, level] => (group, level, "0"),
+        [group, level, ramp] => (group, level, *ramp),
+        _ => return Err("usage: send GROUP LEVEL [RAMP] [network N]".into()),
+    };
+    let group = group
+        .parse::<Target>()
+        .map_err(|e| format!("group: {e}"))?
+        .as_group();
+    let level = match *level {
+        "on" => ON,
+        "off" => OFF,
+        level => Level::new(level.parse().map_err(|e| format!("level: {e}"))?),
+    };
+    let ramp = ramp.parse().map_err(|e| format!("ramp: {e}"))?;
+    let ramp = Ramp::new(ramp).map_err(|e| e.to_string())?;
+    let post = Post::Command(Command::Level(Subject::Group(group), level, ramp));
+    Ok(match network {
+        0 => post,
+        n => Post::Network(n, Box::new(post)),
+    })
+}
+
+/// Carry out the command on `line`, returning its output.
+fn execute(control: &Control, line: &str) -> Result<Vec<String>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["status"] => Ok(status(&control.health)),
+        ["send", args @ ..] => {
+            let post = send(args)?;
+            control
+                .inbound
+                .publish(Source::Control, Event::Hmi(post))
+                .map_err(|e| e.to_string())?;
+            Ok(Vec::new())
+        }
+        ["reconnect"] => {
+            let networks: Vec<Network> = control.health.lock().unwrap().keys().copied().collect();
+            for network in networks {
+                health::reconnect(&control.health, network);
+            }
+            Ok(Vec::new())
+        }
+        ["reconnect", network] => {
+            let network = network.parse().map_err(|e| format!("network: {e}"))?;
+            if health::reconnect(&control.health, network) {
+                Ok(Vec::new())
+            } else {
+                Err(format!("no network {network}"))
+            }
+        }
+        ["reload"] => {
+            control.reload.notify_one();
+            Ok(Vec::new())
+        }
+        [] => Err("no command".into()),
+        [command, ..] => Err(format!("unknown command {command}")),
+    }
+}
+
+/// Answer the commands on one connection.
+async fn serve(stream: UnixStream, control: Control) -> io::Result<()> {
+    let (input, mut output) = stream.into_split();
+    let mut lines = BufReader::new(input).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match execute(&control, &line) {
+            Ok(mut out) => {
+                out.push("ok".into());
+                out
+            }
+            Err(e) => vec![format!("error: {e}")],
+        };
+        for line in reply {
+            output.write_all(line.as_bytes()).await?;
+            output.write_all(b"\n").await?;
+        }
+    }
+    Ok(())
+}
+
+/// Accept connections on the socket at `path` until cancelled.
+pub async fn control_daemon(
+    path: PathBuf,
+    control: Control,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let context = |e: io::Error| io::Error::new(e.kind(), format!("{}: {e}", path.display()));
+    // left by an earlier run
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).map_err(context)?;
+    fs::set_permissions(&path, Permissions::from_mode(0o600)).map_err(context)?;
+    info!("control socket {}", path.display());
+    loop {
+        select! {
+            _ = cancel.cancelled() => break,
+            res = listener.accept() => match res {
+                Ok((stream, _)) => {
+                    let control = control.clone();
+                    task::spawn(async move {
+                        if let Err(e) = serve(stream, control).await {
+                            warn!("control: {e}");
+                        }
+                    });
+                }
+                Err(e) => warn!("control: {e}"),
+            }
+        }
+    }
+    let _ = fs::remove_file(&path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, ChannelConfig, Subscriber};
+    use crate::codec::{Group, INSTANT};
+    use crate::Envelope;
+    use futures_util::FutureExt;
+
+    fn control() -> (Control, Subscriber<Envelope>) {
+        let bus = Bus::new(ChannelConfig::default());
+        let events = bus.events("test");
+        let control = Control {
+            inbound: bus.inbound,
+            health: Health::default(),
+            reload: Reload::default(),
+        };
+        (control, events)
+    }
+
+    #[test]
+    fn sends() {
+        let level = |g, l| Post::Command(Command::Level(Subject::Group(Group(g)), l, INSTANT));
+        assert_eq!(send(&["4", "on"]), Ok(level(4, ON)));
+        assert_eq!(send(&["4", "128", "0"]), Ok(level(4, Level(128))));
+        let elsewhere = Post::Network(2, Box::new(level(4, OFF)));
+        assert_eq!(send(&["4", "off", "network", "2"]), Ok(elsewhere));
+        assert!(send(&["4"]).is_err());
+        assert!(send(&["4", "dim"]).is_err());
+    }
+
+    #[test]
+    fn commands() {
+        let (control, mut events) = control();
+        let session = CancellationToken::new();
+        health::session_started(
+            &control.health,
+            1,
+            session.clone(),
+            tokio::time::Instant::now(),
+        );
+        let status = execute(&control, "status").unwrap();
+        assert_eq!(status.len(), 1);
+        assert!(status[0].starts_with("network 1 down connections=0"));
+
+        execute(&control, "send 7 on").unwrap();
+        let sent = events.recv().now_or_never().flatten().unwrap();
+        assert_eq!(sent.source, Source::Control);
+        assert!(matches!(sent.event, Event::Hmi(Post::Command(_))));
+
+        assert!(execute(&control, "reconnect 0").is_err());
+        execute(&control, "reconnect 1").unwrap();
+        assert!(session.is_cancelled());
+        assert_eq!(
+            execute(&control, "dance"),
+            Err("unknown command dance".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn over_socket() {
+        let path = std::env::temp_dir().join(format!("lights-control-{}", std::process::id()));
+        let (control, _events) = control();
+        let cancel = CancellationToken::new();
+        let daemon = task::spawn(control_daemon(path.clone(), control, cancel.clone()));
+        let stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => task::yield_now().await,
+            }
+        };
+        let (input, mut output) = stream.into_split();
+        output.write_all(b"reload\nsend\n").await.unwrap();
+        let mut lines = BufReader::new(input).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "ok");
+        let usage = lines.next_line().await.unwrap().unwrap();
+        assert!(usage.starts_with("error: usage"));
+        cancel.cancel();
+        daemon.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
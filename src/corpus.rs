@@ -0,0 +1,263 @@
+//! `corpus` collects the frames the codec does not understand.
+//!
+//! Each distinct frame is kept once, with when it was first and last
+//! heard and how often, and saved to `[corpus] file` so that unknown
+//! traffic gathered over weeks survives restarts and can be studied to
+//! extend the codec.  The collection is served at `GET /v1/unrecognised`.
+//! Once it holds `[corpus] limit` frames, the one heard least recently
+//! makes way for each new one.
+use crate::bus::{Filter, Subscriber};
+use crate::codec::Message;
+use crate::daemon::Daemon;
+use crate::persist;
+use crate::time::{since_epoch, Clock};
+use crate::{Envelope, Event, Outbound, Source};
+use futures_util::future::{BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::select;
+use tokio::sync::broadcast::Sender;
+use tokio::time::Duration;
+use tracing::warn;
+
+/// Where and how often to save the corpus, and how much to keep.
+#[derive(PartialEq, Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorpusConfig {
+    /// The corpus file, or none to keep the corpus in memory only.
+    pub file: Option<PathBuf>,
+    /// Seconds between saves, when something new was heard.
+    pub period: u64,
+    /// The most distinct frames to keep.
+    pub limit: usize,
+}
+
+impl Default for CorpusConfig {
+    fn default() -> Self {
+        CorpusConfig {
+            file: None,
+            period: 60,
+            limit: 1000,
+        }
+    }
+}
+
+/// A frame that was not understood.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sighting {
+    /// The frame with any byte other than printable ASCII escaped.
+    pub frame: String,
+    /// Why the codec gave up on it.
+    pub fault: String,
+    /// When first and last heard, in seconds since the epoch.
+    pub first: u64,
+    pub last: u64,
+    pub count: u64,
+}
+
+/// The frames not understood, by frame.
+pub type Corpus = Arc<Mutex<BTreeMap<String, Sighting>>>;
+
+/// Add `mesg` to the corpus as heard at `time`, if it is unrecognised,
+/// and return whether it was.  A new frame displaces the one heard
+/// least recently when there are already `limit`.
+pub fn record(corpus: &Corpus, mesg: &Message, time: SystemTime, limit: usize) -> bool {
+    let Message::Unrecognised(frame, fault) = mesg else {
+        return false;
+    };
+    let frame = frame.escape_ascii().to_string();
+    let time = since_epoch(time).as_secs();
+    let mut corpus = corpus.lock().unwrap();
+    if !corpus.contains_key(&frame) && corpus.len() >= limit {
+        let stale = corpus
+            .values()
+            .min_by_key(|s| s.last)
+            .map(|s| s.frame.clone());
+        if let Some(stale) = stale {
+            corpus.remove(&stale);
+        }
+    }
+    let sighting = corpus.entry(frame.clone()).or_insert_with(|| Sighting {
+        frame,
+        fault: fault.to_string(),
+        first: time,
+        last: time,
+        count: 0,
+    });
+    sighting.last = time;
+    sighting.count += 1;
+    true
+}
+
+/// The corpus file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Snapshot {
+    frames: Vec<Sighting>,
+}
+
+/// Read the corpus saved at `path`, or none if there is no file.
+pub fn load(path: &Path) -> Result<Corpus, String> {
+    let snapshot: Snapshot = persist::load(path)?;
+    let frames = snapshot
+        .frames
+        .into_iter()
+        .map(|s| (s.frame.clone(), s))
+        .collect();
+    Ok(Corpus::new(Mutex::new(frames)))
+}
+
+/// Save the corpus to `path`, replacing the file whole.
+pub fn save(path: &Path, corpus: &Corpus) -> io::Result<()> {
+    let frames = corpus.lock().unwrap().values().cloned().collect();
+    persist::save(path, &Snapshot { frames })
+}
+
+/// Collect unrecognised frames and save them every `config.period`, as
+/// kept by `clock`, when there are new sightings.
+pub async fn corpus_daemon(
+    mut events: Subscriber<Envelope>,
+    corpus: Corpus,
+    config: CorpusConfig,
+    clock: Arc<dyn Clock>,
+) {
+    let period = Duration::from_secs(config.period);
+    let mut due = clock.now() + period;
+    let mut changed = false;
+    loop {
+        select! {
+            _ = clock.sleep_until(due), if changed => {
+                due = clock.now() + period;
+                if let Some(path) = &config.file {
+                    if let Err(e) = save(path, &corpus) {
+                        warn!("cannot save corpus to {}: {e}", path.display());
+                    }
+                }
+                changed = false;
+            }
+            res = events.recv() => match res {
+                Some(Envelope { time, event: Event::Cbus(_, mesg), .. }) => {
+                    changed |= record(&corpus, &mesg, time, config.limit);
+                }
+                Some(_) => (),
+                None => return,
+            }
+        }
+    }
+}
+
+/// The collector as a pluggable daemon.
+pub struct Collector {
+    pub corpus: Corpus,
+    pub config: CorpusConfig,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Daemon for Collector {
+    fn name(&self) -> &'static str {
+        "corpus"
+    }
+
+    fn filter(&self) -> Filter {
+        Filter::all().source(Source::Cbus)
+    }
+
+    fn run(&self, events: Subscriber<Envelope>, _: Sender<Outbound>) -> BoxFuture<'static, ()> {
+        let clock = self.clock.clone();
+        corpus_daemon(events, self.corpus.clone(), self.config.clone(), clock).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, ChannelConfig};
+    use crate::codec;
+    use crate::time::Manual;
+    use bytes::Bytes;
+
+    fn unrecognised(frame: &'static [u8]) -> Message {
+        codec::decode(Bytes::from_static(frame)).remove(0)
+    }
+
+    #[test]
+    fn collected() {
+        let corpus = Corpus::default();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let odd = unrecognised(b"Z\x01zz");
+        assert!(record(&corpus, &odd, start, 10));
+        assert!(record(&corpus, &odd, start + Duration::from_secs(5), 10));
+        assert!(!record(&corpus, &Message::Reset, start, 10));
+        let corpus = corpus.lock().unwrap();
+        let sighting = &corpus["Z\\x01zz"];
+        assert_eq!((sighting.first, sighting.last), (100, 105));
+        assert_eq!(sighting.count, 2);
+        assert_eq!(corpus.len(), 1);
+    }
+
+    #[test]
+    fn limited() {
+        let corpus = Corpus::default();
+        let start = SystemTime::UNIX_EPOCH;
+        let at = |secs| start + Duration::from_secs(secs);
+        record(&corpus, &unrecognised(b"Z\x01"), at(1), 2);
+        record(&corpus, &unrecognised(b"Z\x02"), at(2), 2);
+        record(&corpus, &unrecognised(b"Z\x01"), at(3), 2);
+        record(&corpus, &unrecognised(b"Z\x03"), at(4), 2);
+        let corpus = corpus.lock().unwrap();
+        let frames: Vec<_> = corpus.keys().map(String::as_str).collect();
+        assert_eq!(frames, ["Z\\x01", "Z\\x03"]);
+    }
+
+    #[test]
+    fn saved_and_loaded() {
+        let path = std::env::temp_dir().join(format!("lights-corpus-{}.toml", std::process::id()));
+        assert!(load(&path).unwrap().lock().unwrap().is_empty());
+        let corpus = Corpus::default();
+        record(&corpus, &unrecognised(b"Z\x01zz"), SystemTime::now(), 10);
+        save(&path, &corpus).unwrap();
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(*loaded.lock().unwrap(), *corpus.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn saved_each_period() {
+        let path = std::env::temp_dir().join(format!("lights-saves-{}.toml", std::process::id()));
+        let clock = Arc::new(Manual::default());
+        let bus = Bus::new(ChannelConfig::default());
+        let config = CorpusConfig {
+            file: Some(path.clone()),
+            ..CorpusConfig::default()
+        };
+        let corpus = Corpus::default();
+        let daemon = tokio::spawn(corpus_daemon(
+            bus.events("corpus"),
+            corpus.clone(),
+            config.clone(),
+            clock.clone(),
+        ));
+        let odd = unrecognised(b"Z\x01zz");
+        bus.inbound
+            .publish(Source::Cbus, Event::Cbus(0, odd))
+            .unwrap();
+        while corpus.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert!(!path.exists());
+        clock.advance(Duration::from_secs(config.period));
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+        drop(bus);
+        daemon.await.unwrap();
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(*loaded.lock().unwrap(), *corpus.lock().unwrap());
+    }
+}
@@ -0,0 +1,136 @@
+//! `daemon` lets subsystems be added as plugins.
+//!
+//! A [`Daemon`] reacts to inbound events and may send messages to the
+//! CBUS.  Daemons added to a [`Registry`] are each given a subscription
+//! and run under supervision, so a new subsystem needs no changes to
+//! `main` beyond registering it.
+use crate::bus::{Bus, Filter, Subscriber};
+use crate::supervise::{supervise, Backoff, Restarts};
+use crate::{Envelope, Outbound};
+use futures_util::future::{select_all, BoxFuture, FutureExt};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::broadcast::Sender;
+use tokio::task::{self, JoinError};
+use tokio_util::sync::CancellationToken;
+use tracing::{info_span, Instrument};
+
+/// A subsystem driven by inbound events.
+pub trait Daemon: Send + Sync + 'static {
+    /// Names its subscription, supervision and log records.
+    fn name(&self) -> &'static str;
+
+    /// The events to subscribe to, by default all of them.
+    fn filter(&self) -> Filter {
+        Filter::all()
+    }
+
+    /// Run until `events` closes.  This is called again to restart a
+    /// daemon that failed.  A daemon that only listens ignores `outbound`.
+    fn run(
+        &self,
+        events: Subscriber<Envelope>,
+        outbound: Sender<Outbound>,
+    ) -> BoxFuture<'static, ()>;
+}
+
+/// The daemons to run.
+#[derive(Default)]
+pub struct Registry {
+    daemons: Vec<Arc<dyn Daemon>>,
+}
+
+impl Registry {
+    pub fn register(&mut self, daemon: impl Daemon) {
+        self.daemons.push(Arc::new(daemon));
+    }
+
+    /// Spawn every daemon under supervision, each subscribed before this
+    /// returns.  The result completes when any of them ends, with its name.
+    pub fn spawn(
+        self,
+        bus: &Bus,
+        backoff: Backoff,
+        restarts: &Restarts,
+        cancel: &CancellationToken,
+    ) -> impl Future<Output = (&'static str, Result<(), JoinError>)> {
+        let handles = self.daemons.into_iter().map(|daemon| {
+            let name = daemon.name();
+            let bus = bus.clone();
+            let clock = bus.clock().clone();
+            // subscribe now so that no event sent after spawning is missed
+            let filter = daemon.filter();
+            let mut first = Some(bus.filtered(name, filter.clone()));
+            let start = move || {
+                let events = first
+                    .take()
+                    .unwrap_or_else(|| bus.filtered(name, filter.clone()));
+                daemon.run(events, bus.outbound.clone()).map(Ok)
+            };
+            let supervisor = supervise(
+                name,
+                backoff.clone(),
+                restarts.clone(),
+                cancel.clone(),
+                clock,
+                start,
+            );
+            let handle = task::spawn(supervisor.instrument(info_span!("daemon", name)));
+            Box::pin(async move { (name, handle.await) })
+        });
+        let all = select_all(handles.collect::<Vec<_>>());
+        async move { all.await.0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::ChannelConfig;
+    use crate::codec::{Message, Priority};
+    use crate::{Event, Source};
+    use futures_util::FutureExt;
+    use tokio::time::Duration;
+
+    /// Sends a reset for every echo it hears.
+    struct Resetter;
+
+    impl Daemon for Resetter {
+        fn name(&self) -> &'static str {
+            "resetter"
+        }
+
+        fn run(
+            &self,
+            mut events: Subscriber<Envelope>,
+            outbound: Sender<Outbound>,
+        ) -> BoxFuture<'static, ()> {
+            async move {
+                while let Some(event) = events.event().await {
+                    if let Event::Echo(_) = event {
+                        let _ = outbound.send((0, Message::Reset, Priority::Low));
+                    }
+                }
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn registered_daemon_runs() {
+        let bus = Bus::new(ChannelConfig::default());
+        let mut messages = bus.messages("test");
+        let mut registry = Registry::default();
+        registry.register(Resetter);
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(10));
+        let cancel = CancellationToken::new();
+        let _running = registry.spawn(&bus, backoff, &Restarts::default(), &cancel);
+        bus.inbound
+            .publish(Source::Cbus, Event::Echo(Message::Prompt))
+            .unwrap();
+        assert_eq!(
+            messages.recv().await,
+            Some((0, Message::Reset, Priority::Low))
+        );
+    }
+}
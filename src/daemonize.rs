@@ -0,0 +1,116 @@
+//! `daemonize` runs the daemon in the background on systems without
+//! systemd.
+//!
+//! The process forks twice, with a new session in between, so that it
+//! has no controlling terminal, and sends its output to a log file.  It
+//! keeps the working directory so that relative paths in the
+//! configuration still work.  A pidfile lets init scripts find it.
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Whether and how to run in the background.
+#[derive(PartialEq, Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonizeConfig {
+    /// Fork into the background at startup.
+    pub enabled: bool,
+    /// Where to write our process id, even in the foreground.
+    pub pidfile: Option<PathBuf>,
+    /// Where output goes in the background, otherwise it is discarded.
+    pub log_file: Option<PathBuf>,
+}
+
+fn open_log(path: Option<&Path>) -> io::Result<File> {
+    match path {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display()))),
+        None => OpenOptions::new().write(true).open("/dev/null"),
+    }
+}
+
+/// Fork, leaving only the child running.
+fn fork() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        // without flushing output buffered for the child
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// Make `fd` refer to the same file as `file`.
+fn redirect(file: &File, fd: i32) -> io::Result<()> {
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Continue in the background with output appended to `log_file`.
+/// This must be called before any threads are started, such as the
+/// tokio runtime's.
+pub fn detach(log_file: Option<&Path>) -> io::Result<()> {
+    let log = open_log(log_file)?;
+    let null = File::open("/dev/null")?;
+    fork()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // no longer a session leader, so no terminal can be acquired
+    fork()?;
+    redirect(&null, libc::STDIN_FILENO)?;
+    redirect(&log, libc::STDOUT_FILENO)?;
+    redirect(&log, libc::STDERR_FILENO)
+}
+
+/// Whether there is a process `pid`, perhaps another user's.
+fn running(pid: i32) -> bool {
+    pid > 0
+        && (unsafe { libc::kill(pid, 0) } == 0
+            || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+/// Fail if the pidfile at `path` names a running process.
+pub fn check_pidfile(path: &Path) -> Result<(), String> {
+    match fs::read_to_string(path) {
+        Ok(text) => match text.trim().parse() {
+            Ok(pid) if running(pid) => Err(format!(
+                "already running as process {pid}, see {}",
+                path.display()
+            )),
+            _ => Ok(()),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("{}: {e}", path.display())),
+    }
+}
+
+/// Record our process id at `path`.
+pub fn write_pidfile(path: &Path) -> io::Result<()> {
+    fs::write(path, format!("{}\n", std::process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pidfiles() {
+        let path = std::env::temp_dir().join(format!("lights-pid-{}", std::process::id()));
+        assert_eq!(check_pidfile(&path), Ok(()));
+        write_pidfile(&path).unwrap();
+        assert!(check_pidfile(&path)
+            .unwrap_err()
+            .contains("already running"));
+        // no such process
+        fs::write(&path, format!("{}\n", i32::MAX)).unwrap();
+        assert_eq!(check_pidfile(&path), Ok(()));
+        fs::remove_file(&path).unwrap();
+    }
+}
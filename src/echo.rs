@@ -0,0 +1,88 @@
+//! `echo` recognises our own frames when the PCI repeats them back.
+//!
+//! With local echo on, a command comes back as the line we sent.
+//! With local SAL reporting on, it comes back as a monitored SAL
+//! from unit 0.  Either way it must not be mistaken for bus traffic.
+use crate::codec::{self, Message};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// The most sent frames remembered.
+const RECENT_LEN: usize = 32;
+
+/// Frames recently sent, shared by the writer and the reader.
+pub type Echoes = Arc<Mutex<EchoFilter>>;
+
+#[derive(Debug)]
+pub struct EchoFilter {
+    recent: VecDeque<(Bytes, Message, Instant)>,
+    window: Duration,
+}
+
+impl EchoFilter {
+    /// Remember frames for `window` after they are sent.
+    pub fn new(window: Duration) -> Self {
+        EchoFilter {
+            recent: VecDeque::with_capacity(2 * RECENT_LEN),
+            window,
+        }
+    }
+
+    /// Record the `line` just written for `mesg`, in each form it may be echoed.
+    pub fn sent(&mut self, line: &[u8], mesg: &Message, now: Instant) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let forms = [Some(Bytes::copy_from_slice(line)), codec::echo(mesg)];
+        for form in forms.into_iter().flatten() {
+            if self.recent.len() == 2 * RECENT_LEN {
+                self.recent.pop_front();
+            }
+            self.recent.push_back((form, mesg.clone(), now));
+        }
+    }
+
+    /// If `line` echoes a recent frame, forget it and return its message.
+    pub fn echoed(&mut self, line: &[u8], now: Instant) -> Option<Message> {
+        while let Some((_, _, t)) = self.recent.front() {
+            if now.duration_since(*t) > self.window {
+                self.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+        let i = self
+            .recent
+            .iter()
+            .position(|(form, _, _)| form.eq_ignore_ascii_case(line))?;
+        self.recent.remove(i).map(|(_, mesg, _)| mesg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setvar() -> Message {
+        Message::set(4).on().build().unwrap()
+    }
+
+    #[test]
+    fn both_forms() {
+        let mut echoes = EchoFilter::new(Duration::from_secs(2));
+        let now = Instant::now();
+        echoes.sent(b"\\053800790446g\r", &setvar(), now);
+        assert_eq!(echoes.echoed(b"\\053800790446g", now), Some(setvar()));
+        assert_eq!(echoes.echoed(b"\\053800790446g", now), None);
+        assert_eq!(echoes.echoed(b"05003800790446", now), Some(setvar()));
+    }
+
+    #[test]
+    fn forgotten() {
+        let mut echoes = EchoFilter::new(Duration::from_secs(2));
+        let now = Instant::now();
+        echoes.sent(b"\\053800790446g\r", &setvar(), now);
+        let later = now + Duration::from_secs(3);
+        assert_eq!(echoes.echoed(b"\\053800790446g", later), None);
+    }
+}
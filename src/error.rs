@@ -0,0 +1,34 @@
+//! `error` is the library's error type, so that a program embedding it
+//! can tell a refused command from a dead link or a bad configuration.
+use crate::codec::{Message, RangeError};
+use std::io;
+use tokio::task::JoinError;
+
+/// Why an operation failed.
+#[derive(Debug, thiserror::Error)]
+pub enum LightsError {
+    /// The connection to a PCI, or a file or socket, failed.
+    #[error(transparent)]
+    Transport(#[from] io::Error),
+    /// The PCI did not respond in time.
+    #[error("{0}")]
+    Timeout(String),
+    /// The PCI refused a command.
+    #[error("PCI refused {0}")]
+    Refused(Message),
+    /// A value could not be encoded for the CBUS.
+    #[error(transparent)]
+    Codec(#[from] RangeError),
+    /// The settings are invalid.
+    #[error("{0}")]
+    Config(String),
+    /// The HTTP server could not start.
+    #[cfg(feature = "http")]
+    #[error("http: {0}")]
+    Http(#[from] warp::Error),
+    /// A task panicked or was aborted.
+    #[error(transparent)]
+    Task(#[from] JoinError),
+}
+
+pub type Result<T, E = LightsError> = std::result::Result<T, E>;
@@ -1,40 +1,213 @@
 //! `gaffer` controls lighting by reacting to events and issuing CBUS messages.
 //!
-use crate::{codec::Message, server::Post, Event};
-use tokio::sync::broadcast::{Receiver, Sender};
-
-/// `gaffer` controls the lighting.  
-///
-/// It observes inbound events from CBUS and the HMI
-/// and generates outbound messages to CBUS
-pub async fn gaffer_daemon(mut inbound: Receiver<Event>, outbound: Sender<Message>) {
-    loop {
-        let res = inbound.recv().await;
-        if let Ok(event) = res {
-            match event {
-                Event::Cbus(message) => react_to_cbus(message, &outbound),
-                Event::Hmi(post) => react_to_hmi(post, &outbound),
-            }
-        } else {
-            println!("* gaffer: {res:?}")
+//! Commands from the sources in `[gaffer] suppress` are dropped when,
+//! going by the levels that `state` keeps, they would leave a group
+//! where it is: at the level asked for and not ramping.  Turning
+//! everything off is sent at high priority, ahead of routine traffic
+//! such as polls.
+use crate::codec::{Group, Message, Priority, Target, OFF};
+use crate::state::Levels;
+use crate::{Network, Source};
+use serde::Deserialize;
+use std::time::SystemTime;
+
+/// How the gaffer treats commands, from `[gaffer]`.
+#[derive(PartialEq, Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GafferConfig {
+    /// The sources whose commands are dropped if they would change
+    /// nothing.
+    pub suppress: Vec<Source>,
+}
+
+/// Whether `mesg` would leave its group on `network` as it is at `time`.
+pub fn unchanged(levels: &Levels, network: Network, mesg: &Message, time: SystemTime) -> bool {
+    match mesg {
+        Message::SetVar(app, group, level, _) if app.is_lighting() && *group != Group::ALL => {
+            levels
+                .lock()
+                .unwrap()
+                .get(&(network, group.value()))
+                .is_some_and(|g| g.target == *level && g.deadline <= time)
         }
+        _ => false,
     }
 }
 
-fn react_to_hmi(post: Post, outbound: &Sender<Message>) {
-    let res = match post {
-        Post::Level(g, l, r) => outbound.send(Message::SetVar(g, l, r)),
-        _ => Ok(0),
+/// The priority to send `mesg` at.
+pub fn priority(mesg: &Message) -> Priority {
+    match mesg {
+        Message::Bridged(_, _, m) => priority(m),
+        Message::SetVar(_, _, OFF, _) if mesg.target() == Some(Target::All) => Priority::High,
+        _ => Priority::Low,
+    }
+}
+
+#[cfg(feature = "gaffer")]
+pub use react::{gaffer_daemon, Gaffer};
+
+#[cfg(feature = "gaffer")]
+mod react {
+    use super::{priority, unchanged, GafferConfig};
+    use crate::{
+        bus::Subscriber,
+        codec::{Message, LIGHTING},
+        command::{Layout, SharedLayout},
+        daemon::Daemon,
+        state::Levels,
+        Envelope, Event, Network, Outbound, Post,
     };
+    use futures_util::future::{BoxFuture, FutureExt};
+    use std::time::SystemTime;
+    use tokio::sync::broadcast::Sender;
+    use tracing::{debug, warn};
 
-    if res.is_err() {
-        println!("* gaffer: {res:?}")
+    /// `gaffer` controls the lighting.
+    ///
+    /// It observes inbound events from CBUS and the HMI
+    /// and generates outbound messages to CBUS
+    pub async fn gaffer_daemon(
+        mut inbound: Subscriber<Envelope>,
+        outbound: Sender<Outbound>,
+        layout: SharedLayout,
+        levels: Levels,
+        config: GafferConfig,
+    ) {
+        while let Some(Envelope {
+            source,
+            time,
+            event,
+            ..
+        }) = inbound.recv().await
+        {
+            match event {
+                Event::Cbus(network, message) => react_to_cbus(network, message, &outbound),
+                Event::Hmi(post) => {
+                    let suppress = config.suppress.contains(&source);
+                    let layout = layout.read().unwrap().clone();
+                    react_to_hmi(post, &layout, &outbound, &levels, suppress, time)
+                }
+                // our own commands are never reacted to
+                Event::Delivery(..) | Event::Echo(_) => (),
+                Event::LongLine(..) | Event::Connected(_) => (),
+            }
+        }
+    }
+
+    /// The gaffer as a pluggable daemon, with the rooms and scenes it
+    /// knows and the group levels it consults.
+    pub struct Gaffer {
+        pub layout: SharedLayout,
+        pub levels: Levels,
+        pub config: GafferConfig,
+    }
+
+    impl Daemon for Gaffer {
+        fn name(&self) -> &'static str {
+            "gaffer"
+        }
+
+        fn run(
+            &self,
+            events: Subscriber<Envelope>,
+            outbound: Sender<Outbound>,
+        ) -> BoxFuture<'static, ()> {
+            let (layout, levels) = (self.layout.clone(), self.levels.clone());
+            gaffer_daemon(events, outbound, layout, levels, self.config.clone()).boxed()
+        }
+    }
+
+    fn react_to_hmi(
+        post: Post,
+        layout: &Layout,
+        outbound: &Sender<Outbound>,
+        levels: &Levels,
+        suppress: bool,
+        time: SystemTime,
+    ) {
+        let (network, post) = post.network();
+        match messages_for(post, layout) {
+            Ok(messages) => {
+                for mesg in messages {
+                    if suppress && unchanged(levels, network, &mesg, time) {
+                        debug!("gaffer: {mesg} would change nothing");
+                        continue;
+                    }
+                    let res = outbound.send((network, mesg.clone(), priority(&mesg)));
+                    if let Err(e) = res {
+                        warn!("gaffer: {e}")
+                    }
+                }
+            }
+            Err(e) => warn!("gaffer: {e}"),
+        }
+    }
+
+    /// The CBUS messages that carry out a post, if any.
+    fn messages_for(post: Post, layout: &Layout) -> Result<Vec<Message>, String> {
+        let mesg = match post {
+            Post::Command(command) => return layout.messages(&command),
+            Post::Enable(v, x) => Message::SetNetworkVar(v, x),
+            Post::Display(g, text) => Message::Label(LIGHTING, g, text),
+            Post::Bridged(b, n, post) => {
+                let messages = messages_for(*post, layout)?;
+                return Ok(messages
+                    .into_iter()
+                    .map(|m| Message::Bridged(b.clone(), n, Box::new(m)))
+                    .collect());
+            }
+            _ => return Ok(Vec::new()),
+        };
+        Ok(vec![mesg])
+    }
+
+    fn react_to_cbus(_network: Network, _message: Message, _outbound: &Sender<Outbound>) {
+        // no rules yet
     }
 }
 
-fn react_to_cbus(message: Message, outbound: &Sender<Message>) {
-    match message {
-        Message::SetVar(g, l, r) => (),
-        _ => (),
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Address, Level, Ramp, INSTANT, LIGHTING, ON};
+    use crate::state::{forget, observe};
+    use std::time::Duration;
+
+    #[test]
+    fn redundancy() {
+        let levels = Levels::default();
+        let now = SystemTime::UNIX_EPOCH;
+        let on = Message::SetVar(LIGHTING, Group(4), ON, INSTANT);
+        assert!(!unchanged(&levels, 0, &on, now));
+        observe(&levels, 0, &on, now);
+        assert!(unchanged(&levels, 0, &on, now));
+        assert!(!unchanged(&levels, 1, &on, now));
+
+        // not while ramping
+        let ramp = Ramp::new(4).unwrap();
+        let off = Message::SetVar(LIGHTING, Group(4), OFF, ramp);
+        observe(&levels, 0, &off, now);
+        let off = Message::SetVar(LIGHTING, Group(4), OFF, INSTANT);
+        assert!(!unchanged(&levels, 0, &off, now + Duration::from_secs(1)));
+        assert!(unchanged(&levels, 0, &off, now + Duration::from_secs(4)));
+
+        let report = Message::LevelStatus(LIGHTING, vec![(Group(4), Level(0x80))]);
+        observe(&levels, 0, &report, now);
+        assert!(!unchanged(&levels, 0, &off, now));
+        observe(&levels, 0, &off, now);
+        forget(&levels, 0, &off);
+        assert!(!unchanged(&levels, 0, &off, now));
+    }
+
+    #[test]
+    fn prioritised() {
+        let all_off = Message::SetVar(LIGHTING, Group::ALL, OFF, INSTANT);
+        assert_eq!(priority(&all_off), Priority::High);
+        let bridged = Message::Bridged(Address(2), 1, Box::new(all_off));
+        assert_eq!(priority(&bridged), Priority::High);
+        let on = Message::SetVar(LIGHTING, Group::ALL, ON, INSTANT);
+        assert_eq!(priority(&on), Priority::Low);
+        let off = Message::SetVar(LIGHTING, Group(4), OFF, INSTANT);
+        assert_eq!(priority(&off), Priority::Low);
+    }
 }
@@ -0,0 +1,412 @@
+//! `ha` runs two instances as an active and a standby, so the lights
+//! still work if the box controlling them fails.
+//!
+//! Each instance sends a heartbeat to its peer every second with its
+//! priority and whether it is active.  Only the active instance sends
+//! commands to the CBUS and serves HTTP; the standby monitors the CBUS
+//! read-only.  The standby takes over when the active instance has been
+//! silent for `timeout` seconds.  Should both become active, the one
+//! with the lower priority gives way.
+//!
+//! Heartbeats are only taken from the peer's address and, with a `key`
+//! shared by the pair, only when signed with it.  Each carries a stamp,
+//! the sender's time in milliseconds or one more than its last, and one
+//! no later than the last heard is a replay and ignored.
+//!
+//! ```toml
+//! [ha]
+//! peer = "192.168.1.21:3031"
+//! priority = 2
+//! key = { file = "/etc/lights/ha-key" }
+//! ```
+use crate::secret::Secret;
+use crate::time::{since_epoch, Clock};
+use crate::{LightsError, Result};
+use ring::hmac;
+use serde::Deserialize;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// How often heartbeats are sent.
+const HEARTBEAT_PERIOD: Duration = Duration::from_secs(1);
+
+/// The peer and how to reach it.
+#[derive(PartialEq, Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HaConfig {
+    /// Where the peer listens for heartbeats, or none to always be active.
+    pub peer: Option<SocketAddr>,
+    /// Where to listen for the peer's heartbeats.
+    pub listen: SocketAddr,
+    /// Which instance stays active when both would be; the higher wins.
+    pub priority: u8,
+    /// Seconds without a heartbeat from the active peer before taking over.
+    pub timeout: u64,
+    /// A secret shared with the peer to sign heartbeats, or none to
+    /// trust any from the peer's address.
+    pub key: Option<Secret>,
+}
+
+impl Default for HaConfig {
+    fn default() -> Self {
+        HaConfig {
+            peer: None,
+            listen: ([0, 0, 0, 0], 3031).into(),
+            priority: 1,
+            timeout: 5,
+            key: None,
+        }
+    }
+}
+
+/// Whether this instance is active, shared with the tasks that only
+/// run while it is.
+#[derive(Debug, Clone)]
+pub struct Role(Arc<watch::Sender<bool>>);
+
+impl Role {
+    pub fn new(active: bool) -> Self {
+        Role(Arc::new(watch::channel(active).0))
+    }
+
+    pub fn is_active(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    fn set(&self, active: bool) {
+        if self.is_active() != active {
+            self.0.send_replace(active);
+        }
+    }
+
+    /// Wait until the instance is active, or until it is not.
+    pub async fn until(&self, active: bool) {
+        let mut role = self.0.subscribe();
+        while *role.borrow_and_update() != active {
+            if role.changed().await.is_err() {
+                std::future::pending().await
+            }
+        }
+    }
+}
+
+/// Always active, for a lone instance.
+impl Default for Role {
+    fn default() -> Self {
+        Role::new(true)
+    }
+}
+
+/// Ranks an instance: its priority, then a number drawn at startup to
+/// break ties.
+type Rank = (u8, u64);
+
+/// A heartbeat.
+#[derive(PartialEq, Debug, Clone, Copy)]
+struct Heartbeat {
+    rank: Rank,
+    active: bool,
+    /// Increases with each heartbeat from an instance, across restarts.
+    stamp: u64,
+}
+
+impl Heartbeat {
+    fn encode(&self) -> String {
+        let role = if self.active { "active" } else { "standby" };
+        let (priority, id) = self.rank;
+        format!("lights {priority} {id} {role} {}", self.stamp)
+    }
+
+    fn decode(text: &str) -> Option<Heartbeat> {
+        match text.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["lights", priority, id, role, stamp] => Some(Heartbeat {
+                rank: (priority.parse().ok()?, id.parse().ok()?),
+                active: match *role {
+                    "active" => true,
+                    "standby" => false,
+                    _ => return None,
+                },
+                stamp: stamp.parse().ok()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A heartbeat as sent, signed with `key` if there is one.
+fn seal(beat: &Heartbeat, key: Option<&hmac::Key>) -> String {
+    let text = beat.encode();
+    match key {
+        Some(key) => {
+            let tag = hmac::sign(key, text.as_bytes());
+            let hex: Vec<String> = tag.as_ref().iter().map(|b| format!("{b:02x}")).collect();
+            format!("{text} {}", hex.concat())
+        }
+        None => text,
+    }
+}
+
+/// The heartbeat in a datagram received `from` somewhere, if that is
+/// the peer's address, the heartbeat is stamped later than `last` and,
+/// with a `key`, it is signed.
+fn open(
+    datagram: &[u8],
+    from: SocketAddr,
+    peer: SocketAddr,
+    key: Option<&hmac::Key>,
+    last: u64,
+) -> Option<Heartbeat> {
+    if from.ip() != peer.ip() {
+        return None;
+    }
+    let text = std::str::from_utf8(datagram).ok()?;
+    let text = match key {
+        Some(key) => {
+            let (text, hex) = text.rsplit_once(' ')?;
+            let tag = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            hmac::verify(key, text.as_bytes(), &tag).ok()?;
+            text
+        }
+        None => text,
+    };
+    Heartbeat::decode(text).filter(|beat| beat.stamp > last)
+}
+
+/// Whether to be active, given the last heartbeat from the peer and
+/// when it was heard.
+fn decide(
+    active: bool,
+    rank: Rank,
+    heard: (Heartbeat, Instant),
+    now: Instant,
+    timeout: Duration,
+) -> bool {
+    let (peer, at) = heard;
+    if now.saturating_duration_since(at) >= timeout {
+        return true;
+    }
+    if peer.active {
+        active && rank > peer.rank
+    } else {
+        active || rank > peer.rank
+    }
+}
+
+/// Exchange heartbeats with the peer and keep `role` up to date until
+/// cancelled, timed by `clock`.  Returns at once without a peer.
+pub async fn ha_daemon(
+    config: HaConfig,
+    role: Role,
+    clock: Arc<dyn Clock>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let Some(peer) = config.peer else {
+        return Ok(());
+    };
+    let key = match &config.key {
+        Some(secret) => {
+            let value = secret.reveal().map_err(LightsError::Config)?;
+            Some(hmac::Key::new(hmac::HMAC_SHA256, value.as_bytes()))
+        }
+        None => None,
+    };
+    let socket = UdpSocket::bind(config.listen).await?;
+    let rank = (config.priority, RandomState::new().build_hasher().finish());
+    let timeout = Duration::from_secs(config.timeout);
+    // until heard from, assume the peer is active, so as not to take
+    // over from it when restarting
+    let assumed = Heartbeat {
+        rank: (u8::MAX, u64::MAX),
+        active: true,
+        stamp: 0,
+    };
+    let mut stamp = 0;
+    let mut heard = (assumed, clock.now());
+    let mut due = clock.now();
+    let mut buf = [0u8; 256];
+    info!("standby, listening for {peer} on {}", config.listen);
+    loop {
+        select! {
+            _ = cancel.cancelled() => return Ok(()),
+            _ = clock.sleep_until(due) => {
+                due += HEARTBEAT_PERIOD;
+                let active = decide(role.is_active(), rank, heard, clock.now(), timeout);
+                if active != role.is_active() {
+                    if active {
+                        warn!("taking over as active");
+                    } else {
+                        warn!("{peer} is active, standing by");
+                    }
+                    role.set(active);
+                }
+                let millis = u64::try_from(since_epoch(clock.wall()).as_millis());
+                stamp = (stamp + 1).max(millis.unwrap_or(u64::MAX));
+                let beat = seal(&Heartbeat { rank, active, stamp }, key.as_ref());
+                if let Err(e) = socket.send_to(beat.as_bytes(), peer).await {
+                    warn!("heartbeat to {peer}: {e}");
+                }
+            }
+            res = socket.recv_from(&mut buf) => match res {
+                Ok((len, from)) => {
+                    match open(&buf[..len], from, peer, key.as_ref(), heard.0.stamp) {
+                        Some(beat) => heard = (beat, clock.now()),
+                        None => warn!("ignoring datagram from {from}"),
+                    }
+                }
+                Err(e) => warn!("heartbeat from {peer}: {e}"),
+            }
+        }
+    }
+}
+
+/// Run the task made by `start` whenever `role` is active, stopping it
+/// through the token passed to it when the role is lost, until
+/// cancelled.
+pub async fn while_active<F, T>(role: Role, cancel: CancellationToken, mut start: F) -> Result<()>
+where
+    F: FnMut(CancellationToken) -> T,
+    T: Future<Output = Result<()>>,
+{
+    loop {
+        select! {
+            _ = cancel.cancelled() => return Ok(()),
+            _ = role.until(true) => (),
+        }
+        let stop = cancel.child_token();
+        let task = start(stop.clone());
+        tokio::pin!(task);
+        select! {
+            res = &mut task => return res,
+            _ = role.until(false) => stop.cancel(),
+        }
+        task.await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeats() {
+        let beat = Heartbeat {
+            rank: (2, 77),
+            active: false,
+            stamp: 1000,
+        };
+        assert_eq!(beat.encode(), "lights 2 77 standby 1000");
+        assert_eq!(Heartbeat::decode(&beat.encode()), Some(beat));
+        assert_eq!(Heartbeat::decode("lights 2 77 dormant 1000"), None);
+        assert_eq!(Heartbeat::decode("lights 2 77 standby"), None);
+        assert_eq!(Heartbeat::decode("hello"), None);
+    }
+
+    #[test]
+    fn sealed() {
+        let beat = Heartbeat {
+            rank: (2, 77),
+            active: true,
+            stamp: 1000,
+        };
+        let peer: SocketAddr = ([192, 168, 1, 21], 3031).into();
+        let other: SocketAddr = ([192, 168, 1, 99], 3031).into();
+        let text = seal(&beat, None);
+        assert_eq!(open(text.as_bytes(), peer, peer, None, 0), Some(beat));
+        assert_eq!(open(text.as_bytes(), other, peer, None, 0), None);
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"s3cret");
+        let wrong = hmac::Key::new(hmac::HMAC_SHA256, b"guess");
+        let signed = seal(&beat, Some(&key));
+        assert_eq!(
+            open(signed.as_bytes(), peer, peer, Some(&key), 0),
+            Some(beat)
+        );
+        assert_eq!(open(signed.as_bytes(), other, peer, Some(&key), 0), None);
+        assert_eq!(open(signed.as_bytes(), peer, peer, Some(&wrong), 0), None);
+        assert_eq!(open(text.as_bytes(), peer, peer, Some(&key), 0), None);
+        let forged = signed.replace("active", "standby");
+        assert_eq!(open(forged.as_bytes(), peer, peer, Some(&key), 0), None);
+        // replayed
+        assert_eq!(open(signed.as_bytes(), peer, peer, Some(&key), 1000), None);
+    }
+
+    #[test]
+    fn decisions() {
+        let now = Instant::now();
+        let timeout = Duration::from_secs(5);
+        let beat = |priority, active| Heartbeat {
+            rank: (priority, 0),
+            active,
+            stamp: 1,
+        };
+        let recent = now - Duration::from_secs(1);
+        let stale = now - timeout;
+        // the active peer is alive
+        assert!(!decide(
+            false,
+            (2, 0),
+            (beat(1, true), recent),
+            now,
+            timeout
+        ));
+        // and has gone quiet
+        assert!(decide(false, (1, 0), (beat(2, true), stale), now, timeout));
+        // both are active: the higher priority stays
+        assert!(decide(true, (2, 0), (beat(1, true), recent), now, timeout));
+        assert!(!decide(true, (1, 0), (beat(2, true), recent), now, timeout));
+        // both are standing by: the higher priority takes over
+        assert!(decide(
+            false,
+            (2, 0),
+            (beat(1, false), recent),
+            now,
+            timeout
+        ));
+        assert!(!decide(
+            false,
+            (1, 0),
+            (beat(2, false), recent),
+            now,
+            timeout
+        ));
+        // a standby peer does not displace the active one
+        assert!(decide(true, (1, 0), (beat(2, false), recent), now, timeout));
+    }
+
+    #[tokio::test]
+    async fn stopped_when_demoted() {
+        let role = Role::new(false);
+        let cancel = CancellationToken::new();
+        let (sender, mut runs) = tokio::sync::mpsc::unbounded_channel();
+        let task = tokio::spawn(while_active(role.clone(), cancel.clone(), move |stop| {
+            let sender = sender.clone();
+            async move {
+                sender.send("started").unwrap();
+                stop.cancelled().await;
+                sender.send("stopped").unwrap();
+                Ok(())
+            }
+        }));
+        role.set(true);
+        assert_eq!(runs.recv().await, Some("started"));
+        role.set(false);
+        assert_eq!(runs.recv().await, Some("stopped"));
+        role.set(true);
+        assert_eq!(runs.recv().await, Some("started"));
+        cancel.cancel();
+        task.await.unwrap().unwrap();
+        assert_eq!(runs.recv().await, Some("stopped"));
+    }
+}
@@ -0,0 +1,394 @@
+//! `health` watches the links to the PCIs and escalates when one
+//! looks dead.
+//!
+//! A link that has been silent for the configured period is logged,
+//! then after another period reported to systemd, then forcibly
+//! reconnected and finally, if still silent, the process exits so that
+//! systemd can restart it.  Use with `keepalive` so that a healthy link
+//! is never quiet for long.
+use crate::bus::{Filter, Lags, Subscriber};
+use crate::daemon::Daemon;
+use crate::systemd;
+use crate::time::Clock;
+use crate::{Envelope, Event, Network, Outbound, Source};
+use futures_util::future::{BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::select;
+use tokio::sync::broadcast::Sender;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// How often links are checked.
+const CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// When to escalate.
+#[derive(PartialEq, Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Seconds of silence from a PCI between escalations, or none to
+    /// only report on links.
+    pub silence: Option<u64>,
+}
+
+/// How far concern about a link has escalated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    #[default]
+    Healthy,
+    Logged,
+    Notified,
+    Reconnected,
+    Failed,
+}
+
+impl Stage {
+    /// The stage reached after `silent` when each lasts `period`.
+    fn after(silent: Duration, period: Duration) -> Stage {
+        match silent.as_secs_f64() / period.as_secs_f64() {
+            n if n < 1.0 => Stage::Healthy,
+            n if n < 2.0 => Stage::Logged,
+            n if n < 3.0 => Stage::Notified,
+            n if n < 4.0 => Stage::Reconnected,
+            _ => Stage::Failed,
+        }
+    }
+}
+
+/// What is known about the link to one PCI.
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkHealth {
+    pub connected: bool,
+    /// Successful connections, the first included.
+    pub connections: u64,
+    pub last_error: Option<String>,
+    /// Seconds since a frame was received or, failing that, since
+    /// the link was started.
+    pub silent_secs: u64,
+    pub stage: Stage,
+    /// Attempts to connect since the link was last healthy.
+    pub failures: u32,
+    /// Seconds until the next attempt, while waiting to reconnect.
+    pub retry_secs: Option<f64>,
+    #[serde(skip)]
+    heard: Instant,
+    #[serde(skip)]
+    session: CancellationToken,
+}
+
+impl LinkHealth {
+    /// A link started at `now`, on the clock that measures its silence.
+    fn new(now: Instant) -> Self {
+        LinkHealth {
+            connected: false,
+            connections: 0,
+            last_error: None,
+            silent_secs: 0,
+            stage: Stage::Healthy,
+            failures: 0,
+            retry_secs: None,
+            heard: now,
+            session: CancellationToken::new(),
+        }
+    }
+}
+
+/// The health of each link, by network.
+pub type Health = Arc<Mutex<BTreeMap<Network, LinkHealth>>>;
+
+/// Note that a session has started on `network`, which can be ended
+/// by cancelling `session`.
+pub fn session_started(
+    health: &Health,
+    network: Network,
+    session: CancellationToken,
+    now: Instant,
+) {
+    let mut health = health.lock().unwrap();
+    let link = health
+        .entry(network)
+        .or_insert_with(|| LinkHealth::new(now));
+    link.session = session;
+    link.retry_secs = None;
+}
+
+/// End the session on `network` so that it reconnects, returning false
+/// if there is no such link.
+pub fn reconnect(health: &Health, network: Network) -> bool {
+    match health.lock().unwrap().get(&network) {
+        Some(link) => {
+            link.session.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Note that the session on `network` has ended.
+pub fn session_ended(health: &Health, network: Network, error: Option<String>) {
+    if let Some(link) = health.lock().unwrap().get_mut(&network) {
+        link.connected = false;
+        if error.is_some() {
+            link.last_error = error;
+        }
+    }
+}
+
+/// Note that `network` will reconnect after `delay`, following
+/// `failures` attempts.
+pub fn retrying(health: &Health, network: Network, failures: u32, delay: Duration) {
+    if let Some(link) = health.lock().unwrap().get_mut(&network) {
+        link.failures = failures;
+        link.retry_secs = Some(delay.as_secs_f64());
+    }
+}
+
+/// Note an event that shows a link is alive.
+fn heard(health: &Health, event: &Event, now: Instant) {
+    let mut health = health.lock().unwrap();
+    match event {
+        Event::Cbus(network, _) => {
+            let link = health
+                .entry(*network)
+                .or_insert_with(|| LinkHealth::new(now));
+            link.heard = now;
+        }
+        Event::Connected(network) => {
+            let link = health
+                .entry(*network)
+                .or_insert_with(|| LinkHealth::new(now));
+            link.connected = true;
+            link.connections += 1;
+            link.heard = now;
+        }
+        _ => (),
+    }
+}
+
+/// Bring each link up to date at `now` and return those whose stage
+/// has changed, with their old stage.
+fn check(health: &Health, now: Instant, period: Option<Duration>) -> Vec<(Network, Stage, Stage)> {
+    let mut changes = Vec::new();
+    for (network, link) in health.lock().unwrap().iter_mut() {
+        let silent = now.saturating_duration_since(link.heard);
+        link.silent_secs = silent.as_secs();
+        let stage = period.map_or(Stage::Healthy, |p| Stage::after(silent, p));
+        if stage != link.stage {
+            changes.push((*network, link.stage, stage));
+            link.stage = stage;
+        }
+    }
+    changes
+}
+
+/// Act on a link reaching a new stage.
+fn escalate(
+    health: &Health,
+    network: Network,
+    old: Stage,
+    stage: Stage,
+    shutdown: &CancellationToken,
+) {
+    let silent = health.lock().unwrap()[&network].silent_secs;
+    if stage < old {
+        if stage == Stage::Healthy {
+            info!(network, "link recovered");
+            let _ = systemd::notify("STATUS=connected to the CBUS");
+        }
+        return;
+    }
+    match stage {
+        Stage::Healthy => (),
+        Stage::Logged => warn!(network, "no frames for {silent}s"),
+        Stage::Notified => {
+            error!(network, "no frames for {silent}s, the link may be dead");
+            let status = format!("STATUS=network {network} silent for {silent}s");
+            if let Err(e) = systemd::notify(&status) {
+                warn!("cannot notify systemd: {e}");
+            }
+        }
+        Stage::Reconnected => {
+            error!(network, "no frames for {silent}s, reconnecting");
+            health.lock().unwrap()[&network].session.cancel();
+        }
+        Stage::Failed => {
+            error!(network, "no frames for {silent}s, exiting");
+            shutdown.cancel();
+        }
+    }
+}
+
+/// Watch the links on `networks` and the lag of subscribers, escalating
+/// as configured.  Cancels `shutdown` when a link has failed.
+pub async fn health_daemon(
+    mut events: Subscriber<Envelope>,
+    health: Health,
+    networks: Vec<Network>,
+    lags: Lags,
+    config: HealthConfig,
+    shutdown: CancellationToken,
+    clock: Arc<dyn Clock>,
+) {
+    for network in networks {
+        let mut health = health.lock().unwrap();
+        health
+            .entry(network)
+            .or_insert_with(|| LinkHealth::new(clock.now()));
+    }
+    let period = config.silence.map(Duration::from_secs);
+    let mut due = clock.now();
+    let mut dropped = 0;
+    loop {
+        select! {
+            _ = clock.sleep_until(due) => {
+                due += CHECK_PERIOD;
+                for (network, old, stage) in check(&health, clock.now(), period) {
+                    escalate(&health, network, old, stage, &shutdown);
+                }
+                let total = lags.lock().unwrap().values().map(|l| l.dropped).sum();
+                if total > dropped {
+                    warn!("subscribers have missed {} events", total - dropped);
+                    dropped = total;
+                }
+            }
+            res = events.event() => match res {
+                Some(event) => heard(&health, &event, clock.now()),
+                None => return,
+            }
+        }
+    }
+}
+
+/// The health monitor as a pluggable daemon.
+pub struct HealthMonitor {
+    pub health: Health,
+    pub networks: Vec<Network>,
+    pub lags: Lags,
+    pub config: HealthConfig,
+    pub shutdown: CancellationToken,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Daemon for HealthMonitor {
+    fn name(&self) -> &'static str {
+        "health"
+    }
+
+    fn filter(&self) -> Filter {
+        Filter::all().source(Source::Cbus)
+    }
+
+    fn run(&self, events: Subscriber<Envelope>, _: Sender<Outbound>) -> BoxFuture<'static, ()> {
+        health_daemon(
+            events,
+            self.health.clone(),
+            self.networks.clone(),
+            self.lags.clone(),
+            self.config.clone(),
+            self.shutdown.clone(),
+            self.clock.clone(),
+        )
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, ChannelConfig};
+    use crate::codec::Message;
+    use crate::time::Manual;
+
+    #[test]
+    fn stages() {
+        let period = Duration::from_secs(10);
+        assert_eq!(Stage::after(Duration::from_secs(9), period), Stage::Healthy);
+        assert_eq!(Stage::after(Duration::from_secs(10), period), Stage::Logged);
+        assert_eq!(
+            Stage::after(Duration::from_secs(35), period),
+            Stage::Reconnected
+        );
+        assert_eq!(Stage::after(Duration::from_secs(99), period), Stage::Failed);
+    }
+
+    #[test]
+    fn escalates_until_heard() {
+        let health = Health::default();
+        let start = Instant::now();
+        heard(&health, &Event::Connected(1), start);
+        let period = Some(Duration::from_secs(10));
+        let later = start + Duration::from_secs(21);
+        assert_eq!(
+            check(&health, later, period),
+            [(1, Stage::Healthy, Stage::Notified)]
+        );
+        assert_eq!(check(&health, later, period), []);
+
+        heard(&health, &Event::Cbus(1, Message::Prompt), later);
+        assert_eq!(
+            check(&health, later, period),
+            [(1, Stage::Notified, Stage::Healthy)]
+        );
+        let link = health.lock().unwrap()[&1].clone();
+        assert!(link.connected);
+        assert_eq!(link.connections, 1);
+    }
+
+    #[test]
+    fn forced_reconnect() {
+        let health = Health::default();
+        let session = CancellationToken::new();
+        session_started(&health, 0, session.clone(), Instant::now());
+        check(&health, Instant::now(), None);
+        escalate(
+            &health,
+            0,
+            Stage::Notified,
+            Stage::Reconnected,
+            &CancellationToken::new(),
+        );
+        assert!(session.is_cancelled());
+    }
+
+    #[test]
+    fn backing_off() {
+        let health = Health::default();
+        let now = Instant::now();
+        session_started(&health, 0, CancellationToken::new(), now);
+        retrying(&health, 0, 3, Duration::from_millis(1500));
+        assert_eq!(health.lock().unwrap()[&0].retry_secs, Some(1.5));
+        session_started(&health, 0, CancellationToken::new(), now);
+        let link = health.lock().unwrap()[&0].clone();
+        assert_eq!((link.failures, link.retry_secs), (3, None));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_silence() {
+        let clock = Manual::default();
+        // silence is measured from the start on the clock given
+        clock.advance(Duration::from_secs(60 * 60));
+        let bus = Bus::new(ChannelConfig::default());
+        let (health, shutdown) = (Health::default(), CancellationToken::new());
+        let config = HealthConfig { silence: Some(60) };
+        tokio::spawn(health_daemon(
+            bus.events("health"),
+            health.clone(),
+            vec![0],
+            bus.lags.clone(),
+            config,
+            shutdown.clone(),
+            Arc::new(clock.clone()),
+        ));
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!shutdown.is_cancelled());
+        assert_eq!(health.lock().unwrap()[&0].stage, Stage::Healthy);
+        clock.advance(Duration::from_secs(5 * 60));
+        shutdown.cancelled().await;
+        assert_eq!(health.lock().unwrap()[&0].stage, Stage::Failed);
+    }
+}
@@ -0,0 +1,160 @@
+//! `labels` reads group labels from a unit and keeps them for display.
+//!
+use crate::bus::Subscriber;
+use crate::codec::{Address, Group, Message, Priority};
+use crate::daemon::Daemon;
+use crate::time::Clock;
+use crate::{Envelope, Event, Network, Outbound, Post};
+use futures_util::future::{BoxFuture, FutureExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use tokio::select;
+use tokio::sync::broadcast::Sender;
+use tokio::time::Duration;
+use tracing::{info, warn};
+
+/// Interval between label requests for successive groups.
+const READ_PACE: Duration = Duration::from_millis(100);
+
+/// Group labels by group number.
+pub type Labels = Arc<Mutex<BTreeMap<u8, String>>>;
+
+/// A label read in progress.
+struct Reading {
+    network: Network,
+    unit: u8,
+    next: Option<u8>,
+    pending: BTreeSet<u8>,
+}
+
+/// Read the group labels stored in a unit when requested by the HMI,
+/// pacing the requests by `clock`.
+///
+/// A label request is issued for each group in turn and the
+/// replies from that unit are recorded as they arrive.
+pub async fn labels_daemon(
+    mut inbound: Subscriber<Envelope>,
+    outbound: Sender<Outbound>,
+    labels: Labels,
+    clock: Arc<dyn Clock>,
+) {
+    let mut due = clock.now();
+    let mut reading: Option<Reading> = None;
+    loop {
+        let walking = matches!(reading, Some(Reading { next: Some(_), .. }));
+        select! {
+            _ = clock.sleep_until(due), if walking => {
+                due += READ_PACE;
+                if let Some(r) = reading.as_mut() {
+                    if let Some(g) = r.next {
+                        let mesg = Message::ReadLabel(Address(r.unit), Group(g));
+                        let res = outbound.send((r.network, mesg, Priority::Low));
+                        if let Err(e) = res {
+                            warn!("labels: {e}")
+                        }
+                        r.pending.insert(g);
+                        r.next = g.checked_add(1);
+                    }
+                }
+            }
+            res = inbound.event() => match res {
+                Some(Event::Cbus(n, Message::Reply(Address(a), g, text))) => {
+                    if let Some(r) = reading.as_mut() {
+                        if r.network == n && r.unit == a && r.pending.remove(&g) {
+                            let label = String::from_utf8_lossy(&text).trim().to_string();
+                            if !label.is_empty() {
+                                labels.lock().unwrap().insert(g, label);
+                            }
+                        }
+                    }
+                }
+                Some(Event::Hmi(post)) => if let (network, Post::ReadLabels(Address(unit))) = post.network() {
+                    info!(network, unit, "labels: reading");
+                    reading = Some(Reading {
+                        network,
+                        unit,
+                        next: Some(0),
+                        pending: BTreeSet::new(),
+                    });
+                    due = clock.now();
+                }
+                Some(_) => (),
+                None => return,
+            }
+        }
+    }
+}
+
+/// The label reader as a pluggable daemon.
+pub struct LabelReader {
+    pub labels: Labels,
+    /// Paces the requests.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Daemon for LabelReader {
+    fn name(&self) -> &'static str {
+        "labels"
+    }
+
+    fn run(
+        &self,
+        events: Subscriber<Envelope>,
+        outbound: Sender<Outbound>,
+    ) -> BoxFuture<'static, ()> {
+        labels_daemon(events, outbound, self.labels.clone(), self.clock.clone()).boxed()
+    }
+}
+
+/// The label of the group a message refers to, if known.
+pub fn label_for(labels: &Labels, mesg: &Message) -> Option<String> {
+    let Group(g) = mesg.group()?;
+    labels.lock().unwrap().get(g).cloned()
+}
+
+/// Replace the configured group names `old` with `new`.
+///
+/// A name removed from the configuration is forgotten unless it has
+/// since been read from a unit.
+pub fn rename_groups(labels: &Labels, old: &BTreeMap<u8, String>, new: &BTreeMap<u8, String>) {
+    let mut labels = labels.lock().unwrap();
+    for (g, name) in old {
+        if !new.contains_key(g) && labels.get(g) == Some(name) {
+            labels.remove(g);
+        }
+    }
+    for (g, name) in new {
+        if old.get(g) != Some(name) {
+            labels.insert(*g, name.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Level, INSTANT, LIGHTING};
+
+    #[test]
+    fn label_lookup() {
+        let labels = Labels::default();
+        labels.lock().unwrap().insert(4, "Kitchen".to_string());
+        let on = Message::SetVar(LIGHTING, Group(4), Level(0xff), INSTANT);
+        let other = Message::StopRamp(LIGHTING, Group(5));
+        assert_eq!(label_for(&labels, &on), Some("Kitchen".to_string()));
+        assert_eq!(label_for(&labels, &other), None);
+        assert_eq!(label_for(&labels, &Message::Reset), None);
+    }
+
+    #[test]
+    fn renamed() {
+        let old = BTreeMap::from([(4, "Kitchen".into()), (5, "Hall".into()), (6, "Den".into())]);
+        let labels = Labels::new(Mutex::new(old.clone()));
+        // read from a unit since
+        labels.lock().unwrap().insert(6, "Study".into());
+        let new = BTreeMap::from([(4, "Galley".into())]);
+        rename_groups(&labels, &old, &new);
+        let expect = BTreeMap::from([(4, "Galley".into()), (6, "Study".into())]);
+        assert_eq!(*labels.lock().unwrap(), expect);
+    }
+}
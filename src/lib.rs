@@ -0,0 +1,146 @@
+//! A home automation server with a Clipsal CBUS interface.
+//!
+//! The pieces can be used separately:
+//! [`codec`] encodes and decodes CBUS messages,
+//! [`busio`] frames them on a serial or network connection,
+//! [`session`] maintains the connection to a PCI,
+//! [`gaffer`] and the other daemons react to events,
+//! and [`server`] provides the HTTP interface.
+//! The `gaffer`, `http`, `tls`, `otlp` and `report` features, on by
+//! default, can be turned off for a small build that only bridges to the CBUS.
+//!
+//! Daemons communicate over two broadcast channels:
+//! [`Event`]s inbound and [`Message`]s outbound to the CBUS,
+//! each tagged with the [`Network`] it came from or is for.
+use bytes::Bytes;
+use codec::{Address, Group, Variable};
+use command::Command;
+use serde::{Deserialize, Serialize};
+
+pub mod bus;
+pub mod busio;
+pub mod clock;
+pub mod codec;
+pub mod command;
+pub mod config;
+pub mod confirm;
+pub mod control;
+pub mod corpus;
+pub mod daemon;
+pub mod daemonize;
+pub mod echo;
+pub mod error;
+pub mod gaffer;
+pub mod ha;
+pub mod health;
+pub mod labels;
+pub mod otlp;
+pub mod persist;
+pub mod post;
+pub mod proxy;
+pub mod reload;
+pub mod replay;
+pub mod report;
+pub mod scan;
+pub mod secret;
+#[cfg(feature = "http")]
+pub mod server;
+pub mod session;
+pub mod simulate;
+pub mod state;
+pub mod supervise;
+pub mod systemd;
+pub mod tap;
+pub mod throttle;
+pub mod time;
+pub mod transport;
+pub mod tunnel;
+pub mod writer;
+
+pub use codec::{Message, Outcome, Priority};
+pub use error::{LightsError, Result};
+
+/// Identifies a PCI and so the CBUS network it is on.  The first is 0.
+pub type Network = u8;
+
+/// A message for the CBUS, the network to send it on and the priority
+/// to send it at.
+pub type Outbound = (Network, Message, Priority);
+
+/// Something that happened somewhere in the recent past.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Event {
+    Cbus(Network, Message),
+    Hmi(Post),
+    /// Whether a message sent on a network was confirmed.
+    Delivery(Network, Message, Outcome),
+    /// A message we sent, repeated back by the PCI.
+    Echo(Message),
+    /// A line from the PCI too long to decode: its first bytes and its length.
+    LongLine(Bytes, usize),
+    /// The PCI on a network has been connected and configured.
+    Connected(Network),
+}
+
+impl Event {
+    /// The CBUS message an event carries, if any.
+    pub fn message(&self) -> Option<&Message> {
+        match self {
+            Event::Cbus(_, m) | Event::Delivery(_, m, _) | Event::Echo(m) => Some(m),
+            _ => None,
+        }
+    }
+}
+
+/// Where an event came from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// A PCI, or the session with it.
+    Cbus,
+    /// The HTTP interface.
+    Hmi,
+    /// The local control socket.
+    Control,
+}
+
+/// An event as published, numbered in order and timestamped.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct Envelope {
+    /// Counts up from 0 across all events.
+    pub seq: u64,
+    pub source: Source,
+    /// When published, for measuring intervals.
+    #[serde(skip)]
+    pub at: tokio::time::Instant,
+    /// When published, by the wall clock.
+    pub time: std::time::SystemTime,
+    pub event: Event,
+}
+
+/// A request from the HMI.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Post {
+    Command(Command),
+    Enable(Variable, u8),
+    Scan,
+    ReadLabels(Address),
+    On(Box<str>),
+    Off(Box<str>),
+    /// Text to show for a group on switch displays.
+    Display(Group, Box<str>),
+    /// A post for groups on another network, reached via a bridge unit.
+    Bridged(Address, u8, Box<Post>),
+    /// A post for the network of another PCI.
+    Network(Network, Box<Post>),
+}
+
+impl Post {
+    /// The network a post is for and the post itself.
+    pub fn network(self) -> (Network, Post) {
+        match self {
+            Post::Network(n, post) => (n, *post),
+            post => (0, post),
+        }
+    }
+}
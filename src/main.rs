@@ -1,108 +1,487 @@
-use bytes::Bytes;
-use codec::Message;
-use gaffer::gaffer_daemon;
-use server::{server_daemon, Post};
-use std::fmt::Debug;
-use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::broadcast::{self, Receiver, Sender};
-use tokio::time::{sleep, Duration};
+use futures_util::future::select_all;
+use futures_util::future::{BoxFuture, FutureExt};
+use lights::bus::{Bus, Subscriber};
+use lights::busio::IoStats;
+use lights::clock::Clock;
+use lights::command::SharedLayout;
+use lights::config::{Config, LogConfig};
+use lights::control::{control_daemon, Control};
+use lights::corpus::{self, Collector};
+use lights::daemon::{Daemon, Registry};
+use lights::daemonize;
+#[cfg(feature = "gaffer")]
+use lights::gaffer::Gaffer;
+#[cfg(feature = "http")]
+use lights::ha::while_active;
+use lights::ha::{ha_daemon, Role};
+use lights::health::{Health, HealthMonitor};
+use lights::labels::{label_for, LabelReader, Labels};
+#[cfg(feature = "otlp")]
+use lights::otlp::Exporter;
+use lights::reload::{reload_daemon, Reload};
+#[cfg(feature = "report")]
+use lights::report::Reporter;
+use lights::report::Sentry;
+use lights::scan::{Inventory, Scanner};
+use lights::secret::Secret;
+#[cfg(feature = "http")]
+use lights::server::{server_daemon, Shared};
+use lights::session::{cbus_daemon, Link};
+use lights::state::{self, StateKeeper};
+use lights::supervise::{supervise, Backoff, Restarts};
+use lights::systemd::{self, Notifier};
+use lights::time;
+use lights::transport;
+use lights::{Envelope, Event, Network, Outbound};
+use std::future::Future;
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::runtime::Runtime;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast::Sender;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
 use tokio::{select, task};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
 
-mod busio;
-mod codec;
-mod gaffer;
-mod server;
+/// How long to wait for connections to close on shutdown.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
 
-const HOST: &str = "C228F35.gracelands";
-const PORT: u16 = 10001;
+/// The delays before restarting a failed daemon.
+const RESTART_MIN: Duration = Duration::from_secs(1);
+const RESTART_MAX: Duration = Duration::from_secs(60);
 
-/// Something that happened somewhere in the recent past.
-#[derive(Clone, PartialEq, Debug)]
-pub enum Event {
-    Cbus(Message),
-    Hmi(Post),
+async fn log_task(mut channel: Subscriber<Envelope>, labels: Labels, events: bool) {
+    while let Some(Envelope {
+        seq, source, event, ..
+    }) = channel.recv().await
+    {
+        if !events {
+            continue;
+        }
+        let source = format!("{source:?}").to_lowercase();
+        match &event {
+            Event::Cbus(0, mesg) => match label_for(&labels, mesg) {
+                Some(label) => info!(seq, source, network = 0, label, "{mesg}"),
+                None => info!(seq, source, network = 0, "{mesg}"),
+            },
+            // labels are for network 0
+            Event::Cbus(n, mesg) => info!(seq, source, network = n, "{mesg}"),
+            _ => info!(seq, source, "{event:?}"),
+        }
+    }
 }
 
-async fn input_task<I>(input: I, inbound: Sender<Event>) -> io::Result<()>
-where
-    I: AsyncRead + Unpin,
-{
-    async fn accept(line: Bytes, inbound: &Sender<Event>) {
-        let mesg = codec::decode(line);
-        let _ = inbound.send(Event::Cbus(mesg));
+/// Logs events, with group labels where known.
+struct EventLog {
+    labels: Labels,
+    events: bool,
+}
+
+impl Daemon for EventLog {
+    fn name(&self) -> &'static str {
+        "log"
     }
 
-    busio::read_lines(input, |line| accept(line, &inbound)).await
+    fn run(&self, events: Subscriber<Envelope>, _: Sender<Outbound>) -> BoxFuture<'static, ()> {
+        log_task(events, self.labels.clone(), self.events).boxed()
+    }
 }
 
-async fn output_task<O>(mut outbound: Receiver<Message>, mut output: O) -> io::Result<()>
-where
-    O: AsyncWrite + Unpin,
-{
-    loop {
-        if let Ok(mesg) = outbound.recv().await {
-            println!("< {mesg:?}");
-            output.write_all(&codec::encode(mesg)[..]).await?
+/// Wait for SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("no SIGINT handler: {e}");
+            std::future::pending::<()>().await
+        }
+    };
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => drop(term.recv().await),
+            Err(e) => {
+                warn!("no SIGTERM handler: {e}");
+                std::future::pending().await
+            }
         }
+    };
+    select! {
+        _ = interrupt => (),
+        _ = terminate => (),
     }
 }
 
-async fn cbus_session(inbound: Sender<Event>, outbound: Receiver<Message>) -> io::Result<()> {
-    // Connect to a CBUS device
-    let stream = TcpStream::connect((HOST, PORT)).await?;
-    let (input, mut output) = stream.into_split();
-
-    // configure CBUS device
-    output.write_all(&codec::preamble()[..]).await?;
-
-    // run tasks
-    let input_task = task::spawn(input_task(input, inbound));
-    let output_task = task::spawn(output_task(outbound, output));
-    select! {res = input_task => res?, res = output_task => res?}
+/// Spawn a task that holds `done` until it finishes, so that
+/// shutdown can wait for it.
+fn spawn_tracked<F>(done: &mpsc::Sender<()>, task: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let done = done.clone();
+    task::spawn(async move {
+        let res = task.await;
+        drop(done);
+        res
+    })
 }
 
-// maintain a connection to the CBUS
-async fn cbus_daemon(inbound: Sender<Event>, outbound: Sender<Message>) -> io::Result<()> {
-    loop {
-        println!("* connecting to cbus...");
-        let res = cbus_session(inbound.clone(), outbound.subscribe()).await;
-        println!("* cbus disconnect: {res:?}");
-        sleep(Duration::from_millis(2000)).await;
+/// Log to stdout, filtered by `RUST_LOG` or the configured filter.
+fn init_logging(log: &LogConfig) {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::new(log.filter.as_deref().unwrap_or("info")),
+    };
+    // no colours in the journal
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(std::io::stdout().is_terminal());
+    if log.json {
+        subscriber.json().init()
+    } else {
+        subscriber.init()
     }
 }
 
-async fn log_task<T>(mut channel: Receiver<T>)
+/// Run a daemon under supervision, see `supervise`.
+fn supervised<F, T>(
+    name: &'static str,
+    restarts: &Restarts,
+    cancel: &CancellationToken,
+    clock: &Arc<dyn time::Clock>,
+    start: F,
+) -> impl Future<Output = ()>
 where
-    T: Debug + Clone,
+    F: FnMut() -> T,
+    T: Future<Output = lights::Result<()>> + Send + 'static,
 {
-    loop {
-        let res = channel.recv().await;
-        if let Ok(t) = res {
-            println!("> {t:?}")
-        } else {
-            println!("* log_task: {res:?}")
+    let backoff = Backoff::new(RESTART_MIN, RESTART_MAX);
+    let (restarts, cancel, clock) = (restarts.clone(), cancel.clone(), clock.clone());
+    supervise(name, backoff, restarts, cancel, clock, start).instrument(info_span!("daemon", name))
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let unit = args.first().is_some_and(|a| a == "systemd-unit");
+    if unit {
+        args.remove(0);
+    }
+    let config = match Config::from_args(args.clone()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2)
+        }
+    };
+    if unit {
+        // print a unit that runs us with the remaining arguments
+        match std::env::current_exe() {
+            Ok(exe) => print!("{}", systemd::unit_file(&exe, &args)),
+            Err(e) => {
+                eprintln!("cannot locate this executable: {e}");
+                std::process::exit(1)
+            }
+        }
+        return;
+    }
+    let token = match config.token.as_ref().map(Secret::reveal).transpose() {
+        Ok(token) => token.map(Arc::from),
+        Err(e) => {
+            eprintln!("http token: {e}");
+            std::process::exit(2)
+        }
+    };
+    let sentry = config.report.sentry.as_ref().map(|dsn| {
+        let dsn = dsn.reveal()?;
+        Sentry::parse(&dsn)
+    });
+    let sentry = match sentry.transpose() {
+        Ok(sentry) => sentry,
+        Err(e) => {
+            eprintln!("report sentry: {e}");
+            std::process::exit(2)
+        }
+    };
+    let pidfile = config.daemonize.pidfile.clone();
+    if let Some(path) = &pidfile {
+        if let Err(e) = daemonize::check_pidfile(path) {
+            eprintln!("{e}");
+            std::process::exit(1)
+        }
+    }
+    // before any threads are started
+    if config.daemonize.enabled {
+        if let Err(e) = daemonize::detach(config.daemonize.log_file.as_deref()) {
+            eprintln!("cannot run in the background: {e}");
+            std::process::exit(1)
+        }
+    }
+    if let Some(path) = &pidfile {
+        if let Err(e) = daemonize::write_pidfile(path) {
+            eprintln!("cannot write {}: {e}", path.display());
+            std::process::exit(1)
         }
     }
+    init_logging(&config.log);
+
+    let runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("cannot start: {e}");
+            std::process::exit(1)
+        }
+    };
+    let failed = runtime.block_on(run(args, config, token, sentry));
+    drop(runtime);
+    if let Some(path) = &pidfile {
+        let _ = std::fs::remove_file(path);
+    }
+    // so that systemd restarts us
+    if failed {
+        std::process::exit(1)
+    }
 }
 
-#[tokio::main]
-async fn main() {
+/// Run the daemons until a signal or a failure, returning true on failure.
+async fn run(
+    args: Vec<String>,
+    config: Config,
+    token: Option<Arc<str>>,
+    sentry: Option<Sentry>,
+) -> bool {
     // create the internal pub/sub channels
-    let (inbound, _) = broadcast::channel::<Event>(16);
-    let (outbound, _) = broadcast::channel::<Message>(16);
+    let bus = Bus::new(config.channels);
+    let clock = bus.clock().clone();
 
     // create the tasks
-    let cbus_daemon = task::spawn(cbus_daemon(inbound.clone(), outbound.clone()));
-    let gaffer_daemon = task::spawn(gaffer_daemon(inbound.subscribe(), outbound.clone()));
-    let server_daemon = task::spawn(server_daemon(inbound.clone()));
-    let log_task = task::spawn(log_task(inbound.subscribe()));
+    let cancel = CancellationToken::new();
+    let (done, mut drained) = mpsc::channel::<()>(1);
+    let restarts = Restarts::default();
+    let log = config.log.clone();
+    let labels = Labels::new(Mutex::new(config.groups.clone()));
+    let layout = SharedLayout::new(RwLock::new(Arc::new(config.layout.clone())));
+    let networks: Vec<Network> = config.links().map(|(n, _)| n).collect();
+    let levels = match &config.state.file {
+        Some(path) => state::load(path, clock.wall()).unwrap_or_else(|e| {
+            warn!("state not restored: {e}");
+            Default::default()
+        }),
+        None => Default::default(),
+    };
+    let state = config.state.clone();
+    let corpus = match &config.corpus.file {
+        Some(path) => corpus::load(path).unwrap_or_else(|e| {
+            warn!("corpus not restored: {e}");
+            Default::default()
+        }),
+        None => Default::default(),
+    };
+    let corpus_file = config.corpus.file.clone();
+    let health = Health::default();
+    let inventory = Inventory::default();
+    let role = Role::new(config.ha.peer.is_none());
+    #[cfg(feature = "http")]
+    let shared = Shared {
+        inventory: inventory.clone(),
+        labels: labels.clone(),
+        restarts: restarts.clone(),
+        lags: bus.lags.clone(),
+        health: health.clone(),
+        corpus: corpus.clone(),
+        levels: levels.clone(),
+    };
+    #[cfg(feature = "http")]
+    let server_daemon = spawn_tracked(
+        &done,
+        supervised("server", &restarts, &cancel, &clock, {
+            let (inbound, bind, cancel) = (bus.inbound.clone(), config.bind, cancel.clone());
+            let role = role.clone();
+            move || {
+                let (inbound, shared, token) = (inbound.clone(), shared.clone(), token.clone());
+                // only the active instance serves
+                while_active(role.clone(), cancel.clone(), move |stop| {
+                    let (inbound, shared, token) = (inbound.clone(), shared.clone(), token.clone());
+                    server_daemon(inbound, shared, bind, token, stop)
+                })
+            }
+        }),
+    );
+    #[cfg(not(feature = "http"))]
+    let server_daemon = {
+        // nothing to serve
+        drop(token);
+        std::future::pending::<Result<(), task::JoinError>>()
+    };
+
+    // the daemons that react to events
+    let mut registry = Registry::default();
+    #[cfg(feature = "gaffer")]
+    registry.register(Gaffer {
+        layout: layout.clone(),
+        levels: levels.clone(),
+        config: config.gaffer.clone(),
+    });
+    registry.register(LabelReader {
+        labels: labels.clone(),
+        clock: clock.clone(),
+    });
+    registry.register(Scanner {
+        inventory,
+        clock: clock.clone(),
+    });
+    registry.register(Clock {
+        networks: networks.clone(),
+        clock: clock.clone(),
+    });
+    registry.register(HealthMonitor {
+        health: health.clone(),
+        networks: networks.clone(),
+        lags: bus.lags.clone(),
+        config: config.health.clone(),
+        shutdown: cancel.clone(),
+        clock: clock.clone(),
+    });
+    registry.register(Notifier(networks));
+    registry.register(StateKeeper {
+        levels: levels.clone(),
+        config: state.clone(),
+        lags: bus.lags.clone(),
+        clock: clock.clone(),
+    });
+    registry.register(Collector {
+        corpus: corpus.clone(),
+        config: config.corpus.clone(),
+        clock: clock.clone(),
+    });
+    #[cfg(feature = "otlp")]
+    if config.otlp.endpoint.is_some() {
+        registry.register(Exporter {
+            config: config.otlp.clone(),
+            clock: clock.clone(),
+        });
+    }
+    #[cfg(feature = "report")]
+    if config.report.enabled() {
+        if let Some(reporter) = Reporter::install(config.report.clone(), sentry) {
+            registry.register(reporter);
+        }
+    }
+    #[cfg(not(feature = "report"))]
+    drop(sentry);
+    registry.register(EventLog {
+        labels: labels.clone(),
+        events: log.events,
+    });
+    let backoff = Backoff::new(RESTART_MIN, RESTART_MAX);
+    let daemons = registry.spawn(&bus, backoff, &restarts, &cancel);
+
+    // the links, started once the daemons are listening
+    let cbus_daemons = config.links().map(|(network, cbus)| {
+        let daemon = cbus_daemon(
+            network,
+            cbus.clone(),
+            transport::from_config(cbus),
+            bus.clone(),
+            Link {
+                stats: Arc::new(IoStats::default()),
+                health: health.clone(),
+                role: role.clone(),
+            },
+            cancel.clone(),
+        );
+        spawn_tracked(&done, daemon.instrument(info_span!("cbus", network)))
+    });
+    let cbus_daemon = select_all(cbus_daemons);
+    let reload = Reload::default();
+    let control_daemon = match config.control.socket.clone() {
+        Some(path) => {
+            let control = Control {
+                inbound: bus.inbound.clone(),
+                health: health.clone(),
+                reload: reload.clone(),
+            };
+            let daemon = supervised("control", &restarts, &cancel, &clock, {
+                let cancel = cancel.clone();
+                move || control_daemon(path.clone(), control.clone(), cancel.clone())
+            });
+            spawn_tracked(&done, daemon)
+        }
+        None => task::spawn(std::future::pending()),
+    };
+    let ha_daemon = match config.ha.peer {
+        Some(_) => task::spawn(supervised("ha", &restarts, &cancel, &clock, {
+            let (ha, clock, cancel) = (config.ha.clone(), clock.clone(), cancel.clone());
+            move || ha_daemon(ha.clone(), role.clone(), clock.clone(), cancel.clone())
+        })),
+        None => task::spawn(std::future::pending()),
+    };
+    let reload_daemon = task::spawn(supervised(
+        "reload",
+        &restarts,
+        &cancel,
+        &clock,
+        move || {
+            let (labels, layout) = (labels.clone(), layout.clone());
+            reload_daemon(args.clone(), config.clone(), labels, layout, reload.clone()).map(Ok)
+        },
+    ));
 
     // run all the tasks
-    select! {
-        res = cbus_daemon => println!("exit cbus_daemon: {res:?}"),
-        res = gaffer_daemon => println!("exit gaffer_daemon: {res:?}"),
-        res = server_daemon => println!("exit server_daemon: {res:?}"),
-        res = log_task => println!("exit log_task: {res:?}")
+    let failed = select! {
+        _ = shutdown_signal() => {
+            info!("shutting down");
+            false
+        }
+        // a daemon has given up
+        _ = cancel.cancelled() => true,
+        (res, _, _) = cbus_daemon => {
+            error!("exit cbus_daemon: {res:?}");
+            true
+        }
+        res = server_daemon => {
+            error!("exit server_daemon: {res:?}");
+            true
+        }
+        res = control_daemon => {
+            error!("exit control_daemon: {res:?}");
+            true
+        }
+        res = ha_daemon => {
+            error!("exit ha_daemon: {res:?}");
+            true
+        }
+        res = reload_daemon => {
+            error!("exit reload_daemon: {res:?}");
+            true
+        }
+        (name, res) = daemons => {
+            error!("exit {name}: {res:?}");
+            true
+        }
     };
+
+    // flush the outbound queues, close the connections and
+    // finish serving requests, then exit
+    if let Err(e) = systemd::notify("STOPPING=1") {
+        warn!("cannot notify systemd of stopping: {e}");
+    }
+    cancel.cancel();
+    drop(done);
+    if timeout(SHUTDOWN_GRACE, drained.recv()).await.is_err() {
+        warn!("shutdown timed out");
+    }
+    if let Some(path) = &state.file {
+        if let Err(e) = state::save(path, &levels) {
+            warn!("cannot save state to {}: {e}", path.display());
+        }
+    }
+    if let Some(path) = &corpus_file {
+        if let Err(e) = corpus::save(path, &corpus) {
+            warn!("cannot save corpus to {}: {e}", path.display());
+        }
+    }
+    failed
 }
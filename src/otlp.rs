@@ -0,0 +1,414 @@
+//! `otlp` exports telemetry to an OpenTelemetry collector, so the
+//! lights show up alongside other services.
+//!
+//! Every `interval` seconds, counts of events by source, of reconnects
+//! by network and a histogram of command latency are sent as metrics,
+//! and a span for each command as traces, over OTLP/HTTP with JSON
+//! encoding.  Command latency runs from an HMI post to the delivery of
+//! the first message it caused.
+//!
+//! ```toml
+//! [otlp]
+//! endpoint = "http://collector.gracelands:4318"
+//! ```
+use crate::post::Endpoint;
+use serde::Deserialize;
+
+/// Where to send telemetry, from `[otlp]`.
+#[derive(PartialEq, Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// The collector's OTLP/HTTP endpoint, `http://HOST[:PORT][/PATH]`
+    /// or `https://...`, port 4318 unless given, or none to export nothing.
+    pub endpoint: Option<String>,
+    /// Seconds between exports.
+    pub interval: u64,
+    /// The `service.name` of the telemetry.
+    pub service: String,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        OtlpConfig {
+            endpoint: None,
+            interval: 60,
+            service: "lights".into(),
+        }
+    }
+}
+
+/// The collector's endpoint, on port 4318 unless given.
+pub fn parse_endpoint(endpoint: &str) -> Result<Endpoint, String> {
+    let mut endpoint = Endpoint::parse(endpoint)?;
+    endpoint.port.get_or_insert(4318);
+    Ok(endpoint)
+}
+
+#[cfg(feature = "otlp")]
+pub use export::{otlp_daemon, Exporter};
+
+#[cfg(feature = "otlp")]
+mod export {
+    use super::{parse_endpoint, OtlpConfig};
+    use crate::bus::Subscriber;
+    use crate::codec::Outcome;
+    use crate::daemon::Daemon;
+    use crate::post::post_json;
+    use crate::time::{since_epoch, Clock};
+    use crate::{Envelope, Event, Network, Outbound, Source};
+    use futures_util::future::{BoxFuture, FutureExt};
+    use serde_json::{json, Value};
+    use std::collections::hash_map::RandomState;
+    use std::collections::{BTreeMap, VecDeque};
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::Arc;
+    use std::time::SystemTime;
+    use tokio::select;
+    use tokio::sync::broadcast::Sender;
+    use tokio::time::{Duration, Instant};
+    use tracing::warn;
+
+    /// How long after an HMI post a delivery is taken to be for it.
+    const LATENCY_WINDOW: Duration = Duration::from_secs(10);
+
+    /// The most spans kept between exports; more are dropped.
+    const MAX_SPANS: usize = 1000;
+
+    /// Bucket bounds for command latency in milliseconds.
+    const LATENCY_BOUNDS: [f64; 10] = [
+        10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+    ];
+
+    /// A command from the HMI, from its post to its delivery.
+    #[derive(PartialEq, Debug, Clone)]
+    struct Span {
+        post: String,
+        start: SystemTime,
+        end: SystemTime,
+        outcome: Outcome,
+    }
+
+    /// Measurements since startup, and the spans since the last export.
+    #[derive(Debug)]
+    struct Telemetry {
+        start: SystemTime,
+        events: BTreeMap<&'static str, u64>,
+        connections: BTreeMap<Network, u64>,
+        latency: Vec<u64>,
+        latency_sum: f64,
+        posted: VecDeque<(Instant, SystemTime, String)>,
+        spans: Vec<Span>,
+    }
+
+    impl Telemetry {
+        fn new(start: SystemTime) -> Self {
+            Telemetry {
+                start,
+                events: BTreeMap::new(),
+                connections: BTreeMap::new(),
+                latency: vec![0; LATENCY_BOUNDS.len() + 1],
+                latency_sum: 0.0,
+                posted: VecDeque::new(),
+                spans: Vec::new(),
+            }
+        }
+
+        fn observe(&mut self, envelope: &Envelope) {
+            let source = match envelope.source {
+                Source::Cbus => "cbus",
+                Source::Hmi => "hmi",
+                Source::Control => "control",
+            };
+            *self.events.entry(source).or_default() += 1;
+            match &envelope.event {
+                Event::Connected(network) => *self.connections.entry(*network).or_default() += 1,
+                Event::Hmi(post) => {
+                    let post = format!("{post:?}");
+                    self.posted.push_back((envelope.at, envelope.time, post));
+                }
+                Event::Delivery(_, _, outcome) => {
+                    while let Some((at, ..)) = self.posted.front() {
+                        if envelope.at.saturating_duration_since(*at) <= LATENCY_WINDOW {
+                            break;
+                        }
+                        self.posted.pop_front();
+                    }
+                    if let Some((at, start, post)) = self.posted.pop_front() {
+                        let ms = (envelope.at - at).as_secs_f64() * 1000.0;
+                        let bucket = LATENCY_BOUNDS.iter().take_while(|b| ms > **b).count();
+                        self.latency[bucket] += 1;
+                        self.latency_sum += ms;
+                        if self.spans.len() < MAX_SPANS {
+                            self.spans.push(Span {
+                                post,
+                                start,
+                                end: envelope.time,
+                                outcome: *outcome,
+                            });
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        /// Reconnects by network: the connections after the first.
+        fn reconnects(&self) -> impl Iterator<Item = (Network, u64)> + '_ {
+            self.connections
+                .iter()
+                .map(|(n, count)| (*n, count.saturating_sub(1)))
+        }
+    }
+
+    fn nanos(time: SystemTime) -> String {
+        since_epoch(time).as_nanos().to_string()
+    }
+
+    fn attribute(key: &str, value: impl std::fmt::Display) -> Value {
+        json!({ "key": key, "value": { "stringValue": value.to_string() } })
+    }
+
+    fn resource(service: &str) -> Value {
+        json!({ "attributes": [attribute("service.name", service)] })
+    }
+
+    fn random_hex(words: usize) -> String {
+        (0..words)
+            .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+            .collect()
+    }
+
+    fn counter(name: &str, description: &str, points: Vec<Value>) -> Value {
+        json!({
+            "name": name,
+            "description": description,
+            "sum": {
+                "aggregationTemporality": 2,
+                "isMonotonic": true,
+                "dataPoints": points,
+            },
+        })
+    }
+
+    /// An OTLP metrics request for `telemetry` at `now`.
+    fn metrics(telemetry: &Telemetry, service: &str, now: SystemTime) -> Value {
+        let (start, now) = (nanos(telemetry.start), nanos(now));
+        let point = |key: &str, value: String, count: u64| {
+            json!({
+                "attributes": [attribute(key, value)],
+                "startTimeUnixNano": start,
+                "timeUnixNano": now,
+                "asInt": count.to_string(),
+            })
+        };
+        let events = telemetry
+            .events
+            .iter()
+            .map(|(source, count)| point("source", source.to_string(), *count))
+            .collect();
+        let reconnects = telemetry
+            .reconnects()
+            .map(|(network, count)| point("network", network.to_string(), count))
+            .collect();
+        let count: u64 = telemetry.latency.iter().sum();
+        let latency = json!({
+            "name": "lights.command.latency",
+            "description": "From an HMI post to the delivery of its first message",
+            "unit": "ms",
+            "histogram": {
+                "aggregationTemporality": 2,
+                "dataPoints": [{
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": now,
+                    "count": count.to_string(),
+                    "sum": telemetry.latency_sum,
+                    "bucketCounts": telemetry.latency.iter().map(u64::to_string).collect::<Vec<_>>(),
+                    "explicitBounds": LATENCY_BOUNDS,
+                }],
+            },
+        });
+        json!({
+            "resourceMetrics": [{
+                "resource": resource(service),
+                "scopeMetrics": [{
+                    "scope": { "name": "lights" },
+                    "metrics": [
+                        counter("lights.events", "Events published, by source", events),
+                        counter("lights.reconnects", "Reconnects to the PCI, by network", reconnects),
+                        latency,
+                    ],
+                }],
+            }],
+        })
+    }
+
+    /// An OTLP traces request for `spans`, each in a trace of its own.
+    fn traces(spans: &[Span], service: &str) -> Value {
+        let spans: Vec<Value> = spans
+            .iter()
+            .map(|span| {
+                let failed = span.outcome == Outcome::Failed;
+                json!({
+                    "traceId": random_hex(2),
+                    "spanId": random_hex(1),
+                    "name": "command",
+                    "kind": 2,
+                    "startTimeUnixNano": nanos(span.start),
+                    "endTimeUnixNano": nanos(span.end),
+                    "attributes": [attribute("lights.post", &span.post)],
+                    "status": { "code": if failed { 2 } else { 1 } },
+                })
+            })
+            .collect();
+        json!({
+            "resourceSpans": [{
+                "resource": resource(service),
+                "scopeSpans": [{ "scope": { "name": "lights" }, "spans": spans }],
+            }],
+        })
+    }
+
+    /// Send the telemetry, logging rather than failing if it cannot.
+    async fn export(config: &OtlpConfig, telemetry: &mut Telemetry, now: SystemTime) {
+        let Some(url) = &config.endpoint else {
+            return;
+        };
+        let endpoint = match parse_endpoint(url) {
+            Ok(endpoint) => endpoint,
+            Err(e) => return warn!("{e}"),
+        };
+        let mut requests = vec![("/v1/metrics", metrics(telemetry, &config.service, now))];
+        let spans = std::mem::take(&mut telemetry.spans);
+        if !spans.is_empty() {
+            requests.push(("/v1/traces", traces(&spans, &config.service)));
+        }
+        for (path, body) in requests {
+            if let Err(e) = post_json(&endpoint, path, &[], &body.to_string()).await {
+                warn!("otlp export to {url}: {e}");
+            }
+        }
+    }
+
+    /// Measure the events heard and export them every interval, timed
+    /// by `clock`.
+    pub async fn otlp_daemon(
+        mut events: Subscriber<Envelope>,
+        config: OtlpConfig,
+        clock: Arc<dyn Clock>,
+    ) {
+        let mut telemetry = Telemetry::new(clock.wall());
+        let period = Duration::from_secs(config.interval);
+        let mut due = clock.now() + period;
+        loop {
+            select! {
+                _ = clock.sleep_until(due) => {
+                    due += period;
+                    export(&config, &mut telemetry, clock.wall()).await;
+                }
+                res = events.recv() => match res {
+                    Some(envelope) => telemetry.observe(&envelope),
+                    None => return,
+                }
+            }
+        }
+    }
+
+    /// The exporter as a pluggable daemon.
+    pub struct Exporter {
+        pub config: OtlpConfig,
+        pub clock: Arc<dyn Clock>,
+    }
+
+    impl Daemon for Exporter {
+        fn name(&self) -> &'static str {
+            "otlp"
+        }
+
+        fn run(&self, events: Subscriber<Envelope>, _: Sender<Outbound>) -> BoxFuture<'static, ()> {
+            otlp_daemon(events, self.config.clone(), self.clock.clone()).boxed()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::codec::Message;
+        use crate::Post;
+
+        fn envelope(seq: u64, at: Instant, source: Source, event: Event) -> Envelope {
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(seq);
+            Envelope {
+                seq,
+                source,
+                at,
+                time,
+                event,
+            }
+        }
+
+        #[test]
+        fn measured() {
+            let mut telemetry = Telemetry::new(SystemTime::UNIX_EPOCH);
+            let now = Instant::now();
+            let post = Event::Hmi(Post::Scan);
+            let delivered = Event::Delivery(0, Message::Reset, Outcome::Delivered);
+            let events = [
+                (now, Source::Cbus, Event::Connected(0)),
+                (now, Source::Cbus, Event::Connected(0)),
+                // a post that caused nothing, then one delivered in 120ms
+                (now, Source::Hmi, post.clone()),
+                (now + Duration::from_secs(20), Source::Hmi, post),
+                (now + Duration::from_millis(20120), Source::Cbus, delivered),
+            ];
+            for (seq, (at, source, event)) in events.into_iter().enumerate() {
+                telemetry.observe(&envelope(seq as u64, at, source, event));
+            }
+            assert_eq!(telemetry.events["cbus"], 3);
+            assert_eq!(telemetry.events["hmi"], 2);
+            assert_eq!(telemetry.reconnects().collect::<Vec<_>>(), [(0, 1)]);
+            assert_eq!(telemetry.latency[4], 1);
+            assert!((telemetry.latency_sum - 120.0).abs() < 1.0);
+            assert_eq!(telemetry.spans.len(), 1);
+            assert_eq!(telemetry.spans[0].outcome, Outcome::Delivered);
+            assert!(telemetry.posted.is_empty());
+        }
+
+        #[test]
+        fn encoded() {
+            let mut telemetry = Telemetry::new(SystemTime::UNIX_EPOCH);
+            let at = Instant::now();
+            telemetry.observe(&envelope(1, at, Source::Cbus, Event::Connected(0)));
+            let now = SystemTime::UNIX_EPOCH + Duration::from_secs(60);
+            let body = metrics(&telemetry, "lights", now);
+            let metrics = &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+            assert_eq!(metrics[0]["name"], "lights.events");
+            let point = &metrics[0]["sum"]["dataPoints"][0];
+            assert_eq!(point["asInt"], "1");
+            assert_eq!(point["timeUnixNano"], "60000000000");
+            assert_eq!(metrics[1]["sum"]["dataPoints"][0]["asInt"], "0");
+
+            let span = Span {
+                post: "Scan".into(),
+                start: SystemTime::UNIX_EPOCH,
+                end: now,
+                outcome: Outcome::Failed,
+            };
+            let body = traces(&[span], "lights");
+            let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+            assert_eq!(span["traceId"].as_str().unwrap().len(), 32);
+            assert_eq!(span["spanId"].as_str().unwrap().len(), 16);
+            assert_eq!(span["status"]["code"], 2);
+        }
+
+        #[test]
+        fn endpoints() {
+            let parse = parse_endpoint;
+            let endpoint = parse("http://collector:4318").unwrap();
+            assert_eq!(endpoint.to_string(), "http://collector:4318");
+            let endpoint = parse("http://[::1]/otlp/").unwrap();
+            assert_eq!(endpoint.to_string(), "http://[::1]:4318/otlp");
+            assert!(parse("ftp://collector").is_err());
+            assert!(parse("http://:4318").is_err());
+        }
+    }
+}
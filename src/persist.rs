@@ -0,0 +1,27 @@
+//! `persist` reads and writes the TOML files that carry the daemons'
+//! findings across restarts, such as the group levels and the corpus.
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Read what was saved at `path`, or the default if there is no file.
+pub fn load<T: DeserializeOwned + Default>(path: &Path) -> Result<T, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(format!("{}: {e}", path.display())),
+    };
+    toml::from_str(&text).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+/// Save `value` to `path`, replacing the file whole so that a crash
+/// cannot leave it half written.
+pub fn save<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let text = toml::to_string(value).map_err(io::Error::other)?;
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".new");
+    fs::write(&temp, text)?;
+    fs::rename(&temp, path)
+}
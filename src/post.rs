@@ -0,0 +1,172 @@
+//! `post` sends JSON to an HTTP endpoint, such as a telemetry collector
+//! or a webhook.
+//!
+//! An `https://` endpoint is verified against the system's certificate
+//! authorities.
+use crate::tunnel::{self, TlsConfig};
+use std::fmt;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::time::{timeout, Duration};
+
+/// The certificate authorities trusted for `https://` endpoints.
+const SYSTEM_CA: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+/// How long an endpoint has to take a post.
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to post, from a URL.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Endpoint {
+    pub tls: bool,
+    pub host: String,
+    /// Otherwise 80, or 443 with TLS.
+    pub port: Option<u16>,
+    /// Without a trailing slash.
+    pub path: String,
+}
+
+impl Endpoint {
+    /// Parse `http://HOST[:PORT][/PATH]` or the same with `https://`.
+    pub fn parse(url: &str) -> Result<Endpoint, String> {
+        let (tls, rest) = match url.split_once("://") {
+            Some(("http", rest)) => (false, rest),
+            Some(("https", rest)) => (true, rest),
+            _ => return Err(format!("{url}: not an http:// or https:// url")),
+        };
+        if tls && !cfg!(feature = "tls") {
+            return Err(format!("{url}: this build has no tls"));
+        }
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.ends_with(']') => {
+                let port = port.parse().map_err(|e| format!("{url}: {e}"))?;
+                (host, Some(port))
+            }
+            _ => (authority, None),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("{url}: no host"));
+        }
+        Ok(Endpoint {
+            tls,
+            host: host.into(),
+            port,
+            path: path.trim_end_matches('/').into(),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(if self.tls { 443 } else { 80 })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        write!(f, "{scheme}://{host}:{}{}", self.port(), self.path)
+    }
+}
+
+/// POST `body` as JSON to `path` under `endpoint` with extra `headers`,
+/// failing unless it is accepted.
+pub async fn post_json(
+    endpoint: &Endpoint,
+    path: &str,
+    headers: &[(&str, String)],
+    body: &str,
+) -> io::Result<()> {
+    match timeout(POST_TIMEOUT, post(endpoint, path, headers, body)).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+    }
+}
+
+async fn post(
+    endpoint: &Endpoint,
+    path: &str,
+    headers: &[(&str, String)],
+    body: &str,
+) -> io::Result<()> {
+    let (host, port) = (&endpoint.host, endpoint.port());
+    let tls = endpoint.tls.then(|| TlsConfig {
+        ca: SYSTEM_CA.into(),
+        server_name: None,
+        cert: None,
+        key: None,
+    });
+    let mut stream = tunnel::connect(host, None, port, None, None, tls.as_ref()).await?;
+    let path = format!("{}{path}", endpoint.path);
+    let mut head = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}:{port}\r\n\
+         Content-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status).await?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("{path}: {}", status.trim_end()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parsed() {
+        let endpoint = Endpoint::parse("http://collector:4318").unwrap();
+        assert_eq!((endpoint.tls, endpoint.port), (false, Some(4318)));
+        assert_eq!(endpoint.to_string(), "http://collector:4318");
+        let endpoint = Endpoint::parse("http://[::1]/otlp/").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port()), ("::1", 80));
+        assert_eq!(endpoint.to_string(), "http://[::1]:80/otlp");
+        assert!(Endpoint::parse("ftp://collector").is_err());
+        assert!(Endpoint::parse("http://:4318").is_err());
+        assert!(Endpoint::parse("collector").is_err());
+    }
+
+    #[tokio::test]
+    async fn posted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 1024];
+            let mut len = 0;
+            while !request[..len].ends_with(b"{}") {
+                len += stream.read(&mut request[len..]).await.unwrap();
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request[..len].to_vec()).unwrap()
+        });
+        let endpoint = Endpoint::parse(&format!("http://127.0.0.1:{port}/hook")).unwrap();
+        let headers = [("X-Key", "k".to_string())];
+        post_json(&endpoint, "/v1", &headers, "{}").await.unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hook/v1 HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nX-Key: k\r\n"));
+        assert!(request.contains("\r\nContent-Length: 2\r\n"));
+    }
+}
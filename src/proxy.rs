@@ -0,0 +1,216 @@
+//! `proxy` reaches the CNI through a SOCKS5 or HTTP CONNECT proxy, such
+//! as `ssh -D` on a box in the house or a proxy on its router.
+//!
+//! The proxy looks up the CNI's host name, so a name only known inside
+//! the house network works.
+//!
+//! ```toml
+//! [cbus]
+//! host = "cni.gracelands"
+//! proxy = { protocol = "socks5", host = "vps.example.net", port = 1080 }
+//! ```
+use crate::tunnel::connect_tcp;
+use serde::Deserialize;
+use std::net::IpAddr;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The longest response header accepted from an HTTP proxy.
+const MAX_HEADER: usize = 8192;
+
+/// How to talk to a proxy.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Socks5,
+    Http,
+}
+
+/// A proxy that can reach the CNI, from `[cbus] proxy`.
+#[derive(PartialEq, Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub protocol: Protocol,
+    pub host: String,
+    /// Otherwise 1080 for SOCKS5 and 8080 for HTTP.
+    pub port: Option<u16>,
+}
+
+impl ProxyConfig {
+    fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.protocol {
+            Protocol::Socks5 => 1080,
+            Protocol::Http => 8080,
+        })
+    }
+}
+
+/// Connect to `host` and `port` through the proxy.
+pub async fn open(config: &ProxyConfig, host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut stream = connect_tcp(&config.host, config.port()).await?;
+    let res = match config.protocol {
+        Protocol::Socks5 => socks5(&mut stream, host, port).await,
+        Protocol::Http => http_connect(&mut stream, host, port).await,
+    };
+    res.map_err(|e| io::Error::new(e.kind(), format!("proxy {}: {e}", config.host)))?;
+    Ok(stream)
+}
+
+fn refused(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, reason.into())
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Ask a SOCKS5 proxy (RFC 1928), without authentication, to connect
+/// `stream` to `host` and `port`.
+async fn socks5<S>(stream: &mut S, host: &str, port: u16) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    match choice {
+        [5, 0] => (),
+        [5, _] => return Err(refused("socks5 proxy requires authentication")),
+        _ => return Err(invalid("not a socks5 proxy")),
+    }
+
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend(ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend(ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len()).map_err(|_| invalid("host name too long"))?;
+            request.extend([3, len]);
+            request.extend(host.as_bytes());
+        }
+    }
+    request.extend(port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        return Err(invalid("not a socks5 reply"));
+    }
+    if reply[1] != 0 {
+        let reason = match reply[1] {
+            2 => "not allowed by ruleset",
+            3 => "network unreachable",
+            4 => "host unreachable",
+            5 => "connection refused",
+            6 => "TTL expired",
+            _ => "general failure",
+        };
+        return Err(refused(format!("socks5 proxy: {reason}")));
+    }
+    // skip the address the proxy bound, then its port
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err(invalid("bad socks5 address type")),
+    };
+    let mut bound = vec![0u8; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// Ask an HTTP proxy to connect `stream` to `host` and `port` with the
+/// CONNECT method.
+async fn http_connect<S>(stream: &mut S, host: &str, port: u16) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{ip}]:{port}"),
+        _ => format!("{host}:{port}"),
+    };
+    let request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte so as not to consume what follows the header
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() == MAX_HEADER {
+            return Err(invalid("http proxy response too long"));
+        }
+        header.push(stream.read_u8().await?);
+    }
+    let header = String::from_utf8_lossy(&header);
+    let status = header.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(()),
+        Some(_) => Err(refused(format!("http proxy: {status}"))),
+        None => Err(invalid("not an http proxy")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn socks5_connected() {
+        let (mut client, mut proxy) = io::duplex(256);
+        let server = tokio::spawn(async move {
+            let mut hello = [0u8; 3];
+            proxy.read_exact(&mut hello).await.unwrap();
+            assert_eq!(hello, [5, 1, 0]);
+            proxy.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0u8; 4 + 1 + 3 + 2];
+            proxy.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, *b"\x05\x01\x00\x03\x03cni\x27\x11");
+            proxy
+                .write_all(&[5, 0, 0, 1, 10, 0, 0, 1, 0x9c, 0x40])
+                .await
+                .unwrap();
+            proxy.write_all(b"after").await.unwrap();
+        });
+        socks5(&mut client, "cni", 10001).await.unwrap();
+        let mut after = [0u8; 5];
+        client.read_exact(&mut after).await.unwrap();
+        assert_eq!(&after, b"after");
+        server.await.unwrap();
+
+        let (mut client, mut proxy) = io::duplex(256);
+        proxy.write_all(&[5, 0, 5, 5, 0, 1]).await.unwrap();
+        let e = socks5(&mut client, "10.0.0.5", 10001).await.unwrap_err();
+        assert_eq!(e.to_string(), "socks5 proxy: connection refused");
+    }
+
+    #[tokio::test]
+    async fn http_connected() {
+        let (mut client, mut proxy) = io::duplex(256);
+        proxy
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nafter")
+            .await
+            .unwrap();
+        http_connect(&mut client, "::1", 10001).await.unwrap();
+        let expected = b"CONNECT [::1]:10001 HTTP/1.1\r\nHost: [::1]:10001\r\n\r\n";
+        let mut request = vec![0u8; expected.len()];
+        proxy.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, expected);
+        let mut after = [0u8; 5];
+        client.read_exact(&mut after).await.unwrap();
+        assert_eq!(&after, b"after");
+
+        let (mut client, mut proxy) = io::duplex(256);
+        proxy
+            .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+            .await
+            .unwrap();
+        let e = http_connect(&mut client, "cni", 10001).await.unwrap_err();
+        assert_eq!(e.to_string(), "http proxy: HTTP/1.1 403 Forbidden");
+    }
+}
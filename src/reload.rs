@@ -0,0 +1,149 @@
+//! `reload` re-reads the configuration on SIGHUP.
+//!
+//! Group names, rooms and scenes take effect at once.  Other settings are only read at
+//! startup, so a change to them is reported rather than applied, leaving
+//! the CBUS connections and the HTTP server undisturbed.
+//! A reload can also be requested through a [`Reload`].
+use crate::command::SharedLayout;
+use crate::config::Config;
+use crate::labels::{rename_groups, Labels};
+use std::sync::Arc;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// Requests to reload, as if by SIGHUP.
+pub type Reload = Arc<Notify>;
+
+/// The settings in `new` that differ from `old` but need a restart.
+fn needs_restart(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut sections = Vec::new();
+    if old.cbus != new.cbus || old.networks != new.networks {
+        sections.push("cbus");
+    }
+    if old.bind != new.bind || old.token != new.token {
+        sections.push("http");
+    }
+    if old.channels != new.channels {
+        sections.push("channels");
+    }
+    if old.log != new.log {
+        sections.push("log");
+    }
+    if old.state != new.state {
+        sections.push("state");
+    }
+    if old.corpus != new.corpus {
+        sections.push("corpus");
+    }
+    if old.health != new.health {
+        sections.push("health");
+    }
+    if old.control != new.control {
+        sections.push("control");
+    }
+    if old.daemonize != new.daemonize {
+        sections.push("daemonize");
+    }
+    if old.ha != new.ha {
+        sections.push("ha");
+    }
+    if old.otlp != new.otlp {
+        sections.push("otlp");
+    }
+    if old.report != new.report {
+        sections.push("report");
+    }
+    if old.gaffer != new.gaffer {
+        sections.push("gaffer");
+    }
+    sections
+}
+
+/// Apply the changes from `old` to `new` that can be made while running.
+pub fn apply(old: &Config, new: &Config, labels: &Labels, layout: &SharedLayout) {
+    rename_groups(labels, &old.groups, &new.groups);
+    if old.layout != new.layout {
+        *layout.write().unwrap() = Arc::new(new.layout.clone());
+    }
+    let sections = needs_restart(old, new);
+    if !sections.is_empty() {
+        warn!("restart to apply changes to {}", sections.join(", "));
+    }
+}
+
+/// On each SIGHUP or request, load the configuration again from `args`,
+/// the command line that produced `config`, and apply it.
+pub async fn reload_daemon(
+    args: Vec<String>,
+    mut config: Config,
+    labels: Labels,
+    layout: SharedLayout,
+    requests: Reload,
+) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => Some(hangups),
+        Err(e) => {
+            warn!("no SIGHUP handler: {e}");
+            None
+        }
+    };
+    loop {
+        let hangup = async {
+            match hangups.as_mut() {
+                Some(hangups) => hangups.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        select! {
+            res = hangup => if res.is_none() {
+                return;
+            },
+            _ = requests.notified() => (),
+        }
+        match Config::from_args(args.clone()) {
+            Ok(new) => {
+                apply(&config, &new, &labels, &layout);
+                info!("configuration reloaded");
+                config = new;
+            }
+            Err(e) => warn!("configuration not reloaded: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn groups_applied() {
+        let old = Config::default();
+        let labels = Labels::new(Mutex::new(old.groups.clone()));
+        let layout = SharedLayout::default();
+        let new = Config::from_toml("[groups]\n4 = \"Kitchen\"\n").unwrap();
+        apply(&old, &new, &labels, &layout);
+        assert_eq!(labels.lock().unwrap()[&4], "Kitchen");
+        assert!(needs_restart(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn layout_applied() {
+        let old = Config::default();
+        let labels = Labels::new(Mutex::new(old.groups.clone()));
+        let layout = SharedLayout::default();
+        let new = Config::from_toml("[rooms]\nkitchen = [4, 5]\n").unwrap();
+        apply(&old, &new, &labels, &layout);
+        assert_eq!(**layout.read().unwrap(), new.layout);
+        assert!(needs_restart(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn restart_needed() {
+        let old = Config::default();
+        let new = Config::from_toml("[http]\nbind = \"0.0.0.0:80\"\n[channels]\ninbound = 64\n");
+        assert_eq!(needs_restart(&old, &new.unwrap()), ["http", "channels"]);
+    }
+}
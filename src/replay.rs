@@ -0,0 +1,186 @@
+//! `replay` plays back traffic recorded by the wire tap.
+//!
+//! The lines the PCI sent, marked `>` in the recording, are read again
+//! with their original spacing, divided by `speed`.  What we send is
+//! discarded.  A recording should begin when a connection was made so
+//! that it includes the PCI's replies to the handshake.
+use crate::tap::TIMESTAMP;
+use bytes::{Buf, Bytes};
+use chrono::NaiveDateTime;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::fs;
+use tokio::io::{self, AsyncRead, ReadBuf};
+use tokio::time::{sleep_until, Duration, Instant, Sleep};
+use tracing::{info, warn};
+
+/// One line of a recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub at: NaiveDateTime,
+    /// `>` for inbound or `<` for outbound.
+    pub direction: char,
+    pub line: Vec<u8>,
+}
+
+/// Parse a line written by the wire tap.
+pub fn parse_record(text: &str) -> Option<Record> {
+    let (date, rest) = text.split_once(' ')?;
+    let at = NaiveDateTime::parse_from_str(date, TIMESTAMP).ok()?;
+    let mut chars = rest.chars();
+    let direction = chars.next().filter(|d| *d == '>' || *d == '<')?;
+    let line = unescape(chars.as_str().strip_prefix(' ')?)?;
+    Some(Record {
+        at,
+        direction,
+        line,
+    })
+}
+
+/// Undo `escape_ascii`.
+fn unescape(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = text.bytes();
+    let mut line = Vec::new();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            line.push(b);
+            continue;
+        }
+        let b = match bytes.next()? {
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'n' => b'\n',
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            b => b,
+        };
+        line.push(b);
+    }
+    Some(line)
+}
+
+/// The inbound side of a recorded connection.
+pub struct Replay {
+    /// Inbound lines and their time since the recording began.
+    lines: VecDeque<(Duration, Bytes)>,
+    start: Instant,
+    speed: f64,
+    timer: Pin<Box<Sleep>>,
+    current: Bytes,
+}
+
+impl Replay {
+    /// Replay `records` from now, `speed` times faster than they happened.
+    pub fn new(records: Vec<Record>, speed: f64) -> Replay {
+        let first = records.first().map(|r| r.at);
+        let lines = records
+            .into_iter()
+            .filter(|r| r.direction == '>')
+            .map(|r| {
+                // the clock may have been set back while recording
+                let offset = (r.at - first.unwrap_or(r.at)).to_std().unwrap_or_default();
+                let line = [&r.line[..], b"\r\n"].concat();
+                (offset, Bytes::from(line))
+            })
+            .collect();
+        let start = Instant::now();
+        Replay {
+            lines,
+            start,
+            speed,
+            timer: Box::pin(sleep_until(start)),
+            current: Bytes::new(),
+        }
+    }
+
+    /// Replay the recording in the file at `path`.
+    pub async fn open(path: &Path, speed: f64) -> io::Result<Replay> {
+        let text = fs::read(path).await?;
+        let mut records = Vec::new();
+        for line in String::from_utf8_lossy(&text).lines() {
+            match parse_record(line) {
+                Some(record) => records.push(record),
+                None => warn!("skipping {line:?} in {path:?}"),
+            }
+        }
+        info!("replaying {} records from {path:?}", records.len());
+        Ok(Replay::new(records, speed))
+    }
+}
+
+impl AsyncRead for Replay {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.current.is_empty() {
+            let Some((offset, _)) = this.lines.front() else {
+                // the recording is over, but the link stays up
+                return Poll::Pending;
+            };
+            let due = this.start + offset.div_f64(this.speed);
+            if Instant::now() < due {
+                this.timer.as_mut().reset(due);
+                ready!(this.timer.as_mut().poll(cx));
+            }
+            if let Some((_, line)) = this.lines.pop_front() {
+                this.current = line;
+            }
+            if this.lines.is_empty() {
+                info!("replay finished");
+            }
+        }
+        let n = this.current.len().min(buf.remaining());
+        buf.put_slice(&this.current[..n]);
+        this.current.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn parses_tap_records() {
+        let record = parse_record("2022-03-01T10:00:00.250 > \\x05g.").unwrap();
+        assert_eq!(record.direction, '>');
+        assert_eq!(record.line, b"\x05g.");
+        assert_eq!(
+            record.at.format(TIMESTAMP).to_string(),
+            "2022-03-01T10:00:00.250"
+        );
+        assert!(parse_record("2022-03-01T10:00:00.250 ? x").is_none());
+        assert!(parse_record("nonsense").is_none());
+    }
+
+    #[test]
+    fn unescapes() {
+        let raw = b"a\\b\"c'\t\xff\x05";
+        let text = raw.escape_ascii().to_string();
+        assert_eq!(unescape(&text).unwrap(), raw);
+        assert!(unescape("\\x0").is_none());
+    }
+
+    #[tokio::test]
+    async fn replays_inbound_in_order() {
+        let text = "2022-03-01T10:00:00.000 < ~\n\
+                    2022-03-01T10:00:00.100 > =\n\
+                    2022-03-01T10:00:01.000 > 86FF00\n";
+        let records = text.lines().filter_map(parse_record).collect();
+        let mut replay = Replay::new(records, 100.0);
+        let started = Instant::now();
+        let mut buf = vec![0; 11];
+        replay.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"=\r\n86FF00\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(10));
+    }
+}
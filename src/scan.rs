@@ -0,0 +1,193 @@
+//! `scan` discovers the units on the network and keeps an inventory of them.
+//!
+use crate::bus::Subscriber;
+use crate::codec::{Address, Message, Priority, APPLICATION1, FIRMWARE_VERSION, UNIT_TYPE};
+use crate::daemon::Daemon;
+use crate::time::Clock;
+use crate::{Envelope, Event, Network, Outbound, Post};
+use futures_util::future::{BoxFuture, FutureExt};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use tokio::select;
+use tokio::sync::broadcast::Sender;
+use tokio::time::Duration;
+use tracing::{info, warn};
+
+/// Interval between probing successive unit addresses.
+const SCAN_PACE: Duration = Duration::from_millis(200);
+
+/// What is known about a unit from its replies.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct UnitInfo {
+    pub unit_type: Option<String>,
+    pub firmware: Option<String>,
+    pub application: Option<u8>,
+}
+
+/// Units discovered so far on the network last scanned, by address.
+pub type Inventory = Arc<Mutex<BTreeMap<u8, UnitInfo>>>;
+
+/// The scanner as a pluggable daemon.
+pub struct Scanner {
+    pub inventory: Inventory,
+    /// Paces the probes.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Daemon for Scanner {
+    fn name(&self) -> &'static str {
+        "scan"
+    }
+
+    fn run(
+        &self,
+        events: Subscriber<Envelope>,
+        outbound: Sender<Outbound>,
+    ) -> BoxFuture<'static, ()> {
+        scan_daemon(events, outbound, self.inventory.clone(), self.clock.clone()).boxed()
+    }
+}
+
+/// Scan the network when requested by the HMI, paced by `clock`.
+///
+/// Each address in turn is asked for its unit type, firmware version
+/// and application. Replies to those requests are recorded in the
+/// inventory whenever they arrive, so late replies are not lost, while
+/// other replies, such as labels, are not.  Scanning another network
+/// starts a fresh inventory.
+pub async fn scan_daemon(
+    mut inbound: Subscriber<Envelope>,
+    outbound: Sender<Outbound>,
+    inventory: Inventory,
+    clock: Arc<dyn Clock>,
+) {
+    let mut due = clock.now();
+    let mut next: Option<u8> = None;
+    let mut network: Network = 0;
+    // the unit addresses and parameters asked for but not yet heard
+    let mut pending: BTreeSet<(u8, u8)> = BTreeSet::new();
+    loop {
+        select! {
+            _ = clock.sleep_until(due), if next.is_some() => {
+                due += SCAN_PACE;
+                if let Some(addr) = next {
+                    probe(network, addr, &outbound, &mut pending);
+                    next = addr.checked_add(1);
+                    if next.is_none() {
+                        info!(network, "scan: complete");
+                    }
+                }
+            }
+            res = inbound.event() => match res {
+                Some(Event::Cbus(n, Message::Reply(Address(addr), param, value))) if n == network => {
+                    if pending.remove(&(addr, param)) {
+                        let mut units = inventory.lock().unwrap();
+                        record(units.entry(addr).or_default(), param, &value);
+                    }
+                }
+                Some(Event::Hmi(post)) => if let (n, Post::Scan) = post.network() {
+                    info!(network = n, "scan: starting");
+                    if n != network {
+                        inventory.lock().unwrap().clear();
+                        pending.clear();
+                        network = n;
+                    }
+                    next = Some(0);
+                    due = clock.now();
+                }
+                Some(_) => (),
+                None => return,
+            }
+        }
+    }
+}
+
+fn probe(
+    network: Network,
+    addr: u8,
+    outbound: &Sender<Outbound>,
+    pending: &mut BTreeSet<(u8, u8)>,
+) {
+    let requests = [
+        Message::Identify(Address(addr), UNIT_TYPE),
+        Message::Identify(Address(addr), FIRMWARE_VERSION),
+        Message::Recall(Address(addr), APPLICATION1, 1),
+    ];
+    pending.extend([UNIT_TYPE.0, FIRMWARE_VERSION.0, APPLICATION1.0].map(|p| (addr, p)));
+    for mesg in requests {
+        let res = outbound.send((network, mesg, Priority::Low));
+        if let Err(e) = res {
+            warn!("scan: {e}")
+        }
+    }
+}
+
+/// Update a unit's details from a reply to one of the probes.
+fn record(unit: &mut UnitInfo, param: u8, value: &[u8]) {
+    let text = || String::from_utf8_lossy(value).trim().to_string();
+    match param {
+        p if p == UNIT_TYPE.0 => unit.unit_type = Some(text()),
+        p if p == FIRMWARE_VERSION.0 => unit.firmware = Some(text()),
+        p if p == APPLICATION1.0 => unit.application = value.first().copied(),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, ChannelConfig};
+    use crate::time::Manual;
+    use crate::Source;
+
+    #[test]
+    fn record_replies() {
+        let mut unit = UnitInfo::default();
+        record(&mut unit, UNIT_TYPE.0, b"RELDN12 ");
+        record(&mut unit, FIRMWARE_VERSION.0, b"4.3.00  ");
+        record(&mut unit, APPLICATION1.0, &[0x38]);
+        record(&mut unit, 0x55, &[0x01]);
+        assert_eq!(
+            unit,
+            UnitInfo {
+                unit_type: Some("RELDN12".to_string()),
+                firmware: Some("4.3.00".to_string()),
+                application: Some(0x38),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn labels_ignored() {
+        let bus = Bus::new(ChannelConfig::default());
+        let mut requests = bus.messages("pci");
+        let inventory = Inventory::default();
+        let daemon = tokio::spawn(scan_daemon(
+            bus.events("scan"),
+            bus.outbound.clone(),
+            inventory.clone(),
+            Arc::new(Manual::default()),
+        ));
+        let reply = |param, value: &[u8]| {
+            let mesg = Message::Reply(Address(0), param, value.to_vec());
+            bus.inbound
+                .publish(Source::Cbus, Event::Cbus(0, mesg))
+                .unwrap();
+        };
+        // labels read for groups that share numbers with the probes
+        reply(UNIT_TYPE.0, b"Kitchen");
+        reply(APPLICATION1.0, b"Hall");
+        bus.inbound
+            .publish(Source::Hmi, Event::Hmi(Post::Scan))
+            .unwrap();
+        requests.recv().await.unwrap();
+        reply(UNIT_TYPE.0, b"RELDN12 ");
+        reply(UNIT_TYPE.0, b"Kitchen");
+        drop(bus);
+        daemon.await.unwrap();
+        let units = inventory.lock().unwrap();
+        assert_eq!(units[&0].unit_type.as_deref(), Some("RELDN12"));
+        assert_eq!(units[&0].application, None);
+    }
+}
@@ -0,0 +1,105 @@
+//! `secret` keeps credentials out of the configuration file, so that the
+//! file can be committed to git.
+//!
+//! Where a setting is a secret, the file names where to find it: an
+//! environment variable or a file that only its owner can read.
+//!
+//! ```toml
+//! [http]
+//! token = { file = "/etc/lights/token" }
+//! # or
+//! token = { env = "LIGHTS_TOKEN" }
+//! ```
+use serde::Deserialize;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Where to find a credential.
+#[derive(PartialEq, Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Secret {
+    /// The value of an environment variable.
+    Env(String),
+    /// The contents of a file, less any trailing newline.
+    File(PathBuf),
+}
+
+impl Secret {
+    /// The credential, failing if it is missing or empty, or is in a file
+    /// that others can read.
+    pub fn reveal(&self) -> Result<String, String> {
+        let value = match self {
+            Secret::Env(var) => std::env::var(var).map_err(|e| format!("{var}: {e}"))?,
+            Secret::File(path) => {
+                check_private(path)?;
+                let text =
+                    fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+                text.trim_end_matches(['\r', '\n']).to_string()
+            }
+        };
+        if value.is_empty() {
+            return Err(format!("{self} is empty"));
+        }
+        Ok(value)
+    }
+}
+
+impl std::fmt::Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Secret::Env(var) => write!(f, "${var}"),
+            Secret::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Fail unless the file at `path` is private to its owner, as `ssh`
+/// requires of a private key.
+pub fn check_private(path: &Path) -> Result<(), String> {
+    let meta = fs::metadata(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let mode = meta.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(format!(
+            "{} can be read by others (mode {:o}), chmod 600 it",
+            path.display(),
+            mode & 0o777
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::Permissions;
+
+    #[test]
+    fn revealed() {
+        let path = std::env::temp_dir().join(format!("lights-secret-{}", std::process::id()));
+        fs::write(&path, "s3cret\n").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+        let secret = Secret::File(path.clone());
+        assert_eq!(secret.reveal(), Ok("s3cret".into()));
+
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        let e = secret.reveal().unwrap_err();
+        assert!(e.contains("can be read by others (mode 644)"));
+        fs::remove_file(&path).unwrap();
+
+        let unset = Secret::Env("LIGHTS_TEST_UNSET_SECRET".into());
+        assert!(unset.reveal().is_err());
+    }
+
+    #[test]
+    fn parsed() {
+        #[derive(Deserialize)]
+        struct Http {
+            token: Secret,
+        }
+        let http: Http = toml::from_str("token = { env = \"LIGHTS_TOKEN\" }").unwrap();
+        assert_eq!(http.token, Secret::Env("LIGHTS_TOKEN".into()));
+        assert_eq!(http.token.to_string(), "$LIGHTS_TOKEN");
+        assert!(toml::from_str::<Http>("token = \"hunter2\"").is_err());
+    }
+}
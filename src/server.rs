@@ -1,36 +1,313 @@
-use super::codec::{Group, Level, Ramp};
-use super::Event;
-use tokio::sync::broadcast::Sender;
+use super::bus::Inbound;
+use super::bus::Lags;
+use super::codec::{Address, Group, Level, Ramp, Target, Variable};
+use super::command::{Command, Subject};
+use super::corpus::Corpus;
+use super::health::Health;
+use super::labels::Labels;
+use super::scan::Inventory;
+use super::state::Levels;
+use super::supervise::Restarts;
+use super::{Event, Network, Post, Result, Source};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
 use warp::http::StatusCode;
-use warp::Filter;
-
-#[derive(Clone, PartialEq, Debug)]
-pub enum Post {
-    Level(Group, Level, Ramp),
-    On(Box<str>),
-    Off(Box<str>),
-}
-
-pub async fn server_daemon(inbound: Sender<Event>) {
-    let routes = warp::post()
-        .and(warp::path("v1"))
-        .and(warp::path("level"))
-        .and(warp::header("cbus-group"))
-        .and(warp::header("cbus-level"))
-        .and(warp::header("cbus-ramp"))
-        .map(move |group: u8, level: u8, ramp: u16| {
-            let res = inbound.send(Event::Hmi(Post::Level(
-                Group(group),
-                Level(level),
-                Ramp(ramp),
-            )));
-            if res.is_ok() {
-                StatusCode::OK
-            } else {
-                println!("* server_daemon: {res:?}");
-                StatusCode::INTERNAL_SERVER_ERROR
+use warp::{Filter, Rejection};
+
+/// Route a post via a bridge if the request names one.
+fn routed(bridge: Option<u8>, network: Option<u8>, post: Post) -> Post {
+    match (bridge, network) {
+        (Some(b), Some(n)) => Post::Bridged(Address(b), n, Box::new(post)),
+        _ => post,
+    }
+}
+
+/// Direct a post to another network's PCI if the request names one.
+fn on(interface: Option<Network>, post: Post) -> Post {
+    match interface {
+        Some(n) if n != 0 => Post::Network(n, Box::new(post)),
+        _ => post,
+    }
+}
+
+/// What a command is for, from the `cbus-group`, `cbus-room` or
+/// `cbus-scene` header, or none unless exactly one is given.
+fn subject() -> impl Filter<Extract = (Option<Subject>,), Error = warp::Rejection> + Copy {
+    warp::header::optional::<Target>("cbus-group")
+        .and(warp::header::optional::<String>("cbus-room"))
+        .and(warp::header::optional::<String>("cbus-scene"))
+        .map(
+            |group: Option<Target>, room: Option<String>, scene: Option<String>| match (
+                group, room, scene,
+            ) {
+                (Some(target), None, None) => Some(Subject::Group(target.as_group())),
+                (None, Some(room), None) => Some(Subject::Room(room.into())),
+                (None, None, Some(scene)) => Some(Subject::Scene(scene.into())),
+                _ => None,
+            },
+        )
+}
+
+/// Publish a post from the HMI, reporting the outcome as a status code.
+fn publish(inbound: &Inbound, post: Post) -> StatusCode {
+    let res = inbound.publish(Source::Hmi, Event::Hmi(post));
+    if res.is_ok() {
+        StatusCode::OK
+    } else {
+        warn!("server: {res:?}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// The optional header naming the PCI to use, by network number.
+fn interface() -> impl Filter<Extract = (Option<Network>,), Error = warp::Rejection> + Copy {
+    warp::header::optional("cbus-interface")
+}
+
+/// A request without the bearer token.
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Whether an `Authorization` header presents `token`, compared in
+/// constant time.
+fn presents(header: Option<&str>, token: &str) -> bool {
+    let Some(given) = header.and_then(|h| h.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Reject requests that do not present `token`, if there is one.
+fn authorized(token: Option<Arc<str>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = token.clone();
+            async move {
+                match token {
+                    Some(token) if !presents(header.as_deref(), &token) => {
+                        Err(warp::reject::custom(Unauthorized))
+                    }
+                    _ => Ok(()),
+                }
             }
+        })
+        .untuple_one()
+}
+
+/// Answer a request without the token with 401.
+async fn unauthorized(rejection: Rejection) -> Result<StatusCode, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(StatusCode::UNAUTHORIZED)
+    } else {
+        Err(rejection)
+    }
+}
+
+/// The state shared with other daemons that the server reports.
+#[derive(Debug, Clone, Default)]
+pub struct Shared {
+    pub inventory: Inventory,
+    pub labels: Labels,
+    pub restarts: Restarts,
+    pub lags: Lags,
+    pub health: Health,
+    pub corpus: Corpus,
+    pub levels: Levels,
+}
+
+/// Serve the HMI until cancelled, then finish the requests in progress.
+/// With a `token`, every request must present it as a bearer token.
+pub async fn server_daemon(
+    inbound: Inbound,
+    shared: Shared,
+    bind: SocketAddr,
+    token: Option<Arc<str>>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let Shared {
+        inventory,
+        labels,
+        restarts,
+        lags,
+        health,
+        corpus,
+        levels,
+    } = shared;
+
+    let level = {
+        let inbound = inbound.clone();
+        warp::post()
+            .and(warp::path!("v1" / "level"))
+            .and(subject())
+            .and(warp::header("cbus-level"))
+            .and(warp::header("cbus-ramp"))
+            .and(warp::header::optional("cbus-bridge"))
+            .and(warp::header::optional("cbus-network"))
+            .and(interface())
+            .map(
+                move |subject: Option<Subject>,
+                      level: u8,
+                      ramp: u16,
+                      bridge,
+                      network,
+                      interface| {
+                    match (subject, Ramp::new(ramp)) {
+                        (Some(subject), Ok(ramp)) => {
+                            let command = Command::Level(subject, Level::new(level), ramp);
+                            let post = routed(bridge, network, Post::Command(command));
+                            publish(&inbound, on(interface, post))
+                        }
+                        (None, _) => StatusCode::BAD_REQUEST,
+                        (_, Err(e)) => {
+                            warn!("server: {e}");
+                            StatusCode::BAD_REQUEST
+                        }
+                    }
+                },
+            )
+    };
+
+    let stop = {
+        let inbound = inbound.clone();
+        warp::post()
+            .and(warp::path!("v1" / "stop"))
+            .and(subject())
+            .and(warp::header::optional("cbus-bridge"))
+            .and(warp::header::optional("cbus-network"))
+            .and(interface())
+            .map(
+                move |subject: Option<Subject>, bridge, network, interface| {
+                    let Some(subject) = subject else {
+                        return StatusCode::BAD_REQUEST;
+                    };
+                    let post = Post::Command(Command::Stop(subject));
+                    publish(&inbound, on(interface, routed(bridge, network, post)))
+                },
+            )
+    };
+
+    let poll = {
+        let inbound = inbound.clone();
+        warp::post()
+            .and(warp::path!("v1" / "poll"))
+            .and(warp::header("cbus-group"))
+            .and(interface())
+            .map(move |block: u8, interface| {
+                let query = Command::Query(Subject::Group(Group::new(block)));
+                publish(&inbound, on(interface, Post::Command(query)))
+            })
+    };
+
+    let enable = {
+        let inbound = inbound.clone();
+        warp::post()
+            .and(warp::path!("v1" / "enable"))
+            .and(warp::header("cbus-variable"))
+            .and(warp::header("cbus-value"))
+            .and(interface())
+            .map(move |var: u8, value: u8, interface| {
+                publish(&inbound, on(interface, Post::Enable(Variable(var), value)))
+            })
+    };
+
+    let scan = {
+        let inbound = inbound.clone();
+        warp::post()
+            .and(warp::path!("v1" / "scan"))
+            .and(interface())
+            .map(move |interface| publish(&inbound, on(interface, Post::Scan)))
+    };
+
+    let display = {
+        let inbound = inbound.clone();
+        warp::post()
+            .and(warp::path!("v1" / "display"))
+            .and(warp::header("cbus-group"))
+            .and(warp::body::content_length_limit(256))
+            .and(warp::body::bytes())
+            .and(interface())
+            .map(
+                move |group: u8, text: bytes::Bytes, interface| match std::str::from_utf8(&text) {
+                    Ok(text) => {
+                        let post = Post::Display(Group::new(group), text.into());
+                        publish(&inbound, on(interface, post))
+                    }
+                    Err(_) => StatusCode::BAD_REQUEST,
+                },
+            )
+    };
+
+    let read_labels = warp::post()
+        .and(warp::path!("v1" / "labels"))
+        .and(warp::header("cbus-unit"))
+        .and(interface())
+        .map(move |unit: u8, interface| {
+            publish(&inbound, on(interface, Post::ReadLabels(Address(unit))))
         });
 
-    warp::serve(routes).bind(([127, 0, 0, 1], 3030)).await
+    let group_labels = warp::get()
+        .and(warp::path!("v1" / "labels"))
+        .map(move || warp::reply::json(&*labels.lock().unwrap()));
+
+    let units = warp::get()
+        .and(warp::path!("v1" / "units"))
+        .map(move || warp::reply::json(&*inventory.lock().unwrap()));
+
+    let restarts = warp::get()
+        .and(warp::path!("v1" / "restarts"))
+        .map(move || warp::reply::json(&*restarts.lock().unwrap()));
+
+    let lags = warp::get()
+        .and(warp::path!("v1" / "lags"))
+        .map(move || warp::reply::json(&*lags.lock().unwrap()));
+
+    let health = warp::get()
+        .and(warp::path!("v1" / "health"))
+        .map(move || warp::reply::json(&*health.lock().unwrap()));
+
+    let unrecognised = warp::get()
+        .and(warp::path!("v1" / "unrecognised"))
+        .map(move || {
+            let corpus = corpus.lock().unwrap();
+            warp::reply::json(&corpus.values().collect::<Vec<_>>())
+        });
+
+    let levels = warp::get().and(warp::path!("v1" / "levels")).map(move || {
+        let levels = levels.lock().unwrap();
+        warp::reply::json(&levels.values().collect::<Vec<_>>())
+    });
+
+    let routes = authorized(token)
+        .and(
+            level
+                .or(stop)
+                .or(poll)
+                .or(enable)
+                .or(scan)
+                .or(units)
+                .or(display)
+                .or(read_labels)
+                .or(group_labels)
+                .or(restarts)
+                .or(lags)
+                .or(health)
+                .or(unrecognised)
+                .or(levels),
+        )
+        .recover(unauthorized)
+        .with(warp::trace::request());
+
+    let shutdown = async move { cancel.cancelled().await };
+    let (_, server) = warp::serve(routes).try_bind_with_graceful_shutdown(bind, shutdown)?;
+    server.await;
+    Ok(())
 }
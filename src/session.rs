@@ -0,0 +1,395 @@
+//! `session` maintains the connection to the PCI.
+//!
+//! Each session connects, configures the PCI and then runs a reader,
+//! a writer and the link monitors until the connection fails.
+use crate::bus::{Bus, Filter, Inbound, Subscriber};
+use crate::busio::{self, Framing, IoStats, Line, Metered};
+use crate::codec::{self, Group, Message, Priority, Setting, LIGHTING};
+use crate::config::CbusConfig;
+use crate::confirm::RetryPolicy;
+use crate::echo::{EchoFilter, Echoes};
+use crate::ha::Role;
+use crate::health::{self, Health};
+use crate::tap::{Tapped, WireTap};
+use crate::time::{self, Clock};
+use crate::transport::Transport;
+use crate::writer::{handshake, write_messages, WriterConfig};
+use crate::{Envelope, Event, LightsError, Network, Outbound, Result, Source};
+use std::sync::{Arc, Mutex};
+use tokio::io::{self, AsyncRead};
+use tokio::sync::broadcast::Sender;
+use tokio::time::Duration;
+use tokio::{select, task};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, info_span, warn, Instrument};
+
+const SEND_PACE: Duration = Duration::from_millis(50);
+const QUEUE_LEN: usize = 64;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+const ECHO_WINDOW: Duration = Duration::from_secs(2);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn input_task<I>(
+    network: Network,
+    input: I,
+    config: CbusConfig,
+    stats: Arc<IoStats>,
+    cancel: CancellationToken,
+    echoes: Echoes,
+    inbound: Inbound,
+) -> io::Result<()>
+where
+    I: AsyncRead + Unpin,
+{
+    let options = config.options.options1.clone();
+    let accept = |line: Line| {
+        match line {
+            Line::Text(line) => {
+                let echoed = echoes.lock().unwrap().echoed(&line, inbound.clock().now());
+                match echoed {
+                    Some(mesg) => {
+                        let _ = inbound.publish(Source::Cbus, Event::Echo(mesg));
+                    }
+                    None => {
+                        for mesg in codec::decode_with(line, &options) {
+                            let _ = inbound.publish(Source::Cbus, Event::Cbus(network, mesg));
+                        }
+                    }
+                }
+            }
+            Line::TooLong(head, len) => {
+                let _ = inbound.publish(Source::Cbus, Event::LongLine(head, len));
+            }
+        }
+        async {}
+    };
+
+    let reader = config.reader;
+    match config.framing {
+        Framing::Ascii => busio::read_lines(input, reader, stats, cancel, accept).await,
+        Framing::Binary => busio::read_packets(input, reader, stats, cancel, accept).await,
+    }
+}
+
+/// Fail if no input arrives from the PCI for `timeout`.
+async fn idle_watch(
+    network: Network,
+    mut events: Subscriber<Envelope>,
+    timeout: Option<Duration>,
+    clock: Arc<dyn Clock>,
+) -> Result<()> {
+    let Some(timeout) = timeout else {
+        return std::future::pending().await;
+    };
+    loop {
+        let heard = async {
+            loop {
+                match events.event().await {
+                    Some(Event::Cbus(n, _)) if n == network => return true,
+                    Some(_) => (),
+                    None => return false,
+                }
+            }
+        };
+        match time::timeout(&*clock, timeout, heard).await {
+            Some(true) => (),
+            Some(false) => return Ok(()),
+            None => {
+                let e = format!("no input from the PCI for {timeout:?}");
+                return Err(LightsError::Timeout(e));
+            }
+        }
+    }
+}
+
+/// Periodically re-assert the interface options, a harmless command
+/// that the PCI confirms, so the link is never quiet for long.
+async fn keepalive(
+    network: Network,
+    outbound: Sender<Outbound>,
+    period: Option<Duration>,
+    options: Setting,
+    clock: Arc<dyn Clock>,
+) -> Result<()> {
+    let Some(period) = period else {
+        return std::future::pending().await;
+    };
+    let mut due = clock.now() + period;
+    loop {
+        clock.sleep_until(due).await;
+        due += period;
+        let mesg = Message::SetParam(codec::OPTIONS1, options.clone());
+        let _ = outbound.send((network, mesg, Priority::Low));
+    }
+}
+
+/// Periodically ask for the levels of the first lighting groups, and
+/// fail unless a unit reports them in time.  Unlike the keepalive,
+/// which the PCI confirms itself, this shows that the PCI still relays
+/// the bus.  A standby sends nothing, so asks for nothing.
+async fn heartbeat(
+    network: Network,
+    outbound: Sender<Outbound>,
+    mut events: Subscriber<Envelope>,
+    period: Option<Duration>,
+    role: Role,
+    clock: Arc<dyn Clock>,
+) -> Result<()> {
+    let Some(period) = period else {
+        return std::future::pending().await;
+    };
+    let mut due = clock.now() + period;
+    // when a report is awaited, the time it must come by
+    let mut deadline = None;
+    loop {
+        let active = role.is_active();
+        select! {
+            _ = clock.sleep_until(deadline.unwrap_or(due)), if active => {
+                if deadline.is_some() {
+                    let e = format!("no reply to the heartbeat in {HEARTBEAT_TIMEOUT:?}");
+                    return Err(LightsError::Timeout(e));
+                }
+                due += period;
+                deadline = Some(clock.now() + HEARTBEAT_TIMEOUT);
+                let mesg = Message::LevelRequest(LIGHTING, Group(0));
+                let _ = outbound.send((network, mesg, Priority::Low));
+            }
+            _ = role.until(!active) => {
+                deadline = None;
+                due = clock.now() + period;
+            }
+            res = events.event() => match res {
+                Some(Event::Cbus(n, Message::LevelStatus(..))) if n == network => deadline = None,
+                Some(_) => (),
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+/// What a link shares with the rest of the daemon.
+#[derive(Debug, Clone, Default)]
+pub struct Link {
+    pub stats: Arc<IoStats>,
+    pub health: Health,
+    /// Commands are only sent while active, see `ha`.
+    pub role: Role,
+}
+
+/// Run one connection to the PCI until it fails or is cancelled.
+async fn cbus_session(
+    network: Network,
+    config: CbusConfig,
+    transport: Arc<dyn Transport>,
+    bus: Bus,
+    link: Link,
+    tap: Option<WireTap>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let Link { stats, role, .. } = link;
+    let messages = bus.messages("writer");
+
+    // Connect to a CBUS device
+    let (mut input, mut output) = select! {
+        res = transport.connect() => res?,
+        _ = cancel.cancelled() => return Ok(()),
+    };
+    if let Some(tap) = tap {
+        input = Box::new(Tapped::new(input, tap.clone(), '>'));
+        output = Box::new(Tapped::new(output, tap, '<'));
+    }
+    let input = Metered::new(input, stats.clone());
+    let mut output = Metered::new(output, stats.clone());
+
+    // read replies, then configure CBUS device
+    let from_pci = Filter::all().source(Source::Cbus);
+    let mut replies = bus.filtered("handshake", from_pci.clone());
+    let echoes = Arc::new(Mutex::new(EchoFilter::new(ECHO_WINDOW)));
+    let input_task = task::spawn(
+        input_task(
+            network,
+            input,
+            config.clone(),
+            stats,
+            cancel.clone(),
+            echoes.clone(),
+            bus.inbound.clone(),
+        )
+        .in_current_span(),
+    );
+    let handshake = handshake(
+        &mut output,
+        config.framing,
+        &config.options,
+        network,
+        &mut replies,
+        HANDSHAKE_TIMEOUT,
+        &**bus.clock(),
+    );
+    // if cancelled, go on to let the writer close the connection
+    let res = select! {
+        res = handshake => res,
+        _ = cancel.cancelled() => Ok(config.options.clone()),
+    };
+    let options = match res {
+        Ok(options) => options,
+        Err(e) => {
+            input_task.abort();
+            return Err(e);
+        }
+    };
+    drop(replies);
+    if !cancel.is_cancelled() {
+        let _ = bus.inbound.publish(Source::Cbus, Event::Connected(network));
+    }
+
+    // run tasks
+    let idle = idle_watch(
+        network,
+        bus.filtered("idle", from_pci.clone()),
+        config.idle_timeout,
+        bus.clock().clone(),
+    );
+    let probe = keepalive(
+        network,
+        bus.outbound.clone(),
+        config.keepalive,
+        options.options1.clone(),
+        bus.clock().clone(),
+    );
+    let pulse = heartbeat(
+        network,
+        bus.outbound.clone(),
+        bus.filtered("heartbeat", from_pci.clone().application(LIGHTING.0)),
+        config.heartbeat,
+        role.clone(),
+        bus.clock().clone(),
+    );
+    let writer = WriterConfig {
+        network,
+        policy: RetryPolicy::default(),
+        framing: config.framing,
+        options,
+        pace: SEND_PACE,
+        queue_len: QUEUE_LEN,
+        rate: config.rate,
+        role,
+    };
+    let mut output_task = task::spawn(
+        write_messages(
+            messages,
+            bus.inbound.clone(),
+            bus.filtered("confirmations", from_pci),
+            writer,
+            cancel.clone(),
+            echoes,
+            output,
+        )
+        .in_current_span(),
+    );
+    select! {
+        biased;
+        // the writer flushes its queue and closes the connection
+        _ = cancel.cancelled() => output_task.await?,
+        res = input_task => Ok(res??),
+        res = &mut output_task => res?,
+        res = idle => res,
+        res = probe => res,
+        res = pulse => res,
+    }
+}
+
+/// Maintain a connection to the PCI for `network` through `transport`
+/// until cancelled, reporting on it in `link`.
+pub async fn cbus_daemon(
+    network: Network,
+    config: CbusConfig,
+    transport: Arc<dyn Transport>,
+    bus: Bus,
+    link: Link,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let (stats, health) = (link.stats.clone(), link.health.clone());
+    let tap = config.trace_wire.clone().map(WireTap::start);
+    let clock = bus.clock().clone();
+    let mut backoff = config.reconnect.backoff();
+    let mut failures = 0;
+    loop {
+        info!("connecting via {:?}", config.transport);
+        let started = clock.now();
+        let span = info_span!("session", reconnects = stats.snapshot().reconnects);
+        let session_cancel = cancel.child_token();
+        health::session_started(&health, network, session_cancel.clone(), clock.now());
+        let session = cbus_session(
+            network,
+            config.clone(),
+            transport.clone(),
+            bus.clone(),
+            link.clone(),
+            tap.clone(),
+            session_cancel,
+        );
+        match session.instrument(span).await {
+            Ok(()) => {
+                info!("disconnected");
+                health::session_ended(&health, network, None);
+            }
+            Err(e) => {
+                warn!("disconnected: {e}");
+                health::session_ended(&health, network, Some(e.to_string()));
+            }
+        }
+        info!(stats = %stats.snapshot(), "link statistics");
+        if clock.now() - started > backoff.max() {
+            backoff.reset();
+            failures = 0;
+        }
+        failures += 1;
+        let delay = backoff.delay();
+        health::retrying(&health, network, failures, delay);
+        info!("reconnecting in {delay:.1?}");
+        select! {
+            _ = cancel.cancelled() => return Ok(()),
+            _ = clock.sleep(delay) => (),
+        }
+        stats.reconnected();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::ChannelConfig;
+    use crate::time::Manual;
+    use futures_util::FutureExt;
+
+    #[tokio::test]
+    async fn heartbeat_paused_on_standby() {
+        for active in [true, false] {
+            let clock = Manual::default();
+            let bus = Bus::new(ChannelConfig::default());
+            let mut sent = bus.messages("pci");
+            let pulse = tokio::spawn(heartbeat(
+                0,
+                bus.outbound.clone(),
+                bus.events("heartbeat"),
+                Some(Duration::from_secs(30)),
+                Role::new(active),
+                Arc::new(clock.clone()),
+            ));
+            for _ in 0..10 {
+                clock.advance(Duration::from_secs(30));
+                task::yield_now().await;
+            }
+            if active {
+                let request = (0, Message::LevelRequest(LIGHTING, Group(0)), Priority::Low);
+                assert_eq!(sent.recv().await, Some(request));
+                assert!(pulse.await.unwrap().is_err());
+            } else {
+                assert_eq!(sent.recv().now_or_never(), None);
+                drop(bus);
+                assert!(pulse.await.unwrap().is_ok());
+            }
+        }
+    }
+}
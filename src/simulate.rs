@@ -0,0 +1,296 @@
+//! `simulate` stands in for a PCI and the lighting on its network.
+//!
+//! The virtual PCI speaks the ASCII protocol over an in-process pipe.
+//! It confirms commands, reports them back as monitored SAL as a real
+//! PCI does, follows the level of each lighting group as it ramps and
+//! answers level requests.  Units are not modelled, so scans and label
+//! reads go unanswered.
+use crate::codec::{self, Code, Group, Level, Message, Setting, OFF, OPTIONS1, SR_CHK};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::task;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, Instrument};
+
+/// Capacity of the pipe to the virtual PCI, in bytes.
+const PIPE_LEN: usize = 4096;
+
+/// The address the virtual network reports levels from.
+const UNIT: u8 = 0x01;
+
+/// Groups in each level status report.
+const LEVELS_PER_REPLY: u8 = 12;
+
+/// A lighting group on its way to a level.
+#[derive(Debug, Clone)]
+struct Fade {
+    from: Level,
+    to: Level,
+    start: Instant,
+    over: Duration,
+}
+
+impl Fade {
+    fn level(&self, now: Instant) -> Level {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= self.over {
+            return self.to.clone();
+        }
+        let (from, to) = (f64::from(self.from.value()), f64::from(self.to.value()));
+        let part = elapsed.as_secs_f64() / self.over.as_secs_f64();
+        Level::new((from + (to - from) * part).round() as u8)
+    }
+}
+
+/// The lighting on a virtual network, which outlives connections to it.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualNetwork {
+    groups: Arc<Mutex<BTreeMap<u8, Fade>>>,
+}
+
+impl VirtualNetwork {
+    /// Start a virtual PCI and return our end of the pipe to it.
+    pub fn connect(&self) -> DuplexStream {
+        let (host, pci) = io::duplex(PIPE_LEN);
+        let pci = serve(self.clone(), pci);
+        task::spawn(async { drop(pci.await) }.in_current_span());
+        host
+    }
+
+    /// The level of `group` at `now`, off if it was never set.
+    pub fn level(&self, group: Group, now: Instant) -> Level {
+        let groups = self.groups.lock().unwrap();
+        groups.get(&group.value()).map_or(OFF, |f| f.level(now))
+    }
+
+    /// Follow a lighting command seen on the network.
+    fn apply(&self, mesg: &Message, now: Instant) {
+        let mut groups = self.groups.lock().unwrap();
+        match mesg {
+            Message::SetVar(app, group, to, ramp) if app.is_lighting() => {
+                let targets: Vec<u8> = if *group == Group::ALL {
+                    groups.keys().copied().collect()
+                } else {
+                    vec![group.value()]
+                };
+                for g in targets {
+                    let from = groups.get(&g).map_or(OFF, |f| f.level(now));
+                    let over = ramp.duration();
+                    let to = to.clone();
+                    groups.insert(
+                        g,
+                        Fade {
+                            from,
+                            to,
+                            start: now,
+                            over,
+                        },
+                    );
+                }
+            }
+            Message::StopRamp(app, group) if app.is_lighting() => {
+                if let Some(fade) = groups.get_mut(&group.value()) {
+                    let level = fade.level(now);
+                    *fade = Fade {
+                        from: level.clone(),
+                        to: level,
+                        start: now,
+                        over: Duration::ZERO,
+                    };
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// An extended level status report for the groups from `block`.
+    fn level_status(&self, app: u8, block: u8, now: Instant) -> Vec<u8> {
+        let count = LEVELS_PER_REPLY.min(u8::MAX - block);
+        let mut cal = vec![0xe0 | (3 + 2 * count), 0x07, app, block];
+        for g in block..block + count {
+            let level = self.level(Group::new(g), now).value();
+            cal.extend([level_nibble(level & 0x0f), level_nibble(level >> 4)]);
+        }
+        [&[0x86, UNIT, 0x00, 0x00], &cal[..]].concat()
+    }
+}
+
+/// Encode four bits of a level as two symbols of two bits each.
+fn level_nibble(n: u8) -> u8 {
+    const SYMBOLS: [u8; 4] = [0x5, 0x6, 0x9, 0xa];
+    SYMBOLS[usize::from(n >> 2)] << 4 | SYMBOLS[usize::from(n & 3)]
+}
+
+/// A frame as the PCI sends it: hex with a check byte and CR LF.
+fn frame(bytes: &[u8]) -> Vec<u8> {
+    let mut line: Vec<u8> = bytes
+        .iter()
+        .flat_map(|b| format!("{b:02X}").into_bytes())
+        .collect();
+    line.extend(format!("{:02X}\r\n", codec::checksum(bytes)).into_bytes());
+    line
+}
+
+fn unhex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    let hex = std::str::from_utf8(hex).ok()?;
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The reply to a command that cannot be carried out.
+fn refused(code: Option<u8>) -> Vec<u8> {
+    code.map_or(Vec::new(), |c| vec![c, b'#', b'\r', b'\n'])
+}
+
+/// The PCI's reply to a command line, without its CR.
+///
+/// `options` are the PCI's Options 1, which the host may change.
+fn respond(network: &VirtualNetwork, options: &mut Setting, line: &[u8], now: Instant) -> Vec<u8> {
+    let (line, code) = match line.split_last() {
+        Some((&c, rest)) if Code::new(c).is_some() => (rest, Some(c)),
+        _ => (line, None),
+    };
+    let mut bytes = match line.split_first().and_then(|(_, hex)| unhex(hex)) {
+        Some(bytes) if !bytes.is_empty() => bytes,
+        _ => return refused(code),
+    };
+    if options.contains(&SR_CHK) {
+        if codec::checksum(&bytes) != 0 {
+            return refused(code);
+        }
+        bytes.pop();
+    }
+
+    let mut reply = Vec::new();
+    match (line[0], &bytes[..]) {
+        (b'@', [0xa3, param, 0x00, value]) if *param == OPTIONS1.0 => {
+            *options = Setting::new(*value)
+        }
+        (b'\\', [header, 0xff, 0x00, 0x73, 0x07, app, block]) if header & 0x3f == 0x05 => {
+            reply.extend(frame(&network.level_status(*app, *block, now)))
+        }
+        (b'\\', [header, app, 0x00, data @ ..]) if header & 0x3f == 0x05 && *app != 0xff => {
+            let monitored = [&[0x05, 0x00, *app, 0x00], data].concat();
+            let line = frame(&monitored);
+            let text = Bytes::copy_from_slice(&line[..line.len() - 2]);
+            for mesg in codec::decode_with(text, &SR_CHK) {
+                debug!("simulating {mesg}");
+                network.apply(&mesg, now);
+            }
+            reply.extend(line);
+        }
+        _ => (),
+    }
+    if let Some(c) = code {
+        reply.extend([c, b'.', b'\r', b'\n']);
+    }
+    reply
+}
+
+/// Act as a PCI on `pipe` until the other end is dropped.
+async fn serve(network: VirtualNetwork, pipe: DuplexStream) -> io::Result<()> {
+    let (mut input, mut output) = io::split(pipe);
+    let mut options = Setting::new(0);
+    let mut line = Vec::new();
+    let mut buf = [0; 256];
+    loop {
+        let n = input.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        for &b in &buf[..n] {
+            match b {
+                b'~' => {
+                    options = Setting::new(0);
+                    line.clear();
+                    output.write_all(b"=\r\n").await?
+                }
+                b'\r' => {
+                    let reply = respond(&network, &mut options, &line, Instant::now());
+                    line.clear();
+                    output.write_all(&reply).await?
+                }
+                b'\n' => (),
+                _ => line.push(b),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{encode_confirmed, options1, Ramp, INSTANT, LIGHTING, ON};
+
+    fn command(mesg: Message, code: u8) -> Vec<u8> {
+        let line = encode_confirmed(mesg, &options1(), Code::new(code));
+        line.strip_suffix(b"\r").unwrap().to_vec()
+    }
+
+    #[test]
+    fn echoes_and_confirms() {
+        let network = VirtualNetwork::default();
+        let mut options = options1();
+        let now = Instant::now();
+        let mesg = Message::SetVar(LIGHTING, Group(4), ON, INSTANT);
+        let reply = respond(&network, &mut options, &command(mesg.clone(), b'g'), now);
+        let lines: Vec<&[u8]> = reply.split(|&b| b == b'\n').collect();
+        let echo = Bytes::copy_from_slice(lines[0].strip_suffix(b"\r").unwrap());
+        assert_eq!(codec::decode_with(echo, &SR_CHK), [mesg]);
+        assert_eq!(lines[1], b"g.\r");
+        assert_eq!(network.level(Group(4), now), ON);
+    }
+
+    #[test]
+    fn ramps() {
+        let network = VirtualNetwork::default();
+        let mut options = options1();
+        let now = Instant::now();
+        let ramp = Ramp::new(4).unwrap();
+        let mesg = Message::SetVar(LIGHTING, Group(4), Level(200), ramp);
+        respond(&network, &mut options, &command(mesg, b'h'), now);
+        let later = now + Duration::from_secs(1);
+        assert_eq!(network.level(Group(4), later), Level(50));
+        let stop = Message::StopRamp(LIGHTING, Group(4));
+        respond(&network, &mut options, &command(stop, b'i'), later);
+        assert_eq!(
+            network.level(Group(4), now + Duration::from_secs(10)),
+            Level(50)
+        );
+    }
+
+    #[test]
+    fn reports_levels() {
+        let network = VirtualNetwork::default();
+        let mut options = options1();
+        let now = Instant::now();
+        let mesg = Message::SetVar(LIGHTING, Group(17), Level(0x43), INSTANT);
+        respond(&network, &mut options, &command(mesg, b'g'), now);
+        let request = Message::LevelRequest(LIGHTING, Group(16));
+        let reply = respond(&network, &mut options, &command(request, b'h'), now);
+        let end = reply.iter().position(|&b| b == b'\r').unwrap();
+        let report = codec::decode_with(Bytes::copy_from_slice(&reply[..end]), &SR_CHK);
+        let Message::LevelStatus(app, levels) = &report[0] else {
+            panic!("not a level status: {report:?}");
+        };
+        assert_eq!(*app, LIGHTING);
+        assert_eq!(levels.len(), usize::from(LEVELS_PER_REPLY));
+        assert_eq!(levels[0], (Group(16), OFF));
+        assert_eq!(levels[1], (Group(17), Level(0x43)));
+    }
+
+    #[test]
+    fn bad_checksum_refused() {
+        let network = VirtualNetwork::default();
+        let mut options = options1();
+        let reply = respond(&network, &mut options, b"\\0538007904FFg", Instant::now());
+        assert_eq!(reply, b"g#\r\n");
+    }
+}
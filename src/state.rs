@@ -0,0 +1,565 @@
+//! `state` keeps what each group is doing: its level, and any ramp it
+//! is on, which the gaffer consults and the server reports at
+//! `GET /v1/levels`.
+//!
+//! Levels are learned from commands and status reports seen on the
+//! CBUS, and from the commands sent to it, which are taken to have
+//! worked until a report says otherwise or the writer gives up on them.
+//! They are saved to a file periodically and on shutdown, and read back
+//! at startup, so a restart does not forget which lights were left on
+//! or dimmed.
+//!
+//! On each connection the levels of the groups in `[state] groups` are
+//! asked for, so the state is right without waiting for a change.
+use crate::bus::{self, Filter, Lags, Subscriber};
+use crate::codec::{Group, GroupState, Level, Message, Outcome, Priority, LIGHTING, OFF, ON};
+use crate::daemon::Daemon;
+use crate::persist;
+use crate::time::{since_epoch, Clock};
+use crate::{Envelope, Event, Network, Outbound, Source};
+use futures_util::future::{BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::select;
+use tokio::sync::broadcast::Sender;
+use tokio::time::Duration;
+use tracing::{debug, warn};
+
+/// Groups asked for by each level request, as many as one report holds.
+const GROUPS_PER_REQUEST: usize = 12;
+
+/// Interval between the level requests of a poll.
+const POLL_PACE: Duration = Duration::from_millis(100);
+
+/// Where and how often to save the state.
+#[derive(PartialEq, Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    /// The state file, or none to keep the state in memory only.
+    pub file: Option<PathBuf>,
+    /// Seconds between saves.
+    pub period: u64,
+    /// Whether to ask for the group levels on each connection.
+    pub poll: bool,
+    /// The first and last group to ask for.
+    pub groups: [u8; 2],
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        StateConfig {
+            file: None,
+            period: 60,
+            poll: true,
+            groups: [0, u8::MAX],
+        }
+    }
+}
+
+impl StateConfig {
+    /// The level requests that cover the groups polled.
+    fn requests(&self) -> Vec<Message> {
+        let [first, last] = self.groups;
+        (first..=last)
+            .step_by(GROUPS_PER_REQUEST)
+            .map(|block| Message::LevelRequest(LIGHTING, Group(block)))
+            .collect()
+    }
+}
+
+/// What a group is doing, as far as is known.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct GroupLevel {
+    pub network: Network,
+    pub group: u8,
+    /// The level when last updated, where any ramp starts from.
+    pub current: Level,
+    /// The level being ramped to, or `current` if there is no ramp.
+    pub target: Level,
+    /// When the target is reached.
+    #[serde(serialize_with = "seconds")]
+    pub deadline: SystemTime,
+    /// When last set or reported.
+    #[serde(serialize_with = "seconds")]
+    pub updated: SystemTime,
+}
+
+/// What each group is doing, by network and group number.
+pub type Levels = Arc<Mutex<BTreeMap<(Network, u8), GroupLevel>>>;
+
+/// A time as seconds since the epoch.
+fn seconds<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(since_epoch(*time).as_secs_f64())
+}
+
+impl GroupLevel {
+    /// A group that is still at `level` as of `time`.
+    pub fn still(network: Network, group: u8, level: Level, time: SystemTime) -> GroupLevel {
+        GroupLevel {
+            network,
+            group,
+            current: level.clone(),
+            target: level,
+            deadline: time,
+            updated: time,
+        }
+    }
+
+    /// The level at `time`, partway along any ramp.
+    pub fn level_at(&self, time: SystemTime) -> Level {
+        if time >= self.deadline {
+            return self.target.clone();
+        }
+        let Ok(gone) = time.duration_since(self.updated) else {
+            return self.current.clone();
+        };
+        let whole = self
+            .deadline
+            .duration_since(self.updated)
+            .unwrap_or_default();
+        let (from, to) = (f64::from(self.current.0), f64::from(self.target.0));
+        let level = from + (to - from) * gone.as_secs_f64() / whole.as_secs_f64();
+        Level(level.round() as u8)
+    }
+}
+
+/// Take `level` as reported for `group` on `network` at `time`.  A
+/// report partway along a ramp says how far it has got, so the ramp
+/// carries on from there to its target.
+fn report(
+    levels: &mut BTreeMap<(Network, u8), GroupLevel>,
+    network: Network,
+    group: u8,
+    level: Level,
+    time: SystemTime,
+) {
+    match levels.get_mut(&(network, group)) {
+        Some(known) if time < known.deadline => {
+            known.current = level;
+            known.updated = time;
+        }
+        _ => {
+            let still = GroupLevel::still(network, group, level, time);
+            levels.insert((network, group), still);
+        }
+    }
+}
+
+/// Update `levels` from a message on `network` sent or heard at `time`.
+///
+/// A binary status report only says whether a group is on, so a group
+/// reported on keeps the level it was known to have.
+pub fn observe(levels: &Levels, network: Network, mesg: &Message, time: SystemTime) {
+    let mut levels = levels.lock().unwrap();
+    match mesg {
+        Message::SetVar(app, group, level, ramp) if app.is_lighting() => {
+            let start = |g: Option<&GroupLevel>, group: u8| GroupLevel {
+                network,
+                group,
+                current: g.map_or(level.clone(), |g| g.level_at(time)),
+                target: level.clone(),
+                deadline: time + ramp.duration(),
+                updated: time,
+            };
+            if *group == Group::ALL {
+                for ((_, g), known) in levels.range_mut((network, 0)..=(network, u8::MAX)) {
+                    *known = start(Some(known), *g);
+                }
+            } else {
+                let known = levels.get(&(network, group.value()));
+                let started = start(known, group.value());
+                levels.insert((network, group.value()), started);
+            }
+        }
+        Message::LevelStatus(app, reported) if app.is_lighting() => {
+            for (group, level) in reported {
+                report(&mut levels, network, group.value(), level.clone(), time);
+            }
+        }
+        Message::Status(app, ..) if app.is_lighting() => {
+            for (group, state) in mesg.group_states().unwrap_or_default() {
+                let known = levels.get(&(network, group.value()));
+                let level = match (state, known) {
+                    (GroupState::Off, _) => OFF,
+                    (GroupState::On, Some(g)) if g.level_at(time) != OFF => g.level_at(time),
+                    (GroupState::On, _) => ON,
+                    (GroupState::Error, _) => continue,
+                };
+                report(&mut levels, network, group.value(), level, time);
+            }
+        }
+        // stopped somewhere along the way
+        Message::StopRamp(app, group) if app.is_lighting() => {
+            if let Some(known) = levels.get_mut(&(network, group.value())) {
+                let level = known.level_at(time);
+                *known = GroupLevel::still(network, group.value(), level, time);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Forget the groups on `network` that `mesg` was to set, as it may
+/// not have.
+pub fn forget(levels: &Levels, network: Network, mesg: &Message) {
+    if let Message::SetVar(app, group, _, _) | Message::StopRamp(app, group) = mesg {
+        if app.is_lighting() {
+            let mut levels = levels.lock().unwrap();
+            levels
+                .retain(|(n, g), _| *n != network || (*g != group.value() && *group != Group::ALL));
+        }
+    }
+}
+
+/// The state file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Snapshot {
+    groups: Vec<Saved>,
+}
+
+/// A group level in the state file: only where it was going.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Saved {
+    network: Network,
+    group: u8,
+    level: u8,
+}
+
+/// Read the levels saved at `path`, as still as of `time`, or none if
+/// there is no file.
+pub fn load(path: &Path, time: SystemTime) -> Result<Levels, String> {
+    let snapshot: Snapshot = persist::load(path)?;
+    let levels = snapshot
+        .groups
+        .into_iter()
+        .map(|s| {
+            let still = GroupLevel::still(s.network, s.group, Level::new(s.level), time);
+            ((s.network, s.group), still)
+        })
+        .collect();
+    Ok(Levels::new(Mutex::new(levels)))
+}
+
+/// Save the levels to `path`, replacing the file whole.
+pub fn save(path: &Path, levels: &Levels) -> io::Result<()> {
+    let groups = levels
+        .lock()
+        .unwrap()
+        .values()
+        .map(|g| Saved {
+            network: g.network,
+            group: g.group,
+            level: g.target.value(),
+        })
+        .collect();
+    persist::save(path, &Snapshot { groups })
+}
+
+/// Follow group levels, as heard in `events` and as `sent` at the time
+/// by `clock`, and save them every `config.period`, polling the levels
+/// on each connection.
+pub async fn state_daemon(
+    mut events: Subscriber<Envelope>,
+    mut sent: Subscriber<Outbound>,
+    outbound: Sender<Outbound>,
+    levels: Levels,
+    config: StateConfig,
+    clock: Arc<dyn Clock>,
+) {
+    let period = Duration::from_secs(config.period);
+    let mut save_due = clock.now() + period;
+    let mut poll_due = clock.now();
+    let mut polls: VecDeque<Outbound> = VecDeque::new();
+    loop {
+        select! {
+            _ = clock.sleep_until(save_due), if config.file.is_some() => {
+                save_due += period;
+                if let Some(path) = &config.file {
+                    if let Err(e) = save(path, &levels) {
+                        warn!("cannot save state to {}: {e}", path.display());
+                    }
+                }
+            }
+            _ = clock.sleep_until(poll_due), if !polls.is_empty() => {
+                poll_due = clock.now() + POLL_PACE;
+                if let Some(request) = polls.pop_front() {
+                    if let Err(e) = outbound.send(request) {
+                        warn!("state poll: {e}");
+                    }
+                }
+            }
+            Some((network, mesg, _)) = sent.recv() => observe(&levels, network, &mesg, clock.wall()),
+            res = events.recv() => match res.map(|e| (e.time, e.event)) {
+                Some((time, Event::Cbus(network, mesg))) => observe(&levels, network, &mesg, time),
+                Some((_, Event::Delivery(network, mesg, Outcome::Failed))) => {
+                    forget(&levels, network, &mesg)
+                }
+                Some((_, Event::Connected(network))) if config.poll => {
+                    debug!(network, "state poll: starting");
+                    if polls.is_empty() {
+                        poll_due = clock.now();
+                    }
+                    polls.retain(|(n, ..)| *n != network);
+                    polls.extend(config.requests().into_iter().map(|m| (network, m, Priority::Low)));
+                }
+                Some(_) => (),
+                None => return,
+            }
+        }
+    }
+}
+
+/// The state keeper as a pluggable daemon.
+pub struct StateKeeper {
+    pub levels: Levels,
+    pub config: StateConfig,
+    /// Where its subscription to commands sent reports falling behind.
+    pub lags: Lags,
+    /// Stamps the commands sent.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Daemon for StateKeeper {
+    fn name(&self) -> &'static str {
+        "state"
+    }
+
+    fn filter(&self) -> Filter {
+        Filter::all().source(Source::Cbus)
+    }
+
+    fn run(
+        &self,
+        events: Subscriber<Envelope>,
+        outbound: Sender<Outbound>,
+    ) -> BoxFuture<'static, ()> {
+        let sent = bus::subscribe(&outbound, "state sent", &self.lags);
+        let (levels, config) = (self.levels.clone(), self.config.clone());
+        state_daemon(events, sent, outbound, levels, config, self.clock.clone()).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, ChannelConfig};
+    use crate::codec::{HexBytes, Ramp, INSTANT};
+    use crate::time::{Manual, System};
+    use std::time::Duration;
+
+    const NOW: SystemTime = SystemTime::UNIX_EPOCH;
+
+    fn set(group: Group, level: Level) -> Message {
+        Message::SetVar(LIGHTING, group, level, INSTANT)
+    }
+
+    #[test]
+    fn levels_observed() {
+        let levels = Levels::default();
+        observe(&levels, 1, &set(Group(4), ON), NOW);
+        let report = vec![(Group(5), Level(0x40)), (Group(6), OFF)];
+        observe(&levels, 1, &Message::LevelStatus(LIGHTING, report), NOW);
+        observe(&levels, 0, &set(Group(4), OFF), NOW);
+        observe(&levels, 1, &set(Group::ALL, Level(9)), NOW);
+        let levels = levels.lock().unwrap();
+        assert_eq!(levels[&(0, 4)].target, OFF);
+        assert_eq!(levels[&(1, 4)].target, Level(9));
+        assert_eq!(levels[&(1, 6)].target, Level(9));
+        assert_eq!(levels.len(), 4);
+    }
+
+    #[test]
+    fn tracked() {
+        let levels = Levels::default();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let at = |secs| start + Duration::from_secs(secs);
+        let report = Message::LevelStatus(LIGHTING, vec![(Group(4), OFF)]);
+        observe(&levels, 0, &report, start);
+        let ramp = Ramp::new(8).unwrap();
+        observe(
+            &levels,
+            0,
+            &Message::SetVar(LIGHTING, Group(4), Level(200), ramp),
+            start,
+        );
+        let g = levels.lock().unwrap()[&(0, 4)].clone();
+        assert_eq!((&g.current, &g.target), (&OFF, &Level(200)));
+        assert_eq!((g.deadline, g.updated), (at(8), start));
+        assert_eq!(g.level_at(at(2)), Level(50));
+
+        // stopped a quarter of the way
+        observe(&levels, 0, &Message::StopRamp(LIGHTING, Group(4)), at(2));
+        let g = levels.lock().unwrap()[&(0, 4)].clone();
+        assert_eq!(
+            (g.current, g.target, g.deadline),
+            (Level(50), Level(50), at(2))
+        );
+
+        // group 4 on and group 5 off, in a binary status report
+        let status = Message::Status(LIGHTING, Group(4), HexBytes::from(vec![0x09]));
+        observe(&levels, 0, &status, at(3));
+        let levels = levels.lock().unwrap();
+        assert_eq!(levels[&(0, 4)].current, Level(50));
+        assert_eq!(levels[&(0, 5)].current, OFF);
+
+        let json = serde_json::to_value(&levels[&(0, 4)]).unwrap();
+        assert_eq!(json["target"], 50);
+        assert_eq!(json["updated"], 103.0);
+    }
+
+    #[test]
+    fn reported_mid_ramp() {
+        let levels = Levels::default();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let at = |secs| start + Duration::from_secs(secs);
+        let ramp = Ramp::new(8).unwrap();
+        observe(
+            &levels,
+            0,
+            &Message::SetVar(LIGHTING, Group(4), Level(200), ramp),
+            start,
+        );
+        let report = |level| Message::LevelStatus(LIGHTING, vec![(Group(4), Level(level))]);
+        observe(&levels, 0, &report(60), at(2));
+        let g = levels.lock().unwrap()[&(0, 4)].clone();
+        assert_eq!((&g.current, &g.target), (&Level(60), &Level(200)));
+        assert_eq!((g.deadline, g.updated), (at(8), at(2)));
+        assert_eq!(g.level_at(at(5)), Level(130));
+
+        // once the ramp is over, a report is the level
+        observe(&levels, 0, &report(190), at(9));
+        let g = levels.lock().unwrap()[&(0, 4)].clone();
+        assert_eq!(
+            (g.current, g.target, g.deadline),
+            (Level(190), Level(190), at(9))
+        );
+    }
+
+    #[test]
+    fn saved_and_loaded() {
+        let path = std::env::temp_dir().join(format!("lights-state-{}.toml", std::process::id()));
+        assert!(load(&path, NOW).unwrap().lock().unwrap().is_empty());
+        let levels = Levels::default();
+        observe(&levels, 2, &set(Group(7), Level(0x80)), NOW);
+        save(&path, &levels).unwrap();
+        let loaded = load(&path, NOW).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(*loaded.lock().unwrap(), *levels.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn polled_on_connect() {
+        let clock = Arc::new(Manual::default());
+        let bus = Bus::new(ChannelConfig::default());
+        let mut requests = bus.messages("pci");
+        let config = StateConfig {
+            groups: [4, 30],
+            ..StateConfig::default()
+        };
+        let levels = Levels::default();
+        let daemon = tokio::spawn(state_daemon(
+            bus.events("state"),
+            bus.messages("state sent"),
+            bus.outbound.clone(),
+            levels.clone(),
+            config,
+            clock.clone(),
+        ));
+        bus.inbound
+            .publish(Source::Cbus, Event::Connected(1))
+            .unwrap();
+        for block in [4, 16, 28] {
+            let request = (
+                1,
+                Message::LevelRequest(LIGHTING, Group(block)),
+                Priority::Low,
+            );
+            assert_eq!(requests.recv().await, Some(request));
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(requests.recv().now_or_never(), None);
+            clock.advance(POLL_PACE);
+        }
+        let report = Message::LevelStatus(LIGHTING, vec![(Group(5), Level(0x40))]);
+        bus.inbound
+            .publish(Source::Cbus, Event::Cbus(1, report))
+            .unwrap();
+        drop(bus);
+        daemon.await.unwrap();
+        assert_eq!(levels.lock().unwrap()[&(1, 5)].target, Level(0x40));
+    }
+
+    #[tokio::test]
+    async fn reflected_when_sent() {
+        let bus = Bus::new(ChannelConfig::default());
+        let config = StateConfig {
+            poll: false,
+            ..StateConfig::default()
+        };
+        let levels = Levels::default();
+        observe(&levels, 0, &set(Group(4), OFF), NOW);
+        let daemon = tokio::spawn(state_daemon(
+            bus.events("state"),
+            bus.messages("state sent"),
+            bus.outbound.clone(),
+            levels.clone(),
+            config,
+            Arc::new(System),
+        ));
+        bus.outbound
+            .send((0, set(Group(4), ON), Priority::Low))
+            .unwrap();
+        let reflected = async {
+            while levels.lock().unwrap()[&(0, 4)].target != ON {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), reflected)
+            .await
+            .unwrap();
+        // until a report says otherwise
+        let report = Message::LevelStatus(LIGHTING, vec![(Group(4), Level(0x20))]);
+        bus.inbound
+            .publish(Source::Cbus, Event::Cbus(0, report))
+            .unwrap();
+        drop(bus);
+        daemon.await.unwrap();
+        assert_eq!(levels.lock().unwrap()[&(0, 4)].target, Level(0x20));
+    }
+
+    #[tokio::test]
+    async fn forgotten_when_failed() {
+        let bus = Bus::new(ChannelConfig::default());
+        let config = StateConfig {
+            poll: false,
+            ..StateConfig::default()
+        };
+        let levels = Levels::default();
+        observe(&levels, 0, &set(Group(4), OFF), NOW);
+        observe(&levels, 0, &set(Group(5), OFF), NOW);
+        observe(&levels, 1, &set(Group(4), OFF), NOW);
+        let daemon = tokio::spawn(state_daemon(
+            bus.events("state"),
+            bus.messages("state sent"),
+            bus.outbound.clone(),
+            levels.clone(),
+            config,
+            Arc::new(System),
+        ));
+        let failed = Event::Delivery(0, set(Group(4), ON), Outcome::Failed);
+        bus.inbound.publish(Source::Cbus, failed).unwrap();
+        drop(bus);
+        daemon.await.unwrap();
+        let levels = levels.lock().unwrap();
+        assert_eq!(levels.get(&(0, 4)), None);
+        assert_eq!(levels[&(0, 5)].target, OFF);
+        assert_eq!(levels[&(1, 4)].target, OFF);
+    }
+}
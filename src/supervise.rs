@@ -0,0 +1,206 @@
+//! `supervise` restarts daemons that fail.
+//!
+//! A panic in one daemon should not take down the others, in particular
+//! the link to the CBUS.  A supervised daemon is restarted after a delay
+//! that grows while it keeps failing.
+use crate::report::{self, Kind};
+use crate::time::Clock;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use tokio::time::Duration;
+use tokio::{select, task};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, Instrument};
+
+/// Restarts so far, by daemon name.
+pub type Restarts = Arc<Mutex<BTreeMap<&'static str, RestartInfo>>>;
+
+/// The restart history of one daemon.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RestartInfo {
+    pub count: u64,
+    pub last_error: Option<String>,
+}
+
+/// The share of each reconnection delay that is random.
+const RECONNECT_JITTER: f64 = 0.5;
+
+/// Delays between attempts, doubling from `min` up to `max`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+    jitter: f64,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff {
+            min,
+            max,
+            delay: min,
+            jitter: 0.0,
+        }
+    }
+
+    /// Shorten each delay by a random amount, up to `jitter` of it, so
+    /// that clients do not all retry at once.
+    pub fn with_jitter(self, jitter: f64) -> Self {
+        Backoff { jitter, ..self }
+    }
+
+    /// The delay before the next attempt, lengthening the one after.
+    pub fn delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (delay * 2).min(self.max);
+        delay.mul_f64(1.0 - self.jitter * random())
+    }
+
+    /// Start again from the shortest delay.
+    pub fn reset(&mut self) {
+        self.delay = self.min;
+    }
+
+    /// The longest delay, after which a daemon is considered healthy.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+}
+
+/// A number in `[0, 1)` that differs from call to call.
+fn random() -> f64 {
+    let bits = RandomState::new().build_hasher().finish() >> 11;
+    bits as f64 / (1u64 << 53) as f64
+}
+
+/// Delays between connection attempts, in seconds.  A connection that
+/// lasts longer than `max` starts the delays again from `min`.
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Reconnect {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Reconnect { min: 2, max: 60 }
+    }
+}
+
+impl Reconnect {
+    pub fn backoff(&self) -> Backoff {
+        let (min, max) = (Duration::from_secs(self.min), Duration::from_secs(self.max));
+        Backoff::new(min, max).with_jitter(RECONNECT_JITTER)
+    }
+}
+
+/// Run the daemon made by `start` and restart it whenever it ends or
+/// fails, until cancelled.  A daemon that ends after it is cancelled is not
+/// restarted, so this returns once a cancellable daemon has finished.
+/// The delays are timed by `clock`.
+pub async fn supervise<F, T>(
+    name: &'static str,
+    mut backoff: Backoff,
+    restarts: Restarts,
+    cancel: CancellationToken,
+    clock: Arc<dyn Clock>,
+    mut start: F,
+) where
+    F: FnMut() -> T,
+    T: Future<Output = Result<()>> + Send + 'static,
+{
+    loop {
+        let started = clock.now();
+        let res = task::spawn(start().in_current_span()).await;
+        if cancel.is_cancelled() {
+            return;
+        }
+        // panics are reported by the hook
+        let panicked = matches!(&res, Err(e) if e.is_panic());
+        let reason = match res {
+            Ok(Ok(())) => "exited".to_string(),
+            Ok(Err(e)) => format!("failed: {e}"),
+            Err(e) => e.to_string(),
+        };
+        let count = {
+            let mut restarts = restarts.lock().unwrap();
+            let info = restarts.entry(name).or_default();
+            info.count += 1;
+            info.last_error = Some(reason.clone());
+            info.count
+        };
+        if !panicked {
+            report::incident(Kind::Failure, format!("{name} {reason}"));
+        }
+        if clock.now() - started > backoff.max() {
+            backoff.reset();
+        }
+        let delay = backoff.delay();
+        error!(restarts = count, "{name} {reason}, restarting in {delay:?}");
+        select! {
+            _ = cancel.cancelled() => return,
+            _ = clock.sleep(delay) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::System;
+
+    #[test]
+    fn doubles_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jittered() {
+        let mut backoff = Reconnect { min: 4, max: 8 }.backoff();
+        let first = backoff.delay();
+        assert!(first > Duration::from_secs(2) && first <= Duration::from_secs(4));
+        for _ in 0..10 {
+            let delay = backoff.delay();
+            assert!(delay > Duration::from_secs(4) && delay <= Duration::from_secs(8));
+        }
+    }
+
+    #[tokio::test]
+    async fn restarted_after_panic() {
+        let restarts = Restarts::default();
+        let cancel = CancellationToken::new();
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(10));
+        let mut runs = 0;
+        let clock = Arc::new(System);
+        let supervisor = supervise("flaky", backoff, restarts.clone(), cancel.clone(), clock, {
+            let cancel = cancel.clone();
+            move || {
+                runs += 1;
+                let run = runs;
+                let cancel = cancel.clone();
+                async move {
+                    if run < 3 {
+                        panic!("run {run}");
+                    }
+                    cancel.cancel();
+                    Ok(())
+                }
+            }
+        });
+        supervisor.await;
+        let info = restarts.lock().unwrap()["flaky"].clone();
+        assert_eq!(info.count, 2);
+        assert!(info.last_error.unwrap().contains("panic"));
+    }
+}
@@ -0,0 +1,198 @@
+//! `systemd` tells the service manager how the daemon is doing.
+//!
+//! Under a `Type=notify` unit, systemd counts the service as started
+//! once it sends `READY=1`, which we do when every PCI is connected.
+//! With `WatchdogSec=` set, systemd restarts the service if `WATCHDOG=1`
+//! stops arriving.  Outside systemd `NOTIFY_SOCKET` is unset and
+//! notifications are skipped.
+use crate::bus::{Filter, Subscriber};
+use crate::daemon::Daemon;
+use crate::{Envelope, Event, Network, Outbound, Source};
+use futures_util::future::{BoxFuture, FutureExt};
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::Path;
+use tokio::select;
+use tokio::sync::broadcast::Sender;
+use tokio::time::{interval, Duration};
+use tracing::{info, warn};
+
+/// Send `state`, such as `READY=1`, to the service manager, if any.
+pub fn notify(state: &str) -> io::Result<()> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => notify_to(&path, state),
+        None => Ok(()),
+    }
+}
+
+/// Send `state` to the socket at `path`, which is abstract if it
+/// starts with `@`.
+fn notify_to(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => {
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?
+        }
+        None => socket.send_to(state.as_bytes(), path)?,
+    };
+    Ok(())
+}
+
+/// The watchdog timeout systemd expects us to ping within, if any.
+pub fn watchdog_timeout() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok();
+    let pid = std::env::var("WATCHDOG_PID").ok();
+    parse_watchdog(usec.as_deref(), pid.as_deref(), std::process::id())
+}
+
+/// The watchdog timeout, unless it is meant for another process.
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, me: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != me {
+            return None;
+        }
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+fn notify_or_warn(state: &str) {
+    if let Err(e) = notify(state) {
+        warn!("cannot notify systemd of {state}: {e}");
+    }
+}
+
+/// Report ready once each of `networks` has connected.
+async fn readiness(mut events: Subscriber<Envelope>, networks: Vec<Network>) {
+    let mut waiting: BTreeSet<Network> = networks.into_iter().collect();
+    while let Some(event) = events.event().await {
+        if let Event::Connected(n) = event {
+            if waiting.remove(&n) && waiting.is_empty() {
+                info!("connected, ready");
+                notify_or_warn("READY=1\nSTATUS=connected to the CBUS");
+            }
+        }
+    }
+}
+
+/// Ping the watchdog at half its timeout, for as long as the runtime
+/// is able to schedule us.
+async fn watchdog(timeout: Option<Duration>) {
+    let Some(timeout) = timeout else {
+        return std::future::pending().await;
+    };
+    let mut ticks = interval(timeout / 2);
+    loop {
+        ticks.tick().await;
+        notify_or_warn("WATCHDOG=1");
+    }
+}
+
+/// Keep systemd informed until the event channel closes.
+pub async fn systemd_daemon(events: Subscriber<Envelope>, networks: Vec<Network>) {
+    select! {
+        _ = readiness(events, networks) => (),
+        _ = watchdog(watchdog_timeout()) => (),
+    }
+}
+
+/// Notifications for `networks` as a pluggable daemon.
+pub struct Notifier(pub Vec<Network>);
+
+impl Daemon for Notifier {
+    fn name(&self) -> &'static str {
+        "systemd"
+    }
+
+    fn filter(&self) -> Filter {
+        Filter::all().source(Source::Cbus)
+    }
+
+    fn run(&self, events: Subscriber<Envelope>, _: Sender<Outbound>) -> BoxFuture<'static, ()> {
+        systemd_daemon(events, self.0.clone()).boxed()
+    }
+}
+
+/// A `Type=notify` unit that runs `exe` with `args`.
+pub fn unit_file(exe: &Path, args: &[String]) -> String {
+    let mut command = quote(&exe.to_string_lossy());
+    for arg in args {
+        command.push(' ');
+        command.push_str(&quote(arg));
+    }
+    format!(
+        "\
+[Unit]
+Description=Lights, a home automation server for CBUS
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+ExecStart={command}
+ExecReload=/bin/kill -HUP $MAINPID
+Restart=on-failure
+WatchdogSec=30
+
+[Install]
+WantedBy=multi-user.target
+"
+    )
+}
+
+/// Quote a word for `ExecStart=`, escaping systemd's specifiers.
+fn quote(word: &str) -> String {
+    let word = word.replace('%', "%%").replace('$', "$$");
+    if word.is_empty() || word.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c)) {
+        format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        word
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_to_abstract_socket() {
+        let name = format!("lights-test-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        notify_to(OsStr::new(&format!("@{name}")), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+
+    #[test]
+    fn watchdog_for_this_process() {
+        let timeout = Some(Duration::from_secs(30));
+        assert_eq!(parse_watchdog(Some("30000000"), None, 7), timeout);
+        assert_eq!(parse_watchdog(Some("30000000"), Some("7"), 7), timeout);
+        assert_eq!(parse_watchdog(Some("30000000"), Some("8"), 7), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 7), None);
+        assert_eq!(parse_watchdog(None, Some("7"), 7), None);
+    }
+
+    #[test]
+    fn unit_runs_exe() {
+        let args = ["--config".to_string(), "/etc/my lights.toml".to_string()];
+        let unit = unit_file(Path::new("/usr/bin/lights"), &args);
+        assert!(unit.contains("Type=notify\n"));
+        assert!(unit.contains("ExecStart=/usr/bin/lights --config \"/etc/my lights.toml\"\n"));
+    }
+
+    #[test]
+    fn quoted() {
+        assert_eq!(quote("plain"), "plain");
+        assert_eq!(quote("100%"), "100%%");
+        assert_eq!(quote("a \"b\""), "\"a \\\"b\\\"\"");
+        assert_eq!(quote(""), "\"\"");
+    }
+}
@@ -0,0 +1,184 @@
+//! `tap` records the raw traffic on the link for debugging.
+//!
+//! Each line in either direction is written to a file with a timestamp,
+//! `>` for inbound and `<` for outbound, as in the log.
+//! The file is rotated when it grows large, keeping one old file.
+use bytes::{Buf, BytesMut};
+use chrono::Local;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::warn;
+
+/// Rotate the tap file once it reaches this size.
+const TAP_FILE_LEN: u64 = 8 * 1024 * 1024;
+
+/// The longest partial line held before it is recorded anyway.
+const TAP_LINE_LEN: usize = 1024;
+
+/// The format of the timestamp that begins each record.
+pub const TIMESTAMP: &str = "%Y-%m-%dT%H:%M:%S%.3f";
+
+/// A handle for recording lines, cheap to clone.
+#[derive(Debug, Clone)]
+pub struct WireTap {
+    records: UnboundedSender<String>,
+}
+
+impl WireTap {
+    /// A tap and the records it produces.
+    pub fn new() -> (WireTap, UnboundedReceiver<String>) {
+        let (records, rx) = mpsc::unbounded_channel();
+        (WireTap { records }, rx)
+    }
+
+    /// A tap recording to the file at `path`.
+    pub fn start(path: PathBuf) -> WireTap {
+        let (tap, records) = WireTap::new();
+        tokio::spawn(async move {
+            let res = tap_file(path, records).await;
+            warn!("wire tap stopped: {res:?}");
+        });
+        tap
+    }
+
+    fn record(&self, direction: char, line: &[u8]) {
+        let now = Local::now().format(TIMESTAMP);
+        let _ = self
+            .records
+            .send(format!("{now} {direction} {}\n", line.escape_ascii()));
+    }
+}
+
+async fn open(path: &PathBuf) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let len = file.metadata().await?.len();
+    Ok((file, len))
+}
+
+/// Append records to the file, rotating it as needed.
+async fn tap_file(path: PathBuf, mut records: UnboundedReceiver<String>) -> io::Result<()> {
+    let (mut file, mut len) = open(&path).await?;
+    while let Some(record) = records.recv().await {
+        if len >= TAP_FILE_LEN {
+            file.flush().await?;
+            let mut old = path.clone().into_os_string();
+            old.push(".1");
+            fs::rename(&path, old).await?;
+            (file, len) = open(&path).await?;
+        }
+        file.write_all(record.as_bytes()).await?;
+        len += record.len() as u64;
+    }
+    file.flush().await
+}
+
+/// A connection, or either half of one, whose traffic is recorded.
+pub struct Tapped<S> {
+    inner: S,
+    tap: WireTap,
+    direction: char,
+    partial: BytesMut,
+}
+
+impl<S> Tapped<S> {
+    /// Tap `inner`, labelling its lines with `direction`.
+    pub fn new(inner: S, tap: WireTap, direction: char) -> Self {
+        Tapped {
+            inner,
+            tap,
+            direction,
+            partial: BytesMut::new(),
+        }
+    }
+
+    /// Record each complete line in `data` and hold any remainder.
+    fn observe(&mut self, data: &[u8]) {
+        self.partial.extend_from_slice(data);
+        while let Some(n) = self.partial.iter().position(|c| *c == b'\r' || *c == b'\n') {
+            if n > 0 {
+                self.tap.record(self.direction, &self.partial[..n]);
+            }
+            self.partial.advance(n + 1);
+        }
+        if self.partial.len() > TAP_LINE_LEN {
+            self.tap.record(self.direction, &self.partial);
+            self.partial.clear();
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tapped<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.observe(&buf.filled()[start..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tapped<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.observe(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn records_lines() {
+        let (tap, mut records) = WireTap::new();
+        let mut inp = Tapped::new(Cursor::new(b"86FF00\r\ng.\r\npart"), tap.clone(), '>');
+        let mut out = Tapped::new(Vec::new(), tap, '<');
+        io::copy(&mut inp, &mut out).await.unwrap();
+        drop((inp, out));
+
+        let mut lines = Vec::new();
+        while let Some(record) = records.recv().await {
+            let (_, line) = record.split_once(' ').unwrap();
+            lines.push(line.to_string());
+        }
+        lines.sort();
+        assert_eq!(lines, ["< 86FF00\n", "< g.\n", "> 86FF00\n", "> g.\n"]);
+    }
+
+    #[tokio::test]
+    async fn escaped() {
+        let (tap, mut records) = WireTap::new();
+        let mut inp = Tapped::new(Cursor::new(b"\x05\xff\n"), tap, '>');
+        inp.read_to_end(&mut Vec::new()).await.unwrap();
+        let record = records.recv().await.unwrap();
+        assert!(record.ends_with(" > \\x05\\xff\n"));
+    }
+}
@@ -0,0 +1,89 @@
+//! `throttle` limits the rate of outbound messages.
+//!
+//! The bus carries only a few dozen messages a second and the PCI's
+//! buffer overruns if a scene sends many at once.
+use serde::Deserialize;
+use tokio::time::{Duration, Instant};
+
+/// A sustained rate with allowance for a burst.
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+pub struct RateLimit {
+    /// Messages per second, sustained.
+    pub per_sec: u32,
+    /// Messages that may be sent at once after a quiet period.
+    pub burst: u32,
+}
+
+/// A token bucket enforcing a `RateLimit`.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket that starts full.
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            limit,
+            tokens: f64::from(limit.burst.max(1)),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let capacity = f64::from(self.limit.burst.max(1));
+        self.tokens = (self.tokens + elapsed * f64::from(self.limit.per_sec)).min(capacity);
+        self.updated = now;
+    }
+
+    /// Take a token or, if there are none, return when there will be one.
+    pub fn take(&mut self, now: Instant) -> Result<(), Instant> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / f64::from(self.limit.per_sec.max(1));
+            Err(now + Duration::from_secs_f64(wait))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_paced() {
+        let now = Instant::now();
+        let limit = RateLimit {
+            per_sec: 10,
+            burst: 3,
+        };
+        let mut bucket = TokenBucket::new(limit, now);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(now), Ok(()));
+        }
+        let later = bucket.take(now).unwrap_err();
+        assert_eq!(later, now + Duration::from_millis(100));
+        assert_eq!(bucket.take(later), Ok(()));
+        assert!(bucket.take(later).is_err());
+    }
+
+    #[test]
+    fn refills_to_burst() {
+        let now = Instant::now();
+        let limit = RateLimit {
+            per_sec: 10,
+            burst: 2,
+        };
+        let mut bucket = TokenBucket::new(limit, now);
+        let now = now + Duration::from_secs(60);
+        assert_eq!(bucket.take(now), Ok(()));
+        assert_eq!(bucket.take(now), Ok(()));
+        assert!(bucket.take(now).is_err());
+    }
+}
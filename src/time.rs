@@ -0,0 +1,159 @@
+//! `time` is where the daemons get the time and wait for it.
+//!
+//! Timeouts, pacing, backoff and schedules go through a [`Clock`] so that
+//! tests can run them on a [`Manual`] clock, advancing it by hours in an
+//! instant, rather than waiting in real time.  The clock is carried by
+//! the bus, which also uses it to stamp events.
+use futures_util::future::{BoxFuture, FutureExt};
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+
+/// A source of the time.
+pub trait Clock: Debug + Send + Sync {
+    /// The time for measuring intervals.
+    fn now(&self) -> Instant;
+
+    /// The time of day.
+    fn wall(&self) -> SystemTime;
+
+    /// Wait until `now()` reaches `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+
+    /// Wait for `duration`.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.sleep_until(self.now() + duration)
+    }
+}
+
+/// The clock of the system, through the runtime.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct System;
+
+impl Clock for System {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        tokio::time::sleep_until(deadline).boxed()
+    }
+}
+
+/// A clock that stands still until advanced.
+#[derive(Debug, Clone)]
+pub struct Manual {
+    start: Instant,
+    wall: SystemTime,
+    elapsed: Arc<watch::Sender<Duration>>,
+}
+
+impl Manual {
+    /// A clock that starts at `wall`.
+    pub fn new(wall: SystemTime) -> Self {
+        Manual {
+            start: Instant::now(),
+            wall,
+            elapsed: Arc::new(watch::channel(Duration::ZERO).0),
+        }
+    }
+
+    /// Move the time on by `duration`, waking the sleepers now due.
+    pub fn advance(&self, duration: Duration) {
+        let elapsed = *self.elapsed.borrow() + duration;
+        self.elapsed.send_replace(elapsed);
+    }
+}
+
+impl Default for Manual {
+    fn default() -> Self {
+        Manual::new(SystemTime::UNIX_EPOCH)
+    }
+}
+
+impl Clock for Manual {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.borrow()
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.wall + *self.elapsed.borrow()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let start = self.start;
+        let mut elapsed = self.elapsed.subscribe();
+        async move {
+            while start + *elapsed.borrow_and_update() < deadline {
+                // the sender lives as long as any clock that could advance
+                if elapsed.changed().await.is_err() {
+                    std::future::pending().await
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// How long after the epoch `time` is, or zero if it is before.
+pub fn since_epoch(time: SystemTime) -> Duration {
+    let since = time.duration_since(SystemTime::UNIX_EPOCH);
+    since.unwrap_or_default()
+}
+
+/// The output of `future`, or none if `duration` passes first.
+pub async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    select! {
+        out = future => Some(out),
+        _ = clock.sleep(duration) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    #[test]
+    fn advanced() {
+        let clock = Manual::default();
+        let start = clock.now();
+        let mut nap = clock.sleep(Duration::from_secs(60));
+        let mut long = clock.sleep_until(start + Duration::from_secs(3600));
+        assert!((&mut nap).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(clock.now(), start + Duration::from_secs(60));
+        assert_eq!(
+            clock.wall(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(60)
+        );
+        assert!(nap.now_or_never().is_some());
+        assert!((&mut long).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(3600));
+        assert!(long.now_or_never().is_some());
+    }
+
+    #[test]
+    fn timed_out() {
+        let clock = Manual::default();
+        let mut waiting =
+            timeout(&clock, Duration::from_secs(5), std::future::pending::<()>()).boxed();
+        assert!((&mut waiting).now_or_never().is_none());
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(waiting.now_or_never(), Some(None));
+    }
+}
@@ -0,0 +1,197 @@
+//! `transport` opens connections to a PCI.
+//!
+//! A [`Transport`] is a way of reaching a PCI: over TCP, through a
+//! serial port, on a simulated network or from a recording.  Each
+//! connection is a pair of byte streams.  The session reads lines from
+//! the input with `busio`, writes frames to the output and closes the
+//! output when done, so tests can substitute a transport of their own.
+use crate::busio::{self, Telnet};
+use crate::config::{self, CbusConfig, RemoteSerial};
+use crate::proxy::ProxyConfig;
+use crate::replay::Replay;
+use crate::simulate::VirtualNetwork;
+use crate::tunnel::{self, SshConfig, TlsConfig};
+use crate::Result;
+use futures_util::future::{BoxFuture, FutureExt};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_serial::SerialPortBuilderExt;
+
+/// The half of a connection the PCI sends on.
+pub type Input = Box<dyn AsyncRead + Unpin + Send>;
+
+/// The half of a connection the PCI receives on.
+pub type Output = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// A way to connect to a PCI.
+pub trait Transport: Send + Sync {
+    /// Open a new connection.  This is called again to reconnect.
+    fn connect(&self) -> BoxFuture<'_, Result<(Input, Output)>>;
+}
+
+/// A PCI on the network, such as a CNI.
+#[derive(Debug, Clone)]
+pub struct Tcp {
+    pub host: String,
+    /// Connect here rather than looking up `host`.
+    pub address: Option<IpAddr>,
+    pub port: u16,
+    pub ssh: Option<SshConfig>,
+    pub proxy: Option<ProxyConfig>,
+    pub tls: Option<TlsConfig>,
+    pub telnet: bool,
+    pub remote_serial: Option<RemoteSerial>,
+}
+
+impl Transport for Tcp {
+    fn connect(&self) -> BoxFuture<'_, Result<(Input, Output)>> {
+        async move {
+            let stream = tunnel::connect(
+                &self.host,
+                self.address,
+                self.port,
+                self.ssh.as_ref(),
+                self.proxy.as_ref(),
+                self.tls.as_ref(),
+            )
+            .await?;
+            let (input, mut output) = io::split(stream);
+            if !self.telnet {
+                return Ok((Box::new(input) as Input, Box::new(output) as Output));
+            }
+            output.write_all(&busio::TELNET_OFFER).await?;
+            if let Some(remote) = self.remote_serial {
+                let setup = busio::com_port_setup(remote.baud, remote.flow);
+                output.write_all(&setup).await?;
+            }
+            Ok((
+                Box::new(Telnet::new(input)) as Input,
+                Box::new(Telnet::new(output)) as Output,
+            ))
+        }
+        .boxed()
+    }
+}
+
+/// A PCI on a serial port.
+#[derive(Debug, Clone)]
+pub struct Serial {
+    pub device: String,
+    pub baud: u32,
+}
+
+impl Transport for Serial {
+    fn connect(&self) -> BoxFuture<'_, Result<(Input, Output)>> {
+        async move {
+            let port = tokio_serial::new(&self.device, self.baud)
+                .open_native_async()
+                .map_err(io::Error::from)?;
+            let (input, output) = io::split(port);
+            Ok((Box::new(input) as Input, Box::new(output) as Output))
+        }
+        .boxed()
+    }
+}
+
+/// A PCI on a virtual network, whose lights keep their levels from one
+/// connection to the next.
+#[derive(Debug, Clone, Default)]
+pub struct Simulated(pub VirtualNetwork);
+
+impl Transport for Simulated {
+    fn connect(&self) -> BoxFuture<'_, Result<(Input, Output)>> {
+        let (input, output) = io::split(self.0.connect());
+        async move { Ok((Box::new(input) as Input, Box::new(output) as Output)) }.boxed()
+    }
+}
+
+/// Input recorded by `trace_wire`, played back, with output discarded.
+#[derive(Debug, Clone)]
+pub struct Replayed {
+    pub file: PathBuf,
+    pub speed: f64,
+}
+
+impl Transport for Replayed {
+    fn connect(&self) -> BoxFuture<'_, Result<(Input, Output)>> {
+        async move {
+            let input = Replay::open(&self.file, self.speed).await?;
+            Ok((Box::new(input) as Input, Box::new(io::sink()) as Output))
+        }
+        .boxed()
+    }
+}
+
+/// The transport described by `config`.
+pub fn from_config(config: &CbusConfig) -> Arc<dyn Transport> {
+    match &config.transport {
+        config::Transport::Tcp { host, port } => Arc::new(Tcp {
+            host: host.clone(),
+            address: config.address,
+            port: *port,
+            ssh: config.ssh.clone(),
+            proxy: config.proxy.clone(),
+            tls: config.tls.clone(),
+            telnet: config.telnet,
+            remote_serial: config.remote_serial,
+        }),
+        config::Transport::Serial { device, baud } => Arc::new(Serial {
+            device: device.clone(),
+            baud: *baud,
+        }),
+        config::Transport::Simulate => Arc::new(Simulated::default()),
+        config::Transport::Replay { file, speed } => Arc::new(Replayed {
+            file: file.clone(),
+            speed: *speed,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, ChannelConfig};
+    use crate::health::Health;
+    use crate::session::{cbus_daemon, Link};
+    use tokio::task;
+    use tokio_util::sync::CancellationToken;
+
+    /// A PCI that cannot be reached.
+    struct Unplugged;
+
+    impl Transport for Unplugged {
+        fn connect(&self) -> BoxFuture<'_, Result<(Input, Output)>> {
+            async { Err(io::Error::from(io::ErrorKind::ConnectionRefused).into()) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn injected() {
+        let health = Health::default();
+        let cancel = CancellationToken::new();
+        let daemon = task::spawn(cbus_daemon(
+            0,
+            CbusConfig::default(),
+            Arc::new(Unplugged),
+            Bus::new(ChannelConfig::default()),
+            Link {
+                health: health.clone(),
+                ..Link::default()
+            },
+            cancel.clone(),
+        ));
+        let link = loop {
+            let link = health.lock().unwrap().get(&0).cloned();
+            match link {
+                Some(link) if link.retry_secs.is_some() => break link,
+                _ => task::yield_now().await,
+            }
+        };
+        assert!(!link.connected);
+        assert!(link.last_error.unwrap().contains("refused"));
+        cancel.cancel();
+        daemon.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,388 @@
+//! `tunnel` protects the TCP connection to a CNI on an untrusted network.
+//!
+//! The connection can be made through an SSH server near the CNI, by
+//! running `ssh -W`, and can be wrapped in TLS, for a CNI behind a TLS
+//! terminating proxy such as stunnel.  With both, TLS runs inside the
+//! SSH tunnel.  Instead of SSH, a SOCKS5 or HTTP proxy can be used, see
+//! `proxy`.
+//!
+//! A host with several addresses, such as a dual-stack CNI, is tried at
+//! each of them in the manner of happy eyeballs (RFC 8305), so that one
+//! unreachable address does not hold up the connection.  The host is
+//! looked up afresh on every connection, so a CNI that moves to a new
+//! address is found again on reconnecting; otherwise a fixed `address`
+//! can be given in place of looking it up.
+use crate::proxy::{self, ProxyConfig};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::select;
+use tokio::time::{sleep, timeout, Duration};
+use tracing::debug;
+
+/// How long an attempt to connect has before the next address is tried
+/// alongside it.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long to wait for any one address.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TLS settings, from `[cbus.tls]`.
+#[derive(PartialEq, Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificates of the authorities trusted to sign the server's.
+    pub ca: PathBuf,
+    /// The name the server's certificate is for, if not the host.
+    pub server_name: Option<String>,
+    /// A PEM certificate chain to present to the server, with `key`.
+    pub cert: Option<PathBuf>,
+    /// The PEM private key for `cert`.
+    pub key: Option<PathBuf>,
+}
+
+/// An SSH server that can reach the CNI, from `[cbus.ssh]`.
+#[derive(PartialEq, Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SshConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// The private key to log in with, otherwise ssh's default.
+    pub identity: Option<PathBuf>,
+}
+
+/// A bidirectional byte stream.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Connect to `host` and `port`, or to `address` in place of looking up
+/// `host`, through `ssh` or `proxy` if given, then start TLS if
+/// configured.
+pub async fn connect(
+    host: &str,
+    address: Option<IpAddr>,
+    port: u16,
+    ssh: Option<&SshConfig>,
+    proxy: Option<&ProxyConfig>,
+    tls: Option<&TlsConfig>,
+) -> io::Result<Box<dyn Stream>> {
+    let target = address.map_or_else(|| host.to_string(), |ip| ip.to_string());
+    let stream: Box<dyn Stream> = match (ssh, proxy, address) {
+        (Some(ssh), _, _) => Box::new(SshTunnel::open(ssh, &target, port)?),
+        (None, Some(proxy), _) => Box::new(proxy::open(proxy, &target, port).await?),
+        (None, None, Some(ip)) => Box::new(attempt(SocketAddr::new(ip, port)).await?),
+        (None, None, None) => Box::new(connect_tcp(host, port).await?),
+    };
+    match tls {
+        #[cfg(feature = "tls")]
+        Some(tls) => Ok(Box::new(tls::start(stream, host, tls).await?)),
+        #[cfg(not(feature = "tls"))]
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "built without tls",
+        )),
+        None => Ok(stream),
+    }
+}
+
+/// Connect to whichever of the addresses of `host` answers first.
+pub async fn connect_tcp(host: &str, port: u16) -> io::Result<TcpStream> {
+    let addrs = lookup_host((host, port))
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("{host}: {e}")))?;
+    let addrs = interleave(addrs.collect());
+    debug!("{host} is at {addrs:?}");
+    connect_first(addrs).await
+}
+
+/// `addrs` reordered to alternate between IPv6 and IPv4, starting with
+/// the family of the first.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(|a| a.is_ipv6());
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut other = other.into_iter();
+    let mut ordered = Vec::new();
+    for addr in preferred {
+        ordered.push(addr);
+        ordered.extend(other.next());
+    }
+    ordered.extend(other);
+    ordered
+}
+
+/// One attempt at `addr`, giving up after `ATTEMPT_TIMEOUT`.
+async fn attempt(addr: SocketAddr) -> io::Result<TcpStream> {
+    match timeout(ATTEMPT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(res) => res.map_err(|e| io::Error::new(e.kind(), format!("{addr}: {e}"))),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{addr}: timed out"),
+        )),
+    }
+}
+
+/// Connect to the first of `addrs` to accept, starting on the next
+/// address whenever an attempt fails or is slow, and keeping the
+/// earlier attempts going.  Fails with the last error if none accept.
+async fn connect_first(addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+    let mut addrs = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut error = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+    loop {
+        if attempts.is_empty() {
+            match addrs.next() {
+                Some(addr) => attempts.push(attempt(addr)),
+                None => return Err(error),
+            }
+        }
+        select! {
+            Some(res) = attempts.next() => match res {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = e,
+            },
+            _ = sleep(ATTEMPT_DELAY), if addrs.len() > 0 => {
+                attempts.extend(addrs.next().map(attempt));
+            }
+        }
+    }
+}
+
+/// The arguments to `ssh` to forward its standard input and output
+/// to `host` and `port`.
+fn ssh_args(config: &SshConfig, host: &str, port: u16) -> Vec<String> {
+    let mut args = vec!["-q".into(), "-o".into(), "BatchMode=yes".into()];
+    args.extend(["-W".into(), format!("{host}:{port}")]);
+    if let Some(port) = config.port {
+        args.extend(["-p".into(), port.to_string()]);
+    }
+    if let Some(user) = &config.user {
+        args.extend(["-l".into(), user.clone()]);
+    }
+    if let Some(identity) = &config.identity {
+        args.extend(["-i".into(), identity.to_string_lossy().into_owned()]);
+    }
+    args.extend(["--".into(), config.host.clone()]);
+    args
+}
+
+/// A connection forwarded by an `ssh` process, which is killed when
+/// this is dropped.
+pub struct SshTunnel {
+    _child: Child,
+    input: ChildStdout,
+    output: ChildStdin,
+}
+
+impl SshTunnel {
+    pub fn open(config: &SshConfig, host: &str, port: u16) -> io::Result<SshTunnel> {
+        let mut child = Command::new("ssh")
+            .args(ssh_args(config, host, port))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("ssh: {e}")))?;
+        let input = child.stdout.take().expect("piped stdout");
+        let output = child.stdin.take().expect("piped stdin");
+        Ok(SshTunnel {
+            _child: child,
+            input,
+            output,
+        })
+    }
+}
+
+impl AsyncRead for SshTunnel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.input).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SshTunnel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.output).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.output).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.output).poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature = "tls")]
+mod tls {
+    use super::{Stream, TlsConfig};
+    use crate::secret;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tokio::io;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use tokio_rustls::rustls::{crypto, ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+    use tracing::warn;
+
+    /// Start TLS on `stream` to `host`.
+    pub async fn start<S: Stream>(
+        stream: S,
+        host: &str,
+        config: &TlsConfig,
+    ) -> io::Result<TlsStream<S>> {
+        let name = config.server_name.as_deref().unwrap_or(host);
+        let name = ServerName::try_from(name.to_string()).map_err(invalid)?;
+        let connector = TlsConnector::from(Arc::new(client_config(config)?));
+        connector.connect(name, stream).await
+    }
+
+    fn invalid(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+
+    fn open(path: &Path) -> io::Result<BufReader<File>> {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+    }
+
+    fn certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+        let certs = rustls_pemfile::certs(&mut open(path)?).collect::<io::Result<Vec<_>>>()?;
+        if certs.is_empty() {
+            let msg = format!("{}: no certificates", path.display());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        Ok(certs)
+    }
+
+    fn private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+        let mut file = open(path)?;
+        if let Err(e) = secret::check_private(path) {
+            warn!("tls key {e}");
+        }
+        rustls_pemfile::private_key(&mut file)?.ok_or_else(|| {
+            let msg = format!("{}: no private key", path.display());
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })
+    }
+
+    /// The TLS client settings, reading the files named in `config`.
+    fn client_config(config: &TlsConfig) -> io::Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        for cert in certificates(&config.ca)? {
+            roots.add(cert).map_err(invalid)?;
+        }
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_root_certificates(roots);
+        match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(certificates(cert)?, private_key(key)?)
+                .map_err(invalid),
+            _ => Ok(builder.with_no_client_auth()),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn missing_ca() {
+            let config = TlsConfig {
+                ca: "/nonexistent/ca.pem".into(),
+                server_name: None,
+                cert: None,
+                key: None,
+            };
+            let e = client_config(&config).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::NotFound);
+            assert!(e.to_string().contains("/nonexistent/ca.pem"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn interleaved() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let order: Vec<String> = interleave(addrs).iter().map(|a| a.to_string()).collect();
+        assert_eq!(
+            order,
+            ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        // nothing listens on a port just released
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr();
+        let closed = closed.unwrap();
+        let stream = connect_first(vec![closed, open]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+
+        let e = connect_first(vec![closed]).await.unwrap_err();
+        assert!(e.to_string().starts_with(&closed.to_string()));
+        assert!(connect_first(Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fixed_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        // the host is not looked up
+        let host = "cni.invalid";
+        assert!(connect(host, None, open.port(), None, None, None)
+            .await
+            .is_err());
+        let stream = connect(host, Some(open.ip()), open.port(), None, None, None);
+        assert!(stream.await.is_ok());
+    }
+
+    #[test]
+    fn ssh_command() {
+        let config = SshConfig {
+            host: "gateway".into(),
+            port: Some(2222),
+            user: Some("lights".into()),
+            identity: None,
+        };
+        let args = ssh_args(&config, "cni", 10001).join(" ");
+        assert_eq!(
+            args,
+            "-q -o BatchMode=yes -W cni:10001 -p 2222 -l lights -- gateway"
+        );
+    }
+}
@@ -0,0 +1,511 @@
+//! `writer` sends messages to the PCI.
+//!
+//! Messages are queued, then framed, paced, rate limited and flushed one at a time.
+//! Confirmations are tracked here and overdue commands are resent.
+use crate::bus::{Inbound, Subscriber};
+use crate::busio::{write_frame, Framing};
+use crate::codec::{self, Code, Message, Outcome, PciOptions, Priority, Setting};
+use crate::confirm::{Confirmations, Expiry, RetryPolicy};
+use crate::echo::Echoes;
+use crate::ha::Role;
+use crate::throttle::{RateLimit, TokenBucket};
+use crate::time::{self, Clock};
+use crate::{Envelope, Event, LightsError, Network, Outbound, Result, Source};
+use bytes::BytesMut;
+use std::collections::VecDeque;
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tokio::select;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// How the writer frames and paces its output.
+#[derive(Debug, Clone)]
+pub struct WriterConfig {
+    /// The network of the PCI written to.  Messages for others are ignored.
+    pub network: Network,
+    pub policy: RetryPolicy,
+    pub framing: Framing,
+    /// The interface options, set again if the PCI restarts.
+    pub options: PciOptions,
+    /// The minimum time between frames.
+    pub pace: Duration,
+    /// The most messages held waiting to be sent.
+    pub queue_len: usize,
+    /// A limit on the rate of frames, if any.
+    pub rate: Option<RateLimit>,
+    /// Messages are only sent while active, see `ha`.
+    pub role: Role,
+}
+
+/// A message waiting to be sent, its priority and, if it is a resend,
+/// its code.
+type Queued = (Message, Priority, Option<Code>);
+
+/// Send the preamble one command at a time so each can be framed.
+pub async fn write_preamble<O>(
+    output: &mut O,
+    framing: Framing,
+    options: &PciOptions,
+) -> io::Result<()>
+where
+    O: AsyncWrite + Unpin,
+{
+    let preamble = codec::preamble(options);
+    for command in preamble.split_inclusive(|c| *c == b'\r' || *c == b'~') {
+        write_frame(output, framing, command).await?
+    }
+    output.flush().await
+}
+
+/// Attempts at the handshake before the session is abandoned.
+const HANDSHAKE_ATTEMPTS: u32 = 3;
+
+/// Reset and configure the PCI, checking its responses, and return the
+/// options it accepted.
+///
+/// The reset must be acknowledged and each option setting confirmed
+/// within `timeout` on `clock`, otherwise the whole exchange is tried
+/// again.  A PCI that refuses smart mode or extended status is
+/// configured again with `options.basic()`.  `replies` must be
+/// subscribed before the input is read.
+pub async fn handshake<O>(
+    output: &mut O,
+    framing: Framing,
+    options: &PciOptions,
+    network: Network,
+    replies: &mut Subscriber<Envelope>,
+    timeout: Duration,
+    clock: &dyn Clock,
+) -> Result<PciOptions>
+where
+    O: AsyncWrite + Unpin,
+{
+    let mut options = options.clone();
+    let mut attempt = 1;
+    loop {
+        match try_handshake(output, framing, &options, network, replies, timeout, clock).await {
+            Ok(()) => {
+                info!("PCI in {} mode", options.mode());
+                return Ok(options);
+            }
+            Err(LightsError::Refused(mesg @ Message::SetParam(..)))
+                if options.basic() != options =>
+            {
+                warn!("PCI refused {mesg}, falling back to basic mode");
+                options = options.basic();
+            }
+            Err(e) if attempt < HANDSHAKE_ATTEMPTS => {
+                warn!("handshake failed: {e}, retrying");
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn try_handshake<O>(
+    output: &mut O,
+    framing: Framing,
+    options: &PciOptions,
+    network: Network,
+    replies: &mut Subscriber<Envelope>,
+    timeout: Duration,
+    clock: &dyn Clock,
+) -> Result<()>
+where
+    O: AsyncWrite + Unpin,
+{
+    let mut code = Code::default();
+    for mesg in codec::preamble_messages(options) {
+        let confirm = mesg.is_confirmable().then_some(code);
+        if confirm.is_some() {
+            code = code.succ();
+        }
+        let command = codec::encode_confirmed(mesg.clone(), &Setting::new(0), confirm);
+        write_frame(output, framing, &command).await?;
+        output.flush().await?;
+
+        let reply = async {
+            loop {
+                match (replies.event().await, confirm) {
+                    (Some(Event::Cbus(n, Message::ResetAck)), None) if n == network => {
+                        return Ok(())
+                    }
+                    (Some(Event::Cbus(n, Message::Confirmation(c, outcome))), Some(code))
+                        if n == network && c == code =>
+                    {
+                        return match outcome {
+                            Outcome::Delivered => Ok(()),
+                            Outcome::Failed => Err(LightsError::Refused(mesg.clone())),
+                        }
+                    }
+                    (None, _) => return Err(LightsError::Refused(mesg.clone())),
+                    _ => (),
+                }
+            }
+        };
+        match time::timeout(clock, timeout, reply).await {
+            Some(res) => res?,
+            None => {
+                return Err(LightsError::Timeout(format!("no response to {mesg}")));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Continuously write outbound messages to the PCI.
+///
+/// When cancelled, any queued messages are written without awaiting
+/// confirmation and the output is flushed and shut down.
+/// An error writing to `output` is returned.
+/// Deliveries are reported on `inbound` and confirmations read from `replies`.
+pub async fn write_messages<O>(
+    mut outbound: Subscriber<Outbound>,
+    inbound: Inbound,
+    mut replies: Subscriber<Envelope>,
+    config: WriterConfig,
+    cancel: CancellationToken,
+    echoes: Echoes,
+    mut output: O,
+) -> Result<()>
+where
+    O: AsyncWrite + Unpin,
+{
+    let options = config.options.options1.clone();
+    let mut pending = Confirmations::new(config.policy.clone());
+    let mut queue: VecDeque<Queued> = VecDeque::with_capacity(config.queue_len);
+    let mut buf = BytesMut::with_capacity(64);
+    let clock = inbound.clock().clone();
+    let mut ready = clock.now();
+    let mut bucket = config.rate.map(|r| TokenBucket::new(r, ready));
+
+    async fn expiry(clock: &dyn Clock, deadline: Option<Instant>) {
+        match deadline {
+            Some(d) => clock.sleep_until(d).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn take_token(bucket: &mut Option<TokenBucket>, now: Instant) -> Result<(), Instant> {
+        match bucket {
+            Some(b) => b.take(now),
+            None => Ok(()),
+        }
+    }
+
+    async fn turn(clock: &dyn Clock, ready: Instant, waiting: bool) {
+        if waiting {
+            clock.sleep_until(ready).await
+        } else {
+            std::future::pending().await
+        }
+    }
+
+    loop {
+        select! {
+            _ = cancel.cancelled() => {
+                for (mesg, priority, code) in queue.drain(..) {
+                    info!("sent {mesg} while closing");
+                    buf.clear();
+                    codec::encode_into(&mesg, &options, priority, code, &mut buf);
+                    write_frame(&mut output, config.framing, &buf).await?;
+                }
+                output.flush().await?;
+                return Ok(output.shutdown().await?);
+            },
+            res = outbound.recv() => if let Some((network, mesg, priority)) = res {
+                if network != config.network {
+                    continue;
+                }
+                if !config.role.is_active() {
+                    debug!("standing by, not sending {mesg}");
+                    continue;
+                }
+                if queue.len() < config.queue_len {
+                    queue.push_back((mesg, priority, None));
+                } else {
+                    warn!("writer: queue full, dropping {mesg}");
+                    let _ = inbound.publish(Source::Cbus, Event::Delivery(config.network, mesg, Outcome::Failed));
+                }
+            },
+            _ = turn(&*clock, ready, !queue.is_empty()) => if let Err(later) = take_token(&mut bucket, clock.now()) {
+                ready = later;
+            } else if let Some((mesg, priority, resend)) = queue.pop_front() {
+                let code = match resend {
+                    Some(code) => {
+                        info!(?code, "resent {mesg}");
+                        Some(code)
+                    }
+                    None => {
+                        let code = pending.allocate(&mesg, priority, clock.now());
+                        info!(?code, "sent {mesg}");
+                        code
+                    }
+                };
+                buf.clear();
+                codec::encode_into(&mesg, &options, priority, code, &mut buf);
+                write_frame(&mut output, config.framing, &buf).await?;
+                output.flush().await?;
+                echoes.lock().unwrap().sent(&buf, &mesg, clock.now());
+                ready = clock.now() + config.pace;
+            },
+            res = replies.event() => match res {
+                Some(Event::Cbus(n, Message::Confirmation(code, outcome))) if n == config.network => {
+                    if let Some(mesg) = pending.resolve(&code) {
+                        let _ = inbound.publish(Source::Cbus, Event::Delivery(config.network, mesg, outcome));
+                    }
+                }
+                Some(Event::Cbus(n, Message::PciError | Message::PowerUp)) if n == config.network => {
+                    // the PCI has lost sync or restarted: re-initialise it
+                    warn!("PCI error or power up, re-initialising");
+                    write_preamble(&mut output, config.framing, &config.options).await?
+                }
+                _ => ()
+            },
+            _ = expiry(&*clock, pending.deadline()) => {
+                for expired in pending.expire(clock.now()) {
+                    match expired {
+                        // resends go ahead of new messages
+                        Expiry::Resend(code, mesg, priority) => queue.push_front((mesg, priority, Some(code))),
+                        Expiry::GiveUp(mesg) => {
+                            warn!("unconfirmed: {mesg}");
+                            let _ = inbound.publish(Source::Cbus, Event::Delivery(config.network, mesg, Outcome::Failed));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, ChannelConfig};
+    use crate::echo::EchoFilter;
+    use crate::time::System;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::AsyncReadExt;
+
+    fn echoes() -> Echoes {
+        Arc::new(Mutex::new(EchoFilter::new(Duration::from_secs(2))))
+    }
+
+    #[tokio::test]
+    async fn queued_and_confirmed() {
+        let bus = Bus::new(ChannelConfig::default());
+        let mut events = bus.events("test");
+        let (inbound, outbound) = (bus.inbound.clone(), bus.outbound.clone());
+        let (output, mut pci) = io::duplex(256);
+        let config = WriterConfig {
+            network: 0,
+            policy: RetryPolicy::default(),
+            framing: Framing::Ascii,
+            options: PciOptions::default(),
+            pace: Duration::from_millis(1),
+            queue_len: 4,
+            rate: None,
+            role: Role::default(),
+        };
+        let cancel = CancellationToken::new();
+        tokio::spawn(write_messages(
+            bus.messages("writer"),
+            inbound.clone(),
+            bus.events("confirmations"),
+            config,
+            cancel,
+            echoes(),
+            output,
+        ));
+
+        let on = Message::set(4).on().build().unwrap();
+        let other = Message::set(5).on().build().unwrap();
+        outbound.send((1, other, Priority::Low)).unwrap();
+        outbound.send((0, on.clone(), Priority::Low)).unwrap();
+        let mut frame = [0u8; 15];
+        pci.read_exact(&mut frame).await.unwrap();
+        assert_eq!(&frame, b"\\053800790446g\r");
+
+        let g = Code::new(b'g').unwrap();
+        inbound
+            .publish(
+                Source::Cbus,
+                Event::Cbus(0, Message::Confirmation(g, Outcome::Delivered)),
+            )
+            .unwrap();
+        loop {
+            if let Some(Event::Delivery(n, m, o)) = events.event().await {
+                assert_eq!((n, m, o), (0, on, Outcome::Delivered));
+                break;
+            }
+        }
+    }
+
+    async fn read_frame(pci: &mut io::DuplexStream) -> Vec<u8> {
+        let mut frame = Vec::new();
+        while frame.last() != Some(&b'\r') && frame != b"~" {
+            frame.push(pci.read_u8().await.unwrap());
+        }
+        frame
+    }
+
+    #[tokio::test]
+    async fn handshake_confirmed() {
+        let bus = Bus::new(ChannelConfig::default());
+        let (inbound, mut replies) = (bus.inbound.clone(), bus.events("handshake"));
+        let (mut output, mut pci) = io::duplex(256);
+        let timeout = Duration::from_secs(1);
+        let pci_side = async {
+            let respond = |m| inbound.publish(Source::Cbus, Event::Cbus(0, m)).unwrap();
+            assert_eq!(read_frame(&mut pci).await, b"~");
+            respond(Message::ResetAck);
+            assert_eq!(read_frame(&mut pci).await, b"@A342000Fg\r");
+            respond(Message::Confirmation(
+                Code::new(b'g').unwrap(),
+                Outcome::Delivered,
+            ));
+            assert_eq!(read_frame(&mut pci).await, b"@A3300079h\r");
+            respond(Message::Confirmation(
+                Code::new(b'h').unwrap(),
+                Outcome::Delivered,
+            ));
+        };
+        let options = PciOptions::default();
+        let (res, _) = tokio::join!(
+            handshake(
+                &mut output,
+                Framing::Ascii,
+                &options,
+                0,
+                &mut replies,
+                timeout,
+                &System
+            ),
+            pci_side
+        );
+        assert_eq!(res.unwrap(), options);
+    }
+
+    #[tokio::test]
+    async fn handshake_basic() {
+        let bus = Bus::new(ChannelConfig::default());
+        let (inbound, mut replies) = (bus.inbound.clone(), bus.events("handshake"));
+        let (mut output, mut pci) = io::duplex(256);
+        let timeout = Duration::from_secs(1);
+        let pci_side = async {
+            let respond = |m| inbound.publish(Source::Cbus, Event::Cbus(0, m)).unwrap();
+            let confirm = |c, outcome| Message::Confirmation(Code::new(c).unwrap(), outcome);
+            // an older PCI refuses extended status
+            assert_eq!(read_frame(&mut pci).await, b"~");
+            respond(Message::ResetAck);
+            assert_eq!(read_frame(&mut pci).await, b"@A342000Fg\r");
+            respond(confirm(b'g', Outcome::Failed));
+            assert_eq!(read_frame(&mut pci).await, b"~");
+            respond(Message::ResetAck);
+            assert_eq!(read_frame(&mut pci).await, b"@A3420007g\r");
+            respond(confirm(b'g', Outcome::Delivered));
+            assert_eq!(read_frame(&mut pci).await, b"@A3300069h\r");
+            respond(confirm(b'h', Outcome::Delivered));
+        };
+        let options = PciOptions::default();
+        let (res, _) = tokio::join!(
+            handshake(
+                &mut output,
+                Framing::Ascii,
+                &options,
+                0,
+                &mut replies,
+                timeout,
+                &System
+            ),
+            pci_side
+        );
+        let options = res.unwrap();
+        assert_eq!(options, PciOptions::default().basic());
+        assert_eq!(options.mode(), "basic");
+    }
+
+    #[tokio::test]
+    async fn handshake_retried() {
+        let bus = Bus::new(ChannelConfig::default());
+        let (inbound, mut replies) = (bus.inbound.clone(), bus.events("handshake"));
+        let (mut output, mut pci) = io::duplex(256);
+        let timeout = Duration::from_millis(50);
+        let pci_side = async {
+            // the first reset is answered only by another PCI
+            assert_eq!(read_frame(&mut pci).await, b"~");
+            inbound
+                .publish(Source::Cbus, Event::Cbus(1, Message::ResetAck))
+                .unwrap();
+            assert_eq!(read_frame(&mut pci).await, b"~");
+            inbound
+                .publish(Source::Cbus, Event::Cbus(0, Message::ResetAck))
+                .unwrap();
+            read_frame(&mut pci).await;
+            let g = Code::new(b'g').unwrap();
+            let refusal = Message::Confirmation(g, Outcome::Failed);
+            inbound
+                .publish(Source::Cbus, Event::Cbus(0, refusal))
+                .unwrap();
+        };
+        let options = PciOptions::default();
+        let (res, _) = tokio::join!(
+            handshake(
+                &mut output,
+                Framing::Ascii,
+                &options,
+                0,
+                &mut replies,
+                timeout,
+                &System
+            ),
+            pci_side
+        );
+        assert!(matches!(res, Err(LightsError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn flushed_when_cancelled() {
+        let bus = Bus::new(ChannelConfig::default());
+        let outbound = bus.outbound.clone();
+        let (output, mut pci) = io::duplex(256);
+        let config = WriterConfig {
+            network: 0,
+            policy: RetryPolicy::default(),
+            framing: Framing::Ascii,
+            options: PciOptions::default(),
+            pace: Duration::from_secs(60),
+            queue_len: 4,
+            rate: None,
+            role: Role::default(),
+        };
+        let cancel = CancellationToken::new();
+        let writer = tokio::spawn(write_messages(
+            bus.messages("writer"),
+            bus.inbound.clone(),
+            bus.events("confirmations"),
+            config,
+            cancel.clone(),
+            echoes(),
+            output,
+        ));
+
+        outbound
+            .send((0, Message::set(4).on().build().unwrap(), Priority::Low))
+            .unwrap();
+        outbound
+            .send((0, Message::set(5).on().build().unwrap(), Priority::High))
+            .unwrap();
+        let mut frame = [0u8; 15];
+        pci.read_exact(&mut frame).await.unwrap();
+        tokio::task::yield_now().await;
+        cancel.cancel();
+        writer.await.unwrap().unwrap();
+
+        let mut rest = Vec::new();
+        pci.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"\\8538007905C5\r");
+    }
+}